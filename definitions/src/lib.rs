@@ -1,8 +1,10 @@
 //! Just a crate with structs in common for other workspace crates.
 mod api;
+mod plant;
 mod queue;
 mod robot;
 
 pub use api::*;
+pub use plant::*;
 pub use queue::*;
 pub use robot::*;
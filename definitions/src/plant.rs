@@ -0,0 +1,31 @@
+use chrono::NaiveDate;
+use serde::{Deserialize, Serialize};
+
+use crate::Vec3;
+
+pub type PlantId = u32;
+
+/// A plant in the garden, as stored in the plant registry.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Plant {
+    pub id: PlantId,
+    #[serde(flatten)]
+    pub data: PlantData,
+}
+
+/// Everything about a [Plant] that can be chosen by the user, i.e. all but its id.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PlantData {
+    /// A name to distinguish this plant from the others, e.g. "Tomato near the fence".
+    pub name: String,
+    /// The species of the plant, e.g. "Solanum lycopersicum".
+    pub species: String,
+    /// Where the plant is, in world coordinates (see
+    /// [RobotState::position](crate::RobotState::position)). The robot will move the end
+    /// effector here to water the plant.
+    pub position: Vec3,
+    /// For how many milliseconds to keep the water open when watering this plant.
+    pub watering_duration_ms: u64,
+    /// The day the plant was planted.
+    pub planted_date: NaiveDate,
+}
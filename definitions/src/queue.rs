@@ -2,6 +2,8 @@ use std::path::PathBuf;
 
 use serde::{Deserialize, Serialize};
 
+use crate::GatingRule;
+
 #[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct QueueState {
     pub paused: bool,
@@ -10,18 +12,40 @@ pub struct QueueState {
     pub save_dir: PathBuf,
     pub running_id: Option<ActionId>,
     pub actions: Vec<ActionInfo>,
+    /// The [GatingRule]s that currently apply, i.e. why the queue is not running some or all
+    /// actions even if it is not [paused](QueueState::paused).
+    #[serde(default)]
+    pub gates: Vec<Gate>,
+}
+
+/// A [GatingRule] that currently applies.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Gate {
+    pub rule: GatingRule,
+    /// A human readable explanation, e.g. "Battery level 8% is below 10%".
+    pub reason: String,
 }
 
 #[derive(Debug, Copy, Clone, PartialEq, Default, Serialize, Deserialize)]
 pub enum EmergencyStatus {
+    /// No emergency, the queue runs normally.
     #[default]
     None,
+    /// An emergency was triggered, the running action is being interrupted.
     WaitingForReset,
+    /// The robot is being put in a safe position.
     Resetting,
+    /// The robot is in a safe position, and the queue is waiting for the emergency to be
+    /// cleared manually before resuming.
+    WaitingForClear,
 }
 
 pub type ActionId = u32;
 
+/// Actions with a higher priority are executed first, and preempt running actions with a lower
+/// priority. Actions with the same priority are executed in the order they were added.
+pub type Priority = i32;
+
 #[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct ActionInfo {
     pub id: ActionId,
@@ -29,6 +53,17 @@ pub struct ActionInfo {
     pub save_dir: PathBuf,
     pub is_running: bool,
     pub progress: StepProgress,
+    pub priority: Priority,
+}
+
+/// Like [ActionInfo], but with additional details that are not included in [QueueState] to keep
+/// it small.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ActionDetails {
+    #[serde(flatten)]
+    pub info: ActionInfo,
+    /// All of the errors returned by the action while it was running, oldest first.
+    pub errors: Vec<String>,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone, Default)]
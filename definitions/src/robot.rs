@@ -1,3 +1,4 @@
+use chrono::NaiveDateTime;
 use serde::{Deserialize, Serialize};
 
 #[derive(Debug, Clone, Default, Serialize, Deserialize)]
@@ -31,9 +32,15 @@ pub struct RobotState {
     pub errors: Errors,
     /// Parameters, sizes and settings that determine how the robot is controlled.
     pub parameters: Parameters,
+    /// The name of the currently selected tool among [Parameters::tools], or `None` if no tool
+    /// is selected (i.e. [RobotState::position] has no displacement).
+    pub tool: Option<String>,
+    /// The most recent events sent by the embedded devices on their own (i.e. not as a
+    /// response to a message), oldest first.
+    pub events: Vec<DeviceEvent>,
 }
 
-#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
 pub struct Vec3 {
     pub x: f32,
     pub y: f32,
@@ -77,7 +84,37 @@ pub struct Errors {
     pub peripherals: Option<String>,
 }
 
+/// Something that an embedded device notified on its own, see [RobotState::events].
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DeviceEvent {
+    pub received_at: NaiveDateTime,
+    /// Which device sent the event, i.e. one of `motor_x`, `motor_y`, `motor_z`, `peripherals`
+    /// (or more of them joined by `/`, if they are handled by the same device).
+    pub device: String,
+    pub kind: DeviceEventKind,
+    /// A human readable description of the event, including any detail sent by the device.
+    pub description: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum DeviceEventKind {
+    /// A limit switch was hit while the motor was not resetting.
+    LimitSwitchHit,
+    DriverFault,
+    EncoderMismatch,
+    LimitSwitchTimeout,
+    Stall,
+    InvalidArgument,
+    /// Any other error reported by the device.
+    OtherError,
+    /// An actuator (water, lights, pump or plow) was turned off after its cooldown expired.
+    CooldownExpired,
+}
+
+/// Fields missing when deserializing are taken from [Parameters::default], so that parameter
+/// files written before a field was added can still be read.
 #[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
 pub struct Parameters {
     /// Length of the rotating arm, in meters.
     pub arm_length: f32,
@@ -95,6 +132,92 @@ pub struct Parameters {
     pub water_scale_max: u32,
     /// The capacity of the water tank in liters.
     pub water_tank_liters: f32,
+    /// When watering a specific amount of liters, for how many milliseconds the water level may
+    /// stay unchanged before giving up (e.g. because the tank is empty or the pipe is clogged).
+    pub water_no_flow_timeout_ms: u64,
+
+    /// For how many milliseconds to wait for the motors to reach their target when moving,
+    /// before giving up.
+    pub move_timeout_ms: u64,
+    /// Regions that the Z axis (from the end effector up to the arm) must never pass through
+    /// when moving, e.g. tall plants, a shed or the water tank.
+    pub keep_out_volumes: Vec<KeepOutVolume>,
+    /// The `z` at which the end effector travels when the direct path to the target is blocked
+    /// (see [RobotState::position] for the coordinates), which should be above all
+    /// [Parameters::keep_out_volumes] that are not meant to be walked around.
+    pub safe_travel_height: f32,
+
+    /// The tools that can be attached to the end effector, see [RobotState::tool].
+    pub tools: Vec<Tool>,
+
+    /// Rules to stop the queue from running actions while the battery or the water is low, see
+    /// [QueueState::gates](crate::QueueState::gates).
+    pub gating_rules: Vec<GatingRule>,
+
+    /// The [DeviceEvent]s that trigger an emergency as soon as they are received.
+    pub emergency_events: Vec<DeviceEventKind>,
+}
+
+/// An axis-aligned box in world coordinates (see [RobotState::position]).
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct KeepOutVolume {
+    /// Just to recognize this volume, e.g. "water tank".
+    pub name: String,
+    /// The corner with the smallest `x`, `y` and `z`.
+    pub min: Vec3,
+    /// The corner with the largest `x`, `y` and `z`.
+    pub max: Vec3,
+}
+
+/// A tool that can be attached to the end effector.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Tool {
+    /// Identifies this tool, must be unique among [Parameters::tools].
+    pub name: String,
+    /// The displacement of the tip of the tool from the end effector without tools, in meters:
+    /// * `x`: along the arm, pointing away from the tower
+    /// * `y`: horizontal and perpendicular to the arm, forms a right-handed system of coordinates
+    /// * `z`: points up
+    pub offset: Vec3,
+    /// The peripheral that only works when this tool is attached (e.g. [Peripheral::Plow] for
+    /// the plow), or `None` if the tool does not need any.
+    pub peripheral: Option<Peripheral>,
+    /// The identifier that the peripherals report when this tool is physically attached, or
+    /// `None` if the tool can't be detected.
+    pub hardware_id: Option<u8>,
+}
+
+/// A level measured by the robot, as a proportion between 0.0 and 1.0.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum GatedLevel {
+    /// See [BatteryLevel::proportion].
+    Battery,
+    /// See [WaterLevel::proportion].
+    Water,
+}
+
+/// Stops the queue from running some or all actions while a level is low.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct GatingRule {
+    pub level: GatedLevel,
+    /// The rule starts applying when the level drops below this value.
+    pub below: f32,
+    /// Once applying, the rule stops applying only when the level rises above this value. Should
+    /// be higher than `below`, so that the queue does not keep pausing and resuming when the
+    /// level fluctuates (e.g. the battery voltage while the motors are moving).
+    pub resume_above: f32,
+    /// The type names of the actions not to run while the rule applies (e.g. `"water_all"`), or
+    /// empty to pause the whole queue. Only the type of the action itself is checked, so e.g.
+    /// `"command_list"` or `"script"` must be listed too to skip those that water.
+    pub action_types: Vec<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Peripheral {
+    Water,
+    Lights,
+    Pump,
+    Plow,
 }
 
 /// DO NOT CHANGE THE VALUES HERE, they are just some sensible defaults for tests and for when
@@ -109,6 +232,13 @@ impl Default for Parameters {
             water_scale_min: 8590000,
             water_scale_max: 9140000,
             water_tank_liters: 10.0,
+            water_no_flow_timeout_ms: 10000,
+            move_timeout_ms: 60000,
+            keep_out_volumes: vec![],
+            safe_travel_height: 0.0, // meters, i.e. completely retracted
+            tools: vec![],
+            gating_rules: vec![], // opt-in, since checking the levels keeps polling the devices
+            emergency_events: vec![DeviceEventKind::LimitSwitchHit, DeviceEventKind::DriverFault],
         }
     }
 }
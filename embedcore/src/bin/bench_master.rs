@@ -0,0 +1,60 @@
+//! Measures how many requests per second a [Master] gets answered by a slave over the serial
+//! simulated by [Testable], with different amounts of requests in flight at the same time.
+//! The rate of corrupted and omitted bytes can be passed as arguments, e.g.
+//! `cargo run --release --bin bench_master -- 0.001 0.001`.
+use std::{sync::Arc, time::Duration};
+
+use embedcore::protocol::{cyber::Master, test_harness::{new_testable_slave, Testable}};
+use tokio::time::Instant;
+
+const REQUESTS: usize = 10000;
+const TIMEOUT: Duration = Duration::from_millis(20);
+const RESEND_TIMES: u8 = 10;
+
+#[tokio::main]
+async fn main() {
+    let mut args = std::env::args()
+        .skip(1)
+        .map(|arg| arg.parse::<f64>().expect("Arguments must be numbers between 0 and 1"));
+    let error_rate = args.next().unwrap_or(0.0);
+    let omission_rate = args.next().unwrap_or(0.0);
+
+    println!("Sending {REQUESTS} requests with error rate {error_rate} and omission rate {omission_rate}");
+    for in_flight in [1, 2, 4, 8, 16] {
+        bench(error_rate, omission_rate, in_flight).await;
+    }
+}
+
+async fn bench(error_rate: f64, omission_rate: f64, in_flight: usize) {
+    let (master, slave) = Testable::new(error_rate, omission_rate);
+    let master = Arc::new(Master::new(master, TIMEOUT, RESEND_TIMES));
+    let mut slave = new_testable_slave(slave, *b"bench     ");
+    let slave_task = tokio::spawn(async move { slave.run().await });
+
+    let start = Instant::now();
+    let tasks: Vec<_> = (0..in_flight)
+        .map(|i| {
+            let master = master.clone();
+            tokio::spawn(async move {
+                let mut failed = 0;
+                for _ in (i..REQUESTS).step_by(in_flight) {
+                    if master.get_motor_state().await.is_err() {
+                        failed += 1;
+                    }
+                }
+                failed
+            })
+        })
+        .collect();
+    let mut failed = 0;
+    for task in tasks {
+        failed += task.await.unwrap();
+    }
+    let elapsed = start.elapsed();
+    slave_task.abort();
+
+    println!(
+        "{in_flight:>2} in flight: {:>7.0} requests/s, {failed} failed",
+        REQUESTS as f64 / elapsed.as_secs_f64(),
+    );
+}
@@ -5,7 +5,7 @@ use defmt_or_log::{error, trace};
 use serde::{Deserialize, Serialize};
 use serialmessage::{ParseState, SerMsg};
 
-use crate::protocol::cyber::Response;
+use crate::protocol::cyber::{DeviceError, Response};
 
 use super::AsyncSerial;
 /// Communication wrapper, it shouldn't be used directly.
@@ -55,8 +55,11 @@ pub enum CommunicationError {
     Timeout,
     SerMsgError,
     UnsupportedResponse,
-    ErrorResponse([u8; 10]),
+    ErrorResponse(DeviceError),
     MismatchedResponse(Response),
+    /// The device replied to [Message::WhoAreYou](crate::protocol::cyber::Message::WhoAreYou)
+    /// with a [PROTOCOL_VERSION](crate::protocol::cyber::PROTOCOL_VERSION) different from ours.
+    IncompatibleVersion { expected: u8, actual: u8 },
 }
 
 // TODO timeout shouldn't be handled here anymore, remove every reference to it
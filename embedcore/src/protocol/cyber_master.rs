@@ -1,10 +1,18 @@
+#[cfg(feature = "std")]
+extern crate std;
+
 use core::marker::PhantomData;
 
-use crate::protocol::{communication::CommunicationError, cyber::{DeviceIdentifier, MotorState, PeripheralsState}};
+use crate::protocol::{communication::CommunicationError, cyber::{DeviceIdentifier, Event, MotorState, PeripheralsState, Request, SlaveMessage, PROTOCOL_VERSION}};
 use core::fmt::Debug;
-use defmt_or_log::{debug, trace};
+use defmt_or_log::{debug, trace, warn};
 use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, mutex::Mutex};
+use portable_atomic::{AtomicU32, Ordering};
 use serde::Deserialize;
+#[cfg(feature = "std")]
+use std::collections::{HashMap, VecDeque};
+#[cfg(feature = "std")]
+use tokio::sync::{broadcast, oneshot, Notify, Semaphore};
 
 use super::{
     AsyncSerial,
@@ -12,29 +20,61 @@ use super::{
     cyber_protocol::{Message, Response},
 };
 
+/// How many events are kept for each [Master::subscribe_events] receiver that is lagging behind.
+#[cfg(feature = "std")]
+const EVENTS_CAPACITY: usize = 64;
+
 // this inner struct is behind a mutex. It should be possible to have multiple read-only references to the master struct and be able to send/read messages.
 pub struct InnerMaster<Serial: AsyncSerial> {
     /// Communication wrapper
     com: Communication<Serial>,
-    /// Last sent message id, before sending it get's increased by one until overflow appens, and then restarts from 0.
-    id: u8,
+    /// Id of the last [SlaveMessage::Event] received, to detect lost events.
+    event_id: Option<u8>,
 }
 
 // TODO handle errors
 impl<Serial: AsyncSerial> InnerMaster<Serial> {
-    /// increments id by one, and then sends a message
-    async fn send(&mut self, m: Message) -> Result<(), CommunicationError> {
-        self.id = self.id.wrapping_add(1);
-        trace!("InnerMaster: sending message {:?} with id {}", m, self.id);
-        self.com.send(m, self.id).await
+    /// sends a request, responses are matched to it using [Request::seq]
+    async fn send(&mut self, request: &Request) -> Result<(), CommunicationError> {
+        trace!("InnerMaster: sending message {:?} with seq {}", request.message, request.seq);
+        // the frame id is not used to match responses, it's just there for debugging
+        self.com.send(request, request.seq as u8).await
     }
 
     ///tries to read a message
     async fn try_read<Out: for<'a> Deserialize<'a>>(&mut self) -> Result<(u8, Out), CommunicationError> {
         self.com.try_read().await
     }
+
+    /// Reads messages until a [SlaveMessage::Response] arrives, passing any
+    /// [SlaveMessage::Event] received in the meantime to `on_event`. Returns the
+    /// [Request::seq] of the request being responded to, along with the response.
+    async fn read_response(&mut self, on_event: impl Fn(Event)) -> Result<(u32, Response), CommunicationError> {
+        loop {
+            match self.try_read::<SlaveMessage>().await? {
+                (_, SlaveMessage::Response { seq, response }) => return Ok((seq, response)),
+                (id, SlaveMessage::Event(event)) => {
+                    debug!("InnerMaster: received event {:?} with id {}", event, id);
+                    if let Some(prev_id) = self.event_id {
+                        let lost = id.wrapping_sub(prev_id).wrapping_sub(1);
+                        if lost != 0 {
+                            warn!("InnerMaster: {} events were lost before event with id {}", lost, id);
+                        }
+                    }
+                    self.event_id = Some(id);
+                    on_event(event);
+                }
+            }
+        }
+    }
 }
 
+/// Sends [Message]s to a [Slave](crate::protocol::cyber::Slave) and waits for their responses.
+///
+/// On std, many requests can be outstanding at the same time: each one is written to the serial
+/// as soon as possible, and then waits for its own response (matched using [Request::seq]) for
+/// at most `timeout`, before being resent. While waiting, one of the requests takes care of
+/// reading from the serial and of handing the responses out to all of the others.
 pub struct Master<Serial: AsyncSerial> {
     /// first phantom data, nothing important
     ph: PhantomData<Serial>,
@@ -42,9 +82,44 @@ pub struct Master<Serial: AsyncSerial> {
     inner: Mutex<CriticalSectionRawMutex, InnerMaster<Serial>>,
     /// how many times should a message be resent? Bigger numbers means better communication but possibly slower.
     resend_times: u8,
+    /// The [Request::seq] of the last request, increased by one for every new request.
+    seq: AtomicU32,
     /// how much time should we wait for a message, before trying to resend it?
     #[cfg(feature = "std")]
     timeout: core::time::Duration,
+    /// Where received [SlaveMessage::Event]s are sent, see [Master::subscribe_events].
+    #[cfg(feature = "std")]
+    events: broadcast::Sender<Event>,
+    /// The requests waiting for a response, each with the channel to send the response to.
+    #[cfg(feature = "std")]
+    pending: std::sync::Mutex<HashMap<u32, oneshot::Sender<Response>>>,
+    /// The requests to write to the serial, as soon as someone holds [Master::inner].
+    #[cfg(feature = "std")]
+    outgoing: std::sync::Mutex<VecDeque<Request>>,
+    /// Notified whenever a request is added to [Master::outgoing], so that whoever is holding
+    /// [Master::inner] to read from the serial writes it.
+    #[cfg(feature = "std")]
+    outgoing_added: Notify,
+    /// Limits the requests waiting for a response to
+    /// [MAX_IN_FLIGHT](crate::protocol::cyber::MAX_IN_FLIGHT), since the slave only remembers
+    /// that many responses to replay them to resent requests.
+    #[cfg(feature = "std")]
+    in_flight: Semaphore,
+}
+
+/// Removes a request from [Master::pending] when dropped, i.e. when the caller of
+/// `send_message()` stops waiting for it for whatever reason.
+#[cfg(feature = "std")]
+struct PendingRequest<'a> {
+    pending: &'a std::sync::Mutex<HashMap<u32, oneshot::Sender<Response>>>,
+    seq: u32,
+}
+
+#[cfg(feature = "std")]
+impl Drop for PendingRequest<'_> {
+    fn drop(&mut self) {
+        self.pending.lock().unwrap().remove(&self.seq);
+    }
 }
 
 macro_rules! match_response {
@@ -82,74 +157,167 @@ impl<Serial: AsyncSerial> Master<Serial> {
             ph: PhantomData,
             inner: Mutex::new(InnerMaster {
                 com: Communication::new(serial),
-                id: 0,
+                event_id: None,
             }),
             resend_times,
+            seq: AtomicU32::new(0),
         #[cfg(feature = "std")]
             timeout,
+        #[cfg(feature = "std")]
+            events: broadcast::Sender::new(EVENTS_CAPACITY),
+        #[cfg(feature = "std")]
+            pending: std::sync::Mutex::new(HashMap::new()),
+        #[cfg(feature = "std")]
+            outgoing: std::sync::Mutex::new(VecDeque::new()),
+        #[cfg(feature = "std")]
+            outgoing_added: Notify::new(),
+        #[cfg(feature = "std")]
+            in_flight: Semaphore::new(crate::protocol::cyber::MAX_IN_FLIGHT),
+        }
+    }
+
+    /// Wraps `message` in a [Request] with a new sequence number.
+    fn new_request(&self, message: Message) -> Request {
+        Request {
+            seq: self.seq.fetch_add(1, Ordering::Relaxed).wrapping_add(1),
+            message,
+        }
+    }
+
+    /// Returns a receiver for all of the [SlaveMessage::Event]s received from now on, both while
+    /// waiting for responses and in [`listen_for_events()`](Master::listen_for_events).
+    #[cfg(feature = "std")]
+    pub fn subscribe_events(&self) -> broadcast::Receiver<Event> {
+        self.events.subscribe()
+    }
+
+    /// Events are only read from the serial while the master is waiting for a response, so
+    /// this should be called periodically to receive events even when no message is being sent.
+    /// Reads from the serial for `duration`, then releases it so that messages can be sent.
+    #[cfg(feature = "std")]
+    pub async fn listen_for_events(&self, duration: core::time::Duration) {
+        let deadline = tokio::time::Instant::now() + duration;
+        let mut lock = tokio::select! {
+            lock = self.inner.lock() => lock,
+            // someone else is already reading from the serial
+            _ = tokio::time::sleep_until(deadline) => return,
+        };
+        if let Err(e) = self.serve(&mut lock, deadline, || None::<()>).await {
+            debug!("listen_for_events: {:?}", e);
+        }
+    }
+
+    /// Writes the [Master::outgoing] requests to the serial, and hands the responses read from
+    /// the serial out to the [Master::pending] requests, until `done` returns something. Fails
+    /// with [CommunicationError::Timeout] at `deadline`, or with any error reading/writing.
+    ///
+    /// Communication::try_read() keeps partially read messages in its buffer, so nothing is lost
+    /// when reading is interrupted, while requests are never written only partially.
+    #[cfg(feature = "std")]
+    async fn serve<T>(
+        &self,
+        lock: &mut InnerMaster<Serial>,
+        deadline: tokio::time::Instant,
+        mut done: impl FnMut() -> Option<T>,
+    ) -> Result<T, CommunicationError> {
+        loop {
+            if let Some(result) = done() {
+                return Ok(result);
+            }
+
+            // enable before writing, to not miss requests added in the meantime
+            let outgoing_added = self.outgoing_added.notified();
+            tokio::pin!(outgoing_added);
+            outgoing_added.as_mut().enable();
+            loop {
+                let Some(request) = self.outgoing.lock().unwrap().pop_front() else {
+                    break;
+                };
+                // if this fails the request will just be resent after a timeout
+                lock.send(&request).await?;
+            }
+
+            tokio::select! {
+                res = lock.read_response(|event| { let _ = self.events.send(event); }) => match res {
+                    Ok((seq, response)) => match self.pending.lock().unwrap().remove(&seq) {
+                        Some(sender) => { let _ = sender.send(response); }
+                        // a late response to a request that was already given up on
+                        None => debug!("serve: ignoring response {:?} with seq {}", response, seq),
+                    },
+                    // some bytes were corrupted, the request will just be resent after a timeout
+                    Err(CommunicationError::PostcardError(e)) => debug!("serve: {:?}", e),
+                    Err(e) => return Err(e),
+                },
+                _ = outgoing_added => {}
+                _ = tokio::time::sleep_until(deadline) => return Err(CommunicationError::Timeout),
+            }
         }
     }
 
     #[cfg(feature = "std")]
     async fn send_message(&self, message: Message) -> Result<Response, CommunicationError> {
-        let mut result: Result<Result<_, CommunicationError>, _> = Ok(Err(CommunicationError::Timeout));
-        let mut lock = self.inner.lock().await;
+        // the semaphore is never closed
+        let _in_flight = self.in_flight.acquire().await.unwrap();
+        let request = self.new_request(message);
+        let (sender, mut receiver) = oneshot::channel();
+        self.pending.lock().unwrap().insert(request.seq, sender);
+        let _pending = PendingRequest { pending: &self.pending, seq: request.seq };
+
+        let mut last_error = CommunicationError::Timeout;
         for _ in 0..self.resend_times {
-            let future = async {
-                defmt_or_log::debug!("send_message: sending {:?}", message);
-                lock.send(message.clone()).await?;
-                defmt_or_log::debug!("send_message: sent {:?}", message);
-
-                loop {
-                    let (id_read, msg) = lock.try_read::<Response>().await?;
-                    if id_read != lock.id {
-                        continue;
-                    }
+            defmt_or_log::debug!("send_message: sending {:?}", request);
+            self.outgoing.lock().unwrap().push_back(request.clone());
+            self.outgoing_added.notify_waiters();
+            let deadline = tokio::time::Instant::now() + self.timeout;
 
-                    return Ok(msg)
-                }
+            let result = tokio::select! {
+                lock = self.inner.lock() => {
+                    // nobody else is reading from the serial, so it's up to us
+                    let mut lock = lock;
+                    self.serve(&mut lock, deadline, || receiver.try_recv().ok()).await
+                },
+                // someone else read our response from the serial
+                response = &mut receiver => response.map_err(|_| CommunicationError::Timeout),
+                _ = tokio::time::sleep_until(deadline) => Err(CommunicationError::Timeout),
             };
-            result = tokio::time::timeout(self.timeout, future).await;
-
-            if let Ok(r) = result {
-                if r.is_ok() {
-                    defmt_or_log::debug!("send_message: received {:?}", r);
-                    return r;
-                } else {
-                    result = Ok(r);
+            match result {
+                Ok(response) => {
+                    defmt_or_log::debug!("send_message: received {:?}", response);
+                    return Ok(response);
+                }
+                Err(e) => {
+                    defmt_or_log::debug!("send_message: {:?}", e);
+                    last_error = e;
                 }
             }
-            defmt_or_log::debug!("send_message: timeout");
-        }
-
-        match result {
-            Ok(result) => result,
-            Err(_) => Err(CommunicationError::Timeout),
         }
+        Err(last_error)
     }
 
     // Useless implementation, we don't use Master on embedded (i.e. no-std) anyway.
     #[cfg(not(feature = "std"))]
     async fn send_message(&self, message: Message) -> Result<Response, CommunicationError> {
+        let request = self.new_request(message);
         let mut lock = self.inner.lock().await;
         // will always be overwritten before being returned
         let mut last_error: CommunicationError = CommunicationError::Timeout;
         for _ in 0..self.resend_times {
-            defmt_or_log::debug!("send_message: sending {:?}", message);
-            if let Err(e) = lock.send(message.clone()).await {
+            defmt_or_log::debug!("send_message: sending {:?}", request);
+            if let Err(e) = lock.send(&request).await {
                 last_error = e;
                 continue;
             };
-            defmt_or_log::debug!("send_message: sent {:?}", message);
+            defmt_or_log::debug!("send_message: sent {:?}", request);
 
             // Replace the infinite loop used in the implementation above with a for loop,
             // since we don't have timeouts here.
             // TODO if we end up having Master on embedded (i.e. no-std), properly implement async
             // timeouts.
             for _ in 0..self.resend_times {
-                match lock.try_read::<Response>().await {
-                    Ok((id_read, msg)) => {
-                        if id_read != lock.id {
+                // there is nobody to pass events to on embedded
+                match lock.read_response(|_| {}).await {
+                    Ok((seq, msg)) => {
+                        if seq != request.seq {
                             continue;
                         }
 
@@ -168,12 +336,29 @@ impl<Serial: AsyncSerial> Master<Serial> {
         Err(last_error)
     }
 
-    /// See [Message::WhoAreYou].
+    /// See [Message::WhoAreYou]. Fails with [CommunicationError::IncompatibleVersion] if the
+    /// device speaks a different [PROTOCOL_VERSION], in which case no other message should be sent
+    /// to it.
     pub async fn who_are_you(&self) -> Result<DeviceIdentifier, CommunicationError> {
         debug!("who_are_you(): called");
         match_response!(
             self.send_message(Message::WhoAreYou).await?,
-            Response::IAm(device_identifier) => Ok(device_identifier),
+            Response::IAm(device_identifier) => if device_identifier.version == PROTOCOL_VERSION {
+                Ok(device_identifier)
+            } else {
+                Err(CommunicationError::IncompatibleVersion {
+                    expected: PROTOCOL_VERSION,
+                    actual: device_identifier.version,
+                })
+            },
+        )
+    }
+
+    /// See [Message::Heartbeat].
+    pub async fn heartbeat(&self) -> Result<(), CommunicationError> {
+        match_response!(
+            self.send_message(Message::Heartbeat).await?,
+            Response::Ok => Ok(()),
         )
     }
 
@@ -249,6 +434,14 @@ impl<Serial: AsyncSerial> Master<Serial> {
             Response::Ok => Ok(()),
         )
     }
+
+    /// See [Message::GetAttachedTool].
+    pub async fn get_attached_tool(&self) -> Result<Option<u8>, CommunicationError> {
+        match_response!(
+            self.send_message(Message::GetAttachedTool).await?,
+            Response::AttachedTool(tool) => Ok(tool),
+        )
+    }
 }
 
 ///debug implementation for Master
@@ -2,6 +2,34 @@ use core::fmt::Debug;
 
 use serde::{Deserialize, Serialize};
 
+/// The version of the protocol defined in this file, sent in [DeviceIdentifier::version]. Must be
+/// increased whenever [Message], [Response] or [DeviceIdentifier] change in a way that is not
+/// compatible with devices flashed with an older firmware, since
+/// [Master::who_are_you](crate::protocol::cyber::Master::who_are_you) refuses devices with a
+/// different version.
+pub const PROTOCOL_VERSION: u8 = 5;
+
+/// The firmware build hash sent in [DeviceIdentifier::firmware_hash], i.e. the first 8 bytes of
+/// the `CYBERORTO_FIRMWARE_HASH` environment variable at compile time (e.g. the short git commit
+/// hash), or all zeros if it was not set.
+pub const FIRMWARE_HASH: [u8; 8] = {
+    let mut hash = [0u8; 8];
+    if let Some(env) = option_env!("CYBERORTO_FIRMWARE_HASH") {
+        let env = env.as_bytes();
+        let mut i = 0;
+        while i < hash.len() && i < env.len() {
+            hash[i] = env[i];
+            i += 1;
+        }
+    }
+    hash
+};
+
+/// How many requests the [Master](crate::protocol::cyber::Master) sends at most before receiving
+/// their responses, which is also how many responses the
+/// [Slave](crate::protocol::cyber::Slave) remembers to replay them to resent requests.
+pub const MAX_IN_FLIGHT: usize = 8;
+
 #[repr(u8)]
 #[non_exhaustive]
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
@@ -12,6 +40,10 @@ pub enum Message {
     /// Requests information about the slave. Normally replies with [Response::IAm].
     /// EVERY SLAVE DEVICE IS EXPECTED TO REPLY TO THIS MESSAGE.
     WhoAreYou,
+    /// Just tells the slave that the master is still there, so that it does not call
+    /// [MessagesHandler::on_link_lost]. Normally replies with [Response::Ok].
+    /// EVERY SLAVE DEVICE IS EXPECTED TO REPLY TO THIS MESSAGE.
+    Heartbeat,
 
     // messages only for a slave connected to a motor:
 
@@ -48,13 +80,63 @@ pub enum Message {
     Plow { cooldown_ms: u64 },
     /// Set status led on or off. Normally replies with [Response::Ok].
     SetLed { led: bool },
+    /// Get which tool is physically attached to the end effector.
+    /// Normally replies with [Response::AttachedTool].
+    GetAttachedTool,
+}
+
+impl Message {
+    /// A number that identifies the variant of this message, used as the bit index in
+    /// [Capabilities::messages].
+    pub const fn kind(&self) -> u8 {
+        match self {
+            Message::WhoAreYou => 0,
+            Message::GetMotorState => 1,
+            Message::ResetMotor => 2,
+            Message::MoveMotor { .. } => 3,
+            Message::GetPeripheralsState => 4,
+            Message::Water { .. } => 5,
+            Message::Lights { .. } => 6,
+            Message::Pump { .. } => 7,
+            Message::Plow { .. } => 8,
+            Message::SetLed { .. } => 9,
+            Message::GetAttachedTool => 10,
+            Message::Heartbeat => 11,
+        }
+    }
+
+    /// Whether handling this message changes something on the slave (e.g. opens the water), as
+    /// opposed to just reading its state, so that it must not be handled twice if resent.
+    pub const fn has_effects(&self) -> bool {
+        !matches!(
+            self,
+            Message::WhoAreYou
+                | Message::Heartbeat
+                | Message::GetMotorState
+                | Message::GetPeripheralsState
+                | Message::GetAttachedTool
+        )
+    }
 }
 
-/// Note: there is no hook for [Message::WhoAreYou] here, as that's handled by the
-/// [crate::protocol::cyber_slave::Slave] implementation directly (without passing through the
-/// message handler.
+/// Note: there are no hooks for [Message::WhoAreYou] and [Message::Heartbeat] here, as those are
+/// handled by the [crate::protocol::cyber_slave::Slave] implementation directly (without passing
+/// through the message handler.
 #[allow(unused_variables, async_fn_in_trait)]
 pub trait MessagesHandler {
+    /// Waits for something to happen on the device that the master should know about
+    /// immediately, which is then sent as a [SlaveMessage::Event]. Must be cancel-safe, since
+    /// it is raced against reading the next [Message]. By default never returns.
+    async fn next_event(&mut self) -> Event {
+        core::future::pending().await
+    }
+
+    /// Called when no valid [Message] arrived for longer than the failsafe timeout (see
+    /// [Slave::with_failsafe](crate::protocol::cyber_slave::Slave::with_failsafe)), e.g.
+    /// because the master crashed. Should turn off all actuators and stop the motors where they
+    /// are. Not called again until messages start arriving again. By default does nothing.
+    async fn on_link_lost(&mut self) {}
+
     // functions only for a slave connected to a motor:
 
     async fn get_motor_state(&mut self) -> Response {
@@ -87,11 +169,14 @@ pub trait MessagesHandler {
     async fn set_led(&mut self, state: bool) -> Response {
         Response::Unsupported
     }
+    async fn get_attached_tool(&mut self) -> Response {
+        Response::Unsupported
+    }
 }
 
 #[repr(u8)]
 #[non_exhaustive]
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 #[cfg_attr(feature = "defmt", derive(defmt::Format))]
 /// In our communication protocol we send this structure from the slave to the master.
 /// The master should check if the obtained response is reasonable for the command that it has sent.
@@ -103,7 +188,7 @@ pub enum Response {
     Unsupported,
 
     /// There was an error generating a response to the received [Message].
-    Error([u8; 10]),
+    Error(DeviceError),
 
 
     // responses that could happen in response to only some `Message`s:
@@ -120,13 +205,70 @@ pub enum Response {
 
     /// Response to [Message::GetPeripheralsState].
     PeripheralsState(PeripheralsState),
+
+    /// Response to [Message::GetAttachedTool], with the identifier of the attached tool, or
+    /// `None` if no tool is attached.
+    AttachedTool(Option<u8>),
+}
+
+/// What the master sends to a slave.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Request {
+    /// Increased by one by the master for every new request, but kept the same when resending a
+    /// request after a timeout. Unlike the `u8` id of the serial frames it takes very long to wrap
+    /// around, so a late response can't be mistaken for the response to a newer request.
+    pub seq: u32,
+    pub message: Message,
+}
+
+/// What a slave sends to the master.
+#[derive(Serialize, Deserialize, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum SlaveMessage {
+    /// The response to the [Request] with sequence number `seq`.
+    Response { seq: u32, response: Response },
+    /// Sent without being asked, whenever [MessagesHandler::next_event] returns. Events have
+    /// their own ids, which just count up by one for each event, so that the master can tell
+    /// whether some event was lost.
+    Event(Event),
+}
+
+/// Something that happened on a slave device, sent as a [SlaveMessage::Event] so that the master
+/// can react without waiting until it polls the device again.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Event {
+    /// A limit switch was hit while the motor was not resetting, so the motor is not where it
+    /// thinks it is.
+    LimitSwitchHit,
+    /// An error happened while not handling any [Message], e.g. [DeviceError::DriverFault].
+    Error(DeviceError),
+    /// An actuator turned off by itself because its cooldown expired (see e.g. [Message::Water]).
+    CooldownExpired(Actuator),
+}
+
+/// The actuators handled by the slave with [Role::Peripherals].
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Actuator {
+    Water,
+    Lights,
+    Pump,
+    Plow,
 }
 
 #[derive(Serialize, Deserialize, Clone)]
 #[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub struct DeviceIdentifier {
+    /// A human readable name, only used for debugging.
     pub name: [u8; 10],
+    /// See [PROTOCOL_VERSION].
     pub version: u8,
+    /// What the device can do, used by the master to decide what to use the device for.
+    pub capabilities: Capabilities,
+    /// See [FIRMWARE_HASH].
+    pub firmware_hash: [u8; 8],
 }
 
 impl Debug for DeviceIdentifier {
@@ -137,11 +279,113 @@ impl Debug for DeviceIdentifier {
             Err(_) => f.field("name", &self.name),
         };
         f.field("version", &self.version);
+        f.field("capabilities", &self.capabilities);
+        match str::from_utf8(&self.firmware_hash) {
+            Ok(v) => f.field("firmware_hash", &v),
+            Err(_) => f.field("firmware_hash", &self.firmware_hash),
+        };
         f.finish()
     }
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+/// What a slave device is used for by the master. A device may have more than one role.
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Role {
+    MotorX = 0,
+    MotorY = 1,
+    MotorZ = 2,
+    Peripherals = 3,
+}
+
+impl Role {
+    pub const ALL: [Role; 4] = [Role::MotorX, Role::MotorY, Role::MotorZ, Role::Peripherals];
+
+    /// The messages that a device with this role must support.
+    pub const fn messages(self) -> &'static [Message] {
+        match self {
+            Role::MotorX | Role::MotorY | Role::MotorZ => &[
+                Message::WhoAreYou,
+                Message::Heartbeat,
+                Message::GetMotorState,
+                Message::ResetMotor,
+                Message::MoveMotor { x: 0.0 },
+            ],
+            Role::Peripherals => &[
+                Message::WhoAreYou,
+                Message::Heartbeat,
+                Message::GetPeripheralsState,
+                Message::Water { cooldown_ms: 0 },
+                Message::Lights { cooldown_ms: 0 },
+                Message::Pump { cooldown_ms: 0 },
+                Message::Plow { cooldown_ms: 0 },
+                Message::SetLed { led: false },
+                Message::GetAttachedTool,
+            ],
+        }
+    }
+}
+
+/// The capabilities declared by a slave device in [DeviceIdentifier], as bitmasks to keep
+/// [Response::IAm] small.
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Capabilities {
+    /// Bit `i` is set if the device has the [Role] with value `i`.
+    pub roles: u8,
+    /// Bit `i` is set if the device supports the [Message] whose [kind](Message::kind) is `i`.
+    pub messages: u32,
+}
+
+impl Capabilities {
+    /// The capabilities of a device with the `roles`, supporting exactly the messages needed by
+    /// those roles (see [Role::messages]).
+    pub const fn for_roles(roles: &[Role]) -> Capabilities {
+        let mut res = Capabilities { roles: 0, messages: 0 };
+        let mut i = 0;
+        while i < roles.len() {
+            res.roles |= 1 << roles[i] as u8;
+            let messages = roles[i].messages();
+            let mut j = 0;
+            while j < messages.len() {
+                res.messages |= 1 << messages[j].kind();
+                j += 1;
+            }
+            i += 1;
+        }
+        res
+    }
+
+    pub const fn has_role(&self, role: Role) -> bool {
+        self.roles & (1 << role as u8) != 0
+    }
+
+    pub const fn supports(&self, message: &Message) -> bool {
+        self.messages & (1 << message.kind()) != 0
+    }
+}
+
+impl Debug for Capabilities {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("Capabilities")
+            .field("roles", &DebugRoles(*self))
+            .field("messages", &format_args!("{:#b}", self.messages))
+            .finish()
+    }
+}
+
+struct DebugRoles(Capabilities);
+
+impl Debug for DebugRoles {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_list()
+            .entries(Role::ALL.iter().filter(|role| self.0.has_role(**role)))
+            .finish()
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
 #[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub struct PeripheralsState {
     pub water: bool,
@@ -153,10 +397,34 @@ pub struct PeripheralsState {
     pub water_scale: u32,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 #[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub struct MotorState {
     pub motor_pos: f32,
     pub is_idle: bool,
-    pub error: Option<[u8; 10]>,
+    /// The error that stopped the motor, if any.
+    pub error: Option<DeviceError>,
+}
+
+/// An error reported by a slave device in [Response::Error] or [MotorState::error]. The numbers
+/// give some context about the error, if the device knows it.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum DeviceError {
+    /// The motor driver signaled a fault through its nFault pin, e.g. because of overcurrent,
+    /// overheating or undervoltage.
+    DriverFault,
+    /// The encoder disagrees with the position the motor was driven to, by `steps` encoder
+    /// steps.
+    EncoderMismatch { steps: Option<i32> },
+    /// The limit switch was not reached within `elapsed_ms` milliseconds while resetting the
+    /// motor.
+    LimitSwitchTimeout { elapsed_ms: Option<u32> },
+    /// The motor stopped moving before reaching its target, at `steps` encoder steps, so its
+    /// position can't be trusted anymore until it is reset.
+    Stall { steps: Option<i32> },
+    /// An argument of the [Message] is not acceptable, e.g. a cooldown so long that it overflows.
+    InvalidArgument { value: Option<i64> },
+    /// Any other error, with a short description.
+    Other([u8; 10]),
 }
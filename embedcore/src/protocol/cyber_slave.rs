@@ -1,4 +1,7 @@
-use crate::protocol::cyber::DeviceIdentifier;
+use embassy_futures::select::{select3, Either3};
+use embassy_time::{Duration, Instant, Timer};
+
+use crate::protocol::cyber::{Capabilities, DeviceIdentifier, Request, SlaveMessage, FIRMWARE_HASH, MAX_IN_FLIGHT, PROTOCOL_VERSION};
 
 use super::{
     AsyncSerial,
@@ -13,37 +16,119 @@ pub struct Slave<Serial: AsyncSerial, MA: MessagesHandler> {
     device_identifier: DeviceIdentifier,
     /// struct used to handle all messages
     pub message_handler: MA,
+    /// id of the last event sent, see [SlaveMessage::Event]
+    event_id: u8,
+    /// see [Slave::with_failsafe]
+    failsafe: Option<Duration>,
+    /// The last requests handled (only those that [have effects](Message::has_effects)) and
+    /// their responses, used as a ring buffer. A response is sent again without handling the
+    /// request a second time if the master resends the same request (e.g. because the response
+    /// got lost), so that actions like [Message::Water] are not performed twice. Other requests
+    /// may be handled in between, since the master can have up to [MAX_IN_FLIGHT] of them.
+    recent_responses: [Option<(Request, Response)>; MAX_IN_FLIGHT],
+    /// Where the next entry of [Slave::recent_responses] goes.
+    next_recent_response: usize,
 }
 
 impl<Serial: AsyncSerial, MA: MessagesHandler> Slave<Serial, MA> {
-    /// init this struct, you should provide what serial you will use, and some other configs
-    pub fn new(serial: Serial, name: [u8; 10], message_handler: MA) -> Self {
+    /// init this struct, you should provide what serial you will use, and some other configs.
+    /// `capabilities` are declared to the master in [Response::IAm], and any message not
+    /// supported according to them is replied to with [Response::Unsupported].
+    pub fn new(serial: Serial, name: [u8; 10], capabilities: Capabilities, message_handler: MA) -> Self {
         Self {
             com: Communication::new(serial),
-            device_identifier: DeviceIdentifier { name, version: 0 },
+            device_identifier: DeviceIdentifier {
+                name,
+                version: PROTOCOL_VERSION,
+                capabilities,
+                firmware_hash: FIRMWARE_HASH,
+            },
             message_handler,
+            event_id: 0,
+            failsafe: None,
+            recent_responses: [const { None }; MAX_IN_FLIGHT],
+            next_recent_response: 0,
         }
     }
+
+    /// Makes the slave call [MessagesHandler::on_link_lost] whenever no valid [Message] arrives
+    /// for `timeout_ms`, so the master should send a [Message::Heartbeat] more often than that.
+    pub fn with_failsafe(mut self, timeout_ms: u64) -> Self {
+        self.failsafe = Some(Duration::from_millis(timeout_ms));
+        self
+    }
+
     pub async fn run(&mut self) -> ! {
+        // when to call on_link_lost(), unless a valid message arrives before
+        let mut link_deadline = self.failsafe.map(|timeout| Instant::now() + timeout);
         loop {
-            if let Ok((id, message)) = self.com.try_read::<Message>().await {
-                defmt_or_log::info!("Got message: {:?}", id);
-                let resp = match message {
-                    Message::WhoAreYou => Response::IAm(self.device_identifier.clone()),
-                    Message::GetMotorState => self.message_handler.get_motor_state().await,
-                    Message::ResetMotor => self.message_handler.reset_motor().await,
-                    Message::MoveMotor { x } => self.message_handler.move_motor(x).await,
-                    Message::GetPeripheralsState => self.message_handler.get_peripherals_state().await,
-                    Message::Water { cooldown_ms } => self.message_handler.water(cooldown_ms).await,
-                    Message::Lights { cooldown_ms } => self.message_handler.lights(cooldown_ms).await,
-                    Message::Pump { cooldown_ms } => self.message_handler.pump(cooldown_ms).await,
-                    Message::Plow { cooldown_ms } => self.message_handler.plow(cooldown_ms).await,
-                    Message::SetLed { led } => self.message_handler.set_led(led).await,
-                };
-                if let Err(e) = self.com.send(resp, id).await {
-                    defmt_or_log::error!("Sending response gave error: {:?}", e);
+            let link_lost = async move {
+                match link_deadline {
+                    Some(deadline) => Timer::at(deadline).await,
+                    None => core::future::pending().await,
+                }
+            };
+            // Communication::try_read() keeps partially read messages in its buffer, so it can be
+            // interrupted by an event without losing anything
+            match select3(self.com.try_read::<Request>(), self.message_handler.next_event(), link_lost).await {
+                Either3::First(Ok((id, request))) => {
+                    defmt_or_log::info!("Got message: {:?}", request.seq);
+                    link_deadline = self.failsafe.map(|timeout| Instant::now() + timeout);
+                    let replayed = self.recent_responses.iter()
+                        .flatten()
+                        .find(|(recent_request, _)| *recent_request == request)
+                        .map(|(_, recent_response)| recent_response.clone());
+                    let resp = match replayed {
+                        Some(resp) => {
+                            defmt_or_log::info!("Message {:?} was resent, replaying its response", request.seq);
+                            resp
+                        }
+                        None => {
+                            let resp = self.handle_message(request.message.clone()).await;
+                            if request.message.has_effects() {
+                                self.recent_responses[self.next_recent_response] = Some((request.clone(), resp.clone()));
+                                self.next_recent_response = (self.next_recent_response + 1) % MAX_IN_FLIGHT;
+                            }
+                            resp
+                        }
+                    };
+                    if let Err(e) = self.com.send(SlaveMessage::Response { seq: request.seq, response: resp }, id).await {
+                        defmt_or_log::error!("Sending response gave error: {:?}", e);
+                    }
+                }
+                Either3::First(Err(_)) => {}
+                Either3::Second(event) => {
+                    self.event_id = self.event_id.wrapping_add(1);
+                    defmt_or_log::info!("Sending event {:?} with id {}", event, self.event_id);
+                    if let Err(e) = self.com.send(SlaveMessage::Event(event), self.event_id).await {
+                        defmt_or_log::error!("Sending event gave error: {:?}", e);
+                    }
+                }
+                Either3::Third(()) => {
+                    defmt_or_log::warn!("No valid message received for {:?} ms, the link to the master was lost", self.failsafe.map(|t| t.as_millis()));
+                    self.message_handler.on_link_lost().await;
+                    // wait for the master to come back before calling on_link_lost() again
+                    link_deadline = None;
                 }
             }
         }
     }
+
+    async fn handle_message(&mut self, message: Message) -> Response {
+        match message {
+            _ if !self.device_identifier.capabilities.supports(&message) => Response::Unsupported,
+            Message::WhoAreYou => Response::IAm(self.device_identifier.clone()),
+            Message::Heartbeat => Response::Ok,
+            Message::GetMotorState => self.message_handler.get_motor_state().await,
+            Message::ResetMotor => self.message_handler.reset_motor().await,
+            Message::MoveMotor { x } => self.message_handler.move_motor(x).await,
+            Message::GetPeripheralsState => self.message_handler.get_peripherals_state().await,
+            Message::Water { cooldown_ms } => self.message_handler.water(cooldown_ms).await,
+            Message::Lights { cooldown_ms } => self.message_handler.lights(cooldown_ms).await,
+            Message::Pump { cooldown_ms } => self.message_handler.pump(cooldown_ms).await,
+            Message::Plow { cooldown_ms } => self.message_handler.plow(cooldown_ms).await,
+            Message::SetLed { led } => self.message_handler.set_led(led).await,
+            Message::GetAttachedTool => self.message_handler.get_attached_tool().await,
+        }
+    }
 }
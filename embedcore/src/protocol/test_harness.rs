@@ -1,6 +1,7 @@
 extern crate std;
 
 use std::boxed::Box;
+use std::collections::VecDeque;
 use std::sync::Arc;
 use std::vec::Vec;
 //use std::sync::mpsc::{self, Receiver, Sender};
@@ -86,22 +87,56 @@ impl Default for Dummy {
 pub struct MessageRecorderSlave {
     pub incoming: Vec<Message>,
     pub led_state: bool,
+    /// The motor "moves" instantly to the position requested with [Message::MoveMotor].
+    pub motor_pos: f32,
+    /// If set, [Message::MoveMotor] is recorded but the motor does not move, like a blocked axis.
+    pub motor_stuck: bool,
+    /// Returned as is by [Message::GetAttachedTool].
+    pub attached_tool: Option<u8>,
+    /// Returned as is in [MotorState::error], until [Message::ResetMotor] clears it.
+    pub motor_error: Option<DeviceError>,
+    /// If set, returned as [Response::Error] instead of the state for
+    /// [Message::GetPeripheralsState].
+    pub peripherals_error: Option<DeviceError>,
+    /// Sent to the master one by one as [SlaveMessage::Event]s.
+    pub pending_events: VecDeque<Event>,
+    /// How many times [MessagesHandler::on_link_lost] was called.
+    pub link_lost_count: usize,
     //outgoing: Vec<Response>,
 }
 
 impl MessagesHandler for Arc<std::sync::Mutex<MessageRecorderSlave>> {
+    async fn get_motor_state(&mut self) -> Response {
+        let mut lock = self.lock().unwrap();
+        lock.incoming.push(Message::GetMotorState);
+        Response::MotorState(MotorState {
+            motor_pos: lock.motor_pos,
+            is_idle: true,
+            error: lock.motor_error,
+        })
+    }
     async fn move_motor(&mut self, x: f32) -> Response {
-        self.lock().unwrap().incoming.push(Message::MoveMotor { x });
+        let mut lock = self.lock().unwrap();
+        lock.incoming.push(Message::MoveMotor { x });
+        if !lock.motor_stuck {
+            lock.motor_pos = x;
+        }
         Response::Ok
     }
     async fn reset_motor(&mut self) -> Response {
-        self.lock().unwrap().incoming.push(Message::ResetMotor);
+        let mut lock = self.lock().unwrap();
+        lock.incoming.push(Message::ResetMotor);
+        lock.motor_pos = 0.0;
+        lock.motor_error = None;
         Response::Ok
     }
 
     async fn get_peripherals_state(&mut self) -> Response {
         let mut lock = self.lock().unwrap();
         lock.incoming.push(Message::GetPeripheralsState);
+        if let Some(error) = lock.peripherals_error {
+            return Response::Error(error);
+        }
         Response::PeripheralsState(PeripheralsState {
             water: false,
             lights: false,
@@ -146,6 +181,24 @@ impl MessagesHandler for Arc<std::sync::Mutex<MessageRecorderSlave>> {
         lock.incoming.push(Message::SetLed { led: state });
         Response::Ok
     }
+    async fn get_attached_tool(&mut self) -> Response {
+        let mut lock = self.lock().unwrap();
+        lock.incoming.push(Message::GetAttachedTool);
+        Response::AttachedTool(lock.attached_tool)
+    }
+    async fn next_event(&mut self) -> Event {
+        loop {
+            if let Some(event) = self.lock().unwrap().pending_events.pop_front() {
+                return event;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(1)).await;
+        }
+    }
+    async fn on_link_lost(&mut self) {
+        let mut lock = self.lock().unwrap();
+        lock.link_lost_count += 1;
+        lock.led_state = false;
+    }
 }
 pub fn new_testable_slave<Serial: AsyncSerial>(
     serial: Serial,
@@ -154,6 +207,7 @@ pub fn new_testable_slave<Serial: AsyncSerial>(
     Slave::new(
         serial,
         name,
+        Capabilities::for_roles(&Role::ALL),
         Arc::new(std::sync::Mutex::new(MessageRecorderSlave::default())),
     )
 }
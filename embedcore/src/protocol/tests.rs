@@ -3,14 +3,15 @@ use core::time::Duration;
 use std::sync::Arc;
 
 use super::{
+    communication::{Communication, CommunicationError},
     cyber::*,
-    test_harness::{Dummy, TestMaster, Testable},
+    test_harness::{new_testable_slave, Dummy, TestMaster, Testable},
 };
 
 async fn init_test(timeout_us: u64) -> (TestMaster<Testable>, Slave<Testable, Dummy>) {
     let (master, slave) = Testable::new(0.0, 0.0);
     let master: TestMaster<Testable> = Master::new(master, Duration::from_micros(timeout_us), 10);
-    let slave: Slave<Testable, _> = Slave::new(slave, *b"ciao      ", Dummy::default());
+    let slave: Slave<Testable, _> = Slave::new(slave, *b"ciao      ", Capabilities::for_roles(&[Role::MotorX, Role::Peripherals]), Dummy::default());
 
     (master, slave)
 }
@@ -30,9 +31,9 @@ async fn test_led_set() {
 async fn test_who_are_you() {
     let (master, mut slave) = init_test(10).await;
     let _ = tokio::spawn(async move { slave.run().await });
-    let DeviceIdentifier { name, version } = master.who_are_you().await.unwrap();
+    let DeviceIdentifier { name, version, .. } = master.who_are_you().await.unwrap();
     assert_eq!(name, b"ciao      ".clone());
-    assert_eq!(version, 0);
+    assert_eq!(version, PROTOCOL_VERSION);
 }
 #[tokio::test]
 async fn test_move_to() {
@@ -41,20 +42,35 @@ async fn test_move_to() {
     master.move_motor(0.0).await.unwrap();
 }
 
+/// Replies to [Message::MoveMotor] only after a while, like a motor that has to actually move.
+struct SlowMotor;
+impl MessagesHandler for SlowMotor {
+    async fn move_motor(&mut self, _x: f32) -> Response {
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        Response::Ok
+    }
+}
+
 #[tokio::test]
-async fn test_blocking() {
-    let (master, mut slave) = init_test(10000).await;
+async fn test_request_while_another_is_pending() {
+    let (master, slave) = Testable::new(0.0, 0.0);
+    let master: TestMaster<Testable> = Master::new(master, Duration::from_secs(1), 10);
+    let mut slave: Slave<Testable, _> =
+        Slave::new(slave, *b"ciao      ", Capabilities::for_roles(&[Role::MotorX]), SlowMotor);
     let _ = tokio::spawn(async move { slave.run().await });
     let master = Arc::new(master);
     let m1 = master.clone();
     let q = tokio::spawn(async move { m1.move_motor(1.0).await });
     tokio::time::sleep(Duration::from_millis(10)).await;
-    let DeviceIdentifier { name, version } = master.who_are_you().await.unwrap();
-    assert_eq!(name, b"ciao      ".clone());
-    assert_eq!(version, 0);
     assert!(!q.is_finished());
+
+    // the request is sent right away, and the slave replies to it after the pending one, without
+    // any of the two responses getting lost or mixed up
+    let DeviceIdentifier { name, version, .. } = master.who_are_you().await.unwrap();
+    assert_eq!(name, b"ciao      ".clone());
+    assert_eq!(version, PROTOCOL_VERSION);
     let res = q.await.unwrap();
-    assert_eq!(res, Ok(()))
+    assert!(matches!(res, Ok(())))
 }
 
 #[tokio::test]
@@ -62,9 +78,206 @@ async fn test_timeout() {
     let (master, slave) = Testable::new(0.0, 1.0);
     let master: TestMaster<Testable> = Master::new(master, Duration::from_micros(10), 10);
     let mut slave: Slave<Testable, _> =
-        Slave::new(slave, *b"ciao      ", Dummy::default());
+        Slave::new(slave, *b"ciao      ", Capabilities::for_roles(&[Role::MotorX, Role::Peripherals]), Dummy::default());
     tokio::time::sleep(Duration::from_millis(10)).await;
     let _ = tokio::spawn(async move { slave.run().await });
     let ret = master.who_are_you().await;
     assert!(ret.is_err());
 }
+
+#[tokio::test]
+async fn test_capabilities() {
+    let (master, mut slave) = init_test(10).await;
+    let _ = tokio::spawn(async move { slave.run().await });
+    let DeviceIdentifier { capabilities, firmware_hash, .. } = master.who_are_you().await.unwrap();
+    assert!(capabilities.has_role(Role::MotorX));
+    assert!(!capabilities.has_role(Role::MotorY));
+    assert!(capabilities.has_role(Role::Peripherals));
+    assert!(capabilities.supports(&Message::MoveMotor { x: 1.0 }));
+    assert!(capabilities.supports(&Message::GetAttachedTool));
+    assert_eq!(firmware_hash, FIRMWARE_HASH);
+
+    // messages not declared in the capabilities are refused
+    let capabilities = Capabilities::for_roles(&[Role::MotorZ]);
+    assert!(!capabilities.supports(&Message::SetLed { led: true }));
+    let (master, slave) = Testable::new(0.0, 0.0);
+    let master: TestMaster<Testable> = Master::new(master, Duration::from_micros(10), 10);
+    let mut slave: Slave<Testable, _> = Slave::new(slave, *b"z         ", capabilities, Dummy::default());
+    let _ = tokio::spawn(async move { slave.run().await });
+    assert!(matches!(master.set_led(true).await, Err(CommunicationError::UnsupportedResponse)));
+    master.move_motor(1.0).await.unwrap();
+}
+
+#[tokio::test]
+async fn test_incompatible_version() {
+    let (master, slave) = Testable::new(0.0, 0.0);
+    let master: TestMaster<Testable> = Master::new(master, Duration::from_micros(10), 10);
+    let mut com = Communication::new(slave);
+    let _ = tokio::spawn(async move {
+        loop {
+            if let Ok((id, Request { seq, message: Message::WhoAreYou })) = com.try_read::<Request>().await {
+                let device_identifier = DeviceIdentifier {
+                    name: *b"old       ",
+                    version: PROTOCOL_VERSION + 1,
+                    capabilities: Capabilities::for_roles(&Role::ALL),
+                    firmware_hash: [0; 8],
+                };
+                com.send(SlaveMessage::Response { seq, response: Response::IAm(device_identifier) }, id).await.unwrap();
+            }
+        }
+    });
+    let ret = master.who_are_you().await;
+    assert!(matches!(
+        ret,
+        Err(CommunicationError::IncompatibleVersion { expected: PROTOCOL_VERSION, actual }) if actual == PROTOCOL_VERSION + 1
+    ));
+}
+
+#[tokio::test]
+async fn test_events() {
+    let (master, slave) = Testable::new(0.0, 0.0);
+    let master: TestMaster<Testable> = Master::new(master, Duration::from_millis(10), 10);
+    let mut slave = new_testable_slave(slave, *b"ciao      ");
+    let recorder = slave.message_handler.clone();
+    let _ = tokio::spawn(async move { slave.run().await });
+    let mut events = master.subscribe_events();
+
+    // events are received while no message is being sent
+    recorder.lock().unwrap().pending_events.push_back(Event::LimitSwitchHit);
+    master.listen_for_events(Duration::from_millis(20)).await;
+    assert_eq!(events.try_recv(), Ok(Event::LimitSwitchHit));
+
+    // events interleaved with a request do not break it
+    recorder.lock().unwrap().pending_events.extend([
+        Event::Error(DeviceError::DriverFault),
+        Event::CooldownExpired(Actuator::Water),
+    ]);
+    tokio::time::sleep(Duration::from_millis(5)).await;
+    master.move_motor(2.0).await.unwrap();
+    assert_eq!(recorder.lock().unwrap().motor_pos, 2.0);
+    assert_eq!(events.try_recv(), Ok(Event::Error(DeviceError::DriverFault)));
+    assert_eq!(events.try_recv(), Ok(Event::CooldownExpired(Actuator::Water)));
+    assert!(events.try_recv().is_err());
+}
+
+#[tokio::test]
+async fn test_pipelining() {
+    let (master, slave) = Testable::new(0.0, 0.0);
+    let master: TestMaster<Testable> = Master::new(master, Duration::from_secs(1), 10);
+    let mut com = Communication::new(slave);
+    let _ = tokio::spawn(async move {
+        // only replies after receiving all of the requests, in reverse order
+        let mut seqs = std::vec::Vec::new();
+        while seqs.len() < 3 {
+            if let Ok((_, Request { seq, message: Message::GetAttachedTool })) = com.try_read::<Request>().await {
+                seqs.push(seq);
+            }
+        }
+        for seq in seqs.into_iter().rev() {
+            let response = Response::AttachedTool(Some(seq as u8));
+            com.send(SlaveMessage::Response { seq, response }, 0).await.unwrap();
+        }
+    });
+    let (a, b, c) = tokio::join!(master.get_attached_tool(), master.get_attached_tool(), master.get_attached_tool());
+    let mut tools = [a.unwrap(), b.unwrap(), c.unwrap()];
+    tools.sort();
+    assert_eq!([Some(1), Some(2), Some(3)], tools);
+}
+
+#[tokio::test]
+async fn test_late_response() {
+    let (master, slave) = Testable::new(0.0, 0.0);
+    let master: TestMaster<Testable> = Master::new(master, Duration::from_millis(20), 10);
+    let mut com = Communication::new(slave);
+    let _ = tokio::spawn(async move {
+        let mut late = None;
+        loop {
+            let Ok((_, Request { seq, .. })) = com.try_read::<Request>().await else {
+                continue;
+            };
+            if seq == 1 && late.is_none() {
+                // do not reply the first time, but reply to the resent request
+                late = Some(seq);
+                continue;
+            }
+            if seq == 2 {
+                // the response to the first request, arriving too late
+                let response = Response::AttachedTool(Some(1));
+                com.send(SlaveMessage::Response { seq: 1, response }, 0).await.unwrap();
+            }
+            let response = Response::AttachedTool(Some(seq as u8));
+            com.send(SlaveMessage::Response { seq, response }, 0).await.unwrap();
+        }
+    });
+    assert_eq!(Some(1), master.get_attached_tool().await.unwrap());
+    assert_eq!(Some(2), master.get_attached_tool().await.unwrap());
+}
+
+#[tokio::test]
+async fn test_failsafe() {
+    // nothing ever reaches the slave
+    let (master, slave) = Testable::new(0.0, 1.0);
+    let master: TestMaster<Testable> = Master::new(master, Duration::from_millis(5), 1);
+    let mut slave = new_testable_slave(slave, *b"ciao      ").with_failsafe(20);
+    let recorder = slave.message_handler.clone();
+    recorder.lock().unwrap().led_state = true;
+    let _ = tokio::spawn(async move { slave.run().await });
+
+    assert!(matches!(master.heartbeat().await, Err(CommunicationError::Timeout)));
+    assert_eq!(0, recorder.lock().unwrap().link_lost_count);
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    // called only once, even though the link stayed lost for a long time
+    assert_eq!(1, recorder.lock().unwrap().link_lost_count);
+    assert!(!recorder.lock().unwrap().led_state);
+}
+
+#[tokio::test]
+async fn test_heartbeat() {
+    let (master, slave) = Testable::new(0.0, 0.0);
+    let master: TestMaster<Testable> = Master::new(master, Duration::from_millis(10), 10);
+    let mut slave = new_testable_slave(slave, *b"ciao      ").with_failsafe(50);
+    let recorder = slave.message_handler.clone();
+    let _ = tokio::spawn(async move { slave.run().await });
+
+    for _ in 0..10 {
+        master.heartbeat().await.unwrap();
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    assert_eq!(0, recorder.lock().unwrap().link_lost_count);
+    // heartbeats are handled by the slave itself
+    assert!(recorder.lock().unwrap().incoming.is_empty());
+
+    tokio::time::sleep(Duration::from_millis(150)).await;
+    assert_eq!(1, recorder.lock().unwrap().link_lost_count);
+
+    // the link is back, and can be lost again
+    master.heartbeat().await.unwrap();
+    tokio::time::sleep(Duration::from_millis(150)).await;
+    assert_eq!(2, recorder.lock().unwrap().link_lost_count);
+}
+
+#[tokio::test]
+async fn test_resent_request() {
+    let (master, slave) = Testable::new(0.0, 0.0);
+    let mut com = Communication::new(master);
+    let mut slave = new_testable_slave(slave, *b"ciao      ");
+    let recorder = slave.message_handler.clone();
+    let _ = tokio::spawn(async move { slave.run().await });
+
+    // the master did not get the first response, so it sends the same request again, possibly
+    // after other requests (e.g. a heartbeat or another pipelined request)
+    let water = Request { seq: 1, message: Message::Water { cooldown_ms: 1000 } };
+    let heartbeat = Request { seq: 2, message: Message::Heartbeat };
+    let lights = Request { seq: 3, message: Message::Lights { cooldown_ms: 1000 } };
+    let water_again = Request { seq: 4, message: Message::Water { cooldown_ms: 1000 } };
+    for request in [&water, &water, &heartbeat, &lights, &water, &lights, &water_again] {
+        com.send(request, request.seq as u8).await.unwrap();
+        match com.try_read::<SlaveMessage>().await {
+            Ok((_, SlaveMessage::Response { seq, response: Response::Ok })) => assert_eq!(request.seq, seq),
+            other => panic!("Unexpected response {other:?}"),
+        }
+    }
+    // the resent requests are replied to without handling them again, but a new one waters
+    let incoming = &recorder.lock().unwrap().incoming;
+    assert_eq!(&[water.message.clone(), lights.message, water_again.message], &incoming[..]);
+}
@@ -3,12 +3,17 @@ use std::{
     path::{Path, PathBuf},
 };
 
-use definitions::StepProgress;
+use chrono::NaiveDateTime;
+use definitions::{ActionDetails, ActionInfo, Priority, StepProgress};
 use log::warn;
 
-use crate::{state::StateHandlerError, util::serde::{deserialize_from_json_file, serialize_to_json_file}};
+use crate::{
+    history::{FinalStatus, HistoryEntry},
+    state::StateHandlerError,
+    util::serde::{deserialize_from_json_file, serialize_to_json_file},
+};
 
-use super::{command_list::CommandListAction, emergency::EmergencyAction, Action};
+use super::{registry::ActionRegistry, Action};
 
 /// Holds an `Action` along with some fixed stats needed to keep track of
 /// its execution and/or save it to disk. Can act as a placeholder in the
@@ -29,6 +34,17 @@ pub struct ActionWrapper {
     /// saved to and restored from disk when the orchestrator stop.
     pub errors: Vec<StateHandlerError>,
 
+    /// Decides the position of this action in the queue, see [Priority]. Will remain constant
+    /// throughout the execution of the action.
+    pub priority: Priority,
+
+    /// When the action was first executed, or `None` if it has not been executed yet.
+    pub started_at: Option<NaiveDateTime>,
+
+    /// What the action was asked to do, obtained from [Action::parameters] when the wrapper is
+    /// created, so that it is available even while this is a placeholder.
+    pub parameters: serde_json::Value,
+
     /// Additional information to identify the action and save it to disk. Will remain constant
     /// throughout the execution of the action.
     pub ctx: Context,
@@ -63,18 +79,36 @@ impl ActionWrapper {
     ///   correct [`Action::get_type_name()`](Action::get_type_name()))
     /// * `id` a **unique** ID for this action
     pub fn new<A: Action + 'static>(action: A, id: ActionId, save_dir: &Path) -> ActionWrapper {
+        ActionWrapper::new_boxed(Box::new(action), A::get_type_name(), id, save_dir)
+    }
+
+    /// Like [`new()`](ActionWrapper::new()), but for actions whose type is not known at compile
+    /// time, e.g. those created through the [ActionRegistry].
+    ///
+    /// * `type_name` must be the [`Action::get_type_name()`](Action::get_type_name()) of the
+    ///   concrete type of `action`, otherwise the action could not be loaded back from disk
+    pub fn new_boxed(action: Box<dyn Action>, type_name: &str, id: ActionId, save_dir: &Path) -> ActionWrapper {
         ActionWrapper {
-            action: Some(Box::new(action)),
+            parameters: action.parameters(),
+            action: Some(action),
             progress: StepProgress::Unknown,
             errors: vec![],
+            priority: 0,
+            started_at: None,
             ctx: Context {
                 id,
-                type_name: A::get_type_name().to_string(),
-                save_dir: save_dir.join(format!("{}_{}", id, A::get_type_name())),
+                type_name: type_name.to_string(),
+                save_dir: save_dir.join(format!("{id}_{type_name}")),
             },
         }
     }
 
+    /// Sets the [priority](ActionWrapper::priority), which is `0` by default.
+    pub fn with_priority(mut self, priority: Priority) -> ActionWrapper {
+        self.priority = priority;
+        self
+    }
+
     /// Returns the **unique** ID of the action being wrapped.
     pub fn get_id(&self) -> ActionId {
         self.ctx.id
@@ -103,6 +137,27 @@ impl ActionWrapper {
         &self.progress
     }
 
+    /// Returns the information about this action that is sent to clients as part of the queue
+    /// state.
+    pub fn get_info(&self) -> ActionInfo {
+        ActionInfo {
+            id: self.get_id(),
+            type_name: self.get_type_name().clone(),
+            save_dir: self.get_save_dir().clone(),
+            is_running: self.is_placeholder(),
+            progress: self.get_progress().clone(),
+            priority: self.priority,
+        }
+    }
+
+    /// Like [`get_info()`](ActionWrapper::get_info), but also includes the errors.
+    pub fn get_details(&self) -> ActionDetails {
+        ActionDetails {
+            info: self.get_info(),
+            errors: self.errors.iter().map(|e| format!("{e:?}")).collect(),
+        }
+    }
+
     /// Returns an `Action` after loading it from disk at the location `dir`,
     /// or an error if something went wrong. Chooses the type of the action
     /// to load based on the TYPENAME stored in `dir`'s name.
@@ -110,7 +165,8 @@ impl ActionWrapper {
     /// * `dir` is where to look for the files for the `Action` to load. The
     ///   name must be of the form `ID_TYPENAME`, and ID and TYPENAME will be
     ///   extracted from there.
-    pub fn load_from_disk(dir: &Path) -> Result<ActionWrapper, String> {
+    /// * `registry` is where to look up TYPENAME to find out how to load the action
+    pub fn load_from_disk(dir: &Path, registry: &ActionRegistry) -> Result<ActionWrapper, String> {
         if !dir.is_dir() {
             return Err(format!("Not a directory: {dir:?}"));
         }
@@ -143,25 +199,40 @@ impl ActionWrapper {
             },
         };
 
-        if type_name == EmergencyAction::get_type_name() {
-            Ok(ActionWrapper {
-                action: Some(Box::new(EmergencyAction::load_from_disk(&ctx)?)),
-                progress,
-                errors: vec![],
-                ctx,
-            })
-        } else if type_name == CommandListAction::get_type_name() {
-            Ok(ActionWrapper {
-                action: Some(Box::new(CommandListAction::load_from_disk(&ctx)?)),
-                progress,
-                errors: vec![],
-                ctx,
-            })
+        let priority_file = ctx.save_dir.join("priority.json");
+        let priority = if priority_file.exists() {
+            deserialize_from_json_file(&priority_file)
+                .map_err(|e| format!("Could not read priority for action {id}: {e}"))?
         } else {
-            Err(format!(
-                "Invalid filename: {filename}: invalid type name {type_name}"
-            ))
-        }
+            0 // actions saved before priorities were introduced
+        };
+
+        let started_file = ctx.save_dir.join("started.json");
+        let started_at = if started_file.exists() {
+            match deserialize_from_json_file(&started_file) {
+                Ok(started_at) => Some(started_at),
+                Err(e) => {
+                    warn!("Could not read start time for action {id}: {e}");
+                    None
+                }
+            }
+        } else {
+            None
+        };
+
+        let action_type = registry.get(type_name).ok_or_else(|| format!(
+            "Invalid filename: {filename}: invalid type name {type_name}"
+        ))?;
+        let action = action_type.load_from_disk(&ctx)?;
+        Ok(ActionWrapper {
+            parameters: action.parameters(),
+            action: Some(action),
+            progress,
+            errors: vec![],
+            priority,
+            started_at,
+            ctx,
+        })
     }
 
     /// Saves this `Action` to disk, by storing files inside `self.ctx.save_dir`.
@@ -175,15 +246,55 @@ impl ActionWrapper {
 
         create_dir_all(&self.ctx.save_dir).map_err(|e| e.to_string())?;
 
-        match serialize_to_json_file(&self.progress, &self.ctx.save_dir.join("progress.json")) {
-            Ok(_) => {},
-            Err(e) => warn!("Could not save progress {:?} for action {}: {e}", self.progress, self.ctx.id),
+        if let Err(e) = self.save_progress_to_disk() {
+            warn!("Could not save progress {:?} for action {}: {e}", self.progress, self.ctx.id);
+        }
+        serialize_to_json_file(&self.priority, &self.ctx.save_dir.join("priority.json"))?;
+        if let Err(e) = self.save_started_to_disk() {
+            warn!("Could not save start time for action {}: {e}", self.ctx.id);
         }
 
         action.save_to_disk(&self.ctx)?;
 
         Ok(())
     }
+
+    /// Saves only [`progress`](ActionWrapper::progress) to disk, which also works for
+    /// placeholders. Returns an error if something went wrong.
+    pub fn save_progress_to_disk(&self) -> Result<(), String> {
+        save_progress_to_disk(&self.ctx.save_dir, &self.progress)
+    }
+
+    /// Saves only [`started_at`](ActionWrapper::started_at) to disk (if set), which also works
+    /// for placeholders. Returns an error if something went wrong.
+    pub fn save_started_to_disk(&self) -> Result<(), String> {
+        match &self.started_at {
+            Some(started_at) => serialize_to_json_file(started_at, &self.ctx.save_dir.join("started.json")),
+            None => Ok(()),
+        }
+    }
+
+    /// Returns a record of this action for the [history](crate::history), to be used when the
+    /// action leaves the queue with `status` at time `finished_at`.
+    pub fn to_history_entry(&self, status: FinalStatus, finished_at: NaiveDateTime) -> HistoryEntry {
+        HistoryEntry {
+            id: self.get_id(),
+            type_name: self.get_type_name().clone(),
+            priority: self.priority,
+            status,
+            started_at: self.started_at,
+            finished_at,
+            progress: self.progress.clone(),
+            errors: self.errors.iter().map(|e| format!("{e:?}")).collect(),
+            parameters: self.parameters.clone(),
+        }
+    }
+}
+
+/// Saves `progress` in the directory `save_dir` of an action, where
+/// [`load_from_disk()`](ActionWrapper::load_from_disk) will look for it.
+pub fn save_progress_to_disk(save_dir: &Path, progress: &StepProgress) -> Result<(), String> {
+    serialize_to_json_file(progress, &save_dir.join("progress.json"))
 }
 
 impl Context {
@@ -1,24 +1,66 @@
 use std::{future::Future, time::Duration};
 
-use definitions::StepProgress;
+use definitions::{Peripheral, RobotState, StepProgress};
 use serde::{Deserialize, Serialize};
+use tokio::time::Instant;
 
 use crate::{
-    action::StepResult, state::{StateHandler, StateHandlerError}, util::serde::{deserialize_from_json_file, serialize_to_json_file}
+    action::StepResult, state::{MoveOrdering, StateHandler, StateHandlerError}, util::serde::{deserialize_from_json_file, serialize_to_json_file}
 };
 
-use super::{Action, Context};
+use super::{retry::RetryPolicy, Action, Context};
 
-/// Executes a list of commands directly on the robot state.
+/// How often to check the condition of a [Command::WaitUntil].
+const WAIT_UNTIL_POLL_PERIOD: Duration = Duration::from_millis(200);
+/// How many control flow instructions (e.g. jumps) to execute at most in a single step, so that
+/// e.g. a [Command::Repeat] with an empty body does not block the queue.
+const MAX_CONTROL_FLOW_PER_STEP: usize = 1000;
+
+/// Executes a list of commands directly on the robot state. Each step executes one command,
+/// along with any control flow (i.e. [Command::Repeat] and [Command::If]) preceding it.
 #[derive(Debug, Serialize, Deserialize)]
 pub struct CommandListAction {
     commands: Vec<Command>,
+    /// How many commands were executed so far, not counting control flow, and counting each
+    /// repetition of a command in a [Command::Repeat].
     steps_done_so_far: usize,
+    /// The index of the next instruction to execute in the [compiled](compile) commands. Is
+    /// `None` for actions saved before control flow existed, whose commands were a flat list, in
+    /// which case it is equal to `steps_done_so_far`.
+    #[serde(default)]
+    pc: Option<usize>,
+    /// How many iterations are left in the [Command::Repeat]s being executed, innermost last.
+    #[serde(default)]
+    counters: Vec<usize>,
+    /// Applies to all commands, see [Action::retry_policy].
+    #[serde(default)]
+    retry_policy: RetryPolicy,
+}
+
+/// The JSON accepted to create a [CommandListAction], which can also be just the list of commands
+/// (see [ShorthandData](super::registry::ShorthandData)).
+#[derive(Debug, Deserialize)]
+pub struct CommandListData {
+    pub commands: Vec<Command>,
+    #[serde(default)]
+    pub retry_policy: RetryPolicy,
+}
+
+impl From<Vec<Command>> for CommandListData {
+    fn from(commands: Vec<Command>) -> Self {
+        CommandListData { commands, retry_policy: RetryPolicy::default() }
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub enum Command {
-    Move { x: f32, y: f32, z: f32 },
+    Move {
+        x: f32,
+        y: f32,
+        z: f32,
+        #[serde(default)]
+        ordering: MoveOrdering,
+    },
     Reset,
     Home,
     Retract,
@@ -26,6 +68,9 @@ pub enum Command {
     // TODO rename these, maybe to just Water and WaterManual?
     WaterCooldown(Option<Duration>),
     WaterWait(Duration),
+    /// Waters until the given amount of liters has left the tank, see
+    /// [StateHandler::water_liters].
+    WaterLiters(f32),
     LightsCooldown(Option<Duration>),
     LightsWait(Duration),
     PumpCooldown(Option<Duration>),
@@ -33,6 +78,138 @@ pub enum Command {
     PlowCooldown(Option<Duration>),
     PlowWait(Duration),
     ToggleLed,
+    /// Retracts and selects the tool after checking that it is attached, see
+    /// [StateHandler::change_tool]. `None` means no tool.
+    ChangeTool(Option<String>),
+    /// Selects the tool without moving or checking anything, see [StateHandler::set_tool].
+    /// `None` means no tool.
+    SetTool(Option<String>),
+    /// Executes `body` `times` times.
+    Repeat {
+        times: usize,
+        body: Vec<Command>,
+    },
+    /// Executes `then` if `condition` holds when this command is reached, otherwise `else`.
+    If {
+        condition: Condition,
+        then: Vec<Command>,
+        #[serde(default)]
+        r#else: Vec<Command>,
+    },
+    /// Waits until `condition` holds, failing if it does not hold within `timeout` (if any).
+    WaitUntil {
+        condition: Condition,
+        #[serde(default)]
+        timeout: Option<Duration>,
+    },
+}
+
+/// A condition on the [RobotState], read from the devices every time it is checked.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub enum Condition {
+    /// [WaterLevel::proportion](definitions::WaterLevel::proportion) is below this value.
+    WaterLevelBelow(f32),
+    /// [WaterLevel::proportion](definitions::WaterLevel::proportion) is above this value.
+    WaterLevelAbove(f32),
+    /// [BatteryLevel::proportion](definitions::BatteryLevel::proportion) is below this value.
+    BatteryLevelBelow(f32),
+    /// [BatteryLevel::proportion](definitions::BatteryLevel::proportion) is above this value.
+    BatteryLevelAbove(f32),
+    /// The peripheral is on, see [RobotState::actuators].
+    On(Peripheral),
+    /// The LED is on, see [RobotState::actuators].
+    LedOn,
+    Not(Box<Condition>),
+    /// All of the conditions hold (true if there are none).
+    All(Vec<Condition>),
+    /// At least one of the conditions holds (false if there are none).
+    Any(Vec<Condition>),
+}
+
+impl Condition {
+    pub fn holds(&self, state: &RobotState) -> bool {
+        match self {
+            Condition::WaterLevelBelow(value) => state.water_level.proportion < *value,
+            Condition::WaterLevelAbove(value) => state.water_level.proportion > *value,
+            Condition::BatteryLevelBelow(value) => state.battery_level.proportion < *value,
+            Condition::BatteryLevelAbove(value) => state.battery_level.proportion > *value,
+            Condition::On(Peripheral::Water) => state.actuators.water,
+            Condition::On(Peripheral::Lights) => state.actuators.lights,
+            Condition::On(Peripheral::Pump) => state.actuators.pump,
+            Condition::On(Peripheral::Plow) => state.actuators.plow,
+            Condition::LedOn => state.actuators.led,
+            Condition::Not(condition) => !condition.holds(state),
+            Condition::All(conditions) => conditions.iter().all(|condition| condition.holds(state)),
+            Condition::Any(conditions) => conditions.iter().any(|condition| condition.holds(state)),
+        }
+    }
+}
+
+/// What [Command]s are compiled into, so that the position in the (possibly nested) commands can
+/// be saved as a single index (see [CommandListAction::pc]).
+#[derive(Debug, Clone)]
+enum Instruction {
+    /// Executes a command that is not a control flow one.
+    Run(Command),
+    /// Starts a [Command::Repeat] by pushing its number of iterations to
+    /// [CommandListAction::counters].
+    PushCounter(usize),
+    /// If the innermost counter is `0`, pops it and jumps to `end`, otherwise decrements it.
+    Loop { end: usize },
+    Jump(usize),
+    /// Jumps to `target` if `condition` does not hold.
+    JumpUnless { condition: Condition, target: usize },
+}
+
+fn compile(commands: &[Command]) -> Vec<Instruction> {
+    fn compile_into(commands: &[Command], program: &mut Vec<Instruction>) {
+        for command in commands {
+            match command {
+                Command::Repeat { times, body } => {
+                    program.push(Instruction::PushCounter(*times));
+                    let loop_index = program.len();
+                    program.push(Instruction::Loop { end: 0 }); // end is set below
+                    compile_into(body, program);
+                    program.push(Instruction::Jump(loop_index));
+                    program[loop_index] = Instruction::Loop { end: program.len() };
+                }
+                Command::If { condition, then, r#else } => {
+                    let jump_unless_index = program.len();
+                    program.push(Instruction::Jump(0)); // replaced below
+                    compile_into(then, program);
+                    let jump_index = program.len();
+                    program.push(Instruction::Jump(0)); // target is set below
+                    program[jump_unless_index] = Instruction::JumpUnless {
+                        condition: condition.clone(),
+                        target: program.len(),
+                    };
+                    compile_into(r#else, program);
+                    program[jump_index] = Instruction::Jump(program.len());
+                }
+                command => program.push(Instruction::Run(command.clone())),
+            }
+        }
+    }
+
+    let mut program = vec![];
+    compile_into(commands, &mut program);
+    program
+}
+
+/// How many commands will be executed in total (see [CommandListAction::steps_done_so_far]), or
+/// `None` if it depends on which branches of [Command::If]s are taken.
+fn count_steps(commands: &[Command]) -> Option<usize> {
+    commands.iter().try_fold(0usize, |total, command| {
+        let steps = match command {
+            Command::Repeat { times, body } => count_steps(body)?.checked_mul(*times)?,
+            Command::If { then, r#else, .. } => {
+                let then_steps = count_steps(then)?;
+                (Some(then_steps) == count_steps(r#else)).then_some(then_steps)?
+            }
+            _ => 1,
+        };
+        total.checked_add(steps)
+    })
 }
 
 impl CommandListAction {
@@ -40,9 +217,16 @@ impl CommandListAction {
         CommandListAction {
             commands,
             steps_done_so_far: 0,
+            pc: Some(0),
+            counters: vec![],
+            retry_policy: RetryPolicy::default(),
         }
     }
 
+    pub fn from_data(data: CommandListData) -> Self {
+        CommandListAction { retry_policy: data.retry_policy, ..CommandListAction::new(data.commands) }
+    }
+
     fn duration_to_ms(duration: Duration) -> Result<u64, StateHandlerError> {
         duration.as_millis().try_into()
             .map_err(|e| StateHandlerError::GenericError(
@@ -69,32 +253,39 @@ impl CommandListAction {
         f(state_handler, Self::option_duration_to_ms(duration)?).await
     }
 
-    async fn run_wait_function<'a, F, Fut>(
-        f: F,
-        state_handler: &'a StateHandler,
-        duration: Duration,
-    ) -> Result<(), StateHandlerError>
-    where
-        F: Fn(&'a StateHandler, u64) -> Fut,
-        Fut: Future<Output = Result<(), StateHandlerError>>,
-    {
-        f(state_handler, Self::duration_to_ms(duration)?).await?;
-        tokio::time::sleep(duration).await;
-        f(state_handler, 0).await?;
-        Ok(())
+    fn progress(&self) -> StepProgress {
+        match count_steps(&self.commands) {
+            Some(steps_total) => StepProgress::Ratio { steps_done_so_far: self.steps_done_so_far, steps_total },
+            None => StepProgress::Count { steps_done_so_far: self.steps_done_so_far },
+        }
     }
-}
 
-#[async_trait]
-impl Action for CommandListAction {
-    async fn step(&mut self, _ctx: &Context, state_handler: &StateHandler) -> StepResult {
-        if self.steps_done_so_far >= self.commands.len() {
-            return StepResult::Finished;
+    async fn wait_until(
+        condition: &Condition,
+        timeout: Option<Duration>,
+        state_handler: &StateHandler,
+    ) -> Result<(), StateHandlerError> {
+        let start = Instant::now();
+        loop {
+            if condition.holds(&state_handler.update_state().await?) {
+                return Ok(());
+            }
+            if let Some(timeout) = timeout {
+                if start.elapsed() >= timeout {
+                    return Err(StateHandlerError::GenericError(
+                        format!("Condition {condition:?} did not hold within {timeout:?}")
+                    ));
+                }
+            }
+            tokio::time::sleep(WAIT_UNTIL_POLL_PERIOD).await;
         }
-        let command = self.commands[self.steps_done_so_far].clone();
+    }
 
-        let res = match command {
-            Command::Move { x, y, z } => state_handler.move_to(x, y, z).await,
+    /// Executes a command that is not a control flow one, i.e. not a [Command::Repeat] or a
+    /// [Command::If].
+    pub async fn run_command(command: Command, state_handler: &StateHandler) -> Result<(), StateHandlerError> {
+        match command {
+            Command::Move { x, y, z, ordering } => state_handler.move_to_resetting_on_stall(x, y, z, ordering).await,
             Command::Reset => state_handler.reset().await,
             Command::Home => state_handler.home().await,
             Command::Retract => state_handler.retract().await,
@@ -108,6 +299,7 @@ impl Action for CommandListAction {
             Command::WaterWait(duration) => {
                 Self::run_wait_function(StateHandler::water, state_handler, duration).await
             }
+            Command::WaterLiters(liters) => state_handler.water_liters(liters).await,
             Command::LightsCooldown(duration) => {
                 Self::run_cooldown_function(StateHandler::lights, state_handler, duration).await
             }
@@ -127,24 +319,91 @@ impl Action for CommandListAction {
                 Self::run_wait_function(StateHandler::plow, state_handler, duration).await
             }
             Command::ToggleLed => state_handler.toggle_led().await,
-        };
-
-        if let Err(e) = res {
-            return StepResult::RunningError(e);
+            Command::ChangeTool(tool) => state_handler.change_tool(tool).await,
+            Command::SetTool(tool) => state_handler.set_tool(tool),
+            Command::WaitUntil { condition, timeout } => {
+                Self::wait_until(&condition, timeout, state_handler).await
+            }
+            Command::Repeat { .. } | Command::If { .. } => {
+                unreachable!("Control flow commands are compiled into instructions")
+            }
         }
+    }
+
+    async fn run_wait_function<'a, F, Fut>(
+        f: F,
+        state_handler: &'a StateHandler,
+        duration: Duration,
+    ) -> Result<(), StateHandlerError>
+    where
+        F: Fn(&'a StateHandler, u64) -> Fut,
+        Fut: Future<Output = Result<(), StateHandlerError>>,
+    {
+        f(state_handler, Self::duration_to_ms(duration)?).await?;
+        tokio::time::sleep(duration).await;
+        f(state_handler, 0).await?;
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl Action for CommandListAction {
+    async fn step(&mut self, _ctx: &Context, state_handler: &StateHandler) -> StepResult {
+        let program = compile(&self.commands);
+        let mut pc = self.pc.unwrap_or(self.steps_done_so_far);
 
-        // only increment if there has been no error
-        self.steps_done_so_far += 1;
-        if self.steps_done_so_far >= self.commands.len() {
-            StepResult::Finished
-        } else {
-            StepResult::Running(
-                StepProgress::Ratio {
-                    steps_done_so_far: self.steps_done_so_far,
-                    steps_total: self.commands.len(),
+        for _ in 0..MAX_CONTROL_FLOW_PER_STEP {
+            let Some(instruction) = program.get(pc) else {
+                return StepResult::Finished;
+            };
+            match instruction {
+                Instruction::Run(command) => {
+                    let res = Self::run_command(command.clone(), state_handler).await;
+                    return StepResult::advance_if_ok(res, |()| {
+                        self.pc = Some(pc + 1);
+                        self.steps_done_so_far += 1;
+                        if pc + 1 >= program.len() {
+                            StepResult::Finished
+                        } else {
+                            StepResult::Running(self.progress())
+                        }
+                    });
                 }
-            )
+                Instruction::PushCounter(times) => {
+                    self.counters.push(*times);
+                    pc += 1;
+                }
+                Instruction::Loop { end } => match self.counters.last_mut() {
+                    Some(0) | None => {
+                        self.counters.pop();
+                        pc = *end;
+                    }
+                    Some(counter) => {
+                        *counter -= 1;
+                        pc += 1;
+                    }
+                },
+                Instruction::Jump(target) => pc = *target,
+                Instruction::JumpUnless { condition, target } => {
+                    // on error the condition is evaluated again when the step is retried
+                    let state = match state_handler.update_state().await {
+                        Ok(state) => state,
+                        Err(e) => return StepResult::RunningError(e),
+                    };
+                    pc = if condition.holds(&state) { pc + 1 } else { *target };
+                }
+            }
+            self.pc = Some(pc);
         }
+        StepResult::Running(self.progress())
+    }
+
+    fn retry_policy(&self) -> RetryPolicy {
+        self.retry_policy.clone()
+    }
+
+    fn parameters(&self) -> serde_json::Value {
+        serde_json::json!({ "commands": self.commands, "retry_policy": self.retry_policy })
     }
 
     fn get_type_name() -> &'static str
@@ -165,3 +424,85 @@ impl Action for CommandListAction {
         deserialize_from_json_file(&ctx.get_save_dir().join("data.json"))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use definitions::{Peripheral, RobotState};
+
+    use super::{compile, count_steps, CommandListAction, Command, Condition, Instruction};
+
+    fn lights(ms: u64) -> Command {
+        Command::LightsCooldown(Some(Duration::from_millis(ms)))
+    }
+
+    #[test]
+    fn compile_control_flow() {
+        let program = compile(&[
+            Command::Repeat { times: 2, body: vec![lights(1)] },
+            Command::If { condition: Condition::LedOn, then: vec![lights(2)], r#else: vec![lights(3)] },
+            Command::Home,
+        ]);
+        let program: Vec<String> = program.iter()
+            .map(|instruction| match instruction {
+                Instruction::Run(command) => format!("Run({command:?})"),
+                Instruction::PushCounter(times) => format!("PushCounter({times})"),
+                Instruction::Loop { end } => format!("Loop({end})"),
+                Instruction::Jump(target) => format!("Jump({target})"),
+                Instruction::JumpUnless { target, .. } => format!("JumpUnless({target})"),
+            })
+            .collect();
+        assert_eq!(
+            vec![
+                "PushCounter(2)", "Loop(4)", "Run(LightsCooldown(Some(1ms)))", "Jump(1)",
+                "JumpUnless(7)", "Run(LightsCooldown(Some(2ms)))", "Jump(8)", "Run(LightsCooldown(Some(3ms)))",
+                "Run(Home)",
+            ],
+            program
+        );
+    }
+
+    #[test]
+    fn count_steps_with_control_flow() {
+        assert_eq!(Some(0), count_steps(&[]));
+        assert_eq!(Some(7), count_steps(&[
+            Command::Home,
+            Command::Repeat { times: 3, body: vec![lights(1), lights(2)] },
+        ]));
+        assert_eq!(Some(2), count_steps(&[
+            Command::If { condition: Condition::LedOn, then: vec![lights(1)], r#else: vec![lights(2)] },
+            Command::WaitUntil { condition: Condition::LedOn, timeout: None },
+        ]));
+        assert_eq!(None, count_steps(&[
+            Command::If { condition: Condition::LedOn, then: vec![lights(1)], r#else: vec![] },
+        ]));
+        assert_eq!(None, count_steps(&[
+            Command::Repeat { times: usize::MAX, body: vec![lights(1), lights(2)] },
+        ]));
+    }
+
+    #[test]
+    fn conditions() {
+        let mut state = RobotState::default();
+        state.water_level.proportion = 0.3;
+        state.actuators.pump = true;
+        assert!(Condition::WaterLevelBelow(0.5).holds(&state));
+        assert!(!Condition::WaterLevelAbove(0.5).holds(&state));
+        assert!(Condition::On(Peripheral::Pump).holds(&state));
+        assert!(!Condition::On(Peripheral::Water).holds(&state));
+        assert!(Condition::Not(Box::new(Condition::LedOn)).holds(&state));
+        assert!(Condition::All(vec![]).holds(&state));
+        assert!(!Condition::Any(vec![]).holds(&state));
+        assert!(Condition::Any(vec![Condition::LedOn, Condition::BatteryLevelBelow(0.1)]).holds(&state));
+    }
+
+    #[test]
+    fn load_saved_before_control_flow() {
+        let action: CommandListAction = serde_json::from_str(
+            r#"{"commands":["Home","Reset","Home"],"steps_done_so_far":2}"#
+        ).unwrap();
+        assert_eq!(None, action.pc);
+        assert!(action.counters.is_empty());
+    }
+}
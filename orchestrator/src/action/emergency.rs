@@ -10,10 +10,12 @@ pub struct EmergencyAction {}
 impl Action for EmergencyAction {
     async fn step(&mut self, _ctx: &Context, state_handler: &StateHandler) -> StepResult {
         // TODO implement better emergency logic
-        if let Err(e) = state_handler.reset().await {
-            error!("EmergencyAction could not reset: {e:?}")
+        match state_handler.reset().await {
+            Ok(()) => StepResult::Finished,
+            // the queue will wait for the emergency to be cleared manually anyway, so there is
+            // no point in retrying
+            Err(e) => StepResult::FinishedError(e),
         }
-        StepResult::Finished
     }
 
     fn get_type_name() -> &'static str
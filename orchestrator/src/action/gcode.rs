@@ -0,0 +1,354 @@
+use std::{fmt::Display, time::Duration};
+
+use definitions::{Parameters, Tool, Vec3};
+
+use crate::state::{kinematics::world_to_joint, MoveOrdering};
+
+use super::command_list::Command;
+
+/// Peripherals turned on by M-codes turn themselves off after this time, in case the program is
+/// interrupted before the corresponding "off" M-code.
+const ON_COOLDOWN: Duration = Duration::from_secs(600);
+
+/// An error in a G-code program, with the (1-based) number of the line it happened on.
+#[derive(Debug, Clone, PartialEq)]
+pub struct GcodeError {
+    pub line: usize,
+    pub message: String,
+}
+
+impl Display for GcodeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Line {}: {}", self.line, self.message)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Motion {
+    /// `G0`, moves over obstacles (see [MoveOrdering::ZUpFirst]).
+    Rapid,
+    /// `G1`, moves in a straight line (see [MoveOrdering::Simultaneous]).
+    Linear,
+}
+
+/// The modal state of the interpreter, i.e. what persists from one line to the next.
+struct Interpreter<'a> {
+    params: &'a Parameters,
+    tool: Option<&'a Tool>,
+    /// Meters per unit, changed by `G20` (inches) and `G21` (millimeters, default).
+    scale: f32,
+    /// Changed by `G90` (absolute, default) and `G91` (relative).
+    relative: bool,
+    /// The last `G0` or `G1`, used by lines with only coordinates.
+    motion: Option<Motion>,
+    /// The position in world coordinates. Axes are `None` until they are first set, since the
+    /// position of the robot when the program will start is not known.
+    position: [Option<f32>; 3],
+    commands: Vec<Command>,
+}
+
+/// Converts a G-code program into [Command]s for a
+/// [CommandListAction](super::command_list::CommandListAction). Coordinates are world
+/// coordinates, and every point the program moves to is checked to be reachable with `params` and
+/// `tool`. Returns all of the errors found, if any. The supported subset of G-code is:
+/// - `G0`/`G1` with `X`, `Y`, `Z` (and `F`, which is ignored): move, `G0` goes over obstacles
+/// - `G4` with `P` (milliseconds) or `S` (seconds): dwell
+/// - `G20`/`G21`: inches/millimeters (default)
+/// - `G90`/`G91`: absolute (default)/relative coordinates
+/// - `M3`/`M5` (`S` is ignored): plow on/off
+/// - `M7`/`M8`/`M9`: pump on, water on, both off
+/// - `M355 S1`/`M355 S0`: lights on/off
+/// - `M2`/`M30`: end of program, the rest of the file is ignored
+///
+/// Comments (`;` until the end of the line, or between parentheses), line numbers (`N`) and `%`
+/// lines are ignored.
+pub fn gcode_to_commands(gcode: &str, params: &Parameters, tool: Option<&Tool>) -> Result<Vec<Command>, Vec<GcodeError>> {
+    let mut interpreter = Interpreter {
+        params,
+        tool,
+        scale: 0.001,
+        relative: false,
+        motion: None,
+        position: [None; 3],
+        commands: vec![],
+    };
+    let mut errors = vec![];
+
+    for (index, line) in gcode.lines().enumerate() {
+        let result = parse_words(line).and_then(|words| interpreter.execute(&words));
+        match result {
+            Ok(true) => {}
+            Ok(false) => break, // end of program
+            Err(message) => errors.push(GcodeError { line: index + 1, message }),
+        }
+    }
+
+    if errors.is_empty() {
+        Ok(interpreter.commands)
+    } else {
+        Err(errors)
+    }
+}
+
+/// Splits a line into words made of an uppercase letter and a number, removing comments and
+/// line numbers.
+fn parse_words(line: &str) -> Result<Vec<(char, f32)>, String> {
+    let mut code = String::new();
+    let mut in_parentheses = false;
+    for c in line.chars() {
+        match c {
+            ';' if !in_parentheses => break,
+            '(' => in_parentheses = true,
+            ')' => in_parentheses = false,
+            c if !in_parentheses => code.push(c),
+            _ => {}
+        }
+    }
+    if code.trim() == "%" {
+        return Ok(vec![]);
+    }
+
+    let mut words = vec![];
+    let mut chars = code.chars().filter(|c| !c.is_whitespace()).peekable();
+    while let Some(letter) = chars.next() {
+        if !letter.is_ascii_alphabetic() {
+            return Err(format!("Unexpected character {letter:?}"));
+        }
+        let mut number = String::new();
+        while let Some(c) = chars.next_if(|c| c.is_ascii_digit() || *c == '.' || *c == '-' || *c == '+') {
+            number.push(c);
+        }
+        let value = number.parse::<f32>()
+            .map_err(|_| format!("Invalid number {number:?} after {letter}"))?;
+        let letter = letter.to_ascii_uppercase();
+        if letter != 'N' {
+            words.push((letter, value));
+        }
+    }
+    Ok(words)
+}
+
+fn parse_code(letter: char, value: f32) -> Result<u32, String> {
+    if value < 0.0 || value.fract() != 0.0 {
+        return Err(format!("Invalid code {letter}{value}"));
+    }
+    Ok(value as u32)
+}
+
+impl Interpreter<'_> {
+    /// Executes a line of G-code, returning `false` if it ends the program.
+    fn execute(&mut self, words: &[(char, f32)]) -> Result<bool, String> {
+        let mut motion = None;
+        let mut dwell = false;
+        let mut m_code = None;
+        let mut params = vec![];
+        for &(letter, value) in words {
+            match letter {
+                'G' => match parse_code(letter, value)? {
+                    0 => motion = Some(Motion::Rapid),
+                    1 => motion = Some(Motion::Linear),
+                    4 => dwell = true,
+                    20 => self.scale = 0.0254,
+                    21 => self.scale = 0.001,
+                    90 => self.relative = false,
+                    91 => self.relative = true,
+                    code => return Err(format!("Unsupported code G{code}")),
+                },
+                'M' => {
+                    if m_code.is_some() {
+                        return Err("Only one M-code per line is supported".to_string());
+                    }
+                    m_code = Some(parse_code(letter, value)?);
+                }
+                letter => params.push((letter, value)),
+            }
+        }
+        let param = |letter: char| params.iter().find(|(l, _)| *l == letter).map(|(_, value)| *value);
+
+        if dwell {
+            if motion.is_some() || m_code.is_some() {
+                return Err("G4 can't be on the same line as other commands".to_string());
+            }
+            check_params(&params, &['P', 'S'])?;
+            let secs = match (param('P'), param('S')) {
+                (Some(millis), None) => millis / 1000.0,
+                (None, Some(secs)) => secs,
+                _ => return Err("G4 needs either P (milliseconds) or S (seconds)".to_string()),
+            };
+            let duration = Duration::try_from_secs_f32(secs)
+                .map_err(|e| format!("Invalid dwell time {secs}s: {e}"))?;
+            self.commands.push(Command::Wait(duration));
+            return Ok(true);
+        }
+
+        if let Some(m_code) = m_code {
+            if motion.is_some() {
+                return Err(format!("M{m_code} can't be on the same line as a move"));
+            }
+            return self.execute_m_code(m_code, &params, param('S'));
+        }
+
+        if motion.is_some() {
+            self.motion = motion;
+        }
+        if params.is_empty() {
+            return Ok(true); // e.g. a line with only G21, or an empty line
+        }
+        let Some(motion) = self.motion else {
+            return Err("Coordinates without a G0 or G1 move".to_string());
+        };
+        check_params(&params, &['X', 'Y', 'Z', 'F'])?;
+        self.move_to(motion, [param('X'), param('Y'), param('Z')])?;
+        Ok(true)
+    }
+
+    fn execute_m_code(&mut self, m_code: u32, params: &[(char, f32)], s: Option<f32>) -> Result<bool, String> {
+        let on = Some(ON_COOLDOWN);
+        match m_code {
+            2 | 30 => return Ok(false),
+            3 => self.commands.push(Command::PlowCooldown(on)),
+            5 => self.commands.push(Command::PlowCooldown(None)),
+            7 => self.commands.push(Command::PumpCooldown(on)),
+            8 => self.commands.push(Command::WaterCooldown(on)),
+            9 => {
+                self.commands.push(Command::PumpCooldown(None));
+                self.commands.push(Command::WaterCooldown(None));
+            }
+            355 => match s {
+                Some(0.0) => self.commands.push(Command::LightsCooldown(None)),
+                Some(1.0) => self.commands.push(Command::LightsCooldown(on)),
+                _ => return Err("M355 needs either S0 (lights off) or S1 (lights on)".to_string()),
+            },
+            code => return Err(format!("Unsupported code M{code}")),
+        }
+        check_params(params, &['S'])?;
+        Ok(true)
+    }
+
+    fn move_to(&mut self, motion: Motion, coordinates: [Option<f32>; 3]) -> Result<(), String> {
+        for (axis, (position, coordinate)) in self.position.iter_mut().zip(coordinates).enumerate() {
+            let Some(coordinate) = coordinate else {
+                continue;
+            };
+            let coordinate = coordinate * self.scale;
+            *position = match (self.relative, *position) {
+                (false, _) => Some(coordinate),
+                (true, Some(position)) => Some(position + coordinate),
+                (true, None) => return Err(format!(
+                    "Relative move on axis {} whose position is not known yet", ['X', 'Y', 'Z'][axis]
+                )),
+            };
+        }
+
+        let [Some(x), Some(y), Some(z)] = self.position else {
+            return Err("The first move must set all of X, Y and Z".to_string());
+        };
+        let world = Vec3 { x, y, z };
+        if world_to_joint(&world, self.params, self.tool).is_none() {
+            return Err(format!("Unreachable coordinates {world:?}"));
+        }
+        self.commands.push(Command::Move {
+            x,
+            y,
+            z,
+            ordering: match motion {
+                Motion::Rapid => MoveOrdering::ZUpFirst,
+                Motion::Linear => MoveOrdering::Simultaneous,
+            },
+        });
+        Ok(())
+    }
+}
+
+/// Fails if any of `params` is not in `allowed`.
+fn check_params(params: &[(char, f32)], allowed: &[char]) -> Result<(), String> {
+    match params.iter().find(|(letter, _)| !allowed.contains(letter)) {
+        Some((letter, value)) => Err(format!("Unexpected parameter {letter}{value}")),
+        None => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use definitions::Parameters;
+
+    use crate::{action::command_list::Command, state::MoveOrdering};
+
+    use super::{gcode_to_commands, GcodeError, ON_COOLDOWN};
+
+    fn convert(gcode: &str) -> Result<Vec<String>, Vec<GcodeError>> {
+        gcode_to_commands(gcode, &Parameters::default(), None)
+            .map(|commands| commands.iter().map(|command| format!("{command:?}")).collect())
+    }
+
+    fn error_lines(gcode: &str) -> Vec<usize> {
+        convert(gcode).unwrap_err().iter().map(|error| error.line).collect()
+    }
+
+    #[test]
+    fn moves() {
+        let commands = gcode_to_commands(
+            "G0 X1000 Y500 Z-300\nG1 Z-400 F100\nX1200\nG91\nG1 X-100 Y-100\nG20 G90 X10 Y0 Z0",
+            &Parameters::default(),
+            None,
+        ).unwrap();
+        let expected = [
+            (1.0, 0.5, -0.3, MoveOrdering::ZUpFirst),
+            (1.0, 0.5, -0.4, MoveOrdering::Simultaneous),
+            (1.2, 0.5, -0.4, MoveOrdering::Simultaneous),
+            (1.1, 0.4, -0.4, MoveOrdering::Simultaneous),
+            (0.254, 0.0, 0.0, MoveOrdering::Simultaneous),
+        ];
+        assert_eq!(expected.len(), commands.len());
+        for (command, (ex, ey, ez, eordering)) in commands.iter().zip(expected) {
+            let Command::Move { x, y, z, ordering } = command else {
+                panic!("Unexpected command {command:?}");
+            };
+            let close = |a: f32, b: f32| (a - b).abs() < 1e-6;
+            assert!(close(*x, ex) && close(*y, ey) && close(*z, ez) && *ordering == eordering, "{command:?}");
+        }
+    }
+
+    #[test]
+    fn dwell_and_m_codes() {
+        let commands = gcode_to_commands(
+            "%\nN10 M3 S1000 ; start plowing\nG4 P1500 (wait a bit)\nM5\nM8\nG4 S2\nM9\nM355 S1\nM355 S0\nM30\nthis is ignored",
+            &Parameters::default(),
+            None,
+        ).unwrap();
+        assert_eq!(
+            vec![
+                format!("{:?}", Command::PlowCooldown(Some(ON_COOLDOWN))),
+                format!("{:?}", Command::Wait(Duration::from_millis(1500))),
+                format!("{:?}", Command::PlowCooldown(None)),
+                format!("{:?}", Command::WaterCooldown(Some(ON_COOLDOWN))),
+                format!("{:?}", Command::Wait(Duration::from_secs(2))),
+                format!("{:?}", Command::PumpCooldown(None)),
+                format!("{:?}", Command::WaterCooldown(None)),
+                format!("{:?}", Command::LightsCooldown(Some(ON_COOLDOWN))),
+                format!("{:?}", Command::LightsCooldown(None)),
+            ],
+            commands.iter().map(|command| format!("{command:?}")).collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn errors() {
+        // unreachable coordinates
+        assert_eq!(vec![2, 4], error_lines("G0 X1000 Y0 Z0\nG1 Y2000\nG1 Y1000\nG1 X-2000 Y0"));
+        // coordinates not known yet
+        assert_eq!(vec![1, 2], error_lines("G91 G0 X100\nG90 G0 X1000 Y0\nG0 Z0"));
+        // unsupported codes and parameters
+        assert_eq!(vec![1, 2, 3, 4, 5, 6], error_lines("G2 X0 Y0\nM6\nG0 X0 Y0 Z0 E5\nG4\nG0 X\nG1 X1?"));
+    }
+
+    #[test]
+    fn error_message() {
+        assert_eq!(
+            "Line 2: Unreachable coordinates Vec3 { x: 0.0, y: 2.0, z: 0.0 }",
+            convert("G0 X0 Y0 Z0\nY2000").unwrap_err()[0].to_string()
+        );
+    }
+}
@@ -1,6 +1,12 @@
 pub mod action_wrapper;
 pub mod command_list;
 pub mod emergency;
+pub mod gcode;
+pub mod plow_area;
+pub mod registry;
+pub mod retry;
+pub mod script;
+pub mod water_all;
 
 use std::fmt::Debug;
 
@@ -8,7 +14,7 @@ use definitions::StepProgress;
 
 use crate::state::{StateHandler, StateHandlerError};
 
-use self::action_wrapper::Context;
+use self::{action_wrapper::Context, retry::RetryPolicy};
 
 /// An "action" that the robot can perform, possibly made up of multiple
 /// steps to allow pausing in-between. While being paused, the state of
@@ -58,6 +64,19 @@ pub trait Action: Debug + Send {
     /// * `ctx` contains information on this action, e.g. its id, the save folder, ...
     fn release(&mut self, _ctx: &Context) {}
 
+    /// Returns how the queue should react when [`step()`](Action::step()) returns
+    /// [StepResult::RunningError]. May change from one step to another.
+    fn retry_policy(&self) -> RetryPolicy {
+        RetryPolicy::default()
+    }
+
+    /// Returns what this action was asked to do (e.g. which plants to water) as JSON, to be
+    /// recorded in the [history](crate::history) once the action leaves the queue. Should not
+    /// depend on the progress of the action, since it is obtained only once.
+    fn parameters(&self) -> serde_json::Value {
+        serde_json::Value::Null
+    }
+
     //----
     // SAVING/LOADING TO/FROM DISK
     //----
@@ -68,7 +87,8 @@ pub trait Action: Debug + Send {
     ///
     /// Will be used to keep track of the action type before calling
     /// [`save_to_disk()`](Action::save_to_disk()), and will be used call
-    /// [`load_from_disk()`](Action::load_from_disk()) on the correct action type.
+    /// [`load_from_disk()`](Action::load_from_disk()) on the correct action type,
+    /// which therefore needs to be in the [ActionRegistry](registry::ActionRegistry).
     fn get_type_name() -> &'static str
     where
         Self: Sized;
@@ -77,7 +97,8 @@ pub trait Action: Debug + Send {
     /// of the program.
     ///
     /// Stores data only in the [`ctx.save_dir`](Context::save_dir) directory.
-    /// Note: the file `{ctx.save_dir}/progress.json` is reserved and should not be written to.
+    /// Note: the files `{ctx.save_dir}/progress.json`, `{ctx.save_dir}/priority.json` and
+    /// `{ctx.save_dir}/started.json` are reserved and should not be written to.
     ///
     /// * `ctx` contains information on this action, e.g. its id, the save folder, ...
     fn save_to_disk(&self, ctx: &Context) -> Result<(), String>;
@@ -97,7 +118,8 @@ pub enum StepResult {
     /// The action still has more steps to do.
     Running(StepProgress),
     /// The current step failed, but it can be retried.
-    /// This will make the queue pause, to wait for manual intervention, so USE WITH CARE!
+    /// Unless the [retry policy](Action::retry_policy()) allows retrying the step automatically,
+    /// this will make the queue pause, to wait for manual intervention, so USE WITH CARE!
     RunningError(StateHandlerError),
     /// There are no more steps to do, and the action will be removed from the queue.
     Finished,
@@ -105,3 +127,15 @@ pub enum StepResult {
     /// The action will be removed from the queue.
     FinishedError(StateHandlerError),
 }
+
+impl StepResult {
+    /// Returns [StepResult::RunningError] if the work done in a step failed, so that the same
+    /// work is retried in the next step, or otherwise lets `advance` record the progress (e.g.
+    /// increment a counter) and decide the result.
+    pub fn advance_if_ok<T>(res: Result<T, StateHandlerError>, advance: impl FnOnce(T) -> StepResult) -> StepResult {
+        match res {
+            Ok(value) => advance(value),
+            Err(e) => StepResult::RunningError(e),
+        }
+    }
+}
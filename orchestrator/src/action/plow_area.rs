@@ -0,0 +1,425 @@
+use definitions::{Parameters, StepProgress, Tool, Vec3};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    action::StepResult,
+    state::{current_tool, kinematics::world_to_joint, MoveOrdering, StateHandler, StateHandlerError},
+    util::serde::{deserialize_from_json_file, serialize_to_json_file},
+};
+
+use super::{Action, Context};
+
+/// The distance in meters between consecutive points when looking for the parts of a line that
+/// are out of reach.
+const SAMPLE_DISTANCE: f32 = 0.01;
+/// The precision in meters of the limits of the reachable workspace along a line.
+const BOUNDARY_TOLERANCE: f32 = 0.001;
+/// How long in meters a single plowing step can be at most, so that the action can be paused
+/// in the middle of long lines.
+const MAX_STEP_LENGTH: f32 = 0.5;
+/// The smallest spacing in meters between lines that is accepted.
+const MIN_SPACING: f32 = 0.01;
+/// The maximum number of lines, so that planning does not take too long.
+const MAX_LINES: usize = 1000;
+/// The maximum number of points that are checked along each line when looking for the parts that
+/// are out of reach, so that planning does not take too long.
+const MAX_SAMPLES_PER_LINE: usize = 2000;
+/// The maximum number of vertices of a polygon.
+const MAX_VERTICES: usize = 1000;
+
+/// Plows an area line by line, going back and forth along the X axis (i.e. a boustrophedon
+/// path). The parts of the area that are out of reach are skipped. The path is planned when the
+/// action is created, using the parameters and tool at that time, and then each step moves along
+/// a piece of it. If the action is interrupted in the middle of a line, it restarts from the
+/// beginning of that line, see [`acquire()`](PlowAreaAction::acquire).
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PlowAreaAction {
+    area: Area,
+    spacing: f32,
+    depth: f32,
+    path: Vec<PlowStep>,
+    steps_done_so_far: usize,
+}
+
+/// A region of the garden seen from above, in world coordinates (see
+/// [RobotState::position](definitions::RobotState::position)).
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum Area {
+    /// An axis-aligned rectangle, from the corner with the smallest `x` and `y` to the one with
+    /// the largest.
+    Rectangle { min: (f32, f32), max: (f32, f32) },
+    /// A simple polygon, given as a list of `(x, y)` vertices.
+    Polygon(Vec<(f32, f32)>),
+}
+
+/// The JSON accepted to create a [PlowAreaAction].
+#[derive(Debug, Deserialize)]
+pub struct PlowAreaData {
+    pub area: Area,
+    /// The maximum distance in meters between two lines.
+    pub spacing: f32,
+    /// The world Z coordinate of the tip of the plow while plowing.
+    pub depth: f32,
+}
+
+/// A piece of the path planned by [plan_coverage].
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum PlowStep {
+    /// Turns off the plow and moves over obstacles (see [MoveOrdering::ZUpFirst]) to the start
+    /// of a line.
+    Travel(Vec3),
+    /// Turns on the plow and moves in a straight line.
+    Plow(Vec3),
+}
+
+impl Area {
+    fn vertices(&self) -> Vec<(f32, f32)> {
+        match self {
+            Area::Rectangle { min, max } => vec![*min, (max.0, min.1), *max, (min.0, max.1)],
+            Area::Polygon(vertices) => vertices.clone(),
+        }
+    }
+
+    /// The intervals `(x_start, x_end)` of the horizontal line at `y` that are inside the area,
+    /// ordered by increasing `x`.
+    fn intervals_at(&self, y: f32) -> Vec<(f32, f32)> {
+        let vertices = self.vertices();
+        let mut crossings: Vec<f32> = vertices.iter()
+            .zip(vertices.iter().cycle().skip(1))
+            .filter(|((_, ay), (_, by))| (*ay <= y) != (*by <= y))
+            .map(|((ax, ay), (bx, by))| ax + (y - ay) / (by - ay) * (bx - ax))
+            .collect();
+        crossings.sort_by(f32::total_cmp);
+        crossings.chunks_exact(2).map(|pair| (pair[0], pair[1])).collect()
+    }
+}
+
+/// Plans a boustrophedon path covering `area` at height `depth`, with lines parallel to the X
+/// axis at most `spacing` apart, and clipped to the positions the tip of `tool` can reach.
+/// Returns an error if the data is invalid or if no part of the area can be reached.
+pub fn plan_coverage(
+    area: &Area,
+    spacing: f32,
+    depth: f32,
+    params: &Parameters,
+    tool: Option<&Tool>,
+) -> Result<Vec<PlowStep>, String> {
+    if !spacing.is_finite() || spacing < MIN_SPACING {
+        return Err(format!("Invalid spacing {spacing}, must be at least {MIN_SPACING}"));
+    }
+    let vertices = area.vertices();
+    if vertices.len() < 3 {
+        return Err("A polygon needs at least 3 vertices".to_string());
+    }
+    if vertices.len() > MAX_VERTICES {
+        return Err(format!("A polygon can have at most {MAX_VERTICES} vertices, got {}", vertices.len()));
+    }
+    if let Some(vertex) = vertices.iter().find(|(x, y)| !x.is_finite() || !y.is_finite()) {
+        return Err(format!("Invalid vertex {vertex:?}"));
+    }
+    let (x_min, x_max) = vertices.iter()
+        .fold((f32::INFINITY, f32::NEG_INFINITY), |(min, max), (x, _)| (min.min(*x), max.max(*x)));
+    let (y_min, y_max) = vertices.iter()
+        .fold((f32::INFINITY, f32::NEG_INFINITY), |(min, max), (_, y)| (min.min(*y), max.max(*y)));
+    if y_max - y_min <= 0.0 {
+        return Err("The area is empty".to_string());
+    }
+    if (x_max - x_min) / SAMPLE_DISTANCE > MAX_SAMPLES_PER_LINE as f32 {
+        return Err(format!(
+            "The area is too wide, it can be at most {} meters along the X axis",
+            MAX_SAMPLES_PER_LINE as f32 * SAMPLE_DISTANCE
+        ));
+    }
+
+    // distribute the lines evenly, half a spacing away from the borders (the small tolerance
+    // avoids adding a line because of rounding errors, e.g. in 0.1 / 0.1 = 1.0000001)
+    let lines = ((y_max - y_min) / spacing - 1e-4).ceil().max(1.0);
+    if lines > MAX_LINES as f32 {
+        return Err(format!("Too many lines ({lines}), use a larger spacing or a smaller area"));
+    }
+    let lines = lines as usize;
+    let line_spacing = (y_max - y_min) / lines as f32;
+    let reachable = |x: f32, y: f32| world_to_joint(&Vec3 { x, y, z: depth }, params, tool).is_some();
+
+    let mut path = vec![];
+    let mut backwards = false;
+    for line in 0..lines {
+        let y = y_min + (line as f32 + 0.5) * line_spacing;
+        let mut segments: Vec<(f32, f32)> = area.intervals_at(y).into_iter()
+            .flat_map(|interval| reachable_parts(interval, |x| reachable(x, y)))
+            .collect();
+        if segments.is_empty() {
+            continue;
+        }
+        if backwards {
+            segments = segments.into_iter().rev().map(|(start, end)| (end, start)).collect();
+        }
+        backwards = !backwards;
+
+        for (start, end) in segments {
+            path.push(PlowStep::Travel(Vec3 { x: start, y, z: depth }));
+            let pieces = ((end - start).abs() / MAX_STEP_LENGTH).ceil().max(1.0) as usize;
+            for piece in 1..=pieces {
+                let x = start + (end - start) * piece as f32 / pieces as f32;
+                path.push(PlowStep::Plow(Vec3 { x, y, z: depth }));
+            }
+        }
+    }
+
+    if path.is_empty() {
+        return Err("No part of the area can be reached".to_string());
+    }
+    Ok(path)
+}
+
+/// Splits `(start, end)` into the parts where `reachable` holds, skipping parts shorter than
+/// [BOUNDARY_TOLERANCE].
+fn reachable_parts(
+    (start, end): (f32, f32),
+    reachable: impl Fn(f32) -> bool,
+) -> Vec<(f32, f32)> {
+    // finds the point between `inside` and `outside` where reachability changes
+    let boundary = |mut inside: f32, mut outside: f32| {
+        while (outside - inside).abs() > BOUNDARY_TOLERANCE {
+            let middle = (inside + outside) / 2.0;
+            if reachable(middle) {
+                inside = middle;
+            } else {
+                outside = middle;
+            }
+        }
+        inside
+    };
+
+    let samples = ((end - start) / SAMPLE_DISTANCE).ceil().max(1.0) as usize;
+    let mut parts = vec![];
+    let mut part_start = reachable(start).then_some(start);
+    let mut prev = start;
+    for i in 1..=samples {
+        let x = start + (end - start) * i as f32 / samples as f32;
+        match (part_start, reachable(x)) {
+            (None, true) => part_start = Some(boundary(x, prev)),
+            (Some(s), false) => {
+                parts.push((s, boundary(prev, x)));
+                part_start = None;
+            }
+            _ => {}
+        }
+        prev = x;
+    }
+    if let Some(s) = part_start {
+        parts.push((s, end));
+    }
+    parts.retain(|(start, end)| end - start > BOUNDARY_TOLERANCE);
+    parts
+}
+
+impl PlowAreaAction {
+    /// Plans the path with the current parameters and tool, see [plan_coverage].
+    pub fn new(data: PlowAreaData, state_handler: &StateHandler) -> Result<Self, String> {
+        let state = state_handler.get_state();
+        let path = plan_coverage(&data.area, data.spacing, data.depth, &state.parameters, current_tool(&state))?;
+        Ok(PlowAreaAction {
+            area: data.area,
+            spacing: data.spacing,
+            depth: data.depth,
+            path,
+            steps_done_so_far: 0,
+        })
+    }
+
+    /// If the next step is in the middle of a line, goes back to the [PlowStep::Travel] at the
+    /// start of the line, so that the plow is raised and lowered again there instead of being
+    /// dragged from wherever it was left.
+    fn rewind_to_line_start(&mut self) {
+        if let Some(PlowStep::Plow(_)) = self.path.get(self.steps_done_so_far) {
+            if let Some(line_start) = self.path[..self.steps_done_so_far].iter()
+                .rposition(|step| matches!(step, PlowStep::Travel(_)))
+            {
+                self.steps_done_so_far = line_start;
+            }
+        }
+    }
+
+    async fn execute(step: &PlowStep, state_handler: &StateHandler) -> Result<(), StateHandlerError> {
+        match step {
+            PlowStep::Travel(Vec3 { x, y, z }) => {
+                state_handler.plow(0).await?;
+                state_handler.move_to_resetting_on_stall(*x, *y, *z, MoveOrdering::ZUpFirst).await
+            }
+            PlowStep::Plow(Vec3 { x, y, z }) => {
+                // the plow turns off by itself if the move takes longer than it should
+                let cooldown_ms = state_handler.get_state().parameters.move_timeout_ms;
+                state_handler.plow(cooldown_ms).await?;
+                state_handler.move_to_resetting_on_stall(*x, *y, *z, MoveOrdering::Simultaneous).await
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl Action for PlowAreaAction {
+    /// Called when the action starts or resumes, e.g. after being paused or preempted, or after
+    /// the orchestrator restarted, at which point the plow could be anywhere.
+    fn acquire(&mut self, _ctx: &Context) {
+        self.rewind_to_line_start();
+    }
+
+    async fn step(&mut self, _ctx: &Context, state_handler: &StateHandler) -> StepResult {
+        let Some(step) = self.path.get(self.steps_done_so_far) else {
+            return StepResult::Finished;
+        };
+        let mut res = Self::execute(step, state_handler).await;
+        if res.is_ok() && self.steps_done_so_far + 1 == self.path.len() {
+            res = state_handler.plow(0).await;
+        }
+
+        StepResult::advance_if_ok(res, |()| {
+            self.steps_done_so_far += 1;
+            if self.steps_done_so_far < self.path.len() {
+                StepResult::Running(StepProgress::Ratio {
+                    steps_done_so_far: self.steps_done_so_far,
+                    steps_total: self.path.len(),
+                })
+            } else {
+                StepResult::Finished
+            }
+        })
+    }
+
+    fn parameters(&self) -> serde_json::Value {
+        serde_json::json!({ "area": self.area, "spacing": self.spacing, "depth": self.depth })
+    }
+
+    fn get_type_name() -> &'static str
+    where
+        Self: Sized,
+    {
+        "plow_area"
+    }
+
+    fn save_to_disk(&self, ctx: &Context) -> Result<(), String> {
+        serialize_to_json_file(&self, &ctx.get_save_dir().join("data.json"))
+    }
+
+    fn load_from_disk(ctx: &Context) -> Result<Self, String>
+    where
+        Self: Sized,
+    {
+        deserialize_from_json_file(&ctx.get_save_dir().join("data.json"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use definitions::{Parameters, Vec3};
+
+    use crate::state::kinematics::world_to_joint;
+
+    use super::{plan_coverage, Area, PlowAreaAction, PlowStep};
+
+    fn params() -> Parameters {
+        Parameters { arm_length: 1.0, rail_length: 3.0, ..Default::default() }
+    }
+
+    fn point(step: &PlowStep) -> &Vec3 {
+        match step {
+            PlowStep::Travel(point) | PlowStep::Plow(point) => point,
+        }
+    }
+
+    /// Returns the lines of the path as `(y, x_start, x_end)`, checking that each one starts with
+    /// a [PlowStep::Travel] and is straight.
+    fn lines(path: &[PlowStep]) -> Vec<(f32, f32, f32)> {
+        let mut lines = vec![];
+        for step in path {
+            match step {
+                PlowStep::Travel(Vec3 { x, y, .. }) => lines.push((*y, *x, *x)),
+                PlowStep::Plow(Vec3 { x, y, .. }) => {
+                    let line = lines.last_mut().expect("The path does not start with a Travel");
+                    assert_eq!(line.0, *y);
+                    line.2 = *x;
+                }
+            }
+        }
+        lines
+    }
+
+    #[test]
+    fn rectangle_in_reach() {
+        let area = Area::Rectangle { min: (0.5, -0.4), max: (2.0, 0.4) };
+        let path = plan_coverage(&area, 0.3, -0.2, &params(), None).unwrap();
+        // 3 lines 0.8/3 apart, going back and forth, each with 3 pieces of 0.5 meters
+        assert_eq!(12, path.len());
+        let expected = [(-0.4 + 0.4 / 3.0, 0.5, 2.0), (0.0, 2.0, 0.5), (0.4 - 0.4 / 3.0, 0.5, 2.0)];
+        for ((y, start, end), (ey, estart, eend)) in lines(&path).into_iter().zip(expected) {
+            assert!((y - ey).abs() < 1e-5 && start == estart && end == eend, "{y} {start} {end}");
+        }
+        assert!(path.iter().all(|step| point(step).z == -0.2));
+    }
+
+    #[test]
+    fn clipped_to_workspace() {
+        // sticks out of both ends of the rail and beyond the reach of the arm
+        let area = Area::Rectangle { min: (-2.0, -2.0), max: (5.0, 2.0) };
+        let path = plan_coverage(&area, 0.1, 0.0, &params(), None).unwrap();
+        for step in &path {
+            assert!(world_to_joint(point(step), &params(), None).is_some(), "{step:?}");
+        }
+        let lines = lines(&path);
+        // only lines with |y| <= arm_length can be reached
+        assert_eq!(20, lines.len());
+        // the middle line goes from one end of the workspace to the other
+        let (_, start, end) = lines[10];
+        assert!((start.min(end) - -1.0).abs() < 0.01 && (start.max(end) - 4.0).abs() < 0.01, "{start} {end}");
+    }
+
+    #[test]
+    fn polygon() {
+        // a triangle, so each line is shorter than the previous one
+        let area = Area::Polygon(vec![(0.0, 0.0), (2.0, 0.0), (0.0, 0.8)]);
+        let lines = lines(&plan_coverage(&area, 0.2, 0.0, &params(), None).unwrap());
+        assert_eq!(4, lines.len());
+        let lengths: Vec<f32> = lines.iter().map(|(_, start, end)| (end - start).abs()).collect();
+        assert!(lengths.windows(2).all(|pair| pair[0] > pair[1]), "{lengths:?}");
+        assert!((lengths[0] - 1.75).abs() < 1e-5, "{lengths:?}");
+    }
+
+    #[test]
+    fn invalid() {
+        let area = Area::Rectangle { min: (0.0, 0.0), max: (1.0, 1.0) };
+        assert!(plan_coverage(&area, 0.0, 0.0, &params(), None).is_err());
+        assert!(plan_coverage(&area, f32::NAN, 0.0, &params(), None).is_err());
+        assert!(plan_coverage(&area, 1e-6, 0.0, &params(), None).is_err());
+        // too many lines, or too wide
+        assert!(plan_coverage(&Area::Rectangle { min: (0.0, 0.0), max: (1.0, 100.0) }, 0.01, 0.0, &params(), None).is_err());
+        assert!(plan_coverage(&Area::Rectangle { min: (0.0, 0.0), max: (1e30, 1.0) }, 0.1, 0.0, &params(), None).is_err());
+        assert!(plan_coverage(&Area::Rectangle { min: (0.0, 0.0), max: (f32::INFINITY, 1.0) }, 0.1, 0.0, &params(), None).is_err());
+        let circle = (0..10000).map(|i| (i as f32).to_radians()).map(|a| (a.cos(), a.sin())).collect();
+        assert!(plan_coverage(&Area::Polygon(circle), 0.1, 0.0, &params(), None).is_err());
+        assert!(plan_coverage(&Area::Polygon(vec![(0.0, 0.0), (1.0, 1.0)]), 0.1, 0.0, &params(), None).is_err());
+        assert!(plan_coverage(&Area::Rectangle { min: (0.0, 0.0), max: (1.0, 0.0) }, 0.1, 0.0, &params(), None).is_err());
+        // out of reach
+        assert!(plan_coverage(&Area::Rectangle { min: (0.0, 2.0), max: (1.0, 3.0) }, 0.1, 0.0, &params(), None).is_err());
+    }
+
+    #[test]
+    fn rewind_to_line_start() {
+        let area = Area::Rectangle { min: (0.5, -0.4), max: (2.0, 0.4) };
+        let path = plan_coverage(&area, 0.3, -0.2, &params(), None).unwrap();
+        let mut action = PlowAreaAction { area, spacing: 0.3, depth: -0.2, path, steps_done_so_far: 0 };
+        action.rewind_to_line_start();
+        assert_eq!(0, action.steps_done_so_far);
+
+        // in the middle of the second line, whose Travel is at index 4
+        action.steps_done_so_far = 6;
+        action.rewind_to_line_start();
+        assert_eq!(4, action.steps_done_so_far);
+        action.rewind_to_line_start();
+        assert_eq!(4, action.steps_done_so_far);
+
+        action.steps_done_so_far = action.path.len();
+        action.rewind_to_line_start();
+        assert_eq!(action.path.len(), action.steps_done_so_far);
+    }
+}
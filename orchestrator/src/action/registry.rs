@@ -0,0 +1,190 @@
+use std::{collections::HashMap, fmt::Debug, sync::Arc};
+
+use serde::{de::{DeserializeOwned, IgnoredAny}, Deserialize};
+use serde_json::Value;
+
+use crate::state::StateHandler;
+
+use super::{action_wrapper::Context, command_list::{Command, CommandListAction, CommandListData}, emergency::EmergencyAction, plow_area::{PlowAreaAction, PlowAreaData}, script::{ScriptAction, ScriptData}, water_all::WaterAllAction, Action};
+
+type Loader = fn(&Context) -> Result<Box<dyn Action>, String>;
+type JsonConstructor = Box<dyn Fn(Value, &StateHandler) -> Result<Box<dyn Action>, String> + Send + Sync>;
+
+/// Describes how to load actions of a specific [Action] type from disk, and optionally how to
+/// create new ones from JSON data (e.g. received through the API).
+pub struct ActionType {
+    type_name: &'static str,
+    loader: Loader,
+    json_constructor: Option<JsonConstructor>,
+}
+
+impl ActionType {
+    /// Creates an [ActionType] for `A` that can only be loaded from disk, using
+    /// [`A::load_from_disk()`](Action::load_from_disk()).
+    pub fn new<A: Action + 'static>() -> ActionType {
+        ActionType {
+            type_name: A::get_type_name(),
+            loader: |ctx| Ok(Box::new(A::load_from_disk(ctx)?)),
+            json_constructor: None,
+        }
+    }
+
+    /// Allows creating new actions of this type from JSON, by deserializing the JSON into `T` and
+    /// then passing it to `constructor`. Use [IgnoredAny] as `T` if no data is needed.
+    pub fn with_json_constructor<A, T, F>(self, constructor: F) -> ActionType
+    where
+        A: Action + 'static,
+        T: DeserializeOwned,
+        F: Fn(T, &StateHandler) -> A + Send + Sync + 'static,
+    {
+        self.with_fallible_json_constructor(move |data, state_handler| Ok(constructor(data, state_handler)))
+    }
+
+    /// Like [`with_json_constructor()`](ActionType::with_json_constructor), but `constructor` can
+    /// reject the data even if it deserializes correctly.
+    pub fn with_fallible_json_constructor<A, T, F>(mut self, constructor: F) -> ActionType
+    where
+        A: Action + 'static,
+        T: DeserializeOwned,
+        F: Fn(T, &StateHandler) -> Result<A, String> + Send + Sync + 'static,
+    {
+        self.json_constructor = Some(Box::new(move |data, state_handler| {
+            let data = T::deserialize(data).map_err(|e| e.to_string())?;
+            Ok(Box::new(constructor(data, state_handler)?))
+        }));
+        self
+    }
+
+    pub fn get_type_name(&self) -> &'static str {
+        self.type_name
+    }
+
+    /// Loads an action of this type from disk, see [Action::load_from_disk].
+    pub fn load_from_disk(&self, ctx: &Context) -> Result<Box<dyn Action>, String> {
+        (self.loader)(ctx)
+    }
+
+    /// Creates a new action of this type from `data`. Returns `None` if this type has no
+    /// [JSON constructor](ActionType::with_json_constructor), or `Some(Err)` if `data` is invalid.
+    pub fn create_from_json(&self, data: Value, state_handler: &StateHandler) -> Option<Result<Box<dyn Action>, String>> {
+        self.json_constructor.as_ref().map(|constructor| constructor(data, state_handler))
+    }
+}
+
+/// JSON data for a [JSON constructor](ActionType::with_json_constructor) that can be either the
+/// whole `D` object, or just its main field `T` (e.g. the list of commands of a
+/// [CommandListAction]) if all other fields can have their default value.
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+pub enum ShorthandData<T, D> {
+    Short(T),
+    Full(D),
+}
+
+impl<T, D: From<T>> ShorthandData<T, D> {
+    pub fn into_full(self) -> D {
+        match self {
+            ShorthandData::Short(short) => D::from(short),
+            ShorthandData::Full(full) => full,
+        }
+    }
+}
+
+impl Debug for ActionType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ActionType")
+            .field("type_name", &self.type_name)
+            .field("has_json_constructor", &self.json_constructor.is_some())
+            .finish()
+    }
+}
+
+/// All of the [Action] types the queue knows about, indexed by their
+/// [type name](Action::get_type_name). Used to restore actions saved to disk, and to create new
+/// actions by type name (see `POST /queue/add_action/<type_name>`).
+///
+/// [ActionRegistry::default] contains all of the built-in action types, and new action types can
+/// be made available by [registering](ActionRegistry::register) them.
+#[derive(Debug, Clone)]
+pub struct ActionRegistry {
+    types: Arc<HashMap<&'static str, ActionType>>,
+}
+
+impl ActionRegistry {
+    /// Creates a registry without any action type.
+    pub fn empty() -> ActionRegistry {
+        ActionRegistry { types: Arc::new(HashMap::new()) }
+    }
+
+    /// Adds `action_type` to the registry.
+    ///
+    /// Panics if a type with the same name was already registered, or if the registry has already
+    /// been cloned (i.e. types must all be registered before passing the registry around).
+    pub fn register(mut self, action_type: ActionType) -> ActionRegistry {
+        let types = Arc::get_mut(&mut self.types)
+            .expect("Action types must be registered before the registry is shared");
+        let type_name = action_type.type_name;
+        if types.insert(type_name, action_type).is_some() {
+            panic!("Action type {type_name:?} registered twice");
+        }
+        self
+    }
+
+    pub fn get(&self, type_name: &str) -> Option<&ActionType> {
+        self.types.get(type_name)
+    }
+}
+
+impl Default for ActionRegistry {
+    fn default() -> Self {
+        ActionRegistry::empty()
+            // emergencies can only be started with QueueHandler::emergency()
+            .register(ActionType::new::<EmergencyAction>())
+            .register(ActionType::new::<CommandListAction>()
+                .with_json_constructor(|data: ShorthandData<Vec<Command>, CommandListData>, _| {
+                    CommandListAction::from_data(data.into_full())
+                }))
+            .register(ActionType::new::<WaterAllAction>()
+                .with_json_constructor(|_: IgnoredAny, state_handler| WaterAllAction::new(state_handler)))
+            .register(ActionType::new::<ScriptAction>()
+                .with_fallible_json_constructor(|data: ShorthandData<String, ScriptData>, _| {
+                    ScriptAction::from_data(data.into_full())
+                }))
+            .register(ActionType::new::<PlowAreaAction>()
+                .with_fallible_json_constructor(|data: PlowAreaData, state_handler| PlowAreaAction::new(data, state_handler)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ActionRegistry, ActionType, EmergencyAction, ScriptData, ShorthandData};
+
+    #[test]
+    fn default_registry() {
+        let registry = ActionRegistry::default();
+        assert!(registry.get("emergency").is_some());
+        assert!(registry.get("command_list").is_some());
+        assert!(registry.get("water_all").is_some());
+        assert!(registry.get("script").is_some());
+        assert!(registry.get("plow_area").is_some());
+        assert!(registry.get("unknown").is_none());
+    }
+
+    #[test]
+    fn shorthand_data() {
+        let parse = |json| serde_json::from_value::<ShorthandData<String, ScriptData>>(json).unwrap().into_full();
+        let short = parse(serde_json::json!("home()"));
+        assert_eq!("home()", short.source);
+        assert_eq!(1_000_000, short.limits.max_operations);
+        let full = parse(serde_json::json!({ "source": "home()", "limits": { "max_operations": 5 } }));
+        assert_eq!("home()", full.source);
+        assert_eq!(5, full.limits.max_operations);
+    }
+
+    #[test]
+    #[should_panic]
+    fn register_twice() {
+        let _ = ActionRegistry::default()
+            .register(ActionType::new::<EmergencyAction>());
+    }
+}
@@ -0,0 +1,161 @@
+use std::time::Duration;
+
+use embedcore::protocol::{communication::CommunicationError, cyber::DeviceError};
+use serde::{Deserialize, Serialize};
+
+use crate::state::StateHandlerError;
+
+/// Which errors returned by [`Action::step()`](super::Action::step()) as
+/// [StepResult::RunningError](super::StepResult::RunningError) can be retried automatically.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum RetriableError {
+    /// [StateHandlerError::Communication] with [CommunicationError::Timeout], e.g. because a
+    /// message on the serial port got lost.
+    CommunicationTimeout,
+    /// Any [StateHandlerError::Communication].
+    Communication,
+    /// [StateHandlerError::MoveTimeout].
+    MoveTimeout,
+    /// [StateHandlerError::WaterNotFlowing].
+    WaterNotFlowing,
+    /// [StateHandlerError::Device] with [DeviceError::Stall]. Actions moving the robot reset the
+    /// motors after a stall, so the step can be retried from a known position, see
+    /// [`move_to_resetting_on_stall()`](crate::state::StateHandler::move_to_resetting_on_stall).
+    Stall,
+}
+
+impl RetriableError {
+    pub fn matches(&self, error: &StateHandlerError) -> bool {
+        match (self, error) {
+            (RetriableError::CommunicationTimeout, StateHandlerError::Communication { error, .. }) => {
+                matches!(error, CommunicationError::Timeout)
+            }
+            (RetriableError::Communication, StateHandlerError::Communication { .. }) => true,
+            (RetriableError::MoveTimeout, StateHandlerError::MoveTimeout { .. }) => true,
+            (RetriableError::WaterNotFlowing, StateHandlerError::WaterNotFlowing { .. }) => true,
+            (RetriableError::Stall, StateHandlerError::Device { error, .. }) => {
+                matches!(error, DeviceError::Stall { .. })
+            }
+            _ => false,
+        }
+    }
+}
+
+/// What to do with an action once its [RetryPolicy] is exhausted, or if the error can't be
+/// retried.
+#[derive(Debug, Clone, Copy, PartialEq, Default, Serialize, Deserialize)]
+pub enum Escalation {
+    /// Pause the queue, keeping the action in the queue, so that a human can intervene.
+    #[default]
+    Pause,
+    /// Remove the action from the queue as if it returned
+    /// [StepResult::FinishedError](super::StepResult::FinishedError), and go on with the next one.
+    Finish,
+}
+
+/// Decides whether the queue should retry a step that returned
+/// [StepResult::RunningError](super::StepResult::RunningError), instead of pausing immediately.
+/// The failed step is attempted again after waiting for an exponentially increasing backoff.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RetryPolicy {
+    /// How many times a step is attempted in total before escalating, including the first time.
+    /// `1` means that errors are never retried.
+    pub max_attempts: u32,
+    /// How many milliseconds to wait before the first retry.
+    pub initial_backoff_ms: u64,
+    /// How much the backoff is multiplied by after each retry.
+    pub backoff_multiplier: f32,
+    /// The backoff never gets longer than this many milliseconds.
+    pub max_backoff_ms: u64,
+    /// Only errors matching one of these are retried.
+    pub retry_on: Vec<RetriableError>,
+    pub on_exhausted: Escalation,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_attempts: 3,
+            initial_backoff_ms: 1000,
+            backoff_multiplier: 2.0,
+            max_backoff_ms: 60_000,
+            retry_on: vec![RetriableError::CommunicationTimeout],
+            on_exhausted: Escalation::Pause,
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// A policy that never retries and always pauses the queue.
+    pub fn never() -> Self {
+        RetryPolicy { max_attempts: 1, retry_on: vec![], ..Default::default() }
+    }
+
+    /// Whether to retry after the step failed with `error` for the `failed_attempts`-th time in
+    /// a row.
+    pub fn should_retry(&self, error: &StateHandlerError, failed_attempts: u32) -> bool {
+        failed_attempts < self.max_attempts
+            && self.retry_on.iter().any(|retriable| retriable.matches(error))
+    }
+
+    /// How long to wait before attempting the step again after it failed for the
+    /// `failed_attempts`-th time in a row.
+    pub fn backoff(&self, failed_attempts: u32) -> Duration {
+        let exponent = failed_attempts.saturating_sub(1).min(i32::MAX as u32) as i32;
+        let factor = (self.backoff_multiplier.max(1.0) as f64).powi(exponent);
+        // the float to int conversion saturates, so the result never overflows
+        let backoff_ms = (self.initial_backoff_ms as f64 * factor).min(self.max_backoff_ms as f64) as u64;
+        Duration::from_millis(backoff_ms)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use embedcore::protocol::{communication::CommunicationError, cyber::DeviceError};
+
+    use crate::state::StateHandlerError;
+
+    use super::{RetriableError, RetryPolicy};
+
+    fn communication(error: CommunicationError) -> StateHandlerError {
+        StateHandlerError::Communication { error, device_name: "peripherals", function_call: "water" }
+    }
+
+    #[test]
+    fn should_retry() {
+        let policy = RetryPolicy::default();
+        assert!(policy.should_retry(&communication(CommunicationError::Timeout), 1));
+        assert!(policy.should_retry(&communication(CommunicationError::Timeout), 2));
+        assert!(!policy.should_retry(&communication(CommunicationError::Timeout), 3));
+        assert!(!policy.should_retry(&communication(CommunicationError::SerMsgError), 1));
+        assert!(!policy.should_retry(&StateHandlerError::GenericError("whatever".into()), 1));
+
+        let policy = RetryPolicy { retry_on: vec![RetriableError::Communication], ..Default::default() };
+        assert!(policy.should_retry(&communication(CommunicationError::SerMsgError), 1));
+        assert!(!RetryPolicy::never().should_retry(&communication(CommunicationError::Timeout), 1));
+
+        let stall = |error| StateHandlerError::Device { error, device_name: "motor_x", function_call: "get_motor_state" };
+        let policy = RetryPolicy { retry_on: vec![RetriableError::Stall], ..Default::default() };
+        assert!(policy.should_retry(&stall(DeviceError::Stall { steps: None }), 1));
+        assert!(!policy.should_retry(&stall(DeviceError::DriverFault), 1));
+        assert!(!RetryPolicy::default().should_retry(&stall(DeviceError::Stall { steps: Some(3) }), 1));
+    }
+
+    #[test]
+    fn backoff() {
+        let policy = RetryPolicy {
+            initial_backoff_ms: 100,
+            backoff_multiplier: 3.0,
+            max_backoff_ms: 1000,
+            ..Default::default()
+        };
+        assert_eq!(Duration::from_millis(100), policy.backoff(1));
+        assert_eq!(Duration::from_millis(300), policy.backoff(2));
+        assert_eq!(Duration::from_millis(900), policy.backoff(3));
+        assert_eq!(Duration::from_secs(1), policy.backoff(4));
+        assert_eq!(Duration::from_secs(1), policy.backoff(u32::MAX));
+    }
+}
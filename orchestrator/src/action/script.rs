@@ -0,0 +1,522 @@
+use std::{cell::RefCell, rc::Rc, time::{Duration, Instant}};
+
+use definitions::StepProgress;
+use rhai::{
+    packages::{
+        BasicArrayPackage, BasicBlobPackage, BasicMapPackage, BasicMathPackage, BitFieldPackage,
+        CorePackage, LogicPackage, MoreStringPackage, Package,
+    },
+    Dynamic, Engine, EvalAltResult, AST,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::{
+    action::StepResult, state::{MoveOrdering, StateHandler, StateHandlerError}, util::serde::{deserialize_from_json_file, serialize_to_json_file}
+};
+
+use super::{command_list::{Command, CommandListAction}, Action, Context};
+
+/// Runs a [Rhai](https://rhai.rs) script that controls the robot through a few bindings:
+/// - `move_to(x, y, z)`, `reset()`, `home()`, `wait(secs)`
+/// - `water(secs)`, `water_liters(liters)`, `lights(secs)`, `pump(secs)`, `plow(secs)`
+/// - `get_state()`, which updates the [RobotState](definitions::RobotState) and returns an object
+///   whose fields are read from the latest state when the script accesses them
+/// - `get_plants()`, which returns the plants in the
+///   [PlantRegistry](crate::state::plants::PlantRegistry) as an array of object maps
+///
+/// Each step executes one binding call. Since the interpreter state can't be saved, every step
+/// runs the script again from the beginning, making binding calls return the results recorded
+/// in [ScriptAction::calls] instead of executing them again, until a new call is reached. This
+/// requires scripts to be deterministic, which is why there is no access to time, randomness or
+/// other files. Values read from the state are recorded too, but only the ones the script
+/// actually accessed.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ScriptAction {
+    source: String,
+    /// The binding calls executed and the state values read so far, along with their results,
+    /// oldest first.
+    calls: Vec<CompletedCall>,
+    #[serde(default)]
+    limits: ScriptLimits,
+}
+
+/// Limits that apply to every run of a script, i.e. to every step, starting from when the replay
+/// of the calls that were already executed ends.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ScriptLimits {
+    /// The maximum number of operations, see [Engine::set_max_operations]. 0 means unlimited.
+    pub max_operations: u64,
+    /// The maximum time in milliseconds spent running the script itself, not counting binding
+    /// calls.
+    pub max_run_time_ms: u64,
+}
+
+impl Default for ScriptLimits {
+    fn default() -> Self {
+        ScriptLimits {
+            max_operations: 1_000_000,
+            max_run_time_ms: 1000,
+        }
+    }
+}
+
+/// The JSON accepted to create a [ScriptAction], which can also be just the source of the script
+/// (see [ShorthandData](super::registry::ShorthandData)).
+#[derive(Debug, Deserialize)]
+pub struct ScriptData {
+    pub source: String,
+    #[serde(default)]
+    pub limits: ScriptLimits,
+}
+
+impl From<String> for ScriptData {
+    fn from(source: String) -> Self {
+        ScriptData { source, limits: ScriptLimits::default() }
+    }
+}
+
+/// What the script asked to do with a binding call.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+enum ScriptCall {
+    Command(Command),
+    GetState,
+    GetPlants,
+    /// The script accessed a field (given as the path of keys to reach it) of a [StateView].
+    /// Executed right away during the run, without waiting for the next step.
+    ReadState(Vec<String>),
+}
+
+/// What `get_state()` returns to the script. Accessing a field reads it from the latest state,
+/// returning a nested [StateView] for objects, or the value itself otherwise.
+#[derive(Debug, Clone, Default)]
+struct StateView {
+    path: Vec<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct CompletedCall {
+    call: ScriptCall,
+    result: Value,
+}
+
+/// How a run of the script ended.
+#[derive(Debug)]
+enum RunOutcome {
+    /// The script reached a binding call that was not executed yet.
+    Yielded(ScriptCall),
+    Finished,
+    Failed(String),
+}
+
+/// Shared between the bindings during a run of the script, see [ScriptAction::run_script].
+struct Replay {
+    calls: Vec<CompletedCall>,
+    /// The index in `calls` of the result to return from the next binding call.
+    next: usize,
+    /// The first call that was not executed yet, after which the script is terminated.
+    pending: Option<ScriptCall>,
+    /// Set if the script did not repeat the same calls as in previous runs.
+    error: Option<String>,
+    /// The latest state, from which [ScriptCall::ReadState] calls are executed.
+    state: Value,
+    /// The number of operations and the time at which the replay ended, from which the
+    /// [ScriptLimits] are counted.
+    replay_end: Option<(u64, Instant)>,
+}
+
+impl Replay {
+    /// Returns the recorded result of `call`, or terminates the script if `call` is new.
+    fn call(&mut self, call: ScriptCall) -> Result<Value, Box<EvalAltResult>> {
+        let Some(completed) = self.calls.get(self.next) else {
+            self.pending = Some(call);
+            return Err(terminate());
+        };
+        if serde_json::to_value(&completed.call).ok() != serde_json::to_value(&call).ok() {
+            self.error = Some(format!(
+                "Script is not deterministic: call {} was {:?} but now is {call:?}",
+                self.next, completed.call
+            ));
+            return Err(terminate());
+        }
+        self.next += 1;
+        Ok(completed.result.clone())
+    }
+
+    /// Returns the value at `path` in the state, reading and recording it if it was not read yet
+    /// in a previous run. Objects are recorded as empty, since only their fields are read.
+    fn read(&mut self, path: Vec<String>) -> Result<Dynamic, Box<EvalAltResult>> {
+        let result = if self.is_replaying() {
+            self.call(ScriptCall::ReadState(path.clone()))?
+        } else {
+            let value = path.iter()
+                .try_fold(&self.state, |value, key| value.get(key))
+                .ok_or_else(|| format!("Unknown state field {}", path.join(".")))?;
+            let result = if value.is_object() { Value::Object(Default::default()) } else { value.clone() };
+            self.calls.push(CompletedCall { call: ScriptCall::ReadState(path.clone()), result: result.clone() });
+            self.next += 1;
+            result
+        };
+        if result.is_object() {
+            Ok(Dynamic::from(StateView { path }))
+        } else {
+            rhai::serde::to_dynamic(&result)
+        }
+    }
+
+    fn is_replaying(&self) -> bool {
+        self.next < self.calls.len()
+    }
+}
+
+/// Terminates the script, which can't be prevented with `try`/`catch` in the script.
+fn terminate() -> Box<EvalAltResult> {
+    EvalAltResult::ErrorTerminated(Dynamic::UNIT, rhai::Position::NONE).into()
+}
+
+fn to_f64(value: Dynamic) -> Result<f64, Box<EvalAltResult>> {
+    let type_name = value.type_name();
+    value.as_float()
+        .or_else(|_| value.as_int().map(|value| value as f64))
+        .map_err(|_| format!("Expected a number but got {type_name}").into())
+}
+
+fn to_duration(secs: Dynamic) -> Result<Duration, Box<EvalAltResult>> {
+    let secs = to_f64(secs)?;
+    Duration::try_from_secs_f64(secs)
+        .map_err(|e| format!("Invalid number of seconds {secs}: {e}").into())
+}
+
+/// Creates an engine without access to anything outside of the script (e.g. modules, time,
+/// `eval`), but without any binding. [ScriptLimits] are applied by
+/// [run_script()](ScriptAction::run_script).
+fn sandboxed_engine() -> Engine {
+    let mut engine = Engine::new_raw();
+    CorePackage::new().register_into_engine(&mut engine);
+    BitFieldPackage::new().register_into_engine(&mut engine);
+    LogicPackage::new().register_into_engine(&mut engine);
+    BasicMathPackage::new().register_into_engine(&mut engine);
+    BasicArrayPackage::new().register_into_engine(&mut engine);
+    BasicBlobPackage::new().register_into_engine(&mut engine);
+    BasicMapPackage::new().register_into_engine(&mut engine);
+    MoreStringPackage::new().register_into_engine(&mut engine);
+    engine.disable_symbol("eval");
+
+    engine.set_max_call_levels(64);
+    engine.set_max_expr_depths(64, 32);
+    engine.set_max_string_size(64 * 1024);
+    engine.set_max_array_size(64 * 1024);
+    engine.set_max_map_size(64 * 1024);
+    engine
+}
+
+fn compile(source: &str) -> Result<AST, String> {
+    sandboxed_engine().compile(source)
+        .map_err(|e| format!("Could not compile script: {e}"))
+}
+
+impl ScriptAction {
+    /// Returns an error if the script does not compile.
+    pub fn new(source: String, limits: ScriptLimits) -> Result<Self, String> {
+        compile(&source)?;
+        Ok(ScriptAction { source, calls: vec![], limits })
+    }
+
+    pub fn from_data(data: ScriptData) -> Result<Self, String> {
+        ScriptAction::new(data.source, data.limits)
+    }
+
+    /// Runs the script from the beginning until it finishes or reaches a binding call that is
+    /// not in [ScriptAction::calls], recording any new value read from `state` along the way.
+    /// Is not `async` since Rhai values can't be sent across threads, and should be run with
+    /// [tokio::task::spawn_blocking] since it may take up to [ScriptLimits::max_run_time_ms] (plus
+    /// the replay).
+    fn run_script(&mut self, state: Value) -> RunOutcome {
+        let ast = match compile(&self.source) {
+            Ok(ast) => ast,
+            Err(e) => return RunOutcome::Failed(e),
+        };
+
+        let replay = Rc::new(RefCell::new(Replay {
+            calls: std::mem::take(&mut self.calls),
+            next: 0,
+            pending: None,
+            error: None,
+            state,
+            replay_end: None,
+        }));
+        let mut engine = sandboxed_engine();
+        let binding = || {
+            let replay = replay.clone();
+            move |call| replay.borrow_mut().call(call).and_then(|result| rhai::serde::to_dynamic(&result))
+        };
+
+        let call = binding();
+        engine.register_fn("move_to", move |x: Dynamic, y: Dynamic, z: Dynamic| call(ScriptCall::Command(Command::Move {
+            x: to_f64(x)? as f32,
+            y: to_f64(y)? as f32,
+            z: to_f64(z)? as f32,
+            ordering: MoveOrdering::default(),
+        })));
+        let call = binding();
+        engine.register_fn("reset", move || call(ScriptCall::Command(Command::Reset)));
+        let call = binding();
+        engine.register_fn("home", move || call(ScriptCall::Command(Command::Home)));
+        let call = binding();
+        engine.register_fn("wait", move |secs: Dynamic| call(ScriptCall::Command(Command::Wait(to_duration(secs)?))));
+        let call = binding();
+        engine.register_fn("water", move |secs: Dynamic| call(ScriptCall::Command(Command::WaterWait(to_duration(secs)?))));
+        let call = binding();
+        engine.register_fn("water_liters", move |liters: Dynamic| call(ScriptCall::Command(Command::WaterLiters(to_f64(liters)? as f32))));
+        let call = binding();
+        engine.register_fn("lights", move |secs: Dynamic| call(ScriptCall::Command(Command::LightsWait(to_duration(secs)?))));
+        let call = binding();
+        engine.register_fn("pump", move |secs: Dynamic| call(ScriptCall::Command(Command::PumpWait(to_duration(secs)?))));
+        let call = binding();
+        engine.register_fn("plow", move |secs: Dynamic| call(ScriptCall::Command(Command::PlowWait(to_duration(secs)?))));
+        let call = binding();
+        engine.register_fn("get_state", move || call(ScriptCall::GetState).map(|_| Dynamic::from(StateView::default())));
+        // fields of a StateView are accessed through the indexer, both as `state["x"]` and `state.x`
+        let read_replay = replay.clone();
+        engine.register_type_with_name::<StateView>("State");
+        engine.register_indexer_get(move |view: &mut StateView, key: &str| {
+            let mut path = view.path.clone();
+            path.push(key.to_string());
+            read_replay.borrow_mut().read(path)
+        });
+        let call = binding();
+        engine.register_fn("get_plants", move || call(ScriptCall::GetPlants));
+
+        // avoid printing the same lines again while replaying
+        let print_replay = replay.clone();
+        engine.on_print(move |text| if !print_replay.borrow().is_replaying() {
+            info!("Script: {text}");
+        });
+
+        // the limits only apply after the replay, otherwise they would be reached sooner and
+        // sooner as the script goes on
+        let progress_replay = replay.clone();
+        let limits = self.limits.clone();
+        engine.on_progress(move |operations| {
+            let mut replay = progress_replay.borrow_mut();
+            if replay.is_replaying() {
+                return None;
+            }
+            let (replay_end_operations, replay_end) = *replay.replay_end.get_or_insert((operations, Instant::now()));
+            if limits.max_operations != 0 && operations - replay_end_operations > limits.max_operations {
+                Some(format!("Script exceeded the maximum number of operations {}", limits.max_operations).into())
+            } else if replay_end.elapsed() > Duration::from_millis(limits.max_run_time_ms) {
+                Some(format!("Script exceeded the maximum run time of {} ms", limits.max_run_time_ms).into())
+            } else {
+                None
+            }
+        });
+
+        let result = engine.run_ast(&ast);
+        drop(engine); // drops the other references to `replay`
+        let replay = Rc::try_unwrap(replay).ok().expect("The engine was dropped").into_inner();
+        self.calls = replay.calls;
+        if let Some(error) = replay.error {
+            RunOutcome::Failed(error)
+        } else if let Some(call) = replay.pending {
+            RunOutcome::Yielded(call)
+        } else {
+            match result {
+                Ok(()) => RunOutcome::Finished,
+                // terminated by the limits in on_progress()
+                Err(e) => match *e {
+                    EvalAltResult::ErrorTerminated(reason, _) => RunOutcome::Failed(reason.to_string()),
+                    e => RunOutcome::Failed(format!("Script error: {e}")),
+                },
+            }
+        }
+    }
+
+    async fn execute(call: &ScriptCall, state_handler: &StateHandler) -> Result<Value, StateHandlerError> {
+        let result = match call {
+            ScriptCall::Command(command) => {
+                CommandListAction::run_command(command.clone(), state_handler).await?;
+                Ok(Value::Null)
+            }
+            ScriptCall::GetState => {
+                // the fields are then read from the updated state during the next run
+                state_handler.update_state().await?;
+                Ok(Value::Null)
+            }
+            ScriptCall::GetPlants => serde_json::to_value(state_handler.plants().get_all()),
+            ScriptCall::ReadState(_) => unreachable!("State fields are read while running the script"),
+        };
+        result.map_err(|e| StateHandlerError::GenericError(format!("Could not serialize result of {call:?}: {e}")))
+    }
+}
+
+#[async_trait]
+impl Action for ScriptAction {
+    async fn step(&mut self, _ctx: &Context, state_handler: &StateHandler) -> StepResult {
+        let state = match serde_json::to_value(state_handler.get_state()) {
+            Ok(state) => state,
+            Err(e) => return StepResult::RunningError(StateHandlerError::GenericError(format!("Could not serialize state: {e}"))),
+        };
+        let mut action = self.clone();
+        let outcome = match tokio::task::spawn_blocking(move || (action.run_script(state), action)).await {
+            Ok((outcome, action)) => {
+                *self = action;
+                outcome
+            }
+            Err(e) => RunOutcome::Failed(format!("Script panicked: {e}")),
+        };
+        let call = match outcome {
+            RunOutcome::Yielded(call) => call,
+            RunOutcome::Finished => return StepResult::Finished,
+            RunOutcome::Failed(e) => return StepResult::FinishedError(StateHandlerError::GenericError(e)),
+        };
+
+        let res = Self::execute(&call, state_handler).await;
+        StepResult::advance_if_ok(res, |result| {
+            self.calls.push(CompletedCall { call, result });
+            StepResult::Running(StepProgress::Count { steps_done_so_far: self.calls.len() })
+        })
+    }
+
+    fn parameters(&self) -> Value {
+        serde_json::json!({ "source": self.source, "limits": self.limits })
+    }
+
+    fn get_type_name() -> &'static str
+    where
+        Self: Sized,
+    {
+        "script"
+    }
+
+    fn save_to_disk(&self, ctx: &Context) -> Result<(), String> {
+        serialize_to_json_file(&self, &ctx.get_save_dir().join("data.json"))
+    }
+
+    fn load_from_disk(ctx: &Context) -> Result<Self, String>
+    where
+        Self: Sized,
+    {
+        deserialize_from_json_file(&ctx.get_save_dir().join("data.json"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use serde_json::{json, Value};
+
+    use crate::action::command_list::Command;
+
+    use super::{CompletedCall, RunOutcome, ScriptAction, ScriptCall, ScriptLimits};
+
+    fn completed(call: ScriptCall, result: Value) -> CompletedCall {
+        CompletedCall { call, result }
+    }
+
+    fn assert_yielded(expected: &str, outcome: RunOutcome) {
+        match outcome {
+            RunOutcome::Yielded(call) => assert_eq!(expected, format!("{call:?}")),
+            outcome => panic!("Expected the script to yield {expected}, got {outcome:?}"),
+        }
+    }
+
+    #[test]
+    fn replay() {
+        let mut action = ScriptAction::new(r#"
+            let state = get_state();
+            try { home(); } catch { print("should not be caught"); }
+            if state.battery_level.proportion < 0.5 { lights(1) } else { pump(0.5) }
+        "#.to_string(), ScriptLimits::default()).unwrap();
+        let state = json!({"battery_level": {"proportion": 0.8, "voltage": 13.1}, "water_level": {"proportion": 0.3}});
+        assert_yielded("GetState", action.run_script(state.clone()));
+
+        action.calls.push(completed(ScriptCall::GetState, Value::Null));
+        assert_yielded("Command(Home)", action.run_script(state.clone()));
+
+        action.calls.push(completed(ScriptCall::Command(Command::Home), Value::Null));
+        assert_yielded("Command(PumpWait(500ms))", action.run_script(state.clone()));
+        // only the fields the script accessed were recorded, and only the first time
+        assert_eq!(
+            json!([
+                {"call": "GetState", "result": null},
+                {"call": {"Command": "Home"}, "result": null},
+                {"call": {"ReadState": ["battery_level"]}, "result": {}},
+                {"call": {"ReadState": ["battery_level", "proportion"]}, "result": 0.8},
+            ]),
+            serde_json::to_value(&action.calls).unwrap()
+        );
+
+        // the recorded value is used even if the state changed in the meantime
+        action.calls.push(completed(ScriptCall::Command(Command::PumpWait(Duration::from_millis(500))), Value::Null));
+        let state = json!({"battery_level": {"proportion": 0.1}});
+        assert!(matches!(action.run_script(state), RunOutcome::Finished));
+    }
+
+    #[test]
+    fn read_unknown_field() {
+        let mut action = ScriptAction::new("get_state().unknown".to_string(), ScriptLimits::default()).unwrap();
+        action.calls.push(completed(ScriptCall::GetState, Value::Null));
+        match action.run_script(json!({})) {
+            RunOutcome::Failed(e) => assert!(e.contains("Unknown state field unknown"), "{e}"),
+            outcome => panic!("Expected the script to fail, got {outcome:?}"),
+        }
+    }
+
+    #[test]
+    fn not_deterministic() {
+        let mut action = ScriptAction::new("home(); reset();".to_string(), ScriptLimits::default()).unwrap();
+        action.calls.push(completed(ScriptCall::Command(Command::Reset), Value::Null));
+        match action.run_script(json!({})) {
+            RunOutcome::Failed(e) => assert!(e.contains("not deterministic"), "{e}"),
+            outcome => panic!("Expected the script to fail, got {outcome:?}"),
+        }
+    }
+
+    #[test]
+    fn limits() {
+        let mut action = ScriptAction::new("loop {}".to_string(), ScriptLimits {
+            max_operations: 1000,
+            max_run_time_ms: 10_000,
+        }).unwrap();
+        match action.run_script(json!({})) {
+            RunOutcome::Failed(e) => assert!(e.contains("maximum number of operations"), "{e}"),
+            outcome => panic!("Expected the script to fail, got {outcome:?}"),
+        }
+
+        let mut action = ScriptAction::new("loop {}".to_string(), ScriptLimits {
+            max_operations: 0, // unlimited
+            max_run_time_ms: 10,
+        }).unwrap();
+        match action.run_script(json!({})) {
+            RunOutcome::Failed(e) => assert!(e.contains("maximum run time"), "{e}"),
+            outcome => panic!("Expected the script to fail, got {outcome:?}"),
+        }
+    }
+
+    #[test]
+    fn limits_after_replay() {
+        // each part between calls is below the limit, but all of them together are not
+        let mut action = ScriptAction::new("let x = 0; for i in 0..5 { for j in 0..50 { x += j; } home(); }".to_string(), ScriptLimits {
+            max_operations: 300,
+            max_run_time_ms: 10_000,
+        }).unwrap();
+        for _ in 0..5 {
+            assert_yielded("Command(Home)", action.run_script(json!({})));
+            action.calls.push(completed(ScriptCall::Command(Command::Home), Value::Null));
+        }
+        assert!(matches!(action.run_script(json!({})), RunOutcome::Finished));
+    }
+
+    #[test]
+    fn sandbox() {
+        assert!(ScriptAction::new("let x = ".to_string(), ScriptLimits::default()).is_err());
+        assert!(ScriptAction::new(r#"eval("home()")"#.to_string(), ScriptLimits::default()).is_err());
+        let mut action = ScriptAction::new("timestamp()".to_string(), ScriptLimits::default()).unwrap();
+        assert!(matches!(action.run_script(json!({})), RunOutcome::Failed(_)));
+        let mut action = ScriptAction::new("water(-1)".to_string(), ScriptLimits::default()).unwrap();
+        assert!(matches!(action.run_script(json!({})), RunOutcome::Failed(_)));
+    }
+}
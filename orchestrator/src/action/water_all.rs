@@ -0,0 +1,79 @@
+use definitions::{PlantId, StepProgress};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    action::StepResult, state::StateHandler, util::serde::{deserialize_from_json_file, serialize_to_json_file}
+};
+
+use super::{Action, Context};
+
+/// Waters all plants in the [PlantRegistry](crate::state::plants::PlantRegistry), one plant per
+/// step. The list of plants is taken when the action is created, and plants removed from the
+/// registry in the meantime are skipped.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct WaterAllAction {
+    plant_ids: Vec<PlantId>,
+    steps_done_so_far: usize,
+}
+
+impl WaterAllAction {
+    pub fn new(state_handler: &StateHandler) -> Self {
+        WaterAllAction {
+            plant_ids: state_handler.plants().get_all().iter().map(|plant| plant.id).collect(),
+            steps_done_so_far: 0,
+        }
+    }
+}
+
+#[async_trait]
+impl Action for WaterAllAction {
+    async fn step(&mut self, _ctx: &Context, state_handler: &StateHandler) -> StepResult {
+        if self.steps_done_so_far >= self.plant_ids.len() {
+            return StepResult::Finished;
+        }
+        let plant_id = self.plant_ids[self.steps_done_so_far];
+
+        let res = if let Some(plant) = state_handler.plants().get(plant_id) {
+            state_handler.water_a_plant(&plant.data).await
+        } else {
+            info!("Plant {plant_id} was removed from the registry, not watering it");
+            Ok(())
+        };
+
+        StepResult::advance_if_ok(res, |()| {
+            self.steps_done_so_far += 1;
+            if self.steps_done_so_far >= self.plant_ids.len() {
+                StepResult::Finished
+            } else {
+                StepResult::Running(
+                    StepProgress::Ratio {
+                        steps_done_so_far: self.steps_done_so_far,
+                        steps_total: self.plant_ids.len(),
+                    }
+                )
+            }
+        })
+    }
+
+    fn parameters(&self) -> serde_json::Value {
+        serde_json::json!({ "plant_ids": self.plant_ids })
+    }
+
+    fn get_type_name() -> &'static str
+    where
+        Self: Sized,
+    {
+        "water_all"
+    }
+
+    fn save_to_disk(&self, ctx: &Context) -> Result<(), String> {
+        serialize_to_json_file(&self, &ctx.get_save_dir().join("data.json"))
+    }
+
+    fn load_from_disk(ctx: &Context) -> Result<Self, String>
+    where
+        Self: Sized,
+    {
+        deserialize_from_json_file(&ctx.get_save_dir().join("data.json"))
+    }
+}
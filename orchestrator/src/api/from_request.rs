@@ -13,8 +13,11 @@ use rocket::{
 };
 
 use crate::{
+    api::stream::StateStreamer,
+    history::HistoryHandler,
     queue::QueueHandler,
-    state::StateHandler,
+    scheduler::SchedulerHandler,
+    state::{parameters::ParametersHandler, StateHandler},
 };
 
 #[rocket::async_trait]
@@ -42,3 +45,51 @@ impl<'r> FromRequest<'r> for &'r QueueHandler {
             .map(|request_handler| request_handler.inner())
     }
 }
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for &'r StateStreamer {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> request::Outcome<Self, Self::Error> {
+        request
+            .guard::<&rocket::State<StateStreamer>>()
+            .await
+            .map(|request_handler| request_handler.inner())
+    }
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for &'r ParametersHandler {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> request::Outcome<Self, Self::Error> {
+        request
+            .guard::<&rocket::State<ParametersHandler>>()
+            .await
+            .map(|request_handler| request_handler.inner())
+    }
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for &'r SchedulerHandler {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> request::Outcome<Self, Self::Error> {
+        request
+            .guard::<&rocket::State<SchedulerHandler>>()
+            .await
+            .map(|request_handler| request_handler.inner())
+    }
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for &'r HistoryHandler {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> request::Outcome<Self, Self::Error> {
+        request
+            .guard::<&rocket::State<HistoryHandler>>()
+            .await
+            .map(|request_handler| request_handler.inner())
+    }
+}
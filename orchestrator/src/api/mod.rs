@@ -1,12 +1,19 @@
 use crate::{
-    action::command_list::{Command, CommandListAction},
-    queue::QueueHandler, state::StateHandler,
+    action::{action_wrapper::ActionId, command_list::CommandListAction, gcode::gcode_to_commands, Action},
+    history::{FinalStatus, HistoryEntry, HistoryFilter, HistoryHandler},
+    queue::{AddActionError, ClearEmergencyError, QueueHandler, ReorderError},
+    scheduler::{Schedule, ScheduleData, ScheduleError, ScheduleId, SchedulerHandler},
+    state::{current_tool, parameters::{ParametersHandler, UpdateParametersError}, StateHandler},
 };
-use definitions::RobotQueueState;
-use rocket::serde::json::Json;
+use definitions::{ActionDetails, Parameters, Plant, PlantData, PlantId, Priority, RobotQueueState};
+use chrono::{Local, NaiveDateTime};
+use rocket::{http::Status, response::stream::{Event, EventStream}, serde::json::Json, Shutdown};
 use serde::{Deserialize, Serialize};
 
 mod from_request;
+pub mod stream;
+
+use self::stream::StateStreamer;
 
 /******************************
 * used structures definitions *
@@ -23,6 +30,23 @@ pub struct KillRunningActionResponse {
     success: bool,
 }
 
+#[derive(Serialize, Deserialize)]
+pub struct ReorderArgs {
+    /// The ids of the actions in the queue as the client last saw them, used to
+    /// detect whether the queue changed in the meantime.
+    expected: Vec<ActionId>,
+    /// The same ids as in `expected`, in the new desired order.
+    new: Vec<ActionId>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct AttachedToolResponse {
+    /// What the peripherals reported, see [Tool::hardware_id](definitions::Tool::hardware_id).
+    hardware_id: Option<u8>,
+    /// The name of the tool in the parameters with that `hardware_id`, if any.
+    tool: Option<String>,
+}
+
 /**********************************
 * end used structures definitions *
 // endregion *********************/
@@ -34,14 +58,234 @@ pub async fn get_state(robot_state: &StateHandler, queue: &QueueHandler) -> Resu
     Ok(Json(RobotQueueState { robot: robot_state.try_update_state().await, queue: queue.get_state() }))
 }
 
+/// Server-sent events stream of [RobotQueueState] snapshots, pushed whenever the robot state is
+/// polled (see [StateStreamer::run]) or the queue changes. Prefer this over polling `/state`.
+#[get("/state/stream")]
+pub fn state_stream(streamer: &StateStreamer, mut shutdown: Shutdown) -> EventStream![] {
+    let mut receiver = streamer.subscribe();
+    EventStream! {
+        loop {
+            tokio::select! {
+                res = receiver.changed() => if res.is_err() {
+                    break;
+                },
+                _ = &mut shutdown => break,
+            }
+            let state = receiver.borrow_and_update().clone();
+            yield Event::json(&state);
+        }
+    }
+}
+
+#[get("/parameters")]
+pub fn get_parameters(robot_state: &StateHandler) -> Json<Parameters> {
+    Json(robot_state.get_state().parameters)
+}
+
+/// Takes a JSON merge patch with only the parameters to change, e.g. `{"arm_length": 1.5}`, and
+/// returns the updated parameters.
+#[patch("/parameters", data = "<patch>")]
+pub fn patch_parameters(
+    robot_state: &StateHandler,
+    queue: &QueueHandler,
+    parameters: &ParametersHandler,
+    patch: Json<serde_json::Value>,
+) -> Result<Json<Parameters>, (Status, String)> {
+    parameters.update(robot_state, queue, patch.0).map(Json).map_err(|e| match e {
+        UpdateParametersError::QueueNotIdle => (
+            Status::Conflict,
+            "Parameters can't be changed while an action is running".to_string(),
+        ),
+        UpdateParametersError::Invalid(e) => (Status::BadRequest, e),
+        UpdateParametersError::Save(e) => (Status::InternalServerError, e),
+    })
+}
+
 #[get("/toggle_led")]
 pub async fn toggle_led(robot_state: &StateHandler) -> Result<(), String> {
     robot_state.toggle_led().await.map_err(|e| format!("{e:?}"))
 }
 
+#[get("/tools/attached")]
+pub async fn get_attached_tool(robot_state: &StateHandler) -> Result<Json<AttachedToolResponse>, String> {
+    let hardware_id = robot_state.get_attached_tool().await.map_err(|e| format!("{e:?}"))?;
+    let tool = hardware_id.and_then(|hardware_id| {
+        robot_state.get_state().parameters.tools.into_iter()
+            .find(|tool| tool.hardware_id == Some(hardware_id))
+            .map(|tool| tool.name)
+    });
+    Ok(Json(AttachedToolResponse { hardware_id, tool }))
+}
+
+fn add_action_error_to_status(e: AddActionError) -> (Status, String) {
+    match e {
+        AddActionError::UnknownType(type_name) => (Status::NotFound, format!("Unknown action type {type_name:?}")),
+        AddActionError::NotConstructible(type_name) => (
+            Status::BadRequest,
+            format!("Actions of type {type_name:?} can't be created through the API"),
+        ),
+        AddActionError::Invalid(e) => (Status::BadRequest, e),
+    }
+}
+
+/// Same as `POST /queue/add_action/command_list`, kept for older clients.
 #[post("/queue/add_action_list", data = "<commands>")]
-pub fn add_action_command_list(queue: &QueueHandler, commands: Json<Vec<Command>>) {
-    queue.add_action(CommandListAction::new(commands.0));
+pub fn add_action_command_list(queue: &QueueHandler, commands: String) -> Result<Json<ActionId>, (Status, String)> {
+    add_action(queue, CommandListAction::get_type_name(), None, commands)
+}
+
+/// Creates an action of any type in the [ActionRegistry](crate::action::registry::ActionRegistry)
+/// from the JSON body (which can be omitted if the action type needs no data), and returns its id.
+/// The [Priority] is `0` if not specified.
+#[post("/queue/add_action/<type_name>?<priority>", data = "<data>")]
+pub fn add_action(
+    queue: &QueueHandler,
+    type_name: &str,
+    priority: Option<Priority>,
+    data: String,
+) -> Result<Json<ActionId>, (Status, String)> {
+    let data = if data.trim().is_empty() {
+        serde_json::Value::Null
+    } else {
+        serde_json::from_str(&data).map_err(|e| (Status::BadRequest, e.to_string()))?
+    };
+    queue.add_action_from_json(type_name, data, priority.unwrap_or(0))
+        .map(Json)
+        .map_err(add_action_error_to_status)
+}
+
+/// Converts a G-code program (see [gcode_to_commands]) into a [CommandListAction], checking that
+/// all points are reachable with the current parameters and tool, and returns the id of the
+/// action. Fails with all of the line-numbered errors, one per line, if the program is invalid.
+#[post("/queue/add_gcode?<priority>", data = "<gcode>")]
+pub fn add_gcode(
+    robot_state: &StateHandler,
+    queue: &QueueHandler,
+    priority: Option<Priority>,
+    gcode: String,
+) -> Result<Json<ActionId>, (Status, String)> {
+    let state = robot_state.get_state();
+    let commands = gcode_to_commands(&gcode, &state.parameters, current_tool(&state))
+        .map_err(|errors| (
+            Status::BadRequest,
+            errors.iter().map(|e| e.to_string()).collect::<Vec<_>>().join("\n"),
+        ))?;
+    Ok(Json(queue.add_action_with_priority(CommandListAction::new(commands), priority.unwrap_or(0))))
+}
+
+#[get("/plants")]
+pub fn get_plants(robot_state: &StateHandler) -> Json<Vec<Plant>> {
+    Json(robot_state.plants().get_all())
+}
+
+#[get("/plants/<id>")]
+pub fn get_plant(robot_state: &StateHandler, id: PlantId) -> Result<Json<Plant>, Status> {
+    robot_state.plants().get(id).map(Json).ok_or(Status::NotFound)
+}
+
+#[post("/plants", data = "<plant>")]
+pub fn add_plant(robot_state: &StateHandler, plant: Json<PlantData>) -> Result<Json<Plant>, (Status, String)> {
+    robot_state.plants().add(plant.0)
+        .map(Json)
+        .map_err(|e| (Status::InternalServerError, e))
+}
+
+#[put("/plants/<id>", data = "<plant>")]
+pub fn update_plant(
+    robot_state: &StateHandler,
+    id: PlantId,
+    plant: Json<PlantData>,
+) -> Result<Json<Plant>, (Status, String)> {
+    match robot_state.plants().update(id, plant.0) {
+        Ok(Some(plant)) => Ok(Json(plant)),
+        Ok(None) => Err((Status::NotFound, format!("There is no plant with id {id}"))),
+        Err(e) => Err((Status::InternalServerError, e)),
+    }
+}
+
+#[delete("/plants/<id>")]
+pub fn remove_plant(robot_state: &StateHandler, id: PlantId) -> Result<(), (Status, String)> {
+    match robot_state.plants().remove(id) {
+        Ok(true) => Ok(()),
+        Ok(false) => Err((Status::NotFound, format!("There is no plant with id {id}"))),
+        Err(e) => Err((Status::InternalServerError, e)),
+    }
+}
+
+#[get("/schedules")]
+pub fn get_schedules(scheduler: &SchedulerHandler) -> Json<Vec<Schedule>> {
+    Json(scheduler.get_all())
+}
+
+/// The action of the schedule is checked like in `POST /queue/add_action/<type_name>`, but is only
+/// created and added to the queue when the schedule runs.
+#[post("/schedules", data = "<schedule>")]
+pub fn add_schedule(
+    scheduler: &SchedulerHandler,
+    queue: &QueueHandler,
+    schedule: Json<ScheduleData>,
+) -> Result<Json<Schedule>, (Status, String)> {
+    let action = &schedule.action;
+    queue.validate_action_json(&action.type_name, action.data.clone())
+        .map_err(add_action_error_to_status)?;
+    scheduler.add(schedule.0, Local::now().naive_local())
+        .map(Json)
+        .map_err(|e| match e {
+            ScheduleError::Invalid(e) => (Status::BadRequest, e),
+            ScheduleError::Save(e) => (Status::InternalServerError, e),
+        })
+}
+
+fn set_schedule_enabled(scheduler: &SchedulerHandler, id: ScheduleId, enabled: bool) -> Result<Json<Schedule>, (Status, String)> {
+    match scheduler.set_enabled(id, enabled, Local::now().naive_local()) {
+        Ok(Some(schedule)) => Ok(Json(schedule)),
+        Ok(None) => Err((Status::NotFound, format!("There is no schedule with id {id}"))),
+        Err(e) => Err((Status::InternalServerError, e)),
+    }
+}
+
+#[post("/schedules/<id>/enable")]
+pub fn enable_schedule(scheduler: &SchedulerHandler, id: ScheduleId) -> Result<Json<Schedule>, (Status, String)> {
+    set_schedule_enabled(scheduler, id, true)
+}
+
+#[post("/schedules/<id>/disable")]
+pub fn disable_schedule(scheduler: &SchedulerHandler, id: ScheduleId) -> Result<Json<Schedule>, (Status, String)> {
+    set_schedule_enabled(scheduler, id, false)
+}
+
+#[delete("/schedules/<id>")]
+pub fn remove_schedule(scheduler: &SchedulerHandler, id: ScheduleId) -> Result<(), (Status, String)> {
+    match scheduler.remove(id) {
+        Ok(true) => Ok(()),
+        Ok(false) => Err((Status::NotFound, format!("There is no schedule with id {id}"))),
+        Err(e) => Err((Status::InternalServerError, e)),
+    }
+}
+
+fn parse_history_time(name: &str, time: Option<&str>) -> Result<Option<NaiveDateTime>, (Status, String)> {
+    time.map(|time| time.parse().map_err(|e| (Status::BadRequest, format!("Invalid {name} {time:?}: {e}"))))
+        .transpose()
+}
+
+/// Returns the actions that left the queue, oldest first, optionally only those of a specific
+/// type, with a specific [FinalStatus], or that finished between `from` and `to` (both in local
+/// time, e.g. `2025-04-25T07:00:00`).
+#[get("/history?<type_name>&<status>&<from>&<to>")]
+pub fn get_history(
+    history: &HistoryHandler,
+    type_name: Option<String>,
+    status: Option<FinalStatus>,
+    from: Option<&str>,
+    to: Option<&str>,
+) -> Result<Json<Vec<HistoryEntry>>, (Status, String)> {
+    let filter = HistoryFilter {
+        type_name,
+        status,
+        from: parse_history_time("from", from)?,
+        to: parse_history_time("to", to)?,
+    };
+    Ok(Json(history.query(&filter)))
 }
 
 #[post("/queue/pause")]
@@ -59,6 +303,54 @@ pub fn clear(queue: &QueueHandler) {
     queue.clear();
 }
 
+#[post("/queue/reorder", data = "<reorder_args>")]
+pub fn reorder(queue: &QueueHandler, reorder_args: Json<ReorderArgs>) -> Result<(), (Status, String)> {
+    let ReorderArgs { expected, new } = reorder_args.0;
+    queue.reorder(expected, new).map_err(|e| match e {
+        ReorderError::MismatchedExpectedNew => (
+            Status::BadRequest,
+            "The expected and new lists do not contain the same ids".to_string(),
+        ),
+        ReorderError::QueueChanged => (
+            Status::Conflict,
+            "The queue changed in the meantime, reload it and try again".to_string(),
+        ),
+    })
+}
+
+#[get("/queue/action/<id>")]
+pub fn get_action(queue: &QueueHandler, id: ActionId) -> Result<Json<ActionDetails>, Status> {
+    queue.get_action_details(id).map(Json).ok_or(Status::NotFound)
+}
+
+#[delete("/queue/action/<id>")]
+pub fn remove_action(queue: &QueueHandler, id: ActionId) -> Result<(), Status> {
+    if queue.remove_action(id) {
+        Ok(())
+    } else {
+        Err(Status::NotFound)
+    }
+}
+
+#[post("/emergency")]
+pub fn emergency(queue: &QueueHandler) {
+    queue.emergency();
+}
+
+#[post("/emergency/clear")]
+pub fn clear_emergency(queue: &QueueHandler) -> Result<(), (Status, String)> {
+    queue.clear_emergency().map_err(|e| match e {
+        ClearEmergencyError::NoEmergency => (
+            Status::Conflict,
+            "There is no emergency to clear".to_string(),
+        ),
+        ClearEmergencyError::StillResetting => (
+            Status::Conflict,
+            "The robot is still being put in a safe position, try again later".to_string(),
+        ),
+    })
+}
+
 #[post("/queue/kill_running_action", data = "<kill_running_action_args>")]
 pub fn kill_running_action(
     queue: &QueueHandler,
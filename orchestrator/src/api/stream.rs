@@ -0,0 +1,82 @@
+use std::time::Duration;
+
+use definitions::RobotQueueState;
+use tokio::{sync::watch, time::MissedTickBehavior};
+
+use crate::{queue::QueueHandler, state::StateHandler};
+
+/// Keeps the latest [RobotQueueState] snapshot and shares it with any number of clients (see
+/// [`api::state_stream`](crate::api::state_stream)). The serial devices are polled only by
+/// [`run()`](StateStreamer::run), so the amount of serial traffic does not depend on how many
+/// clients are watching, and the same polls are used to update the queue gates.
+#[derive(Debug, Clone)]
+pub struct StateStreamer {
+    sender: watch::Sender<RobotQueueState>,
+}
+
+impl StateStreamer {
+    pub fn new() -> StateStreamer {
+        StateStreamer {
+            sender: watch::Sender::new(RobotQueueState::default()),
+        }
+    }
+
+    /// Returns a receiver that will be marked as changed every time a new snapshot is available.
+    /// The current (possibly outdated) snapshot is already marked as seen.
+    pub fn subscribe(&self) -> watch::Receiver<RobotQueueState> {
+        self.sender.subscribe()
+    }
+
+    /// Never returns (unless the queue handler is dropped), so should be spawned in its own task.
+    ///
+    /// Every `period` the serial devices are polled with
+    /// [`update_state()`](StateHandler::update_state), the queue gates are updated with
+    /// [`update_gates()`](QueueHandler::update_gates) and a new snapshot is published, but only
+    /// if there is at least one subscriber or gating rule. Whenever the queue changes instead, a
+    /// new snapshot is published immediately, using the last known robot state without polling.
+    pub async fn run(&self, state_handler: StateHandler, queue_handler: QueueHandler, period: Duration) {
+        let mut queue_changes = queue_handler.subscribe_changes();
+        let mut ticker = tokio::time::interval(period);
+        ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+        loop {
+            let robot = tokio::select! {
+                _ = ticker.tick() => {
+                    let state = state_handler.get_state();
+                    if self.sender.receiver_count() == 0 && state.parameters.gating_rules.is_empty() {
+                        // nobody is watching and there is nothing to gate, avoid talking to the
+                        // serial devices for nothing (but lift the gates of removed rules)
+                        queue_handler.update_gates(&state, true);
+                        continue;
+                    }
+                    match state_handler.update_state().await {
+                        Ok(state) => {
+                            queue_handler.update_gates(&state, true);
+                            state
+                        }
+                        Err(_) => {
+                            // the levels might be stale, so they must not change any gate
+                            let state = state_handler.get_state();
+                            queue_handler.update_gates(&state, false);
+                            state
+                        }
+                    }
+                }
+                res = queue_changes.changed() => {
+                    if res.is_err() {
+                        return; // the queue handler was dropped
+                    }
+                    state_handler.get_state()
+                }
+            };
+
+            self.sender.send_replace(RobotQueueState { robot, queue: queue_handler.get_state() });
+        }
+    }
+}
+
+impl Default for StateStreamer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
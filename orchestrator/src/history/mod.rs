@@ -0,0 +1,212 @@
+use std::{collections::VecDeque, fs::create_dir_all, path::{Path, PathBuf}, sync::{Arc, Mutex}};
+
+use chrono::NaiveDateTime;
+use definitions::{ActionId, Priority, StepProgress};
+use rocket::FromFormField;
+use serde::{Deserialize, Serialize};
+
+use crate::util::serde::{deserialize_from_json_file, serialize_to_json_file};
+
+const HISTORY_FILE: &str = "history.json";
+const HISTORY_BACKUP_FILE: &str = "history.json.bak";
+
+/// How an action left the queue.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, FromFormField)]
+pub enum FinalStatus {
+    /// The action returned [StepResult::Finished](crate::action::StepResult::Finished).
+    Finished,
+    /// The action returned [StepResult::FinishedError](crate::action::StepResult::FinishedError),
+    /// possibly after its [retry policy](crate::action::retry::RetryPolicy) was exhausted.
+    Failed,
+    /// The action was killed while running and not kept in the queue.
+    Killed,
+    /// The action was removed from the queue (possibly while running) before finishing.
+    Removed,
+}
+
+/// A record of an action that is not in the queue anymore.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HistoryEntry {
+    pub id: ActionId,
+    pub type_name: String,
+    pub priority: Priority,
+    pub status: FinalStatus,
+    /// When the action was first executed, or `None` if it never ran.
+    pub started_at: Option<NaiveDateTime>,
+    pub finished_at: NaiveDateTime,
+    /// The last progress reported by the action.
+    pub progress: StepProgress,
+    /// All of the errors returned by the action while it was running, oldest first.
+    pub errors: Vec<String>,
+    /// What the action was asked to do, see [Action::parameters](crate::action::Action::parameters).
+    pub parameters: serde_json::Value,
+}
+
+/// Which [HistoryEntry]s to return from [HistoryHandler::query]. `None` fields match anything.
+#[derive(Debug, Clone, Default)]
+pub struct HistoryFilter {
+    pub type_name: Option<String>,
+    pub status: Option<FinalStatus>,
+    /// Only entries that finished at or after this time.
+    pub from: Option<NaiveDateTime>,
+    /// Only entries that finished at or before this time.
+    pub to: Option<NaiveDateTime>,
+}
+
+impl HistoryFilter {
+    fn matches(&self, entry: &HistoryEntry) -> bool {
+        self.type_name.as_ref().is_none_or(|type_name| *type_name == entry.type_name)
+            && self.status.is_none_or(|status| status == entry.status)
+            && self.from.is_none_or(|from| entry.finished_at >= from)
+            && self.to.is_none_or(|to| entry.finished_at <= to)
+    }
+}
+
+/// Keeps a record of the last actions that left the queue, and persists it in
+/// `${data_dir}/history.json` after every change. Only the last `max_entries` entries are kept.
+/// Can be cloned cheaply, and all clones refer to the same history.
+#[derive(Debug, Clone)]
+pub struct HistoryHandler {
+    entries: Arc<Mutex<VecDeque<HistoryEntry>>>,
+    data_dir: PathBuf,
+    max_entries: usize,
+}
+
+impl HistoryHandler {
+    /// Loads the history from `${data_dir}/history.json`, or starts with an empty history if the
+    /// file does not exist. If the file can't be read, it is backupped before starting from scratch.
+    pub fn load_from_disk(data_dir: &Path, max_entries: usize) -> HistoryHandler {
+        let history_file = &data_dir.join(HISTORY_FILE);
+        let mut entries: VecDeque<HistoryEntry> = if history_file.exists() {
+            match deserialize_from_json_file(history_file) {
+                Ok(entries) => entries,
+                Err(e) => {
+                    let history_backup_file = &data_dir.join(HISTORY_BACKUP_FILE);
+                    log::error!("Could not read history from {history_file:?}, starting with an empty history: {e}");
+                    log::error!("Backupping {history_file:?} file to {history_backup_file:?}...");
+                    if let Err(e) = std::fs::rename(history_file, history_backup_file) {
+                        log::error!("Could not create backup {history_backup_file:?}: {e:?}");
+                    }
+                    VecDeque::new()
+                }
+            }
+        } else {
+            VecDeque::new()
+        };
+        let excess = entries.len().saturating_sub(max_entries);
+        entries.drain(..excess);
+
+        HistoryHandler {
+            entries: Arc::new(Mutex::new(entries)),
+            data_dir: data_dir.to_path_buf(),
+            max_entries,
+        }
+    }
+
+    /// Adds `entries` to the history, dropping the oldest entries if there are too many, and saves
+    /// the history to disk. The entries are kept in memory even if saving fails.
+    pub fn record(&self, entries: impl IntoIterator<Item = HistoryEntry>) -> Result<(), String> {
+        let mut history = self.entries.lock().unwrap();
+        history.extend(entries);
+        let excess = history.len().saturating_sub(self.max_entries);
+        history.drain(..excess);
+
+        create_dir_all(&self.data_dir)
+            .map_err(|e| format!("Could not create data directory {:?}: {e:?}", self.data_dir))?;
+        let history_file = &self.data_dir.join(HISTORY_FILE);
+        serialize_to_json_file(&*history, history_file)
+            .map_err(|e| format!("Could not serialize and save history to {history_file:?}: {e}"))
+    }
+
+    /// Returns the entries matching `filter`, oldest first.
+    pub fn query(&self, filter: &HistoryFilter) -> Vec<HistoryEntry> {
+        self.entries.lock().unwrap().iter()
+            .filter(|entry| filter.matches(entry))
+            .cloned()
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{NaiveDate, NaiveDateTime};
+    use definitions::StepProgress;
+
+    use super::{FinalStatus, HistoryEntry, HistoryFilter, HistoryHandler};
+
+    fn at(hour: u32) -> NaiveDateTime {
+        NaiveDate::from_ymd_opt(2025, 4, 25).unwrap().and_hms_opt(hour, 0, 0).unwrap()
+    }
+
+    fn entry(id: u32, type_name: &str, status: FinalStatus, finished_at: NaiveDateTime) -> HistoryEntry {
+        HistoryEntry {
+            id,
+            type_name: type_name.to_string(),
+            priority: 0,
+            status,
+            started_at: None,
+            finished_at,
+            progress: StepProgress::Unknown,
+            errors: vec![],
+            parameters: serde_json::Value::Null,
+        }
+    }
+
+    fn ids(entries: Vec<HistoryEntry>) -> Vec<u32> {
+        entries.iter().map(|entry| entry.id).collect()
+    }
+
+    #[test]
+    fn query_filters() {
+        let data_dir = tempdir::TempDir::new("cyberorto_test").unwrap();
+        let history = HistoryHandler::load_from_disk(data_dir.path(), 10);
+        history.record([entry(0, "water_all", FinalStatus::Finished, at(7))]).unwrap();
+        history.record([
+            entry(1, "command_list", FinalStatus::Failed, at(8)),
+            entry(2, "water_all", FinalStatus::Removed, at(9)),
+        ]).unwrap();
+
+        assert_eq!(vec![0, 1, 2], ids(history.query(&HistoryFilter::default())));
+        assert_eq!(vec![0, 2], ids(history.query(&HistoryFilter {
+            type_name: Some("water_all".to_string()),
+            ..Default::default()
+        })));
+        assert_eq!(vec![1], ids(history.query(&HistoryFilter {
+            status: Some(FinalStatus::Failed),
+            ..Default::default()
+        })));
+        assert_eq!(vec![1, 2], ids(history.query(&HistoryFilter {
+            from: Some(at(8)),
+            ..Default::default()
+        })));
+        assert_eq!(vec![0], ids(history.query(&HistoryFilter {
+            type_name: Some("water_all".to_string()),
+            to: Some(at(8)),
+            ..Default::default()
+        })));
+    }
+
+    #[test]
+    fn bounded_and_persisted() {
+        let data_dir = tempdir::TempDir::new("cyberorto_test").unwrap();
+        let history = HistoryHandler::load_from_disk(data_dir.path(), 3);
+        for id in 0..5 {
+            history.record([entry(id, "water_all", FinalStatus::Finished, at(id))]).unwrap();
+        }
+        assert_eq!(vec![2, 3, 4], ids(history.query(&HistoryFilter::default())));
+
+        let history = HistoryHandler::load_from_disk(data_dir.path(), 3);
+        assert_eq!(vec![2, 3, 4], ids(history.query(&HistoryFilter::default())));
+        let history = HistoryHandler::load_from_disk(data_dir.path(), 2);
+        assert_eq!(vec![3, 4], ids(history.query(&HistoryFilter::default())));
+    }
+
+    #[test]
+    fn corrupt_file() {
+        let data_dir = tempdir::TempDir::new("cyberorto_test").unwrap();
+        std::fs::write(data_dir.path().join("history.json"), "not json").unwrap();
+        let history = HistoryHandler::load_from_disk(data_dir.path(), 3);
+        assert!(history.query(&HistoryFilter::default()).is_empty());
+        assert!(data_dir.path().join("history.json.bak").exists());
+    }
+}
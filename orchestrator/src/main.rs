@@ -1,6 +1,6 @@
 #![feature(assert_matches)]
 
-use std::{env, path::PathBuf, thread};
+use std::{env, path::PathBuf, thread, time::Duration};
 
 use clap::Parser;
 use env_logger::Env;
@@ -9,11 +9,13 @@ use state::StateHandler;
 use tokio::{signal::unix::{signal, SignalKind}, sync::oneshot};
 use util::cors::Cors;
 
-use crate::{state::parameters::{load_parameters_from_disk, save_parameters_to_disk}, util::{serial::SerialPorts, test_devices::test_devices}};
+use crate::{action::registry::ActionRegistry, api::stream::StateStreamer, history::HistoryHandler, scheduler::SchedulerHandler, state::{parameters::{load_parameters_from_disk, save_parameters_to_disk, ParametersHandler}, plants::PlantRegistry}, util::{serial::SerialPorts, test_devices::test_devices}};
 
 mod action;
 mod api;
+mod history;
 mod queue;
+mod scheduler;
 mod state;
 mod util;
 
@@ -29,8 +31,9 @@ struct Args {
     /// - "simulated" to simulate connecting to fake motors and fake peripherals,
     /// - "PORT1,PORT2" to specify comma separated port names (e.g. "/dev/ttyACM0")
     ///
-    /// The type (i.e. motor x, y, z or peripherals) of each connected device will be determined
-    /// based on their name automatically.
+    /// The role (i.e. motor x, y, z or peripherals) of each connected device will be determined
+    /// automatically based on the capabilities it declares, and devices speaking a different
+    /// protocol version are refused.
     ///
     /// The port baud rate will always be 115200.
     #[arg(short, long, value_parser = SerialPorts::parse, default_value = "auto")]
@@ -42,10 +45,34 @@ struct Args {
 
     /// Whether to save parameters to `${data_dir}/parameters.json` when the orchestrator is
     /// shutting down. Note: the JSON file will be rewritten from scratch, so any comments will be
-    /// lost. That's why this field is `false` by default.
+    /// lost. That's why this field is `false` by default. Parameters changed through the
+    /// `/parameters` API are saved immediately anyway.
     #[arg(short, long)]
     save_parameters: bool,
 
+    /// How often (in milliseconds) to poll the serial devices to update the state pushed to
+    /// clients connected to `/state/stream`, and to check the battery and water levels against
+    /// the gating rules in the parameters, which may pause the queue or skip some actions while
+    /// the levels are low. No polling happens if no client is connected and there are no rules.
+    #[arg(long, default_value_t = 100)]
+    state_stream_period_ms: u64,
+
+    /// How many of the actions that left the queue to keep in `${data_dir}/history.json`, see the
+    /// `/history` API. The oldest ones are forgotten first.
+    #[arg(long, default_value_t = 1000)]
+    history_max_entries: usize,
+
+    /// How often (in milliseconds) to read the events that the serial devices send on their own,
+    /// e.g. when a limit switch is hit, which may trigger an emergency (see the parameters).
+    #[arg(long, default_value_t = 100)]
+    events_period_ms: u64,
+
+    /// How often (in milliseconds) to send a heartbeat to the serial devices. If a device does not
+    /// receive any message for a while (2 seconds for the simulated devices) it assumes that the
+    /// orchestrator crashed, and turns off all actuators and stops the motors.
+    #[arg(long, default_value_t = 500)]
+    heartbeat_period_ms: u64,
+
     /// If this option is passed, the orchestrator will not start, and instead some checks will be
     /// performed on connected serial port peripherals, to check if they work and print some
     /// information about them. Some of the other args are useless if this option is passed.
@@ -68,25 +95,87 @@ async fn main() {
 
     let (masters, simulation_join_handles) = args.ports.to_masters().await;
     let parameters = load_parameters_from_disk(&args.data_dir);
-    let state_handler = StateHandler::new(masters, parameters);
-    let queue_handler = QueueHandler::new(state_handler.clone(), args.data_dir.join("queue/"));
+    let plants = PlantRegistry::load_from_disk(&args.data_dir);
+    let state_handler = StateHandler::new(masters, parameters, plants);
+    let parameters_handler = ParametersHandler::new(args.data_dir.clone());
+    let history_handler = HistoryHandler::load_from_disk(&args.data_dir, args.history_max_entries);
+    let queue_handler = QueueHandler::new(
+        state_handler.clone(),
+        args.data_dir.join("queue/"),
+        ActionRegistry::default(),
+        history_handler.clone(),
+    );
 
     let queue_handler_clone = queue_handler.clone();
     let queue_handler_thread = thread::spawn(move || queue_handler_clone.run());
 
+    let scheduler_handler = SchedulerHandler::load_from_disk(&args.data_dir);
+    let scheduler_task = tokio::task::spawn({
+        let scheduler_handler = scheduler_handler.clone();
+        let queue_handler = queue_handler.clone();
+        async move { scheduler_handler.run(queue_handler).await }
+    });
+
+    let state_streamer = StateStreamer::new();
+    let state_streamer_task = tokio::task::spawn({
+        let state_streamer = state_streamer.clone();
+        let state_handler = state_handler.clone();
+        let queue_handler = queue_handler.clone();
+        let period = Duration::from_millis(args.state_stream_period_ms);
+        async move { state_streamer.run(state_handler, queue_handler, period).await }
+    });
+
+    let device_events_task = tokio::task::spawn({
+        let queue_handler = queue_handler.clone();
+        let period = Duration::from_millis(args.events_period_ms);
+        async move { queue_handler.run_device_events(period).await }
+    });
+
+    let heartbeat_task = tokio::task::spawn({
+        let state_handler = state_handler.clone();
+        let period = Duration::from_millis(args.heartbeat_period_ms);
+        async move { state_handler.run_heartbeat(period).await }
+    });
+
     // start Rocket
     let rocket_error = rocket::build()
         .attach(Cors)
         .manage(state_handler.clone()) // used by `impl FromRequest for State`
         .manage(queue_handler.clone()) // used by `impl FromRequest for &QueueHandler`
+        .manage(state_streamer) // used by `impl FromRequest for &StateStreamer`
+        .manage(parameters_handler) // used by `impl FromRequest for &ParametersHandler`
+        .manage(scheduler_handler) // used by `impl FromRequest for &SchedulerHandler`
+        .manage(history_handler) // used by `impl FromRequest for &HistoryHandler`
         .mount("/", routes![
             api::pause,
             api::unpause,
             api::clear,
             api::kill_running_action,
+            api::reorder,
+            api::get_action,
+            api::remove_action,
+            api::emergency,
+            api::clear_emergency,
             api::get_state,
+            api::state_stream,
+            api::get_parameters,
+            api::patch_parameters,
             api::toggle_led,
-            api::add_action_command_list
+            api::get_attached_tool,
+            api::add_action_command_list,
+            api::add_action,
+            api::add_gcode,
+            api::get_plants,
+            api::get_plant,
+            api::add_plant,
+            api::update_plant,
+            api::remove_plant,
+            api::get_schedules,
+            api::add_schedule,
+            api::enable_schedule,
+            api::disable_schedule,
+            api::remove_schedule,
+            api::get_history
         ])
         .launch()
         .await;
@@ -96,7 +185,9 @@ async fn main() {
 
     // save parameters (note that this will reformat the file and delete comments!)
     if args.save_parameters {
-        save_parameters_to_disk(&state_handler.get_state().parameters, &args.data_dir);
+        if let Err(e) = save_parameters_to_disk(&state_handler.get_state().parameters, &args.data_dir) {
+            error!("{e}");
+        }
     }
 
     // launch().await will block until it receives a shutdown request (e.g. Ctrl+C)
@@ -106,6 +197,10 @@ async fn main() {
         warn!("Press Ctrl+C again to force kill and delete any running action");
     }
 
+    // no more scheduled actions should be added to the queue from now on
+    scheduler_task.abort();
+    device_events_task.abort();
+
     // this just tells the current action to stop after it has finished its current step,
     // but the current action will still remain in the queue and will resume next time
     // the orchestrator is started
@@ -136,6 +231,9 @@ async fn main() {
     let _ = sigint_stop_tx.send(());
     sigint_thread.await.unwrap();
 
+    state_streamer_task.abort();
+    // the devices stay on until the last step of the running action has finished
+    heartbeat_task.abort();
     for join_handle in simulation_join_handles {
         join_handle.abort();
     }
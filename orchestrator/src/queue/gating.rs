@@ -0,0 +1,45 @@
+use definitions::{Gate, GatedLevel, GatingRule, RobotState};
+
+/// Returns the [Gate]s for the `rules` that apply in `state`. A rule applies if its level is
+/// below [GatingRule::below], or if it was already applying (i.e. it is in `active`) and its
+/// level is not yet above [GatingRule::resume_above]. If `state` is `None` the levels are
+/// unknown (e.g. the devices could not be read), so the rules keep applying or not applying as
+/// in `active`: an unknown level must neither lift a gate, nor start one that would then be kept
+/// by the hysteresis.
+pub fn evaluate_gates(rules: &[GatingRule], state: Option<&RobotState>, active: &[Gate]) -> Vec<Gate> {
+    rules.iter()
+        .filter_map(|rule| {
+            let name = match rule.level {
+                GatedLevel::Battery => "Battery",
+                GatedLevel::Water => "Water",
+            };
+            let was_active = active.iter().any(|gate| &gate.rule == rule);
+            let Some(state) = state else {
+                return active.iter().find(|gate| &gate.rule == rule).cloned();
+            };
+            let level = match rule.level {
+                GatedLevel::Battery => state.battery_level.proportion,
+                GatedLevel::Water => state.water_level.proportion,
+            };
+            let percent = |proportion: f32| format!("{:.0}%", proportion * 100.0);
+            let reason = if level < rule.below {
+                format!("{name} level {} is below {}", percent(level), percent(rule.below))
+            } else if was_active && level <= rule.resume_above {
+                format!("{name} level {} has not yet risen above {}", percent(level), percent(rule.resume_above))
+            } else {
+                return None;
+            };
+            Some(Gate { rule: rule.clone(), reason })
+        })
+        .collect()
+}
+
+/// Whether any of the `gates` pauses the whole queue.
+pub fn pauses_queue(gates: &[Gate]) -> bool {
+    gates.iter().any(|gate| gate.rule.action_types.is_empty())
+}
+
+/// Whether any of the `gates` prevents actions of type `type_name` from running.
+pub fn skips_type(gates: &[Gate], type_name: &str) -> bool {
+    gates.iter().any(|gate| gate.rule.action_types.iter().any(|t| t == type_name))
+}
@@ -1,29 +1,64 @@
 mod tests;
 mod test_helpers;
 mod prev_next_action;
+mod persistence;
+mod gating;
 
 use std::{
-    collections::{HashMap, VecDeque}, fs::create_dir_all, future::Future, panic::{catch_unwind, AssertUnwindSafe, UnwindSafe}, path::PathBuf, sync::{Arc, Condvar, Mutex, MutexGuard}
+    collections::{HashMap, VecDeque}, future::Future, panic::{catch_unwind, AssertUnwindSafe, UnwindSafe}, path::PathBuf, sync::{Arc, Condvar, Mutex, MutexGuard}, time::Duration
 };
 
-use definitions::{ActionInfo, EmergencyStatus, QueueState, StepProgress};
+use chrono::Local;
+use definitions::{ActionDetails, EmergencyStatus, Gate, Priority, QueueState, RobotState, StepProgress};
 use log::trace;
 use rocket::{error, futures::FutureExt};
-use serde::{Deserialize, Serialize};
-use tokio::sync::oneshot;
+use tokio::{sync::{oneshot, watch}, time::MissedTickBehavior};
 
 use crate::{
     action::{
-        action_wrapper::{ActionId, ActionWrapper}, emergency::EmergencyAction, Action, StepResult
-    }, queue::prev_next_action::{NextAction, PrevAction}, state::{StateHandler, StateHandlerError}, util::serde::{deserialize_from_json_file, serialize_to_json_file}
+        action_wrapper::{ActionId, ActionWrapper}, emergency::EmergencyAction, registry::ActionRegistry, retry::Escalation, Action, StepResult
+    }, history::{FinalStatus, HistoryEntry, HistoryHandler}, queue::{gating::{evaluate_gates, pauses_queue, skips_type}, persistence::{append_progress_to_journal, compact_journal, load_queue, progress_snapshot, save_queue_data, QueueData}, prev_next_action::{NextAction, PrevAction}}, state::{StateHandler, StateHandlerError}
 };
 
+/// For how long [`run_device_events()`](QueueHandler::run_device_events) reads events from the
+/// devices at every period, during which no other message can be sent to them.
+const DEVICE_EVENTS_LISTEN_DURATION: Duration = Duration::from_millis(10);
+
 #[derive(Debug)]
 pub enum ReorderError {
     MismatchedExpectedNew,
     QueueChanged,
 }
 
+#[derive(Debug)]
+pub enum AddActionError {
+    /// There is no action type with the requested name in the [ActionRegistry].
+    UnknownType(String),
+    /// The action type exists, but can't be created from JSON.
+    NotConstructible(String),
+    /// The data passed to the action constructor is not valid.
+    Invalid(String),
+}
+
+#[derive(Debug)]
+pub enum ClearEmergencyError {
+    /// There is no emergency to clear.
+    NoEmergency,
+    /// The robot is still being put in a safe position, wait for
+    /// [EmergencyStatus::WaitingForClear] before clearing the emergency.
+    StillResetting,
+}
+
+/// Sent to the running action through [Queue::running_killer] to interrupt it.
+#[derive(Debug, Clone, Copy)]
+enum Interruption {
+    /// See [QueueHandler::kill_running_action].
+    Kill { keep_in_queue: bool },
+    /// See [QueueHandler::emergency]. The action is kept in the queue and will continue
+    /// running once the emergency is cleared.
+    Emergency,
+}
+
 #[derive(Debug)]
 struct Queue {
     actions: VecDeque<ActionWrapper>,
@@ -31,18 +66,79 @@ struct Queue {
     stopped: bool,
     emergency: EmergencyStatus,
     running_id: Option<ActionId>,
-    running_killer: Option<oneshot::Sender<bool>>,
+    running_killer: Option<oneshot::Sender<Interruption>>,
+    /// Whether the main loop is holding an action between its `acquire()` and `release()`, i.e.
+    /// from [`get_next_action()`](QueueHandler::get_next_action) returning it until
+    /// [`release_prev_action()`](QueueHandler::release_prev_action) puts it back or deletes it.
+    /// `running_id` alone is not enough, since it is `None` in between steps.
+    holding_action: bool,
+    /// The gating rules that currently apply, see [`update_gates()`](QueueHandler::update_gates).
+    gates: Vec<Gate>,
 
     id_counter: ActionId,
     save_dir: PathBuf,
+    /// What was last saved to `queue.json`, to avoid rewriting it if nothing changed.
+    persisted: Option<QueueData>,
 }
 
 impl Queue {
+    /// Whether an action is running, or is going to run its next step as soon as the main loop
+    /// gets to it, see [QueueHandler::run_if_idle].
+    fn is_busy(&self) -> bool {
+        self.running_id.is_some()
+            || self.holding_action
+            || self.actions.iter().any(|a| a.is_placeholder())
+    }
+
     fn create_action_wrapper<A: Action + 'static>(&mut self, action: A) -> ActionWrapper {
         let res = ActionWrapper::new(action, self.id_counter, &self.save_dir);
         self.id_counter = self.id_counter.wrapping_add(1);
         res
     }
+
+    /// Saves the list of actions to disk (see [persistence](self::persistence)), unless it did
+    /// not change since the last time. Must be called before deleting the data of any action
+    /// removed from the queue, and after saving the data of any action added to the queue.
+    fn persist(&mut self) {
+        let data = QueueData {
+            action_save_dirs: self.actions.iter()
+                .filter_map(|a| a.get_save_dir().file_name())
+                .map(PathBuf::from)
+                .collect(),
+            id_counter: self.id_counter,
+        };
+        if self.persisted.as_ref() == Some(&data) {
+            return;
+        }
+        match save_queue_data(&self.save_dir, &data) {
+            Ok(()) => self.persisted = Some(data),
+            // TODO log error to disk
+            Err(e) => error!("{e}"),
+        }
+    }
+
+    /// Adds `action` to the queue after all actions with the same or a higher priority, after
+    /// saving it to disk. If `action` ends up before the running action's placeholder, it will
+    /// preempt the running action at the end of its current step (see
+    /// [`get_next_action()`](QueueHandler::get_next_action())).
+    fn insert(&mut self, action: ActionWrapper) -> ActionId {
+        if let Err(e) = action.save_to_disk() {
+            error!("Error saving action {:?}: {e}", action.ctx);
+        }
+        let id = action.get_id();
+        let index = self.actions.iter()
+            .rposition(|a| a.priority >= action.priority)
+            .map_or(0, |index| index + 1);
+        self.actions.insert(index, action);
+        self.persist();
+        id
+    }
+
+    fn create_boxed_action_wrapper(&mut self, action: Box<dyn Action>, type_name: &str) -> ActionWrapper {
+        let res = ActionWrapper::new_boxed(action, type_name, self.id_counter, &self.save_dir);
+        self.id_counter = self.id_counter.wrapping_add(1);
+        res
+    }
 }
 
 #[cfg(test)]
@@ -56,19 +152,26 @@ pub struct QueueTestStats {
 pub struct QueueHandler {
     queue: Arc<(Mutex<Queue>, Condvar)>,
     state_handler: StateHandler,
+    /// The action types that can be loaded from disk or created with
+    /// [QueueHandler::add_action_from_json].
+    action_registry: ActionRegistry,
+    /// Where actions are recorded when they leave the queue, see [FinalStatus].
+    history: HistoryHandler,
+    /// Signals any change to the queue (e.g. an action being added, the queue being paused, some
+    /// progress being reported) to whoever [subscribed](QueueHandler::subscribe_changes).
+    changes: watch::Sender<()>,
     #[cfg(test)]
     test_stats: Arc<Mutex<QueueTestStats>>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-struct QueueData {
-    action_save_dirs: Vec<PathBuf>,
-    id_counter: ActionId,
-}
-
 /// TODO: handle panics nicely with [std::panic::catch_unwind]
 impl QueueHandler {
-    pub fn new(state_handler: StateHandler, save_dir: PathBuf) -> QueueHandler {
+    pub fn new(
+        state_handler: StateHandler,
+        save_dir: PathBuf,
+        action_registry: ActionRegistry,
+        history: HistoryHandler,
+    ) -> QueueHandler {
         QueueHandler {
             queue: Arc::new((
                 Mutex::new(Queue {
@@ -78,12 +181,18 @@ impl QueueHandler {
                     emergency: EmergencyStatus::None,
                     running_id: None,
                     running_killer: None,
+                    holding_action: false,
+                    gates: Vec::new(),
                     id_counter: 0,
                     save_dir,
+                    persisted: None,
                 }),
                 Condvar::new(),
             )),
             state_handler,
+            action_registry,
+            history,
+            changes: watch::Sender::new(()),
             #[cfg(test)]
             test_stats: Arc::new(Mutex::new(QueueTestStats {
                 wait_counter: 0,
@@ -102,6 +211,12 @@ impl QueueHandler {
         self.test_stats.lock().unwrap().tick_counter += 1;
     }
 
+    fn record_history(&self, entries: impl IntoIterator<Item = HistoryEntry>) {
+        if let Err(e) = self.history.record(entries) {
+            error!("{e}");
+        }
+    }
+
     /// Readds the last current action to the queue at the position where its
     /// placeholder is. This allows the current action to be moved around the
     /// queue or deleted even while it is being executed.
@@ -149,9 +264,6 @@ impl QueueHandler {
                 action.ctx.delete_data_on_disk();
             }
         } else {
-            // The current action has finished executing, delete any of its data on disk.
-            action.ctx.delete_data_on_disk();
-
             if let Some(index) = queue
                 .actions
                 .iter()
@@ -162,10 +274,15 @@ impl QueueHandler {
                 // No need to call release() since it has already been called
                 // in the main loop.
                 queue.actions.remove(index);
+                queue.persist();
             }
             // `else`, the current action has finished executing and its corresponding
             // placeholder has already been deleted from the queue, so nothing to do
+
+            // The current action has finished executing, delete any of its data on disk.
+            action.ctx.delete_data_on_disk();
         }
+        queue.holding_action = false;
         queue
     }
 
@@ -182,19 +299,42 @@ impl QueueHandler {
                     continue;
                 }
                 return None;
-            } else if queue.paused || queue.emergency != EmergencyStatus::None {
-                if queue.emergency == EmergencyStatus::WaitingForReset {
-                    if let Some(prev_action) = std::mem::take(&mut prev_action) {
-                        // we are pausing for a while during the emergency,
-                        // so release resources for the current action
-                        queue = self.release_prev_action(queue, prev_action);
-                        continue;
+            } else if queue.paused || queue.emergency != EmergencyStatus::None || pauses_queue(&queue.gates) {
+                match queue.emergency {
+                    EmergencyStatus::WaitingForReset => {
+                        if let Some(prev_action) = std::mem::take(&mut prev_action) {
+                            // we are pausing for a while during the emergency,
+                            // so release resources for the current action
+                            queue = self.release_prev_action(queue, prev_action);
+                            continue;
+                        }
+                        queue.emergency = EmergencyStatus::Resetting;
+                        let action_wrapper = queue.create_action_wrapper(EmergencyAction {});
+                        queue.holding_action = true;
+                        self.notify_changed();
+                        return Some(NextAction { action: action_wrapper.action.unwrap(), ctx: action_wrapper.ctx });
                     }
-                    queue.emergency = EmergencyStatus::Resetting;
-                    let action_wrapper = queue.create_action_wrapper(EmergencyAction {});
-                    return Some(NextAction { action: action_wrapper.action.unwrap(), ctx: action_wrapper.ctx });
+                    EmergencyStatus::Resetting => {
+                        // While resetting, the only action that can have been executed is the
+                        // emergency action (which is not in the queue), even if the queue is paused.
+                        if let Some(prev_action) = std::mem::take(&mut prev_action) {
+                            if let Some(action) = prev_action.action {
+                                // the emergency action has some more steps to do
+                                return Some(NextAction { action, ctx: prev_action.ctx });
+                            }
+                            info!("Emergency action finished, waiting for the emergency to be cleared");
+                            queue.emergency = EmergencyStatus::WaitingForClear;
+                            queue = self.release_prev_action(queue, prev_action);
+                            continue;
+                        }
+                    }
+                    EmergencyStatus::None | EmergencyStatus::WaitingForClear => {}
                 }
-            } else if let Some(id) = queue.actions.front().map(|a| a.get_id()) {
+            } else if let Some(index) = queue.actions.iter()
+                .position(|a| !skips_type(&queue.gates, a.get_type_name()))
+            {
+                // the first action in the queue whose type is not being skipped because of a gate
+                let id = queue.actions[index].get_id();
                 if let Some(prev_action) = std::mem::take(&mut prev_action) {
                     if id == prev_action.ctx.get_id() {
                         if let Some(action) = prev_action.action {
@@ -208,16 +348,24 @@ impl QueueHandler {
                     continue;
                 }
 
-                // The id of the first action in the queue changed, so we are going to execute a new
-                // action. The action is therefore extracted from the queue, and replaced with a
-                // placeholder (i.e. an ActionWrapper with action=None).
-                let action_in_queue = queue.actions.front_mut().unwrap();
+                // The id of the first runnable action in the queue changed, so we are going to
+                // execute a new action. The action is therefore extracted from the queue, and
+                // replaced with a placeholder (i.e. an ActionWrapper with action=None).
+                let action_in_queue = &mut queue.actions[index];
                 let mut next_action = std::mem::take(&mut action_in_queue.action)
                     .expect("Unxpected placeholder in the queue");
+                if action_in_queue.started_at.is_none() {
+                    action_in_queue.started_at = Some(Local::now().naive_local());
+                    if let Err(e) = action_in_queue.save_started_to_disk() {
+                        error!("Could not save start time for action {}: {e}", action_in_queue.get_id());
+                    }
+                }
                 let next_ctx = action_in_queue.ctx.clone();
+                queue.holding_action = true;
 
                 // Release the lock before calling `.acquire()`.
                 drop(queue);
+                self.notify_changed();
 
                 // We call acquire() to abide by the action lifecycle.
                 next_action.acquire(&next_ctx);
@@ -231,6 +379,9 @@ impl QueueHandler {
                 continue;
             }
 
+            // the queue might have changed while releasing the previous action
+            self.notify_changed();
+
             #[cfg(test)]
             self.increase_wait_counter();
             queue = condvar.wait(queue).unwrap();
@@ -242,16 +393,19 @@ impl QueueHandler {
     /// before `stepper` terminates.
     /// Returns:
     /// - [StepResult::Running] or [StepResult::RunningError] if there are some more steps
-    ///   available,
+    ///   available, or if the action was interrupted because of an emergency,
     /// - [StepResult::Finished] or [StepResult::FinishedError] if the action has finished
     ///   executing, or if there was an unexpected panic.
+    ///
+    /// The returned boolean is `true` if the step was interrupted through `killer_rx`, in which
+    /// case the result must not be subject to the [retry policy](Action::retry_policy).
     async fn step_or_kill<F: Future<Output = StepResult> + UnwindSafe>(
         stepper: F,
-        killer_rx: oneshot::Receiver<bool>,
-    ) -> StepResult {
+        killer_rx: oneshot::Receiver<Interruption>,
+    ) -> (StepResult, bool) {
         tokio::select! {
             output = stepper.catch_unwind() => {
-                match output {
+                let output = match output {
                     // Just forward the value from `stepper()`
                     Ok(output) => output,
                     // Some panic happened, do as if the action has finished executing
@@ -261,22 +415,31 @@ impl QueueHandler {
                             format!("Panic while stepping action: {err:?}")
                         ))
                     },
-                }
+                };
+                (output, false)
             }
 
             // Calling unwrap() here, since the tx is never going to be dropped,
             // without sending anything, while this future is still being executed.
             // The tx is only dropped after it sends something, or after the other branch of
             // this select! has returned before this one.
-            output = killer_rx => if output.unwrap() {
-                info!("Received kill signal for action, with keep_in_queue = true");
-                // keep in queue
-                StepResult::RunningError(StateHandlerError::GenericError("Killed".to_string()))
-            } else {
-                info!("Received kill signal for action, with keep_in_queue = false");
-                // remove from queue
-                StepResult::FinishedError(StateHandlerError::GenericError("Killed".to_string()))
-            },
+            output = killer_rx => (match output.unwrap() {
+                Interruption::Kill { keep_in_queue: true } => {
+                    info!("Received kill signal for action, with keep_in_queue = true");
+                    // keep in queue
+                    StepResult::RunningError(StateHandlerError::GenericError("Killed".to_string()))
+                }
+                Interruption::Kill { keep_in_queue: false } => {
+                    info!("Received kill signal for action, with keep_in_queue = false");
+                    // remove from queue
+                    StepResult::FinishedError(StateHandlerError::GenericError("Killed".to_string()))
+                }
+                Interruption::Emergency => {
+                    info!("Received emergency signal for action");
+                    // keep in queue, without pausing and keeping the previous progress
+                    StepResult::Running(StepProgress::Unknown)
+                }
+            }, true),
         }
     }
 
@@ -287,6 +450,8 @@ impl QueueHandler {
             .unwrap();
 
         let mut prev_action = None; // will be None only the first iteration
+        // how many times in a row the step of the action with this id failed and was retried
+        let mut failed_attempts: Option<(ActionId, u32)> = None;
         loop {
             let next_action = self.get_next_action(prev_action);
             #[cfg(test)]
@@ -302,28 +467,72 @@ impl QueueHandler {
                     queue.running_id = Some(id);
                     queue.running_killer = Some(killer_tx);
                 }
-
-                // `action.step()` returns `true` if there are some more steps available,
-                // or `false` if the action has finished executing. The `killer_rx` channel
-                // will also do the same, i.e. return `true` if the action should be kept
-                // in the queue, or `false` otherwise.
-                let step_result = runtime.block_on(Self::step_or_kill(
+                self.notify_changed();
+
+                // `action.step()` returns whether there are some more steps available, or if
+                // the action has finished executing. The `killer_rx` channel may interrupt
+                // the step, see `step_or_kill()`.
+                // If the previous attempt at this step failed, wait for the backoff before
+                // retrying. The backoff is part of the step so that it can be interrupted, too.
+                let retry_policy = action.retry_policy();
+                let failed_attempts_so_far = match failed_attempts {
+                    Some((failed_id, attempts)) if failed_id == id => attempts,
+                    _ => 0,
+                };
+                let backoff = (failed_attempts_so_far > 0).then(|| retry_policy.backoff(failed_attempts_so_far));
+                let (step_result, interrupted) = runtime.block_on(Self::step_or_kill(
                     // Here we kindly ask the compiler to not check unwind safety. Losing unwind
                     // safety is not undefined behavior, though it could possibly lead to logic bugs
                     // if the panic happens in the middle of operations that leave `action` or
                     // `state_handler` in invalid states.
                     // See https://old.reddit.com/r/rust/comments/7diwt1 and
                     // https://users.rust-lang.org/t/73067/3 .
-                    AssertUnwindSafe(action.step(&ctx, &self.state_handler)),
+                    AssertUnwindSafe(async {
+                        if let Some(backoff) = backoff {
+                            tokio::time::sleep(backoff).await;
+                        }
+                        action.step(&ctx, &self.state_handler).await
+                    }),
                     killer_rx,
                 ));
 
+                // Decide whether to retry a failed step or to escalate according to the policy.
+                let mut retrying = false;
+                let step_result = match step_result {
+                    StepResult::RunningError(error) if !interrupted => {
+                        let attempt = failed_attempts_so_far + 1;
+                        if retry_policy.should_retry(&error, attempt) {
+                            warn!(
+                                "action.step() with id {id} failed (attempt {attempt}/{}), retrying in {:?}: {error:?}",
+                                retry_policy.max_attempts, retry_policy.backoff(attempt),
+                            );
+                            failed_attempts = Some((id, attempt));
+                            retrying = true;
+                            StepResult::RunningError(error)
+                        } else {
+                            failed_attempts = None;
+                            match retry_policy.on_exhausted {
+                                Escalation::Pause => StepResult::RunningError(error),
+                                Escalation::Finish => StepResult::FinishedError(error),
+                            }
+                        }
+                    }
+                    step_result => {
+                        failed_attempts = None;
+                        step_result
+                    }
+                };
+
                 let (should_release_action, should_pause_queue, new_progress, error) = match step_result {
                     StepResult::Running(step_progress) => {
                         // Nothing to do, action is kept in the queue.
                         trace!("action.step() with id {id} reported progress {step_progress:?}");
                         (false, false, step_progress, None)
                     },
+                    StepResult::RunningError(state_handler_error) if retrying => {
+                        // The error is recorded, and the step will be attempted again.
+                        (false, false, StepProgress::Unknown, Some(state_handler_error))
+                    },
                     StepResult::RunningError(state_handler_error) => {
                         // Action failed with an error, but should remain in queue, so pause the
                         // queue.
@@ -341,6 +550,12 @@ impl QueueHandler {
                     },
                 };
 
+                let final_status = should_release_action.then_some(match (&error, interrupted) {
+                    (None, _) => FinalStatus::Finished,
+                    (Some(_), true) => FinalStatus::Killed,
+                    (Some(_), false) => FinalStatus::Failed,
+                });
+
                 let action = if should_release_action {
                     // The action has finished executing, release its resources and remove it from
                     // the queue. Run this with catch_unwind just in case there is some panic!() in
@@ -354,23 +569,60 @@ impl QueueHandler {
                     Some(action)
                 };
 
-                {
+                let (history_entry, journaled_progress, progress_to_compact, save_dir) = {
                     let mut queue = self.queue.0.lock().unwrap();
+                    let queue = &mut *queue;
                     queue.running_id = None;
                     queue.running_killer = None;
                     if should_pause_queue {
                         queue.paused = true;
                     }
-                    if !matches!(new_progress, StepProgress::Unknown) || error.is_some() {
-                        if let Some(item) = queue.actions.iter_mut().find(|a| a.get_id() == id) {
-                            if !matches!(new_progress, StepProgress::Unknown) {
-                                item.progress = new_progress;
-                            }
-                            if let Some(error) = error {
-                                item.errors.push(error);
-                            }
+                    let mut journaled_progress = None;
+                    let mut item = queue.actions.iter_mut().find(|a| a.get_id() == id);
+                    if let Some(item) = item.as_deref_mut() {
+                        if !matches!(new_progress, StepProgress::Unknown) {
+                            journaled_progress = Some(new_progress.clone());
+                            item.progress = new_progress;
+                        }
+                        if let Some(error) = error {
+                            item.errors.push(error);
                         }
                     }
+                    // If the placeholder is not in the queue anymore, the action was removed
+                    // while running, and remove_action() already recorded it in the history.
+                    let history_entry = final_status.zip(item)
+                        .map(|(status, item)| item.to_history_entry(status, Local::now().naive_local()));
+                    // Compact the journal whenever an action leaves the queue, so that it does
+                    // not keep growing. Only the main loop changes the progress of actions, so
+                    // the snapshot can be saved after releasing the lock.
+                    let progress_to_compact = should_release_action.then(|| progress_snapshot(&queue.actions));
+                    (history_entry, journaled_progress, progress_to_compact, queue.save_dir.clone())
+                };
+                self.notify_changed();
+                if let Some(history_entry) = history_entry {
+                    self.record_history([history_entry]);
+                }
+
+                // Writing to the journal waits for the disk, so it is done without holding the
+                // lock. Only the main loop writes to the journal, so no entry can get lost.
+                if let Some(progress) = &journaled_progress {
+                    if let Err(e) = append_progress_to_journal(&save_dir, id, progress) {
+                        error!("{e}");
+                    }
+                }
+                if let Some(progress_to_compact) = &progress_to_compact {
+                    if let Err(e) = compact_journal(&save_dir, progress_to_compact) {
+                        error!("{e}");
+                    }
+                }
+
+                if let Some(action) = &action {
+                    // Save the action after each step, so that it can resume from where it left
+                    // off even if the orchestrator is killed. If the action was removed from the
+                    // queue in the meantime, release_prev_action() will delete its data anyway.
+                    if let Err(e) = action.save_to_disk(&ctx) {
+                        error!("Error saving action {ctx:?}: {e}");
+                    }
                 }
 
                 prev_action = Some(PrevAction { action, ctx });
@@ -382,48 +634,16 @@ impl QueueHandler {
 
     fn load_from_disk(&self) {
         let mut queue: MutexGuard<'_, Queue> = self.queue.0.lock().unwrap();
-        let data = deserialize_from_json_file::<QueueData>(&queue.save_dir.join("queue.json"));
-        let data = match data {
-            Ok(data) => data,
-            Err(e) => {
-                // TODO log error
-                println!("Error deserializing queue.json: {e}");
-                return;
-            }
-        };
-
-        queue.id_counter = data.id_counter;
-        queue.actions.clear();
-        for save_dir in data.action_save_dirs {
-            match ActionWrapper::load_from_disk(&save_dir) {
-                Ok(action) => queue.actions.push_back(action),
-                Err(e) => {
-                    // TODO log error
-                    println!("Error deserializing action {save_dir:?}: {e}")
-                }
-            }
-        }
+        let (actions, id_counter) = load_queue(&queue.save_dir, &self.action_registry);
+        queue.actions = actions;
+        queue.id_counter = id_counter;
+        // some actions may have been quarantined
+        queue.persist();
     }
 
     fn save_to_disk(&self) {
-        let queue = self.queue.0.lock().unwrap();
-        let data = QueueData {
-            action_save_dirs: queue
-                .actions
-                .iter()
-                .map(|a| a.get_save_dir().clone())
-                .collect(),
-            id_counter: queue.id_counter,
-        };
-
-        if let Err(e) = create_dir_all(&queue.save_dir) {
-            // TODO log error
-            println!("Error creating save directory: {e}")
-        }
-        if let Err(e) = serialize_to_json_file(&data, &queue.save_dir.join("queue.json")) {
-            // TODO log error
-            println!("Error serializing queue.json: {e}")
-        }
+        let mut queue = self.queue.0.lock().unwrap();
+        queue.persist();
 
         for action in &queue.actions {
             // TODO log any error to disk
@@ -431,6 +651,9 @@ impl QueueHandler {
                 println!("Error serializing action {:?}: {e}", action.ctx)
             }
         }
+        if let Err(e) = compact_journal(&queue.save_dir, &progress_snapshot(&queue.actions)) {
+            error!("{e}");
+        }
     }
 
     pub fn run(&self) {
@@ -442,19 +665,74 @@ impl QueueHandler {
     fn mutate_queue_and_notify<T>(&self, f: impl FnOnce(MutexGuard<'_, Queue>) -> T) -> T {
         let (queue, condvar) = &*self.queue;
         let res = f(queue.lock().unwrap());
+        queue.lock().unwrap().persist();
         condvar.notify_all();
+        self.notify_changed();
         res
     }
 
+    fn notify_changed(&self) {
+        self.changes.send_replace(());
+    }
+
+    /// Returns a receiver that is marked as changed whenever something in the queue changes,
+    /// i.e. whenever the value returned by [`get_state()`](QueueHandler::get_state) might differ.
+    pub fn subscribe_changes(&self) -> watch::Receiver<()> {
+        self.changes.subscribe()
+    }
+
+    /// Adds `action` to the queue with priority `0`, see
+    /// [`add_action_with_priority()`](QueueHandler::add_action_with_priority).
     pub fn add_action<A: Action + 'static>(&self, action: A) -> ActionId {
+        self.add_action_with_priority(action, 0)
+    }
+
+    /// Adds `action` to the queue after all of the actions with the same or a higher `priority`.
+    /// If `priority` is higher than that of the running action, the running action is paused
+    /// after its current step, and resumes once all actions with a higher priority are done.
+    pub fn add_action_with_priority<A: Action + 'static>(&self, action: A, priority: Priority) -> ActionId {
         self.mutate_queue_and_notify(|mut queue| {
-            let action = queue.create_action_wrapper(action);
-            let id = action.get_id();
-            queue.actions.push_back(action);
-            id
+            let action = queue.create_action_wrapper(action).with_priority(priority);
+            queue.insert(action)
         })
     }
 
+    /// Creates an action of the type registered as `type_name` in the [ActionRegistry] from
+    /// `data` (see [ActionType::create_from_json](crate::action::registry::ActionType::create_from_json)), and
+    /// adds it to the queue like [`add_action_with_priority()`](QueueHandler::add_action_with_priority).
+    pub fn add_action_from_json(
+        &self,
+        type_name: &str,
+        data: serde_json::Value,
+        priority: Priority,
+    ) -> Result<ActionId, AddActionError> {
+        let (action, type_name) = self.create_action_from_json(type_name, data)?;
+        Ok(self.mutate_queue_and_notify(|mut queue| {
+            let action = queue.create_boxed_action_wrapper(action, type_name)
+                .with_priority(priority);
+            queue.insert(action)
+        }))
+    }
+
+    /// Checks that [`add_action_from_json()`](QueueHandler::add_action_from_json) would accept
+    /// `type_name` and `data`, without adding anything to the queue.
+    pub fn validate_action_json(&self, type_name: &str, data: serde_json::Value) -> Result<(), AddActionError> {
+        self.create_action_from_json(type_name, data).map(|_| ())
+    }
+
+    fn create_action_from_json(
+        &self,
+        type_name: &str,
+        data: serde_json::Value,
+    ) -> Result<(Box<dyn Action>, &'static str), AddActionError> {
+        let action_type = self.action_registry.get(type_name)
+            .ok_or_else(|| AddActionError::UnknownType(type_name.to_string()))?;
+        let action = action_type.create_from_json(data, &self.state_handler)
+            .ok_or_else(|| AddActionError::NotConstructible(type_name.to_string()))?
+            .map_err(AddActionError::Invalid)?;
+        Ok((action, action_type.get_type_name()))
+    }
+
     pub fn reorder(&self, expected: Vec<ActionId>, new: Vec<ActionId>) -> Result<(), ReorderError> {
         {
             let mut expected_sorted = expected.clone();
@@ -493,8 +771,42 @@ impl QueueHandler {
         })
     }
 
+    /// Removes the action with id `id` from the queue, returning `false` if there is no such
+    /// action. If the action is running, it will be removed only after its current step finishes
+    /// (like with [`clear()`](QueueHandler::clear)).
+    pub fn remove_action(&self, id: ActionId) -> bool {
+        let history_entry = self.mutate_queue_and_notify(|mut queue| {
+            let index = queue.actions.iter().position(|action| action.get_id() == id)?;
+            let action = queue.actions.remove(index).unwrap();
+            queue.persist();
+            if !action.is_placeholder() && action.get_save_dir().exists() {
+                // If the action is a placeholder, its data will be deleted by
+                // release_prev_action() once the current step finishes.
+                action.ctx.delete_data_on_disk();
+            }
+            Some(action.to_history_entry(FinalStatus::Removed, Local::now().naive_local()))
+        });
+        history_entry.map(|history_entry| self.record_history([history_entry])).is_some()
+    }
+
     pub fn clear(&self) {
-        self.mutate_queue_and_notify(|mut queue| queue.actions.clear())
+        let history_entries = self.mutate_queue_and_notify(|mut queue| {
+            let actions = std::mem::take(&mut queue.actions);
+            queue.persist();
+            let now = Local::now().naive_local();
+            actions.into_iter()
+                .map(|action| {
+                    // placeholders are handled by release_prev_action(), like in remove_action()
+                    if !action.is_placeholder() {
+                        action.ctx.delete_data_on_disk();
+                    }
+                    action.to_history_entry(FinalStatus::Removed, now)
+                })
+                .collect::<Vec<_>>()
+        });
+        if !history_entries.is_empty() {
+            self.record_history(history_entries);
+        }
     }
 
     pub fn pause(&self) {
@@ -511,7 +823,20 @@ impl QueueHandler {
 
     pub fn is_idle(&self) -> bool {
         let queue = self.queue.0.lock().unwrap();
-        queue.running_id.is_none()
+        !queue.is_busy()
+    }
+
+    /// Runs `f` only if no action is running, while making sure that no action starts running
+    /// until `f` returns. Returns `None` without running `f` if an action is running, including
+    /// when it is in between two steps or still being acquired.
+    pub fn run_if_idle<R>(&self, f: impl FnOnce() -> R) -> Option<R> {
+        let queue = self.queue.0.lock().unwrap();
+        if queue.is_busy() {
+            return None;
+        }
+        let res = f();
+        drop(queue);
+        Some(res)
     }
 
     /// Always pauses the queue, and maintains it paused even after killing is finished.
@@ -528,7 +853,7 @@ impl QueueHandler {
             queue.paused = true;
             if queue.running_id == Some(running_id) {
                 if let Some(running_killer) = queue.running_killer.take() {
-                    return running_killer.send(keep_in_queue).is_ok();
+                    return running_killer.send(Interruption::Kill { keep_in_queue }).is_ok();
                 }
             }
             false
@@ -539,11 +864,106 @@ impl QueueHandler {
         self.mutate_queue_and_notify(|mut queue| {
             queue.paused = true;
             if let Some(running_killer) = queue.running_killer.take() {
-                let _ = running_killer.send(false);
+                let _ = running_killer.send(Interruption::Kill { keep_in_queue: false });
             }
         });
     }
 
+    /// Triggers an emergency. The running action (if any) is interrupted immediately, and kept
+    /// in the queue to be resumed later. Then [EmergencyAction] is executed to put the robot in
+    /// a safe position, after which the queue will not execute anything until
+    /// [`clear_emergency()`](QueueHandler::clear_emergency) is called.
+    ///
+    /// Does nothing if the emergency action is already going to be executed or is being
+    /// executed, but triggers it again if an emergency was not yet cleared.
+    pub fn emergency(&self) {
+        self.mutate_queue_and_notify(|mut queue| {
+            if matches!(queue.emergency, EmergencyStatus::WaitingForReset | EmergencyStatus::Resetting) {
+                return;
+            }
+            warn!("Emergency triggered");
+            queue.emergency = EmergencyStatus::WaitingForReset;
+            if let Some(running_killer) = queue.running_killer.take() {
+                let _ = running_killer.send(Interruption::Emergency);
+            }
+        });
+    }
+
+    /// Allows the queue to resume executing actions after an emergency, but only if the robot
+    /// was already put in a safe position (i.e. [EmergencyStatus::WaitingForClear]).
+    pub fn clear_emergency(&self) -> Result<(), ClearEmergencyError> {
+        self.mutate_queue_and_notify(|mut queue| match queue.emergency {
+            EmergencyStatus::None => Err(ClearEmergencyError::NoEmergency),
+            EmergencyStatus::WaitingForReset | EmergencyStatus::Resetting => {
+                Err(ClearEmergencyError::StillResetting)
+            }
+            EmergencyStatus::WaitingForClear => {
+                info!("Emergency cleared");
+                queue.emergency = EmergencyStatus::None;
+                Ok(())
+            }
+        })
+    }
+
+    /// Evaluates [Parameters::gating_rules](definitions::Parameters::gating_rules) against the
+    /// battery and water levels in `state`, which are considered unknown (so the gates stay as
+    /// they are) if `!levels_known`, e.g. because the devices could not be read. While a rule
+    /// applies, the
+    /// queue does not start (or continue, after the current step) any action of the types in the
+    /// rule, or any action at all if the rule has no types. Once the level recovers, the queue
+    /// resumes by itself. Called by [StateStreamer::run](crate::api::stream::StateStreamer::run).
+    pub fn update_gates(&self, state: &RobotState, levels_known: bool) {
+        let (queue_mutex, condvar) = &*self.queue;
+        let mut queue = queue_mutex.lock().unwrap();
+        let gates = evaluate_gates(&state.parameters.gating_rules, levels_known.then_some(state), &queue.gates);
+        let changed = !gates.iter().map(|g| &g.rule).eq(queue.gates.iter().map(|g| &g.rule));
+        if changed {
+            for gate in &gates {
+                if !queue.gates.iter().any(|g| g.rule == gate.rule) {
+                    warn!("Gating the queue: {}", gate.reason);
+                }
+            }
+            for gate in &queue.gates {
+                if !gates.iter().any(|g| g.rule == gate.rule) {
+                    info!("Not gating the queue anymore: {:?}", gate.rule);
+                }
+            }
+        }
+        // the reasons are updated anyway, since they contain the current levels
+        queue.gates = gates;
+        drop(queue);
+        if changed {
+            condvar.notify_all();
+            self.notify_changed();
+        }
+    }
+
+    /// Never returns, so should be spawned in its own task. Every `period` the events sent by the
+    /// serial devices are received with [`receive_events()`](StateHandler::receive_events), and
+    /// an [`emergency()`](QueueHandler::emergency) is triggered for those in
+    /// [Parameters::emergency_events](definitions::Parameters::emergency_events).
+    pub async fn run_device_events(&self, period: Duration) {
+        let mut events = self.state_handler.subscribe_events();
+        let mut ticker = tokio::time::interval(period);
+        ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        loop {
+            ticker.tick().await;
+            let received = self.state_handler.receive_events(&mut events, DEVICE_EVENTS_LISTEN_DURATION).await;
+            if received.is_empty() {
+                continue;
+            }
+            let emergency_events = self.state_handler.get_state().parameters.emergency_events;
+            for event in received {
+                if emergency_events.contains(&event.kind) {
+                    warn!("Emergency event from {}: {}", event.device, event.description);
+                    self.emergency();
+                } else {
+                    info!("Event from {}: {}", event.device, event.description);
+                }
+            }
+        }
+    }
+
     pub fn get_state(&self) -> QueueState {
         let queue = self.queue.0.lock().unwrap();
         QueueState {
@@ -552,13 +972,17 @@ impl QueueHandler {
             emergency: queue.emergency,
             save_dir: queue.save_dir.clone(),
             running_id: queue.running_id,
-            actions: queue.actions.iter().map(|action| ActionInfo {
-                id: action.get_id(),
-                type_name: action.get_type_name().clone(),
-                save_dir: action.get_save_dir().clone(),
-                is_running: action.is_placeholder(),
-                progress: action.get_progress().clone(),
-            }).collect(),
+            actions: queue.actions.iter().map(ActionWrapper::get_info).collect(),
+            gates: queue.gates.clone(),
         }
     }
+
+    /// Returns the details about the action with id `id` in the queue (possibly the running
+    /// one), or `None` if there is no such action.
+    pub fn get_action_details(&self, id: ActionId) -> Option<ActionDetails> {
+        let queue = self.queue.0.lock().unwrap();
+        queue.actions.iter()
+            .find(|action| action.get_id() == id)
+            .map(ActionWrapper::get_details)
+    }
 }
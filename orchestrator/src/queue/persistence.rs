@@ -0,0 +1,222 @@
+//! Saves the queue to disk in a way that survives the orchestrator being killed (or the power
+//! going off) at any moment:
+//! - `queue.json` lists the actions in the queue by the name of their directory (so that the whole
+//!   save directory can be moved around), and is rewritten atomically (see
+//!   [serialize_to_json_file]) every time the queue changes;
+//! - each action is saved in its own `ID_TYPENAME` directory when it is added to the queue, and
+//!   again after each of its steps;
+//! - the progress reported by actions is appended to `journal.jsonl`, which is compacted into
+//!   each action's `progress.json` whenever an action leaves the queue, and replayed if the
+//!   orchestrator is killed before that;
+//! - action directories that can't be loaded, or that are not listed in `queue.json`, are moved to
+//!   `quarantine/` instead of being dropped, so that they can be inspected later.
+
+use std::{
+    collections::{HashSet, VecDeque},
+    fs::{create_dir_all, read_dir, remove_file, rename},
+    io::ErrorKind,
+    path::{Path, PathBuf},
+};
+
+use definitions::StepProgress;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    action::{action_wrapper::{save_progress_to_disk, ActionId, ActionWrapper}, registry::ActionRegistry},
+    util::serde::{append_json_line, deserialize_from_json_file, serialize_to_json_file},
+};
+
+const QUEUE_FILE: &str = "queue.json";
+const JOURNAL_FILE: &str = "journal.jsonl";
+pub const QUARANTINE_DIR: &str = "quarantine";
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct QueueData {
+    /// The names of the action directories inside the save directory, in queue order. Absolute
+    /// paths are accepted too, but only their last component is used.
+    pub action_save_dirs: Vec<PathBuf>,
+    pub id_counter: ActionId,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct JournalEntry {
+    id: ActionId,
+    progress: StepProgress,
+}
+
+pub fn save_queue_data(save_dir: &Path, data: &QueueData) -> Result<(), String> {
+    create_dir_all(save_dir).map_err(|e| format!("Could not create save directory {save_dir:?}: {e}"))?;
+    serialize_to_json_file(data, &save_dir.join(QUEUE_FILE))
+        .map_err(|e| format!("Could not save {QUEUE_FILE}: {e}"))
+}
+
+/// Records that the action with id `id` reported `progress`, without having to rewrite anything.
+pub fn append_progress_to_journal(save_dir: &Path, id: ActionId, progress: &StepProgress) -> Result<(), String> {
+    append_json_line(&JournalEntry { id, progress: progress.clone() }, &save_dir.join(JOURNAL_FILE))
+        .map_err(|e| format!("Could not append to {JOURNAL_FILE}: {e}"))
+}
+
+/// Returns the progress of each action in `actions` along with its directory, to be passed to
+/// [compact_journal] after releasing any lock on the queue.
+pub fn progress_snapshot<'a>(actions: impl IntoIterator<Item = &'a ActionWrapper>) -> Vec<(PathBuf, StepProgress)> {
+    actions.into_iter()
+        .map(|action| (action.get_save_dir().clone(), action.progress.clone()))
+        .collect()
+}
+
+/// Deletes the journal after the progress of each action (as obtained from [progress_snapshot])
+/// has been saved in its own directory. Returns an error (and keeps the journal) if the progress
+/// of any action could not be saved. Actions whose directory does not exist anymore are skipped,
+/// since they were removed from the queue in the meantime.
+pub fn compact_journal(save_dir: &Path, progress: &[(PathBuf, StepProgress)]) -> Result<(), String> {
+    for (dir, progress) in progress {
+        if !dir.exists() {
+            continue;
+        }
+        save_progress_to_disk(dir, progress)
+            .map_err(|e| format!("Could not save progress to {dir:?}: {e}"))?;
+    }
+    match remove_file(save_dir.join(JOURNAL_FILE)) {
+        Err(e) if e.kind() != ErrorKind::NotFound => Err(format!("Could not delete {JOURNAL_FILE}: {e}")),
+        _ => Ok(()),
+    }
+}
+
+/// Loads the actions in the queue from `save_dir`, recovering from any state the queue could have
+/// been left in if the orchestrator was killed. Returns the actions and the id counter.
+pub fn load_queue(save_dir: &Path, registry: &ActionRegistry) -> (VecDeque<ActionWrapper>, ActionId) {
+    let action_dirs = find_action_dirs(save_dir);
+
+    let queue_file = save_dir.join(QUEUE_FILE);
+    let data = if queue_file.exists() {
+        match deserialize_from_json_file::<QueueData>(&queue_file) {
+            Ok(data) => data,
+            Err(e) => {
+                // queue.json is always written atomically, so this should never happen unless
+                // something else is wrong with the disk; try to recover as much as possible
+                log::error!("Could not read {queue_file:?}, rebuilding the queue from action directories: {e}");
+                quarantine(save_dir, &queue_file);
+                QueueData {
+                    action_save_dirs: action_dirs.iter().map(|(_, dir)| dir.clone()).collect(),
+                    id_counter: 0,
+                }
+            }
+        }
+    } else {
+        QueueData { action_save_dirs: vec![], id_counter: 0 }
+    };
+
+    // never reuse the id of an action directory, even if it is going to be deleted below
+    let id_counter = action_dirs.iter()
+        .map(|(id, _)| id.wrapping_add(1))
+        .fold(data.id_counter, ActionId::max);
+
+    // only the name of each directory is relevant, in case the save directory was moved
+    let listed_dirs: Vec<PathBuf> = data.action_save_dirs.iter()
+        .filter_map(|dir| dir.file_name())
+        .map(|name| save_dir.join(name))
+        .collect();
+
+    let mut actions = VecDeque::new();
+    for dir in &listed_dirs {
+        match ActionWrapper::load_from_disk(dir, registry) {
+            Ok(action) => actions.push_back(action),
+            Err(e) => {
+                log::error!("Could not load action {dir:?}: {e}");
+                if dir.exists() {
+                    quarantine(save_dir, dir);
+                }
+            }
+        }
+    }
+
+    // Action directories not listed in queue.json belong to actions that were being added or
+    // removed when the orchestrator was killed, since queue.json is saved after adding an action
+    // directory and before deleting one. Still, they are quarantined rather than deleted, in case
+    // queue.json was wrong.
+    let listed: HashSet<&PathBuf> = listed_dirs.iter().collect();
+    for (_, dir) in &action_dirs {
+        if !listed.contains(dir) {
+            log::warn!("Action directory {dir:?} is not in the queue");
+            quarantine(save_dir, dir);
+        }
+    }
+
+    replay_journal(save_dir, &mut actions);
+    if let Err(e) = compact_journal(save_dir, &progress_snapshot(&actions)) {
+        log::error!("{e}");
+    }
+
+    (actions, id_counter)
+}
+
+/// Returns the directories named `ID_TYPENAME` in `save_dir`, sorted by id.
+fn find_action_dirs(save_dir: &Path) -> Vec<(ActionId, PathBuf)> {
+    let Ok(entries) = read_dir(save_dir) else {
+        return vec![];
+    };
+    let mut action_dirs: Vec<(ActionId, PathBuf)> = entries
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+        .filter(|path| path.is_dir())
+        .filter_map(|path| {
+            let filename = path.file_name()?.to_str()?;
+            let (id, type_name) = filename.split_once('_')?;
+            (!type_name.is_empty()).then_some(())?;
+            Some((id.parse().ok()?, path))
+        })
+        .collect();
+    action_dirs.sort();
+    action_dirs
+}
+
+fn replay_journal(save_dir: &Path, actions: &mut VecDeque<ActionWrapper>) {
+    let journal_file = save_dir.join(JOURNAL_FILE);
+    let journal = match std::fs::read_to_string(&journal_file) {
+        Ok(journal) => journal,
+        Err(e) if e.kind() == ErrorKind::NotFound => return,
+        Err(e) => {
+            log::error!("Could not read {journal_file:?}: {e}");
+            return;
+        }
+    };
+
+    for line in journal.lines() {
+        match serde_json::from_str::<JournalEntry>(line) {
+            Ok(JournalEntry { id, progress }) => {
+                if let Some(action) = actions.iter_mut().find(|action| action.get_id() == id) {
+                    action.progress = progress;
+                }
+            }
+            // most likely the last line, which was being written when the orchestrator was killed
+            Err(e) => log::warn!("Ignoring invalid line {line:?} in {journal_file:?}: {e}"),
+        }
+    }
+}
+
+/// Moves `path` (either a file or a directory) to the quarantine directory inside `save_dir`,
+/// adding a numeric suffix if something with the same name was already quarantined.
+fn quarantine(save_dir: &Path, path: &Path) {
+    let quarantine_dir = save_dir.join(QUARANTINE_DIR);
+    if let Err(e) = create_dir_all(&quarantine_dir) {
+        log::error!("Could not create quarantine directory {quarantine_dir:?}: {e}");
+        return;
+    }
+    let Some(name) = path.file_name() else {
+        return;
+    };
+
+    let mut target = quarantine_dir.join(name);
+    let mut suffix = 1;
+    while target.exists() {
+        let mut name = name.to_owned();
+        name.push(format!(".{suffix}"));
+        target = quarantine_dir.join(name);
+        suffix += 1;
+    }
+
+    log::error!("Moving {path:?} to {target:?}");
+    if let Err(e) = rename(path, &target) {
+        log::error!("Could not quarantine {path:?}: {e}");
+    }
+}
@@ -2,13 +2,15 @@
 
 use definitions::StepProgress;
 use futures::future::BoxFuture;
+use serde::{Deserialize, Serialize};
 
-use std::{thread::JoinHandle, time::Duration};
+use std::{path::Path, thread::JoinHandle, time::Duration};
 
 use super::*;
 use crate::{
-    action::{action_wrapper::Context, StepResult},
+    action::{action_wrapper::Context, registry::{ActionRegistry, ActionType}, retry::RetryPolicy, StepResult},
     state::tests::{get_test_state, TestState},
+    util::serde::{deserialize_from_json_file, serialize_to_json_file},
 };
 
 pub struct TestQueue {
@@ -17,11 +19,29 @@ pub struct TestQueue {
     pub queue_join_handle: JoinHandle<()>,
 }
 
+/// The default action types, plus the ones only used in tests.
+pub fn test_action_registry() -> ActionRegistry {
+    ActionRegistry::default()
+        .register(ActionType::new::<InfiniteTestAction>()
+            .with_json_constructor(|action: InfiniteTestAction, _| action))
+        .register(ActionType::new::<StepResultTestAction>())
+        .register(ActionType::new::<ProgressTestAction>())
+}
+
+pub fn test_history(data_dir: &Path) -> HistoryHandler {
+    HistoryHandler::load_from_disk(data_dir, 100)
+}
+
 pub fn get_test_state_queue() -> (TestState, TestQueue) {
     let test_state = get_test_state();
 
     let save_dir = tempdir::TempDir::new("cyberorto_test").unwrap().into_path();
-    let queue_handler = QueueHandler::new(test_state.state_handler.clone(), save_dir.clone());
+    let queue_handler = QueueHandler::new(
+        test_state.state_handler.clone(),
+        save_dir.clone(),
+        test_action_registry(),
+        test_history(&save_dir),
+    );
     let queue_handler_clone = queue_handler.clone();
     let queue_join_handle = std::thread::spawn(move || queue_handler_clone.run());
 
@@ -114,6 +134,30 @@ pub async fn wait_for_nth_tick(
     panic!("Queue did not get to {wait_counter}th wait and {tick_counter}th tick in time");
 }
 
+pub async fn wait_for_emergency_status(
+    q: &mut TestQueue,
+    emergency: EmergencyStatus,
+    timeout_millis: usize,
+) {
+    for _ in 0..timeout_millis {
+        if q.queue_handler.get_state().emergency == emergency {
+            return;
+        }
+        tokio::time::sleep(Duration::from_millis(1)).await;
+    }
+    panic!("Queue did not get to emergency status {emergency:?} in time");
+}
+
+pub async fn wait_for_running_id(q: &mut TestQueue, running_id: Option<ActionId>, timeout_millis: usize) {
+    for _ in 0..timeout_millis {
+        if q.queue_handler.get_state().running_id == running_id {
+            return;
+        }
+        tokio::time::sleep(Duration::from_millis(1)).await;
+    }
+    panic!("Queue did not get to run action {running_id:?} in time");
+}
+
 #[derive(Debug, Serialize, Deserialize, Default)]
 pub struct InfiniteTestAction {
     pub i: u64,
@@ -144,6 +188,7 @@ impl Action for InfiniteTestAction {
 #[derive(Debug, Default)]
 pub struct StepResultTestAction {
     pub results: VecDeque<StepResult>,
+    pub retry_policy: RetryPolicy,
 }
 
 #[async_trait]
@@ -153,7 +198,67 @@ impl Action for StepResultTestAction {
         self.results.pop_front().unwrap_or(StepResult::Finished)
     }
 
+    fn retry_policy(&self) -> RetryPolicy { self.retry_policy.clone() }
     fn get_type_name() -> &'static str { "step_result" }
     fn save_to_disk(&self, _ctx: &Context) -> Result<(), String> { Ok(()) }
     fn load_from_disk(_ctx: &Context) -> Result<Self, String> { Ok(Self::default()) }
 }
+
+/// Finishes after `steps_total` steps, reporting its progress after each one.
+#[derive(Debug, Serialize, Deserialize, Default)]
+pub struct ProgressTestAction {
+    pub steps_done_so_far: usize,
+    pub steps_total: usize,
+}
+
+#[async_trait]
+impl Action for ProgressTestAction {
+    async fn step(&mut self, _: &Context, _: &StateHandler) -> StepResult {
+        tokio::time::sleep(Duration::from_millis(1)).await;
+        self.steps_done_so_far += 1;
+        if self.steps_done_so_far >= self.steps_total {
+            StepResult::Finished
+        } else {
+            StepResult::Running(StepProgress::Ratio {
+                steps_done_so_far: self.steps_done_so_far,
+                steps_total: self.steps_total,
+            })
+        }
+    }
+
+    fn get_type_name() -> &'static str { "progress" }
+    fn save_to_disk(&self, ctx: &Context) -> Result<(), String> {
+        serialize_to_json_file(&self, &ctx.get_save_dir().join("data.json"))
+    }
+    fn load_from_disk(ctx: &Context) -> Result<Self, String> {
+        deserialize_from_json_file(&ctx.get_save_dir().join("data.json"))
+    }
+}
+
+/// Finishes after `steps_total` quick steps, but takes `save_millis` to save itself to disk after
+/// each step, so that the queue spends most of the time in between steps.
+#[derive(Debug, Default)]
+pub struct SlowSaveTestAction {
+    pub steps_done_so_far: usize,
+    pub steps_total: usize,
+    pub save_millis: u64,
+}
+
+#[async_trait]
+impl Action for SlowSaveTestAction {
+    async fn step(&mut self, _: &Context, _: &StateHandler) -> StepResult {
+        self.steps_done_so_far += 1;
+        if self.steps_done_so_far >= self.steps_total {
+            StepResult::Finished
+        } else {
+            StepResult::Running(StepProgress::Unknown)
+        }
+    }
+
+    fn get_type_name() -> &'static str { "slow_save" }
+    fn save_to_disk(&self, _ctx: &Context) -> Result<(), String> {
+        std::thread::sleep(Duration::from_millis(self.save_millis));
+        Ok(())
+    }
+    fn load_from_disk(_ctx: &Context) -> Result<Self, String> { Ok(Self::default()) }
+}
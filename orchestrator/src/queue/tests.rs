@@ -1,10 +1,14 @@
 #![cfg(test)]
 
-use std::{assert_matches::assert_matches, fs};
+use std::{assert_matches::assert_matches, fs, path::Path, time::Duration};
+
+use definitions::{GatedLevel, GatingRule, PlantData, Priority, QueueState, Vec3};
+use embedcore::protocol::{communication::CommunicationError, cyber::{Actuator, DeviceError, Event, Message}};
 
 use super::*;
 use super::test_helpers::*;
-use crate::{test_with_queue, with_locked_queue};
+use crate::{action::{command_list::{Command, CommandListAction, CommandListData, Condition}, plow_area::{Area, PlowAreaAction, PlowAreaData}, retry::{Escalation, RetriableError, RetryPolicy}, script::{ScriptAction, ScriptLimits}, water_all::WaterAllAction}, history::{FinalStatus, HistoryFilter}, state::{parameters::{ParametersHandler, UpdateParametersError}, MoveOrdering}, test_with_queue, util::serde::deserialize_from_json_file, with_locked_queue};
+use super::persistence::{save_queue_data, QueueData, QUARANTINE_DIR};
 
 test_with_queue!(
     async fn test_stop(_s: &mut TestState, q: &mut TestQueue) {
@@ -26,10 +30,7 @@ test_with_queue!(
         let saved = fs::read_to_string(q.save_dir.join("queue.json"))
             .expect("Queue did not save itself to disk");
         assert_eq!(
-            format!(
-                r#"{{"action_save_dirs":[{:?}],"id_counter":1}}"#,
-                action_dir
-            ),
+            r#"{"action_save_dirs":["0_infinite"],"id_counter":1}"#,
             saved
         );
         let saved = fs::read_to_string(action_dir.join("data.json"))
@@ -50,10 +51,7 @@ test_with_queue!(
         let saved = fs::read_to_string(q.save_dir.join("queue.json"))
             .expect("Queue did not save itself to disk");
         assert_eq!(
-            format!(
-                r#"{{"action_save_dirs":[{:?}],"id_counter":1}}"#,
-                action_dir
-            ),
+            r#"{"action_save_dirs":["0_infinite"],"id_counter":1}"#,
             saved
         );
         let saved = fs::read_to_string(action_dir.join("data.json"))
@@ -138,6 +136,7 @@ test_with_queue!(
                     StepResult::Finished,
                     StepResult::Finished,
                 ].into(),
+                ..Default::default()
             }
         );
 
@@ -160,6 +159,7 @@ test_with_queue!(
                     StepResult::Running(StepProgress::Unknown),
                     StepResult::Running(StepProgress::Count { steps_done_so_far: 12 }),
                 ].into(),
+                ..Default::default()
             }
         );
 
@@ -188,6 +188,7 @@ test_with_queue!(
                 results: vec![
                     StepResult::RunningError(StateHandlerError::GenericError("whatever".into())),
                 ].into(),
+                ..Default::default()
             }
         );
 
@@ -212,6 +213,7 @@ test_with_queue!(
                     StepResult::Running(StepProgress::Count { steps_done_so_far: 42 }),
                     StepResult::RunningError(StateHandlerError::GenericError("whatever".into())),
                 ].into(),
+                ..Default::default()
             }
         );
 
@@ -238,6 +240,7 @@ test_with_queue!(
                 results: vec![
                     StepResult::FinishedError(StateHandlerError::GenericError("whatever".into())),
                 ].into(),
+                ..Default::default()
             }
         );
 
@@ -250,3 +253,996 @@ test_with_queue!(
     }
 );
 
+
+fn timeout_error() -> StateHandlerError {
+    StateHandlerError::Communication {
+        error: CommunicationError::Timeout,
+        device_name: "peripherals",
+        function_call: "water",
+    }
+}
+
+fn fast_retry_policy(max_attempts: u32, on_exhausted: Escalation) -> RetryPolicy {
+    RetryPolicy {
+        max_attempts,
+        initial_backoff_ms: 1,
+        on_exhausted,
+        ..Default::default()
+    }
+}
+
+test_with_queue!(
+    async fn test_retry_running_error(_s: &mut TestState, q: &mut TestQueue) {
+        q.queue_handler.add_action(
+            StepResultTestAction {
+                results: vec![
+                    StepResult::RunningError(timeout_error()),
+                    StepResult::RunningError(timeout_error()),
+                    StepResult::Running(StepProgress::Count { steps_done_so_far: 42 }),
+                    // not retriable, so the queue is paused immediately
+                    StepResult::RunningError(StateHandlerError::GenericError("whatever".into())),
+                ].into(),
+                retry_policy: fast_retry_policy(3, Escalation::Pause),
+            }
+        );
+
+        wait_for_nth_tick(q, 2, 4, 200).await;
+        with_locked_queue!(q, locked_queue, {
+            assert!(locked_queue.paused);
+            assert_eq!(1, locked_queue.actions.len());
+            assert_matches!(locked_queue.actions[0].progress, StepProgress::Count { steps_done_so_far: 42 });
+            // every failed attempt is recorded
+            assert_matches!(
+                locked_queue.actions[0].errors[..],
+                [
+                    StateHandlerError::Communication { .. },
+                    StateHandlerError::Communication { .. },
+                    StateHandlerError::GenericError(_),
+                ]
+            );
+        });
+    }
+);
+
+test_with_queue!(
+    async fn test_retry_exhausted_pause(_s: &mut TestState, q: &mut TestQueue) {
+        q.queue_handler.add_action(
+            StepResultTestAction {
+                results: (0..3).map(|_| StepResult::RunningError(timeout_error())).collect(),
+                retry_policy: fast_retry_policy(3, Escalation::Pause),
+            }
+        );
+
+        wait_for_nth_tick(q, 2, 3, 200).await;
+        with_locked_queue!(q, locked_queue, {
+            assert!(locked_queue.paused);
+            assert_eq!(1, locked_queue.actions.len());
+            assert_eq!(3, locked_queue.actions[0].errors.len());
+        });
+
+        // the attempts start from scratch after unpausing
+        q.queue_handler.unpause();
+        wait_for_nth_tick(q, 3, 4, 200).await;
+        with_locked_queue!(q, locked_queue, {
+            assert!(!locked_queue.paused);
+            assert_eq!(0, locked_queue.actions.len());
+        });
+    }
+);
+
+test_with_queue!(
+    async fn test_retry_exhausted_finish(_s: &mut TestState, q: &mut TestQueue) {
+        q.queue_handler.add_action(
+            StepResultTestAction {
+                results: (0..2).map(|_| StepResult::RunningError(timeout_error())).collect(),
+                retry_policy: fast_retry_policy(2, Escalation::Finish),
+            }
+        );
+
+        wait_for_nth_tick(q, 2, 2, 200).await;
+        with_locked_queue!(q, locked_queue, {
+            assert!(!locked_queue.paused);
+            assert_eq!(0, locked_queue.actions.len());
+        });
+    }
+);
+
+test_with_queue!(
+    async fn test_history_records_finished_actions(_s: &mut TestState, q: &mut TestQueue) {
+        let finished = q.queue_handler.add_action(CommandListAction::new(vec![]));
+        let failed = q.queue_handler.add_action(StepResultTestAction {
+            results: [StepResult::FinishedError(timeout_error())].into(),
+            ..Default::default()
+        });
+        wait_for_nth_tick(q, 2, 2, 200).await;
+
+        let killed = q.queue_handler.add_action(InfiniteTestAction::default());
+        wait_for_nth_tick(q, 2, 3, 50).await;
+        q.queue_handler.kill_running_action(killed, /* keep_in_queue = */ false);
+        wait_for_nth_tick(q, 3, 3, 50).await;
+
+        let removed = q.queue_handler.add_action(InfiniteTestAction::default());
+        assert!(q.queue_handler.remove_action(removed));
+
+        let history = q.queue_handler.history.query(&HistoryFilter::default());
+        let summary: Vec<(ActionId, &str, FinalStatus)> = history.iter()
+            .map(|entry| (entry.id, entry.type_name.as_str(), entry.status))
+            .collect();
+        assert_eq!(vec![
+            (finished, "command_list", FinalStatus::Finished),
+            (failed, "step_result", FinalStatus::Failed),
+            (killed, "infinite", FinalStatus::Killed),
+            (removed, "infinite", FinalStatus::Removed),
+        ], summary);
+
+        assert!(history[0].started_at.is_some_and(|started_at| started_at <= history[0].finished_at));
+        assert_matches!(history[0].progress, StepProgress::Proportion(1.0));
+        assert_eq!(serde_json::json!([]), history[0].parameters["commands"]);
+        assert_eq!(1, history[1].errors.len());
+        assert_eq!(1, history[2].errors.len());
+        assert_eq!(None, history[3].started_at);
+
+        let filter = HistoryFilter { type_name: Some("infinite".to_string()), ..Default::default() };
+        let ids: Vec<ActionId> = q.queue_handler.history.query(&filter).iter().map(|e| e.id).collect();
+        assert_eq!(vec![killed, removed], ids);
+
+        // the history is saved to disk
+        assert_eq!(4, test_history(&q.save_dir).query(&HistoryFilter::default()).len());
+    }
+);
+
+test_with_queue!(
+    async fn test_changes_notified(_s: &mut TestState, q: &mut TestQueue) {
+        let mut changes = q.queue_handler.subscribe_changes();
+        assert!(!changes.has_changed().unwrap());
+
+        q.queue_handler.pause();
+        assert!(changes.has_changed().unwrap());
+        wait_for_nth_tick(q, 2, 0, 50).await;
+
+        changes.mark_unchanged();
+        q.queue_handler.add_action(InfiniteTestAction::default());
+        assert!(changes.has_changed().unwrap());
+
+        // the queue is paused, so nothing should change on its own
+        wait_for_nth_tick(q, 3, 0, 50).await;
+        changes.mark_unchanged();
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        assert!(!changes.has_changed().unwrap());
+
+        // the running action stepping and reporting progress is a change, too
+        q.queue_handler.unpause();
+        wait_for_nth_tick(q, 3, 1, 50).await;
+        changes.mark_unchanged();
+        tokio::time::timeout(Duration::from_millis(50), changes.changed())
+            .await
+            .expect("No change was notified while the action was running")
+            .unwrap();
+    }
+);
+
+test_with_queue!(
+    async fn test_reorder(_s: &mut TestState, q: &mut TestQueue) {
+        q.queue_handler.pause();
+        let a = q.queue_handler.add_action(InfiniteTestAction::default());
+        let b = q.queue_handler.add_action(InfiniteTestAction::default());
+        let c = q.queue_handler.add_action(InfiniteTestAction::default());
+
+        assert_matches!(
+            q.queue_handler.reorder(vec![a, b, c], vec![c, a]),
+            Err(ReorderError::MismatchedExpectedNew)
+        );
+        assert_matches!(
+            q.queue_handler.reorder(vec![c, b, a], vec![a, c, b]),
+            Err(ReorderError::QueueChanged)
+        );
+        q.queue_handler.reorder(vec![a, b, c], vec![c, a, b]).unwrap();
+
+        let ids: Vec<ActionId> = q.queue_handler.get_state().actions.iter().map(|a| a.id).collect();
+        assert_eq!(vec![c, a, b], ids);
+    }
+);
+
+test_with_queue!(
+    async fn test_remove_action(_s: &mut TestState, q: &mut TestQueue) {
+        q.queue_handler.pause();
+        let a = q.queue_handler.add_action(InfiniteTestAction::default());
+        let b = q.queue_handler.add_action(InfiniteTestAction::default());
+
+        assert!(q.queue_handler.remove_action(b));
+        assert!(!q.queue_handler.remove_action(b));
+        assert!(!q.queue_handler.remove_action(42));
+
+        let ids: Vec<ActionId> = q.queue_handler.get_state().actions.iter().map(|a| a.id).collect();
+        assert_eq!(vec![a], ids);
+    }
+);
+
+test_with_queue!(
+    async fn test_remove_running_action(_s: &mut TestState, q: &mut TestQueue) {
+        let id = q.queue_handler.add_action(InfiniteTestAction::default());
+        wait_for_nth_tick(q, 1, 1, 50).await;
+        assert_eq!(Some(id), q.queue_handler.get_state().running_id);
+
+        // the placeholder is removed immediately, the action only after its step finishes
+        assert!(q.queue_handler.remove_action(id));
+        assert_eq!(0, q.queue_handler.get_state().actions.len());
+        wait_for_nth_tick(q, 2, 1, 50).await;
+        with_locked_queue!(q, locked_queue, {
+            assert!(!locked_queue.paused);
+            assert_eq!(None, locked_queue.running_id);
+            assert_eq!(0, locked_queue.actions.len());
+        });
+    }
+);
+
+test_with_queue!(
+    async fn test_priority_order(s: &mut TestState, q: &mut TestQueue) {
+        q.queue_handler.pause();
+        let a = q.queue_handler.add_action(InfiniteTestAction::default());
+        let b = q.queue_handler.add_action_with_priority(InfiniteTestAction::default(), 5);
+        let c = q.queue_handler.add_action(InfiniteTestAction::default());
+        let d = q.queue_handler.add_action_with_priority(InfiniteTestAction::default(), 5);
+        let e = q.queue_handler.add_action_with_priority(InfiniteTestAction::default(), -1);
+
+        let order = |state: QueueState| -> Vec<(ActionId, Priority)> {
+            state.actions.iter().map(|a| (a.id, a.priority)).collect()
+        };
+        let expected = vec![(b, 5), (d, 5), (a, 0), (c, 0), (e, -1)];
+        assert_eq!(expected, order(q.queue_handler.get_state()));
+
+        // priorities are restored from disk
+        stop_queue_and_wait(q, 50).await;
+        let queue_handler = QueueHandler::new(s.state_handler.clone(), q.save_dir.clone(), test_action_registry(), test_history(&q.save_dir));
+        queue_handler.load_from_disk();
+        assert_eq!(expected, order(queue_handler.get_state()));
+    }
+);
+
+test_with_queue!(
+    async fn test_priority_preemption(_s: &mut TestState, q: &mut TestQueue) {
+        let slow = q.queue_handler.add_action(InfiniteTestAction::default());
+        wait_for_running_id(q, Some(slow), 50).await;
+
+        let urgent = q.queue_handler.add_action_with_priority(
+            ProgressTestAction { steps_done_so_far: 0, steps_total: 3 },
+            1,
+        );
+        // the running action is preempted at the end of its current step...
+        wait_for_running_id(q, Some(urgent), 50).await;
+        with_locked_queue!(q, locked_queue, {
+            let ids: Vec<ActionId> = locked_queue.actions.iter().map(|a| a.get_id()).collect();
+            assert_eq!(vec![urgent, slow], ids);
+            // ...and put back in the queue, keeping its state
+            let preempted = format!("{:?}", locked_queue.actions[1].action);
+            assert!(!preempted.contains("i: 0"), "{preempted}");
+            assert_ne!("None", preempted);
+        });
+
+        // then it resumes once the urgent action has finished
+        wait_for_running_id(q, Some(slow), 100).await;
+        let ids: Vec<ActionId> = q.queue_handler.get_state().actions.iter().map(|a| a.id).collect();
+        assert_eq!(vec![slow], ids);
+
+        // actions with the same priority don't preempt each other
+        let other = q.queue_handler.add_action(InfiniteTestAction::default());
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        // running_id may be briefly None in-between steps
+        wait_for_running_id(q, Some(slow), 50).await;
+        let ids: Vec<ActionId> = q.queue_handler.get_state().actions.iter().map(|a| a.id).collect();
+        assert_eq!(vec![slow, other], ids);
+    }
+);
+
+test_with_queue!(
+    async fn test_get_action_details(_s: &mut TestState, q: &mut TestQueue) {
+        let id = q.queue_handler.add_action(
+            StepResultTestAction {
+                results: vec![
+                    StepResult::Running(StepProgress::Count { steps_done_so_far: 42 }),
+                    StepResult::RunningError(StateHandlerError::GenericError("whatever".into())),
+                ].into(),
+                ..Default::default()
+            }
+        );
+        assert!(q.queue_handler.get_action_details(id + 1).is_none());
+
+        wait_for_nth_tick(q, 2, 2, 50).await;
+        let details = q.queue_handler.get_action_details(id).unwrap();
+        assert_eq!(id, details.info.id);
+        assert_eq!("step_result", details.info.type_name);
+        assert!(!details.info.is_running);
+        assert_matches!(details.info.progress, StepProgress::Count { steps_done_so_far: 42 });
+        assert_eq!(vec![r#"GenericError("whatever")"#.to_string()], details.errors);
+    }
+);
+
+test_with_queue!(
+    async fn test_emergency(_s: &mut TestState, q: &mut TestQueue) {
+        assert_matches!(q.queue_handler.clear_emergency(), Err(ClearEmergencyError::NoEmergency));
+
+        q.queue_handler.emergency();
+        assert_matches!(
+            q.queue_handler.get_state().emergency,
+            EmergencyStatus::WaitingForReset | EmergencyStatus::Resetting
+        );
+        assert_matches!(q.queue_handler.clear_emergency(), Err(ClearEmergencyError::StillResetting));
+
+        wait_for_emergency_status(q, EmergencyStatus::WaitingForClear, 1000).await;
+        with_locked_queue!(q, locked_queue, {
+            // the emergency action is not part of the queue
+            assert_eq!(None, locked_queue.running_id);
+            assert_eq!(0, locked_queue.actions.len());
+        });
+
+        q.queue_handler.clear_emergency().unwrap();
+        assert_eq!(EmergencyStatus::None, q.queue_handler.get_state().emergency);
+        assert_matches!(q.queue_handler.clear_emergency(), Err(ClearEmergencyError::NoEmergency));
+    }
+);
+
+test_with_queue!(
+    async fn test_emergency_during_action(_s: &mut TestState, q: &mut TestQueue) {
+        let id = q.queue_handler.add_action(InfiniteTestAction::default());
+        wait_for_nth_tick(q, 1, 1, 50).await;
+
+        q.queue_handler.emergency();
+        wait_for_emergency_status(q, EmergencyStatus::WaitingForClear, 1000).await;
+        with_locked_queue!(q, locked_queue, {
+            // the interrupted action is put back in the queue, without pausing or errors
+            assert!(!locked_queue.paused);
+            assert_eq!(None, locked_queue.running_id);
+            assert_eq!(1, locked_queue.actions.len());
+            assert!(locked_queue.actions[0].action.is_some());
+            assert!(locked_queue.actions[0].errors.is_empty());
+        });
+
+        // nothing runs until the emergency is cleared
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        assert_eq!(None, q.queue_handler.get_state().running_id);
+
+        q.queue_handler.clear_emergency().unwrap();
+        for _ in 0..50 {
+            if q.queue_handler.get_state().running_id == Some(id) {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(1)).await;
+        }
+        panic!("The interrupted action was not resumed after clearing the emergency");
+    }
+);
+
+test_with_queue!(
+    async fn test_water_all(s: &mut TestState, q: &mut TestQueue) {
+        let plant_data = |x: f32, watering_ms: u64| PlantData {
+            name: "tomato".to_string(),
+            species: "Solanum lycopersicum".to_string(),
+            position: Vec3 { x, y: 0.5, z: -0.3 },
+            watering_duration_ms: watering_ms,
+            planted_date: chrono::NaiveDate::from_ymd_opt(2025, 4, 25).unwrap(),
+        };
+        let plants = s.state_handler.plants();
+        plants.add(plant_data(1.0, 10)).unwrap();
+        let removed = plants.add(plant_data(2.0, 20)).unwrap();
+        plants.add(plant_data(3.0, 30)).unwrap();
+
+        q.queue_handler.add_action(WaterAllAction::new(&s.state_handler));
+        // plants removed after the action is created are skipped
+        plants.remove(removed.id).unwrap();
+        // one step per plant, including the removed one
+        wait_for_nth_tick(q, 2, 3, 1000).await;
+        assert_eq!(0, q.queue_handler.get_state().actions.len());
+
+        let waterings: Vec<Message> = s.slave_bot_data.lock().unwrap().incoming
+            .iter()
+            .filter(|message| matches!(message, Message::Water { .. }))
+            .cloned()
+            .collect();
+        assert_eq!(
+            vec![
+                Message::Water { cooldown_ms: 10 },
+                Message::Water { cooldown_ms: 0 },
+                Message::Water { cooldown_ms: 30 },
+                Message::Water { cooldown_ms: 0 },
+            ],
+            waterings
+        );
+    }
+);
+
+test_with_queue!(
+    async fn test_plow_area(s: &mut TestState, q: &mut TestQueue) {
+        let action = PlowAreaAction::new(PlowAreaData {
+            area: Area::Rectangle { min: (1.0, 0.4), max: (1.6, 0.6) },
+            spacing: 0.25,
+            depth: -0.3,
+        }, &s.state_handler).unwrap();
+        q.queue_handler.add_action(action);
+
+        // a single line, split into two pieces
+        wait_for_nth_tick(q, 2, 3, 1000).await;
+        assert_eq!(0, q.queue_handler.get_state().actions.len());
+
+        let cooldown_ms = s.state_handler.get_state().parameters.move_timeout_ms;
+        let plows: Vec<Message> = s.slave_bot_data.lock().unwrap().incoming
+            .iter()
+            .filter(|message| matches!(message, Message::Plow { .. }))
+            .cloned()
+            .collect();
+        assert_eq!(
+            vec![
+                Message::Plow { cooldown_ms: 0 },
+                Message::Plow { cooldown_ms },
+                Message::Plow { cooldown_ms },
+                Message::Plow { cooldown_ms: 0 },
+            ],
+            plows
+        );
+    }
+);
+
+test_with_queue!(
+    async fn test_command_list_control_flow(s: &mut TestState, q: &mut TestQueue) {
+        let lights = |ms: u64| Command::LightsCooldown(Some(Duration::from_millis(ms)));
+        let id = q.queue_handler.add_action(CommandListAction::new(vec![
+            Command::Repeat { times: 3, body: vec![lights(1)] },
+            // the test peripherals report a water level of about 39%
+            Command::If { condition: Condition::WaterLevelBelow(0.5), then: vec![lights(2)], r#else: vec![lights(3)] },
+            Command::WaitUntil { condition: Condition::WaterLevelAbove(0.1), timeout: None },
+            // the test peripherals never turn the LED on
+            Command::WaitUntil { condition: Condition::LedOn, timeout: None },
+        ]));
+
+        // one step per command, also when it is preceded by control flow
+        wait_for_nth_tick(q, 1, 6, 1000).await;
+        assert_matches!(
+            q.queue_handler.get_action_details(id).unwrap().info.progress,
+            StepProgress::Ratio { steps_done_so_far: 5, steps_total: 6 }
+        );
+
+        let lights: Vec<Message> = s.slave_bot_data.lock().unwrap().incoming
+            .iter()
+            .filter(|message| matches!(message, Message::Lights { .. }))
+            .cloned()
+            .collect();
+        assert_eq!(
+            vec![
+                Message::Lights { cooldown_ms: 1 },
+                Message::Lights { cooldown_ms: 1 },
+                Message::Lights { cooldown_ms: 1 },
+                Message::Lights { cooldown_ms: 2 },
+            ],
+            lights
+        );
+    }
+);
+
+test_with_queue!(
+    async fn test_command_list_condition_read_error(s: &mut TestState, q: &mut TestQueue) {
+        let lights = |ms: u64| Command::LightsCooldown(Some(Duration::from_millis(ms)));
+        s.slave_bot_data.lock().unwrap().peripherals_error = Some(DeviceError::DriverFault);
+        let id = q.queue_handler.add_action(CommandListAction::new(vec![
+            // the test peripherals report a water level of about 39%
+            Command::If { condition: Condition::WaterLevelBelow(0.5), then: vec![lights(2)], r#else: vec![lights(3)] },
+        ]));
+
+        // the condition can't be evaluated, so the queue is paused instead of taking a branch
+        wait_for_nth_tick(q, 2, 1, 1000).await;
+        with_locked_queue!(q, locked_queue, {
+            assert!(locked_queue.paused);
+            assert_eq!(1, locked_queue.actions[0].errors.len());
+        });
+
+        // the condition is evaluated again once the peripherals can be read
+        s.slave_bot_data.lock().unwrap().peripherals_error = None;
+        q.queue_handler.unpause();
+        for _ in 0..1000 {
+            if q.queue_handler.get_state().actions.is_empty() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(1)).await;
+        }
+        assert!(q.queue_handler.get_action_details(id).is_none());
+        let lights: Vec<Message> = s.slave_bot_data.lock().unwrap().incoming
+            .iter()
+            .filter(|message| matches!(message, Message::Lights { .. }))
+            .cloned()
+            .collect();
+        assert_eq!(vec![Message::Lights { cooldown_ms: 2 }], lights);
+    }
+);
+
+test_with_queue!(
+    async fn test_command_list_wait_until_timeout(_s: &mut TestState, q: &mut TestQueue) {
+        q.queue_handler.add_action(CommandListAction::new(vec![
+            Command::WaitUntil { condition: Condition::WaterLevelAbove(0.9), timeout: Some(Duration::from_millis(10)) },
+        ]));
+
+        wait_for_nth_tick(q, 2, 1, 1000).await;
+        with_locked_queue!(q, locked_queue, {
+            assert!(locked_queue.paused);
+            assert_eq!(1, locked_queue.actions.len());
+            assert_eq!(1, locked_queue.actions[0].errors.len());
+        });
+    }
+);
+
+test_with_queue!(
+    async fn test_script(s: &mut TestState, q: &mut TestQueue) {
+        let id = q.queue_handler.add_action(ScriptAction::new(r#"
+            // the test peripherals report a water level of about 39%
+            let state = get_state();
+            if state.water_level.proportion < 0.5 { lights(0.002) } else { lights(0.003) }
+            lights(0.001);
+        "#.to_string(), ScriptLimits::default()).unwrap());
+
+        // one step per binding call, plus one to finish
+        wait_for_nth_tick(q, 2, 4, 1000).await;
+        let history = q.queue_handler.history.query(&HistoryFilter::default());
+        assert_eq!(vec![(id, FinalStatus::Finished)], history.iter().map(|entry| (entry.id, entry.status)).collect::<Vec<_>>());
+
+        let lights: Vec<Message> = s.slave_bot_data.lock().unwrap().incoming
+            .iter()
+            .filter(|message| matches!(message, Message::Lights { .. }))
+            .cloned()
+            .collect();
+        assert_eq!(
+            vec![
+                Message::Lights { cooldown_ms: 2 },
+                Message::Lights { cooldown_ms: 0 },
+                Message::Lights { cooldown_ms: 1 },
+                Message::Lights { cooldown_ms: 0 },
+            ],
+            lights
+        );
+    }
+);
+
+test_with_queue!(
+    async fn test_add_action_from_json(_s: &mut TestState, q: &mut TestQueue) {
+        assert_matches!(
+            q.queue_handler.add_action_from_json("unknown", serde_json::json!(null), 0),
+            Err(AddActionError::UnknownType(_))
+        );
+        assert_matches!(
+            q.queue_handler.add_action_from_json("step_result", serde_json::json!(null), 0),
+            Err(AddActionError::NotConstructible(_))
+        );
+        assert_matches!(
+            q.queue_handler.add_action_from_json("infinite", serde_json::json!({"i": "a"}), 0),
+            Err(AddActionError::Invalid(_))
+        );
+        assert_matches!(
+            q.queue_handler.validate_action_json("infinite", serde_json::json!({"i": "a"})),
+            Err(AddActionError::Invalid(_))
+        );
+        assert_matches!(q.queue_handler.validate_action_json("infinite", serde_json::json!({"i": 5})), Ok(()));
+        assert!(q.queue_handler.get_state().actions.is_empty());
+
+        let id = q.queue_handler.add_action_from_json("infinite", serde_json::json!({"i": 5}), 0).unwrap();
+        wait_for_nth_tick(q, 1, 1, 50).await;
+        stop_queue_and_wait(q, 50).await;
+
+        // the action is restored from disk using the registry
+        let action_dir = q.save_dir.join(format!("{id}_infinite"));
+        let action = ActionWrapper::load_from_disk(&action_dir, &q.queue_handler.action_registry).unwrap();
+        assert_eq!(id, action.get_id());
+        assert_eq!("infinite", action.get_type_name());
+        assert_eq!("InfiniteTestAction { i: 7 }", format!("{:?}", action.action.unwrap()));
+        assert_matches!(ActionWrapper::load_from_disk(&action_dir, &ActionRegistry::default()), Err(_));
+    }
+);
+
+fn read_queue_data(save_dir: &Path) -> QueueData {
+    deserialize_from_json_file(&save_dir.join("queue.json")).expect("Could not read queue.json")
+}
+
+test_with_queue!(
+    async fn test_persist_on_every_change(_s: &mut TestState, q: &mut TestQueue) {
+        q.queue_handler.pause();
+        let a = q.queue_handler.add_action(InfiniteTestAction::default());
+        let b = q.queue_handler.add_action(InfiniteTestAction { i: 5 });
+        let name_a = PathBuf::from(format!("{a}_infinite"));
+        let name_b = PathBuf::from(format!("{b}_infinite"));
+        let dir_a = q.save_dir.join(&name_a);
+        let dir_b = q.save_dir.join(&name_b);
+        // saved without waiting for the queue to stop
+        assert_eq!(vec![name_a.clone(), name_b.clone()], read_queue_data(&q.save_dir).action_save_dirs);
+        assert_eq!("{\"i\":5}", fs::read_to_string(dir_b.join("data.json")).unwrap());
+
+        q.queue_handler.reorder(vec![a, b], vec![b, a]).unwrap();
+        assert_eq!(vec![name_b.clone(), name_a.clone()], read_queue_data(&q.save_dir).action_save_dirs);
+
+        assert!(q.queue_handler.remove_action(b));
+        assert_eq!(vec![name_a.clone()], read_queue_data(&q.save_dir).action_save_dirs);
+        assert!(!dir_b.exists());
+
+        q.queue_handler.clear();
+        assert_eq!(Vec::<PathBuf>::new(), read_queue_data(&q.save_dir).action_save_dirs);
+        assert!(!dir_a.exists());
+    }
+);
+
+test_with_queue!(
+    async fn test_progress_journal(_s: &mut TestState, q: &mut TestQueue) {
+        let id = q.queue_handler.add_action(ProgressTestAction { steps_done_so_far: 0, steps_total: 1000 });
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        q.queue_handler.pause();
+        // wait for the queue to release the action after pausing
+        for _ in 0..100 {
+            if q.queue_handler.test_stats.lock().unwrap().wait_counter == 2 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(1)).await;
+        }
+        assert_eq!(2, q.queue_handler.test_stats.lock().unwrap().wait_counter);
+
+        // the progress of every step was appended to the journal
+        let journal = fs::read_to_string(q.save_dir.join("journal.jsonl")).unwrap();
+        let steps = journal.lines().count();
+        assert!(steps > 1, "{journal}");
+        assert!(journal.lines().last().unwrap().contains(&format!("\"steps_done_so_far\":{steps},")), "{journal}");
+        // the action itself was saved after its last step
+        let dir = q.save_dir.join(format!("{id}_progress"));
+        assert_eq!(
+            format!("{{\"steps_done_so_far\":{steps},\"steps_total\":1000}}"),
+            fs::read_to_string(dir.join("data.json")).unwrap()
+        );
+
+        // the journal is compacted into progress.json when the queue stops
+        stop_queue_and_wait(q, 50).await;
+        assert!(!q.save_dir.join("journal.jsonl").exists());
+        let progress: StepProgress = deserialize_from_json_file(&dir.join("progress.json")).unwrap();
+        assert_matches!(progress, StepProgress::Ratio { steps_done_so_far, steps_total: 1000 } if steps_done_so_far == steps);
+    }
+);
+
+test_with_queue!(
+    async fn test_recover_corrupt_data(s: &mut TestState, q: &mut TestQueue) {
+        q.queue_handler.pause();
+        let ids: Vec<ActionId> = (0..4)
+            .map(|_| q.queue_handler.add_action(ProgressTestAction { steps_done_so_far: 0, steps_total: 10 }))
+            .collect();
+        stop_queue_and_wait(q, 50).await;
+        let name = |id: ActionId| PathBuf::from(format!("{id}_progress"));
+        let dir = |id: ActionId| q.save_dir.join(name(id));
+
+        // a half-written action, an unknown action type, a half-written journal line and a
+        // directory not in the queue
+        fs::write(dir(ids[1]).join("data.json"), "{\"steps_done_so").unwrap();
+        fs::rename(dir(ids[2]), q.save_dir.join(format!("{}_unknown", ids[2]))).unwrap();
+        let mut data = read_queue_data(&q.save_dir);
+        data.action_save_dirs[2] = PathBuf::from(format!("{}_unknown", ids[2]));
+        save_queue_data(&q.save_dir, &data).unwrap();
+        fs::write(
+            q.save_dir.join("journal.jsonl"),
+            format!("{{\"id\":{},\"progress\":{{\"Count\":{{\"steps_done_so_far\":3}}}}}}\n{{\"id\":{},\"pro", ids[3], ids[0]),
+        ).unwrap();
+        fs::create_dir(q.save_dir.join("41_progress")).unwrap();
+
+        let queue_handler = QueueHandler::new(s.state_handler.clone(), q.save_dir.clone(), test_action_registry(), test_history(&q.save_dir));
+        queue_handler.load_from_disk();
+        let state = queue_handler.get_state();
+        assert_eq!(vec![ids[0], ids[3]], state.actions.iter().map(|a| a.id).collect::<Vec<_>>());
+        assert_matches!(state.actions[0].progress, StepProgress::Unknown);
+        assert_matches!(state.actions[1].progress, StepProgress::Count { steps_done_so_far: 3 });
+
+        let quarantine = q.save_dir.join(QUARANTINE_DIR);
+        assert!(quarantine.join(format!("{}_progress", ids[1])).join("data.json").exists());
+        assert!(quarantine.join(format!("{}_unknown", ids[2])).join("data.json").exists());
+        assert!(quarantine.join("41_progress").exists());
+        assert!(!q.save_dir.join("41_progress").exists());
+        assert!(!q.save_dir.join("journal.jsonl").exists());
+        assert_eq!(vec![name(ids[0]), name(ids[3])], read_queue_data(&q.save_dir).action_save_dirs);
+        // never reuse the ids of deleted directories
+        assert_eq!(42, queue_handler.add_action(InfiniteTestAction::default()));
+
+        // if queue.json itself is corrupt, the queue is rebuilt from the action directories
+        fs::write(q.save_dir.join("queue.json"), "[").unwrap();
+        let queue_handler = QueueHandler::new(s.state_handler.clone(), q.save_dir.clone(), test_action_registry(), test_history(&q.save_dir));
+        queue_handler.load_from_disk();
+        let ids_after: Vec<ActionId> = queue_handler.get_state().actions.iter().map(|a| a.id).collect();
+        assert_eq!(vec![ids[0], ids[3], 42], ids_after);
+        assert_eq!("[", fs::read_to_string(quarantine.join("queue.json")).unwrap());
+    }
+);
+
+test_with_queue!(
+    async fn test_journal_compacted_when_action_exits(_s: &mut TestState, q: &mut TestQueue) {
+        q.queue_handler.add_action(ProgressTestAction { steps_done_so_far: 0, steps_total: 3 });
+        // does not report any progress, so it never writes to the journal
+        let other = q.queue_handler.add_action(InfiniteTestAction::default());
+        wait_for_running_id(q, Some(other), 50).await;
+        // the first action appended its progress to the journal, which was then compacted when it
+        // finished
+        assert!(!q.save_dir.join("journal.jsonl").exists());
+    }
+);
+
+test_with_queue!(
+    async fn test_load_moved_save_dir(s: &mut TestState, q: &mut TestQueue) {
+        q.queue_handler.pause();
+        let ids: Vec<ActionId> = (0..2)
+            .map(|i| q.queue_handler.add_action(InfiniteTestAction { i }))
+            .collect();
+        stop_queue_and_wait(q, 50).await;
+
+        // the whole data directory is moved somewhere else, and queue.json is in the format of
+        // older versions, which stored absolute paths
+        let mut data = read_queue_data(&q.save_dir);
+        data.action_save_dirs[1] = q.save_dir.join(&data.action_save_dirs[1]);
+        save_queue_data(&q.save_dir, &data).unwrap();
+        let moved_dir = tempdir::TempDir::new("cyberorto_test").unwrap().into_path().join("moved");
+        fs::rename(&q.save_dir, &moved_dir).unwrap();
+
+        let queue_handler = QueueHandler::new(s.state_handler.clone(), moved_dir.clone(), test_action_registry(), test_history(&moved_dir));
+        queue_handler.load_from_disk();
+        let state = queue_handler.get_state();
+        assert_eq!(ids, state.actions.iter().map(|a| a.id).collect::<Vec<_>>());
+        assert!(!moved_dir.join(QUARANTINE_DIR).exists());
+        assert_eq!(
+            vec![PathBuf::from(format!("{}_infinite", ids[0])), PathBuf::from(format!("{}_infinite", ids[1]))],
+            read_queue_data(&moved_dir).action_save_dirs
+        );
+    }
+);
+
+const CRASH_TEST_DIR_VAR: &str = "CYBERORTO_CRASH_TEST_DIR";
+
+/// Not a real test: it is run in a child process by [test_killed_at_random_points], and keeps
+/// changing the queue in the directory passed through [CRASH_TEST_DIR_VAR] until it is killed.
+#[tokio::test]
+#[ignore]
+async fn crash_test_child() {
+    let Some(save_dir) = std::env::var_os(CRASH_TEST_DIR_VAR) else {
+        return;
+    };
+    let save_dir = PathBuf::from(save_dir);
+    let test_state = crate::state::tests::get_test_state();
+    let queue_handler = QueueHandler::new(test_state.state_handler.clone(), save_dir.clone(), test_action_registry(), test_history(&save_dir));
+    let queue_handler_clone = queue_handler.clone();
+    std::thread::spawn(move || queue_handler_clone.run());
+
+    loop {
+        let ids: Vec<ActionId> = queue_handler.get_state().actions.iter().map(|a| a.id).collect();
+        match rand::random_range(0..10) {
+            0..6 => {
+                let steps_total = rand::random_range(1..20);
+                queue_handler.add_action(ProgressTestAction { steps_done_so_far: 0, steps_total });
+            }
+            6..8 if !ids.is_empty() => {
+                queue_handler.remove_action(ids[rand::random_range(0..ids.len())]);
+            }
+            8 => {
+                let mut new = ids.clone();
+                new.reverse();
+                let _ = queue_handler.reorder(ids, new);
+            }
+            _ => queue_handler.clear(),
+        }
+        tokio::time::sleep(Duration::from_millis(rand::random_range(0..5))).await;
+    }
+}
+
+test_with_queue!(
+    async fn test_killed_at_random_points(s: &mut TestState, _q: &mut TestQueue) {
+        for _ in 0..10 {
+            let save_dir = tempdir::TempDir::new("cyberorto_test").unwrap().into_path();
+            let mut child = std::process::Command::new(std::env::current_exe().unwrap())
+                .args(["queue::tests::crash_test_child", "--exact", "--ignored", "--nocapture"])
+                .env(CRASH_TEST_DIR_VAR, &save_dir)
+                .stdout(std::process::Stdio::null())
+                .stderr(std::process::Stdio::null())
+                .spawn()
+                .expect("Could not start child process");
+            tokio::time::sleep(Duration::from_millis(rand::random_range(100..500))).await;
+            child.kill().unwrap();
+            child.wait().unwrap();
+
+            let queue_handler = QueueHandler::new(s.state_handler.clone(), save_dir.clone(), test_action_registry(), test_history(&save_dir));
+            queue_handler.load_from_disk();
+
+            // being killed must never leave corrupt data behind, at most the directory of an
+            // action that was being added or removed is quarantined
+            let state = queue_handler.get_state();
+            let quarantine = save_dir.join(QUARANTINE_DIR);
+            if quarantine.exists() {
+                for entry in fs::read_dir(&quarantine).unwrap() {
+                    let name = entry.unwrap().file_name().into_string().unwrap();
+                    let (id, _) = name.split_once('_').expect("Only action directories can be quarantined");
+                    let id: ActionId = id.parse().unwrap();
+                    assert!(state.actions.iter().all(|a| a.id != id), "{name}");
+                }
+            }
+            let mut dirs: Vec<PathBuf> = fs::read_dir(&save_dir).unwrap()
+                .map(|entry| entry.unwrap().path())
+                .filter(|path| path.is_dir() && *path != quarantine)
+                .collect();
+            dirs.sort();
+            let action_dirs: Vec<PathBuf> = state.actions.iter().map(|a| a.save_dir.clone()).collect();
+            let action_names: Vec<PathBuf> = action_dirs.iter().map(|dir| dir.file_name().unwrap().into()).collect();
+            assert_eq!(action_names, read_queue_data(&save_dir).action_save_dirs);
+            let mut action_dirs_sorted = action_dirs.clone();
+            action_dirs_sorted.sort();
+            assert_eq!(action_dirs_sorted, dirs);
+        }
+    }
+);
+
+test_with_queue!(
+    async fn test_retry_after_stall(s: &mut TestState, q: &mut TestQueue) {
+        s.motor_bots_data[0].lock().unwrap().motor_error = Some(DeviceError::Stall { steps: None });
+        let id = q.queue_handler.add_action(CommandListAction::from_data(CommandListData {
+            commands: vec![Command::Move { x: 1.0, y: 0.5, z: -0.3, ordering: MoveOrdering::Simultaneous }],
+            retry_policy: RetryPolicy {
+                initial_backoff_ms: 10,
+                retry_on: vec![RetriableError::Stall],
+                ..Default::default()
+            },
+        }));
+        for _ in 0..1000 {
+            if q.queue_handler.get_state().actions.is_empty() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(1)).await;
+        }
+
+        // the motors were reset after the stall, and the move succeeded when retried
+        assert!(s.motor_bots_data[0].lock().unwrap().incoming.contains(&Message::ResetMotor));
+        let history = q.queue_handler.history.query(&HistoryFilter::default());
+        assert_eq!(vec![(id, FinalStatus::Finished)], history.iter().map(|e| (e.id, e.status)).collect::<Vec<_>>());
+        assert_eq!(1, history[0].errors.len());
+    }
+);
+
+test_with_queue!(
+    async fn test_emergency_on_device_event(s: &mut TestState, q: &mut TestQueue) {
+        let queue_handler = q.queue_handler.clone();
+        let device_events_task = tokio::spawn(async move {
+            queue_handler.run_device_events(Duration::from_millis(1)).await
+        });
+
+        // not in the default Parameters::emergency_events
+        s.slave_bot_data.lock().unwrap().pending_events.push_back(Event::CooldownExpired(Actuator::Pump));
+        for _ in 0..1000 {
+            if !s.state_handler.get_state().events.is_empty() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(1)).await;
+        }
+        assert_eq!(1, s.state_handler.get_state().events.len());
+        assert_eq!(EmergencyStatus::None, q.queue_handler.get_state().emergency);
+
+        s.motor_bots_data[2].lock().unwrap().pending_events.push_back(Event::LimitSwitchHit);
+        wait_for_emergency_status(q, EmergencyStatus::WaitingForClear, 1000).await;
+        device_events_task.abort();
+    }
+);
+
+fn test_gating_rules() -> Vec<GatingRule> {
+    vec![
+        GatingRule { level: GatedLevel::Water, below: 0.05, resume_above: 0.15, action_types: vec!["infinite".to_string()] },
+        GatingRule { level: GatedLevel::Battery, below: 0.1, resume_above: 0.3, action_types: vec![] },
+    ]
+}
+
+#[test]
+fn test_evaluate_gates() {
+    let rules = test_gating_rules();
+    let mut state = RobotState::default();
+    state.battery_level.proportion = 0.8;
+    state.water_level.proportion = 0.5;
+    assert_eq!(Vec::<Gate>::new(), evaluate_gates(&rules, Some(&state), &[]));
+
+    state.water_level.proportion = 0.01;
+    let gates = evaluate_gates(&rules, Some(&state), &[]);
+    assert_eq!(1, gates.len());
+    assert_eq!(rules[0], gates[0].rule);
+    assert_eq!("Water level 1% is below 5%", gates[0].reason);
+
+    // hysteresis: the rule keeps applying until the level rises above resume_above...
+    state.water_level.proportion = 0.1;
+    let gates = evaluate_gates(&rules, Some(&state), &gates);
+    assert_eq!(1, gates.len());
+    assert_eq!("Water level 10% has not yet risen above 15%", gates[0].reason);
+    // ...but only if it was already applying
+    assert_eq!(Vec::<Gate>::new(), evaluate_gates(&rules, Some(&state), &[]));
+
+    state.water_level.proportion = 0.2;
+    state.battery_level.proportion = 0.05;
+    let gates = evaluate_gates(&rules, Some(&state), &gates);
+    assert_eq!(1, gates.len());
+    assert_eq!(rules[1], gates[0].rule);
+    assert!(pauses_queue(&gates));
+    assert!(!skips_type(&gates, "infinite"));
+
+    // unknown levels neither lift the gates that apply, nor start new ones
+    assert_eq!(gates, evaluate_gates(&rules, None, &gates));
+    assert_eq!(Vec::<Gate>::new(), evaluate_gates(&rules, None, &[]));
+
+    // a failed poll in between does not make the hysteresis keep a rule that never applied
+    state.battery_level.proportion = 0.2;
+    let gates = evaluate_gates(&rules, None, &[]);
+    assert_eq!(Vec::<Gate>::new(), evaluate_gates(&rules, Some(&state), &gates));
+}
+
+test_with_queue!(
+    async fn test_gating(s: &mut TestState, q: &mut TestQueue) {
+        let mut state = s.state_handler.get_state();
+        state.parameters.gating_rules = test_gating_rules();
+        state.battery_level.proportion = 0.8;
+        state.water_level.proportion = 0.01;
+        q.queue_handler.update_gates(&state, true);
+
+        // the infinite action is skipped, but the other one can run
+        let skipped = q.queue_handler.add_action(InfiniteTestAction::default());
+        let other = q.queue_handler.add_action(ProgressTestAction { steps_done_so_far: 0, steps_total: 3 });
+        wait_for_running_id(q, Some(other), 50).await;
+        for _ in 0..100 {
+            if q.queue_handler.get_state().actions.len() == 1 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(1)).await;
+        }
+        let queue_state = q.queue_handler.get_state();
+        assert_eq!(vec![skipped], queue_state.actions.iter().map(|a| a.id).collect::<Vec<_>>());
+        assert_eq!(1, queue_state.gates.len());
+        assert!(!queue_state.paused);
+        with_locked_queue!(q, locked_queue, {
+            // make sure the skipped action has not started executing
+            assert!(locked_queue.actions[0].action.is_some());
+        });
+
+        // the water recovered, but now the battery is low, so the whole queue is gated
+        state.water_level.proportion = 0.5;
+        state.battery_level.proportion = 0.05;
+        q.queue_handler.update_gates(&state, true);
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        let queue_state = q.queue_handler.get_state();
+        assert_eq!(vec![GatedLevel::Battery], queue_state.gates.iter().map(|g| g.rule.level).collect::<Vec<_>>());
+        assert_eq!(None, queue_state.running_id);
+
+        // the battery recovered, so the queue resumes by itself
+        state.battery_level.proportion = 0.5;
+        q.queue_handler.update_gates(&state, true);
+        wait_for_running_id(q, Some(skipped), 50).await;
+        assert!(q.queue_handler.get_state().gates.is_empty());
+
+        // the devices could not be read, but unknown levels do not gate the queue...
+        state.battery_level.proportion = 0.05;
+        q.queue_handler.update_gates(&state, false);
+        assert!(q.queue_handler.get_state().gates.is_empty());
+        // ...nor lift the gates that already apply
+        q.queue_handler.update_gates(&state, true);
+        state.battery_level.proportion = 0.5;
+        q.queue_handler.update_gates(&state, false);
+        assert_eq!(1, q.queue_handler.get_state().gates.len());
+    }
+);
+
+test_with_queue!(
+    async fn test_update_parameters_between_steps(s: &mut TestState, q: &mut TestQueue) {
+        let parameters_handler = ParametersHandler::new(q.save_dir.clone());
+        let patch = serde_json::json!({ "arm_length": 2.0 });
+        q.queue_handler.add_action(SlowSaveTestAction { steps_done_so_far: 0, steps_total: 3, save_millis: 30 });
+
+        // the first step is instantaneous, so the action is now being saved before the second step
+        wait_for_nth_tick(q, 1, 1, 50).await;
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        assert_eq!(None, q.queue_handler.get_state().running_id);
+        assert_matches!(
+            parameters_handler.update(&s.state_handler, &q.queue_handler, patch.clone()),
+            Err(UpdateParametersError::QueueNotIdle)
+        );
+        assert_ne!(2.0, s.state_handler.get_state().parameters.arm_length);
+
+        for _ in 0..200 {
+            if q.queue_handler.get_state().actions.is_empty() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(1)).await;
+        }
+        assert_matches!(parameters_handler.update(&s.state_handler, &q.queue_handler, patch), Ok(_));
+        assert_eq!(2.0, s.state_handler.get_state().parameters.arm_length);
+    }
+);
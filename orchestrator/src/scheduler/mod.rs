@@ -0,0 +1,372 @@
+use std::{fs::create_dir_all, path::{Path, PathBuf}, sync::{Arc, Mutex}, time::Duration};
+
+use chrono::{Datelike, Local, NaiveDateTime, NaiveTime, TimeDelta, Weekday};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tokio::time::MissedTickBehavior;
+
+use crate::{
+    queue::QueueHandler,
+    util::serde::{deserialize_from_json_file, serialize_to_json_file_pretty},
+};
+
+const SCHEDULES_FILE: &str = "schedules.json";
+const SCHEDULES_BACKUP_FILE: &str = "schedules.json.bak";
+/// How often [SchedulerHandler::run] checks whether some schedule is due.
+const SCHEDULER_PERIOD: Duration = Duration::from_secs(1);
+/// How late a run can be noticed before it is considered missed (e.g. because the orchestrator
+/// was down), in which case [ScheduleData::catch_up] decides what to do.
+const MISSED_RUN_TOLERANCE: TimeDelta = TimeDelta::minutes(1);
+
+pub type ScheduleId = u32;
+
+/// When a schedule should run. All times are naive dates and times in the local time zone of the
+/// orchestrator, so "every day at 7:00" stays at 7:00 even when daylight saving time changes.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum Recurrence {
+    /// Runs only once, at the provided date and time.
+    Once(NaiveDateTime),
+    /// Runs every day at `time`, but only on `weekdays` if the list is not empty.
+    Daily {
+        time: NaiveTime,
+        #[serde(default)]
+        weekdays: Vec<Weekday>,
+    },
+    /// Runs at `start`, and then again every `every_secs` seconds.
+    Every {
+        start: NaiveDateTime,
+        every_secs: u64,
+    },
+}
+
+impl Recurrence {
+    /// The first time this recurrence should run strictly after `after`, if any.
+    fn next_after(&self, after: NaiveDateTime) -> Option<NaiveDateTime> {
+        match self {
+            Recurrence::Once(at) => (*at > after).then_some(*at),
+            Recurrence::Daily { time, weekdays } => {
+                // a week and a day are enough to find any weekday, even if today's run has passed
+                after.date().iter_days().take(8)
+                    .map(|date| date.and_time(*time))
+                    .find(|at| *at > after && (weekdays.is_empty() || weekdays.contains(&at.weekday())))
+            }
+            Recurrence::Every { start, every_secs } => {
+                if *start > after {
+                    return Some(*start);
+                }
+                let every = TimeDelta::try_seconds(i64::try_from(*every_secs).ok()?)?;
+                let elapsed = (after - *start).num_milliseconds();
+                let runs_so_far = elapsed / every.num_milliseconds().max(1) + 1;
+                start.checked_add_signed(every * i32::try_from(runs_so_far).ok()?)
+            }
+        }
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        if let Recurrence::Every { every_secs: 0, .. } = self {
+            return Err("A schedule can't run more often than every second".to_string());
+        }
+        Ok(())
+    }
+}
+
+/// What to do with runs that were missed because the orchestrator was not running at that time.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub enum CatchUp {
+    /// Missed runs are just skipped, e.g. for turning on lights at night.
+    #[default]
+    Skip,
+    /// If at least one run was missed, the action is added to the queue once as soon as possible,
+    /// e.g. for watering plants.
+    RunOnce,
+}
+
+/// The action to add to the queue when a schedule runs. It is created only when the schedule runs,
+/// through the [ActionRegistry](crate::action::registry::ActionRegistry), exactly like
+/// `POST /queue/add_action/<type_name>` with `data` as the body.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ScheduledAction {
+    pub type_name: String,
+    #[serde(default)]
+    pub data: Value,
+}
+
+impl ScheduledAction {
+    fn add_to_queue(&self, queue_handler: &QueueHandler) {
+        if let Err(e) = queue_handler.add_action_from_json(&self.type_name, self.data.clone(), 0) {
+            log::error!("Could not add scheduled action of type {:?} to the queue: {e:?}", self.type_name);
+        }
+    }
+}
+
+/// The parts of a [Schedule] that can be chosen by the user.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ScheduleData {
+    /// Just to recognize this schedule, e.g. "water every morning".
+    pub name: String,
+    pub recurrence: Recurrence,
+    pub action: ScheduledAction,
+    #[serde(default)]
+    pub catch_up: CatchUp,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Schedule {
+    pub id: ScheduleId,
+    #[serde(flatten)]
+    pub data: ScheduleData,
+    /// Disabled schedules never run, and don't catch up on runs missed while disabled.
+    pub enabled: bool,
+    /// When the schedule will run next, or `None` if it is disabled or will never run again.
+    pub next_run: Option<NaiveDateTime>,
+    /// When the action was last added to the queue, if ever.
+    pub last_run: Option<NaiveDateTime>,
+}
+
+#[derive(Debug)]
+pub enum ScheduleError {
+    /// The schedule data is not valid.
+    Invalid(String),
+    /// The schedules could not be saved, so nothing was changed.
+    Save(String),
+}
+
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+struct SchedulesData {
+    id_counter: ScheduleId,
+    schedules: Vec<Schedule>,
+}
+
+/// Keeps track of scheduled actions and adds them to the queue at the right time (see
+/// [SchedulerHandler::run]). Schedules are persisted in `${data_dir}/schedules.json` after every
+/// change. Can be cloned cheaply, and all clones refer to the same schedules.
+#[derive(Debug, Clone)]
+pub struct SchedulerHandler {
+    data: Arc<Mutex<SchedulesData>>,
+    data_dir: PathBuf,
+}
+
+impl SchedulerHandler {
+    /// Loads the schedules from `${data_dir}/schedules.json`, or starts with no schedules if the
+    /// file does not exist. If the file can't be read, it is backupped before starting from scratch.
+    pub fn load_from_disk(data_dir: &Path) -> SchedulerHandler {
+        let schedules_file = &data_dir.join(SCHEDULES_FILE);
+        let data = if schedules_file.exists() {
+            match deserialize_from_json_file(schedules_file) {
+                Ok(data) => data,
+                Err(e) => {
+                    let schedules_backup_file = &data_dir.join(SCHEDULES_BACKUP_FILE);
+                    log::error!("Could not read schedules from {schedules_file:?}, starting with no schedules: {e}");
+                    log::error!("Backupping {schedules_file:?} file to {schedules_backup_file:?}...");
+                    if let Err(e) = std::fs::rename(schedules_file, schedules_backup_file) {
+                        log::error!("Could not create backup {schedules_backup_file:?}: {e:?}");
+                    }
+                    SchedulesData::default()
+                }
+            }
+        } else {
+            SchedulesData::default()
+        };
+
+        SchedulerHandler {
+            data: Arc::new(Mutex::new(data)),
+            data_dir: data_dir.to_path_buf(),
+        }
+    }
+
+    fn save_to_disk(&self, data: &SchedulesData) -> Result<(), String> {
+        create_dir_all(&self.data_dir)
+            .map_err(|e| format!("Could not create data directory {:?}: {e:?}", self.data_dir))?;
+        let schedules_file = &self.data_dir.join(SCHEDULES_FILE);
+        serialize_to_json_file_pretty(data, schedules_file)
+            .map_err(|e| format!("Could not serialize and save schedules to {schedules_file:?}: {e}"))
+    }
+
+    /// Applies `f` to a copy of the data, and replaces the data with the copy only if the copy
+    /// could be saved to disk, so that the schedules and the file never diverge.
+    fn mutate_and_save<R>(&self, f: impl FnOnce(&mut SchedulesData) -> R) -> Result<R, String> {
+        let mut data = self.data.lock().unwrap();
+        let mut new_data = data.clone();
+        let res = f(&mut new_data);
+        self.save_to_disk(&new_data)?;
+        *data = new_data;
+        Ok(res)
+    }
+
+    pub fn get_all(&self) -> Vec<Schedule> {
+        self.data.lock().unwrap().schedules.clone()
+    }
+
+    /// The new schedule is enabled, and its first run is the first one after `now`.
+    pub fn add(&self, data: ScheduleData, now: NaiveDateTime) -> Result<Schedule, ScheduleError> {
+        data.recurrence.validate().map_err(ScheduleError::Invalid)?;
+        self.mutate_and_save(|schedules| {
+            let schedule = Schedule {
+                id: schedules.id_counter,
+                next_run: data.recurrence.next_after(now),
+                data,
+                enabled: true,
+                last_run: None,
+            };
+            schedules.id_counter += 1;
+            schedules.schedules.push(schedule.clone());
+            schedule
+        }).map_err(ScheduleError::Save)
+    }
+
+    /// Returns `None` if there is no schedule with the provided `id`. When a schedule is enabled
+    /// again, its next run is the first one after `now`.
+    pub fn set_enabled(&self, id: ScheduleId, enabled: bool, now: NaiveDateTime) -> Result<Option<Schedule>, String> {
+        self.mutate_and_save(|schedules| {
+            let schedule = schedules.schedules.iter_mut().find(|schedule| schedule.id == id)?;
+            if schedule.enabled != enabled {
+                schedule.enabled = enabled;
+                schedule.next_run = if enabled { schedule.data.recurrence.next_after(now) } else { None };
+            }
+            Some(schedule.clone())
+        })
+    }
+
+    /// Returns `false` if there is no schedule with the provided `id`.
+    pub fn remove(&self, id: ScheduleId) -> Result<bool, String> {
+        self.mutate_and_save(|schedules| {
+            let len_before = schedules.schedules.len();
+            schedules.schedules.retain(|schedule| schedule.id != id);
+            schedules.schedules.len() != len_before
+        })
+    }
+
+    /// Returns the actions of the schedules that are due at `now`, and moves those schedules to
+    /// their next run. Runs that should have happened more than [MISSED_RUN_TOLERANCE] before
+    /// `now` are handled according to [ScheduleData::catch_up].
+    fn tick(&self, now: NaiveDateTime) -> Result<Vec<ScheduledAction>, String> {
+        let is_due = |schedule: &Schedule| schedule.next_run.is_some_and(|next_run| next_run <= now);
+        if !self.data.lock().unwrap().schedules.iter().any(is_due) {
+            return Ok(vec![]); // avoid saving to disk for nothing
+        }
+
+        self.mutate_and_save(|schedules| {
+            let mut actions = vec![];
+            for schedule in schedules.schedules.iter_mut().filter(|schedule| is_due(schedule)) {
+                let missed = schedule.next_run.is_some_and(|next_run| now - next_run > MISSED_RUN_TOLERANCE);
+                if !missed || schedule.data.catch_up == CatchUp::RunOnce {
+                    actions.push(schedule.data.action.clone());
+                    schedule.last_run = Some(now);
+                } else {
+                    log::warn!("Skipping missed run of schedule {:?} due at {:?}", schedule.data.name, schedule.next_run);
+                }
+                // skips any other missed runs
+                schedule.next_run = schedule.data.recurrence.next_after(now);
+            }
+            actions
+        })
+    }
+
+    /// Never returns, so should be spawned in its own task. Adds the actions of due schedules to
+    /// the queue, using the local time of the orchestrator.
+    pub async fn run(&self, queue_handler: QueueHandler) {
+        let mut ticker = tokio::time::interval(SCHEDULER_PERIOD);
+        ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+        loop {
+            ticker.tick().await;
+            match self.tick(Local::now().naive_local()) {
+                Ok(actions) => for action in actions {
+                    action.add_to_queue(&queue_handler);
+                },
+                // will be retried at the next tick
+                Err(e) => log::error!("Could not update schedules: {e}"),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::assert_matches::assert_matches;
+
+    use chrono::{NaiveDate, NaiveDateTime, NaiveTime, Weekday};
+    use serde_json::Value;
+
+    use super::{CatchUp, Recurrence, ScheduleData, ScheduleError, ScheduledAction, SchedulerHandler};
+
+    fn at(day: u32, hour: u32, min: u32) -> NaiveDateTime {
+        // 2025-09-01 is a Monday
+        NaiveDate::from_ymd_opt(2025, 9, day).unwrap().and_hms_opt(hour, min, 0).unwrap()
+    }
+
+    fn schedule_data(recurrence: Recurrence, catch_up: CatchUp) -> ScheduleData {
+        ScheduleData {
+            name: "test".to_string(),
+            recurrence,
+            action: ScheduledAction { type_name: "water_all".to_string(), data: Value::Null },
+            catch_up,
+        }
+    }
+
+    #[test]
+    fn recurrence_next_after() {
+        let once = Recurrence::Once(at(2, 7, 0));
+        assert_eq!(Some(at(2, 7, 0)), once.next_after(at(1, 7, 0)));
+        assert_eq!(None, once.next_after(at(2, 7, 0)));
+
+        let seven = NaiveTime::from_hms_opt(7, 0, 0).unwrap();
+        let daily = Recurrence::Daily { time: seven, weekdays: vec![] };
+        assert_eq!(Some(at(1, 7, 0)), daily.next_after(at(1, 6, 59)));
+        assert_eq!(Some(at(2, 7, 0)), daily.next_after(at(1, 7, 0)));
+
+        let weekly = Recurrence::Daily { time: seven, weekdays: vec![Weekday::Mon, Weekday::Thu] };
+        assert_eq!(Some(at(4, 7, 0)), weekly.next_after(at(1, 7, 0)));
+        assert_eq!(Some(at(8, 7, 0)), weekly.next_after(at(4, 8, 0)));
+
+        let every = Recurrence::Every { start: at(1, 7, 0), every_secs: 90 * 60 };
+        assert_eq!(Some(at(1, 7, 0)), every.next_after(at(1, 0, 0)));
+        assert_eq!(Some(at(1, 8, 30)), every.next_after(at(1, 7, 0)));
+        assert_eq!(Some(at(2, 7, 0)), every.next_after(at(2, 6, 0)));
+    }
+
+    #[test]
+    fn scheduler_tick() {
+        let data_dir = tempdir::TempDir::new("cyberorto_test").unwrap();
+        let scheduler = SchedulerHandler::load_from_disk(data_dir.path());
+        let daily = Recurrence::Daily { time: NaiveTime::from_hms_opt(7, 0, 0).unwrap(), weekdays: vec![] };
+        let skip = scheduler.add(schedule_data(daily.clone(), CatchUp::Skip), at(1, 12, 0)).unwrap();
+        let run_once = scheduler.add(schedule_data(daily, CatchUp::RunOnce), at(1, 12, 0)).unwrap();
+        assert_eq!(Some(at(2, 7, 0)), skip.next_run);
+
+        assert_eq!(0, scheduler.tick(at(2, 6, 59)).unwrap().len());
+        let actions = scheduler.tick(at(2, 7, 0)).unwrap();
+        assert_eq!(2, actions.len());
+        assert_eq!(skip.data.action, actions[0]);
+        assert_eq!(0, scheduler.tick(at(2, 7, 0)).unwrap().len());
+
+        // the orchestrator was down for a few days, and the schedules are read back from disk
+        let scheduler = SchedulerHandler::load_from_disk(data_dir.path());
+        assert_eq!(1, scheduler.tick(at(5, 12, 0)).unwrap().len());
+        let schedules = scheduler.get_all();
+        assert_eq!(Some(at(2, 7, 0)), schedules[0].last_run);
+        assert_eq!(Some(at(5, 12, 0)), schedules[1].last_run);
+        assert!(schedules.iter().all(|schedule| schedule.next_run == Some(at(6, 7, 0))));
+
+        // disabled schedules don't run, and don't catch up when enabled again
+        scheduler.set_enabled(run_once.id, false, at(5, 12, 0)).unwrap().unwrap();
+        assert!(scheduler.remove(skip.id).unwrap());
+        assert_eq!(0, scheduler.tick(at(7, 12, 0)).unwrap().len());
+        let run_once = scheduler.set_enabled(run_once.id, true, at(7, 12, 0)).unwrap().unwrap();
+        assert_eq!(Some(at(8, 7, 0)), run_once.next_run);
+        assert_eq!(0, scheduler.tick(at(7, 12, 1)).unwrap().len());
+        assert!(scheduler.set_enabled(42, true, at(7, 12, 0)).unwrap().is_none());
+    }
+
+    #[test]
+    fn invalid_schedule() {
+        let data_dir = tempdir::TempDir::new("cyberorto_test").unwrap();
+        let scheduler = SchedulerHandler::load_from_disk(data_dir.path());
+        let every = Recurrence::Every { start: at(1, 7, 0), every_secs: 0 };
+        assert_matches!(
+            scheduler.add(schedule_data(every, CatchUp::Skip), at(1, 7, 0)),
+            Err(ScheduleError::Invalid(_))
+        );
+        assert!(scheduler.get_all().is_empty());
+    }
+}
@@ -1,8 +1,9 @@
 use std::{sync::Arc, time::Duration};
 
+use definitions::Parameters;
 use embedcore::{
     common::controllers::pid::PidController,
-    protocol::cyber::{MessagesHandler, MotorState, PeripheralsState, Response},
+    protocol::cyber::{DeviceError, MessagesHandler, MotorState, PeripheralsState, Response},
     std::{get_fake_motor, FakeDriver, FakeEncoder},
     EncoderTrait,
 };
@@ -15,26 +16,75 @@ pub struct DummyMessageHandler {
     pump_state: bool,
     plow_state: bool,
     led_state: bool,
-    time_finished: Instant,
+    water_time_finished: Instant,
+    lights_time_finished: Instant,
+    pump_time_finished: Instant,
+    plow_time_finished: Instant,
+    /// The liters in the simulated tank, which drains while the water is on.
+    water_liters: f32,
+    /// Until when [DummyMessageHandler::water_liters] has been updated.
+    water_last_update: Instant,
     motor: Arc<Mutex<PidController<FakeEncoder, FakeDriver>>>,
 }
 
 impl DummyMessageHandler {
     const METERS_TO_STEPS: f32 = 100000.0;
+    /// How many steps the motor can be from the objective to be considered idle.
+    const IDLE_STEPS: f32 = 20.0;
+    /// How many liters per second leave the simulated tank while the water is on.
+    pub const WATER_FLOW_LITERS_PER_SECOND: f32 = 0.5;
+    /// The maximum noise added to the simulated water scale readings, in scale units.
+    const WATER_SCALE_NOISE: i64 = 100;
 
+    /// Starts with a full tank, see [DummyMessageHandler::with_water_liters].
     pub fn new() -> (DummyMessageHandler, Arc<Mutex<PidController<FakeEncoder, FakeDriver>>>) {
         let motor = Arc::new(Mutex::new(PidController::new(get_fake_motor(), 2.0, 2.0)));
+        let now = Instant::now();
         (Self {
             water_state: false,
             lights_state: false,
             pump_state: false,
             plow_state: false,
             led_state: false,
-            time_finished: Instant::now(),
+            water_time_finished: now,
+            lights_time_finished: now,
+            pump_time_finished: now,
+            plow_time_finished: now,
+            water_liters: Parameters::default().water_tank_liters,
+            water_last_update: now,
             motor: motor.clone(),
         }, motor)
     }
 
+    /// Changes the liters in the simulated tank. The simulated water scale uses the
+    /// [default parameters](Parameters::default) to convert liters to readings.
+    #[cfg(test)]
+    pub fn with_water_liters(mut self, water_liters: f32) -> Self {
+        self.water_liters = water_liters;
+        self
+    }
+
+    /// Drains the simulated tank according to how long the water has been on since the last call.
+    fn update_water_liters(&mut self) {
+        let now = Instant::now();
+        if self.water_state {
+            let water_end = now.min(self.water_time_finished);
+            let elapsed = water_end.saturating_duration_since(self.water_last_update);
+            self.water_liters = (self.water_liters
+                - elapsed.as_secs_f32() * Self::WATER_FLOW_LITERS_PER_SECOND).max(0.0);
+        }
+        self.water_last_update = now;
+    }
+
+    fn water_scale(&self) -> u32 {
+        let params = Parameters::default();
+        let proportion = self.water_liters / params.water_tank_liters;
+        let scale = params.water_scale_min as f32
+            + proportion * (params.water_scale_max - params.water_scale_min) as f32;
+        let noise = rand::random_range(-Self::WATER_SCALE_NOISE..=Self::WATER_SCALE_NOISE);
+        (scale as i64 + noise) as u32
+    }
+
     fn update_time_finished(var: &mut bool, time_finished: &mut Instant, cooldown_ms: u64) -> Response {
         if cooldown_ms == 0 {
             *var = false;
@@ -47,7 +97,7 @@ impl DummyMessageHandler {
                 *time_finished = t;
                 Response::Ok
             }
-            None => Response::Error(*b"duration +"),
+            None => Response::Error(DeviceError::InvalidArgument { value: cooldown_ms.try_into().ok() }),
         }
     }
 }
@@ -55,9 +105,11 @@ impl DummyMessageHandler {
 impl MessagesHandler for DummyMessageHandler {
     async fn get_motor_state(&mut self) -> Response {
         let mut motor = self.motor.lock().await;
+        let pos = motor.motor.read();
         Response::MotorState(MotorState {
-            motor_pos: (motor.motor.read() as f32) / Self::METERS_TO_STEPS,
-            is_idle: false, // TODO detect if idle
+            motor_pos: (pos as f32) / Self::METERS_TO_STEPS,
+            // PidController::update() stops driving the motor when this close to the objective
+            is_idle: (motor.pid.setpoint - pos as f32).abs() <= Self::IDLE_STEPS,
             error: None, // TODO maybe introduce errors sometimes?
         })
     }
@@ -77,11 +129,17 @@ impl MessagesHandler for DummyMessageHandler {
     }
 
     async fn get_peripherals_state(&mut self) -> Response {
-        if self.time_finished <= Instant::now() {
-            self.water_state = false;
-            self.lights_state = false;
-            self.pump_state = false;
-            self.plow_state = false;
+        self.update_water_liters();
+        let now = Instant::now();
+        for (state, time_finished) in [
+            (&mut self.water_state, self.water_time_finished),
+            (&mut self.lights_state, self.lights_time_finished),
+            (&mut self.pump_state, self.pump_time_finished),
+            (&mut self.plow_state, self.plow_time_finished),
+        ] {
+            if time_finished <= now {
+                *state = false;
+            }
         }
         let resp_state = PeripheralsState {
             water: self.water_state,
@@ -90,26 +148,42 @@ impl MessagesHandler for DummyMessageHandler {
             plow: self.plow_state,
             led: self.led_state,
             battery_voltage: rand::random_range(13.0..=13.4),
-            water_scale: rand::random_range(8800000..=8900000),
+            water_scale: self.water_scale(),
         };
         // TODO add logging
         //println!("Got request for state: {resp_state:?}");
         Response::PeripheralsState(resp_state)
     }
     async fn water(&mut self, cooldown_ms: u64) -> Response {
-        Self::update_time_finished(&mut self.water_state, &mut self.time_finished, cooldown_ms)
+        // account for the water that flowed with the previous state before changing it
+        self.update_water_liters();
+        Self::update_time_finished(&mut self.water_state, &mut self.water_time_finished, cooldown_ms)
     }
     async fn lights(&mut self, cooldown_ms: u64) -> Response {
-        Self::update_time_finished(&mut self.lights_state, &mut self.time_finished, cooldown_ms)
+        Self::update_time_finished(&mut self.lights_state, &mut self.lights_time_finished, cooldown_ms)
     }
     async fn pump(&mut self, cooldown_ms: u64) -> Response {
-        Self::update_time_finished(&mut self.pump_state, &mut self.time_finished, cooldown_ms)
+        Self::update_time_finished(&mut self.pump_state, &mut self.pump_time_finished, cooldown_ms)
     }
     async fn plow(&mut self, cooldown_ms: u64) -> Response {
-        Self::update_time_finished(&mut self.plow_state, &mut self.time_finished, cooldown_ms)
+        Self::update_time_finished(&mut self.plow_state, &mut self.plow_time_finished, cooldown_ms)
     }
     async fn set_led(&mut self, state: bool) -> Response {
         self.led_state = state;
         Response::Ok
     }
+    async fn get_attached_tool(&mut self) -> Response {
+        // the simulation can't detect tools
+        Response::AttachedTool(None)
+    }
+    async fn on_link_lost(&mut self) {
+        self.update_water_liters();
+        self.water_state = false;
+        self.lights_state = false;
+        self.pump_state = false;
+        self.plow_state = false;
+        let mut motor = self.motor.lock().await;
+        let pos = motor.motor.read();
+        motor.set_objective(pos);
+    }
 }
@@ -1,49 +1,80 @@
 use std::f32::consts::{PI, TAU};
 
-use definitions::{Parameters, Vec3};
+use definitions::{Parameters, Tool, Vec3};
 
 const EPSILON: f32 = 1e-6; // 1 micrometer
 
-pub fn joint_to_world(pos: &Vec3, params: &Parameters) -> Vec3 {
+pub fn joint_to_world(pos: &Vec3, params: &Parameters, tool: Option<&Tool>) -> Vec3 {
+    let arm = EffectiveArm::new(params, tool);
+    let angle = pos.y + arm.angle_offset;
     Vec3 {
-        x: pos.x - params.arm_length * pos.y.cos(),
-        y: - params.arm_length * pos.y.sin(),
-        z: pos.z,
+        x: pos.x - arm.length * angle.cos(),
+        y: - arm.length * angle.sin(),
+        z: pos.z + arm.z_offset,
     }
 }
 
-/// TODO implement any corrections due to tools
 /// TODO maybe do calculations in f64 or f128 to avoid precision errors
-pub fn world_to_joint(pos: &Vec3, params: &Parameters) -> Option<Vec3> {
-    let mut angle = (- pos.y / params.arm_length).asin();
+pub fn world_to_joint(pos: &Vec3, params: &Parameters, tool: Option<&Tool>) -> Option<Vec3> {
+    let arm = EffectiveArm::new(params, tool);
+    let mut angle = (- pos.y / arm.length).asin();
     if angle.is_nan() {
         return None;
     }
 
-    if pos.x >= params.rail_length - params.arm_length {
+    if pos.x >= params.rail_length - arm.length {
         angle = PI - angle;
-        if angle >= PI {
-            angle -= TAU; // 2 pi
-        }
     }
 
-    let new_x = pos.x + params.arm_length * angle.cos();
+    let new_x = pos.x + arm.length * angle.cos();
     if new_x < -EPSILON || new_x > params.rail_length + EPSILON {
         return None;
     }
 
+    angle -= arm.angle_offset;
+    if angle >= PI {
+        angle -= TAU; // 2 pi
+    } else if angle < -PI {
+        angle += TAU;
+    }
+
     Some(Vec3 {
         x: new_x,
         y: angle,
-        z: pos.z
+        z: pos.z - arm.z_offset,
     })
 }
 
+/// Seen from above, a tool offset just makes the arm look longer and rotated, since the tool
+/// rotates together with the arm.
+struct EffectiveArm {
+    /// The horizontal distance between the tower and the tip of the tool.
+    length: f32,
+    /// The angle between the arm and the segment from the tower to the tip of the tool.
+    angle_offset: f32,
+    /// How much higher the tip of the tool is with respect to the end effector.
+    z_offset: f32,
+}
+
+impl EffectiveArm {
+    fn new(params: &Parameters, tool: Option<&Tool>) -> EffectiveArm {
+        let Some(tool) = tool else {
+            return EffectiveArm { length: params.arm_length, angle_offset: 0.0, z_offset: 0.0 };
+        };
+        let along_arm = params.arm_length + tool.offset.x;
+        EffectiveArm {
+            length: along_arm.hypot(tool.offset.y),
+            angle_offset: tool.offset.y.atan2(along_arm),
+            z_offset: tool.offset.z,
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use std::f32::consts::{FRAC_PI_2, PI};
 
-    use definitions::{Parameters, Vec3};
+    use definitions::{Parameters, Tool, Vec3};
     use rand::{rngs::StdRng, Rng, SeedableRng};
 
     use crate::state::kinematics::{joint_to_world, world_to_joint, EPSILON};
@@ -56,6 +87,13 @@ mod tests {
         water_scale_min: 0,
         water_scale_max: 1,
         water_tank_liters: 1.0,
+        water_no_flow_timeout_ms: 1000,
+        move_timeout_ms: 1000,
+        keep_out_volumes: Vec::new(),
+        safe_travel_height: 0.0,
+        tools: Vec::new(),
+        gating_rules: Vec::new(),
+        emergency_events: Vec::new(),
     };
 
     const fn vec3(x: f32, y: f32, z: f32) -> Vec3 {
@@ -88,28 +126,28 @@ mod tests {
     }
 
     fn assert_world_to_joint_to_world(expected_world: &Vec3, params: &Parameters) {
-        let Some(joint) = world_to_joint(expected_world, params) else {
+        let Some(joint) = world_to_joint(expected_world, params, None) else {
             panic!("unexpected failed conversion from world space {expected_world:?} to joint space");
         };
-        let actual_world = joint_to_world(&joint, params);
+        let actual_world = joint_to_world(&joint, params, None);
         assert_vec3_roughly_eq(expected_world, &actual_world, "world to joint to world");
     }
 
     fn assert_world_to_joint(world: &Vec3, expected_joint: &Vec3, params: &Parameters) {
-        let Some(actual_joint) = world_to_joint(world, params) else {
+        let Some(actual_joint) = world_to_joint(world, params, None) else {
             panic!("unexpected failed conversion from world space {world:?} to joint space");
         };
         assert_vec3_roughly_eq(expected_joint, &actual_joint, "world to joint");
     }
 
     fn assert_world_to_joint_invalid(world: &Vec3, params: &Parameters) {
-        if let Some(joint) = world_to_joint(world, params) {
+        if let Some(joint) = world_to_joint(world, params, None) {
             panic!("Unexpected {joint:?} configuration generated from invalid world coordinates {world:?}");
         }
     }
 
     fn assert_joint_to_world(joint: &Vec3, expected_world: &Vec3, params: &Parameters) {
-        let actual_world = joint_to_world(joint, params);
+        let actual_world = joint_to_world(joint, params, None);
         assert_vec3_roughly_eq(expected_world, &actual_world, "joint to world");
     }
 
@@ -222,4 +260,47 @@ mod tests {
         assert_joint_to_world(&vec3(3.0, -pi2, 0.0), &vec3( 3.0,  1.0, 0.0), &PARAMS_1_3);
         assert_joint_to_world(&vec3(3.0,  pi2, 0.0), &vec3( 3.0, -1.0, 0.0), &PARAMS_1_3);
     }
+
+    #[test]
+    fn tool_offset() {
+        let tool = Tool {
+            name: "plow".to_string(),
+            offset: vec3(0.5, 0.2, -0.1),
+            peripheral: None,
+            hardware_id: None,
+        };
+        // the tool points in the same direction as the arm, and its tip is a bit to the side
+        let world = joint_to_world(&vec3(2.0, 0.0, -0.3), &PARAMS_1_3, Some(&tool));
+        assert_vec3_roughly_eq(&vec3(0.5, -0.2, -0.4), &world, "joint to world with tool");
+        let world = joint_to_world(&vec3(2.0, FRAC_PI_2, -0.3), &PARAMS_1_3, Some(&tool));
+        assert_vec3_roughly_eq(&vec3(2.2, -1.5, -0.4), &world, "joint to world with tool");
+        assert!(world_to_joint(&vec3(0.0, 1.55, 0.0), &PARAMS_1_3, Some(&tool)).is_none());
+    }
+
+    #[test]
+    fn tool_offset_random() {
+        let mut rng = StdRng::from_seed([42; 32]);
+        for _ in 0..1000 {
+            let tool = Tool {
+                name: "random".to_string(),
+                offset: vec3(rng.random_range(-0.3..=0.3), rng.random_range(-0.3..=0.3), rng.random_range(-0.3..=0.3)),
+                peripheral: None,
+                hardware_id: None,
+            };
+            let joint = vec3(rng.random_range(0.0..=3.0), rng.random_range(-PI..PI), rng.random_range(-1.0..=0.0));
+            let world = joint_to_world(&joint, &PARAMS_1_3, Some(&tool));
+            // the joint configuration might be different (e.g. at the other side of the rail),
+            // but it must still lead to the same world position
+            let Some(actual_joint) = world_to_joint(&world, &PARAMS_1_3, Some(&tool)) else {
+                panic!("unexpected failed conversion from world space {world:?} to joint space with {tool:?}");
+            };
+            let actual_world = joint_to_world(&actual_joint, &PARAMS_1_3, Some(&tool));
+            assert!(
+                (world.x - actual_world.x).abs() <= 1e-5 &&
+                (world.y - actual_world.y).abs() <= 1e-5 &&
+                (world.z - actual_world.z).abs() <= 1e-5,
+                "Expected {world:?}, got {actual_world:?}, for {joint:?} with {tool:?}"
+            );
+        }
+    }
 }
@@ -2,29 +2,55 @@
 
 pub(crate) mod tests;
 pub mod dummy_message_handler;
-mod kinematics;
+pub mod kinematics;
 pub mod parameters;
+mod planner;
+pub mod plants;
 
 use std::{
     sync::{Arc, Mutex, MutexGuard}, time::Duration
 };
 
-use definitions::{Parameters, RobotState, Vec3};
-use embedcore::protocol::{communication::CommunicationError, cyber::Master};
+use chrono::{Local, NaiveDateTime};
+use definitions::{DeviceEvent, DeviceEventKind, Parameters, Peripheral, PlantData, RobotState, Tool, Vec3, WaterLevel};
+use embedcore::protocol::{communication::CommunicationError, cyber::{DeviceError, Event, Master, MotorState}};
 use rocket::futures::future::{self, join4};
+use serde::{Deserialize, Serialize};
+use tokio::{sync::broadcast::{self, error::TryRecvError}, time::{Instant, MissedTickBehavior}};
 use tokio_serial::SerialStream;
 
-use crate::{state::kinematics::{joint_to_world, world_to_joint}, util::serial::Masters};
-
-#[derive(Debug, Clone)]
-pub struct Plant {
-    x: f32,
-    y: f32,
-    z: f32,
-}
+use crate::{state::{kinematics::{joint_to_world, world_to_joint}, planner::plan_path, plants::PlantRegistry}, util::serial::Masters};
 
 type State = RobotState;
 
+/// The cooldown to send to the peripherals when opening the water in
+/// [`water_liters()`](StateHandler::water_liters), renewed at every poll.
+const WATER_LITERS_COOLDOWN_MS: u64 = 2000;
+/// How often to read the water scale in [`water_liters()`](StateHandler::water_liters).
+const WATER_LITERS_POLL_PERIOD: Duration = Duration::from_millis(100);
+/// The minimum decrease in liters for the water to be considered flowing, to ignore scale noise.
+const WATER_LITERS_MIN_FLOW: f32 = 0.02;
+/// How often to poll the motors while waiting for them to reach their target.
+const MOVE_POLL_PERIOD: Duration = Duration::from_millis(50);
+/// How far (in joint space units, i.e. meters or radians) a motor can be from its target to be
+/// considered arrived.
+const MOVE_TOLERANCE: f32 = 0.005;
+/// How many of the most recent [RobotState::events] to keep.
+const MAX_EVENTS: usize = 100;
+
+/// How the axes are moved in [`move_to()`](StateHandler::move_to).
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub enum MoveOrdering {
+    /// All axes move at the same time along a straight line, if no keep-out volume is in the
+    /// way. Otherwise the end effector goes over the obstacles like with [MoveOrdering::ZUpFirst].
+    #[default]
+    Simultaneous,
+    /// First the Z axis goes up to [Parameters::safe_travel_height], then X and Y move together,
+    /// and finally Z goes down to the target, so that the end effector does not drag through
+    /// plants or obstacles.
+    ZUpFirst,
+}
+
 #[derive(Debug, Clone)]
 pub struct StateHandler {
     state: Arc<Mutex<State>>,
@@ -32,6 +58,13 @@ pub struct StateHandler {
     motor_y: Arc<Master<SerialStream>>,
     motor_z: Arc<Master<SerialStream>>,
     peripherals: Arc<Master<SerialStream>>,
+    plants: PlantRegistry,
+}
+
+/// Receives the events sent by each device, see [`subscribe_events()`](StateHandler::subscribe_events).
+pub struct DeviceEvents {
+    /// The name of the device, its master and the receiver for the master's events.
+    devices: Vec<(String, Arc<Master<SerialStream>>, broadcast::Receiver<Event>)>,
 }
 
 fn acquire(state: &Arc<Mutex<State>>) -> MutexGuard<'_, State> {
@@ -57,17 +90,17 @@ macro_rules! handle_errors {
             if let Err(e) = &res {
                 mutate_state!(&$self.state, errors.$master = Some(format!("{e:?}")));
             }
-            res.map_err(|e| StateHandlerError::Communication {
-                error: e,
-                device_name: stringify!($master),
-                function_call: stringify!($func),
-            })
+            res.map_err(|e| StateHandlerError::from_communication(
+                e,
+                stringify!($master),
+                stringify!($func),
+            ))
         }
     };
 }
 
 impl StateHandler {
-    pub fn new(masters: Masters, parameters: Parameters) -> StateHandler {
+    pub fn new(masters: Masters, parameters: Parameters, plants: PlantRegistry) -> StateHandler {
         StateHandler {
             state: Arc::new(Mutex::new(State {
                 // TODO read parameters from file
@@ -78,6 +111,7 @@ impl StateHandler {
             motor_y: masters.y,
             motor_z: masters.z,
             peripherals: masters.peripherals,
+            plants,
         }
     }
 
@@ -85,40 +119,163 @@ impl StateHandler {
         acquire(&self.state).clone()
     }
 
-    pub async fn water_a_plant(&self, x: f32, y: f32, z: f32) -> Result<(), StateHandlerError> {
-        self.move_to(x, y, z).await?;
-        // TODO allow passing amount of time
-        self.water(5_000).await?;
-        tokio::time::sleep(Duration::from_millis(5_000)).await;
-        self.water(0).await?;
+    /// Replaces the parameters used e.g. for kinematics. The caller is responsible for making sure
+    /// that they are valid (see [validate_parameters](parameters::validate_parameters)), and
+    /// that no action is moving the robot in the meantime.
+    pub fn set_parameters(&self, parameters: Parameters) {
+        let mut state = acquire(&self.state);
+        state.parameters = parameters;
+        // e.g. the arm length or the tool offsets might have changed
+        update_world_positions(&mut state);
+    }
+
+    /// Selects the tool used for kinematics (see [RobotState::tool]), without checking whether
+    /// it is actually attached, e.g. for tools that can't be detected. Use `None` for no tool.
+    pub fn set_tool(&self, tool: Option<String>) -> Result<(), StateHandlerError> {
+        let mut state = acquire(&self.state);
+        if let Some(name) = &tool {
+            if !state.parameters.tools.iter().any(|t| &t.name == name) {
+                return Err(StateHandlerError::UnknownTool(name.clone()));
+            }
+        }
+        state.tool = tool;
+        update_world_positions(&mut state);
         Ok(())
     }
 
-    pub fn add_plant(&self, x: f32, y: f32, z: f32) {
-        // TODO add plant to DB
+    /// Retracts the Z axis so that the tool can be swapped, and then selects `tool` (see
+    /// [set_tool()](StateHandler::set_tool)) after checking that the peripherals detect it as
+    /// attached. Tools without a [Tool::hardware_id] are selected without any check.
+    pub async fn change_tool(&self, tool: Option<String>) -> Result<(), StateHandlerError> {
+        self.retract().await?;
+
+        let hardware_id = {
+            let state = acquire(&self.state);
+            match &tool {
+                Some(name) => match state.parameters.tools.iter().find(|t| &t.name == name) {
+                    Some(t) => t.hardware_id,
+                    None => return Err(StateHandlerError::UnknownTool(name.clone())),
+                },
+                None => None,
+            }
+        };
+        if tool.is_none() || hardware_id.is_some() {
+            let attached = self.get_attached_tool().await?;
+            if attached != hardware_id {
+                return Err(StateHandlerError::ToolNotAttached { tool, attached });
+            }
+        }
+
+        self.set_tool(tool)
     }
 
-    pub async fn water_all(&self) -> Result<(), StateHandlerError> {
-        let plants: Vec<Plant> = vec![]; // TODO load plant from DB
-        for plant in plants {
-            self.water_a_plant(plant.x, plant.y, plant.z).await?;
+    /// Asks the peripherals for the [Tool::hardware_id] of the tool that is physically attached.
+    pub async fn get_attached_tool(&self) -> Result<Option<u8>, StateHandlerError> {
+        handle_errors!(self.peripherals.get_attached_tool()).await
+    }
+
+    /// Fails if `peripheral` needs a tool (see [Tool::peripheral]) that is not currently selected.
+    fn check_tool_for(&self, peripheral: Peripheral) -> Result<(), StateHandlerError> {
+        let state = acquire(&self.state);
+        let needs_tool = state.parameters.tools.iter().any(|tool| tool.peripheral == Some(peripheral));
+        let has_tool = current_tool(&state).is_some_and(|tool| tool.peripheral == Some(peripheral));
+        if needs_tool && !has_tool {
+            return Err(StateHandlerError::WrongTool { peripheral, tool: state.tool.clone() });
         }
         Ok(())
     }
 
+    pub fn plants(&self) -> &PlantRegistry {
+        &self.plants
+    }
+
+    pub async fn water_a_plant(&self, plant: &PlantData) -> Result<(), StateHandlerError> {
+        let Vec3 { x, y, z } = plant.position;
+        // go over the other plants instead of through them
+        self.move_to_resetting_on_stall(x, y, z, MoveOrdering::ZUpFirst).await?;
+        self.water(plant.watering_duration_ms).await?;
+        tokio::time::sleep(Duration::from_millis(plant.watering_duration_ms)).await;
+        self.water(0).await?;
+        Ok(())
+    }
+
     pub async fn water(&self, cooldown_ms: u64) -> Result<(), StateHandlerError> {
+        if cooldown_ms != 0 {
+            self.check_tool_for(Peripheral::Water)?;
+        }
         handle_errors!(self.peripherals.water(cooldown_ms)).await
     }
 
+    /// Opens the water until `liters` liters have left the tank, according to the water scale,
+    /// and then closes it. The water is opened with a short cooldown that is renewed at every
+    /// reading, so that it closes by itself if the orchestrator stops talking to the peripherals.
+    /// Fails with [StateHandlerError::WaterNotFlowing] if the water level does not decrease for
+    /// [Parameters::water_no_flow_timeout_ms], e.g. because the tank is empty.
+    pub async fn water_liters(&self, liters: f32) -> Result<(), StateHandlerError> {
+        if !liters.is_finite() || liters <= 0.0 {
+            return Err(StateHandlerError::GenericError(format!("Invalid amount of liters {liters}")));
+        }
+        self.check_tool_for(Peripheral::Water)?;
+        let res = self.water_liters_until_done(liters).await;
+        // always try to close the water, even if there were errors
+        let res_close = self.water(0).await;
+        res?;
+        res_close
+    }
+
+    async fn water_liters_until_done(&self, liters: f32) -> Result<(), StateHandlerError> {
+        let no_flow_timeout = Duration::from_millis(self.get_state().parameters.water_no_flow_timeout_ms);
+        let initial_liters = self.read_water_liters().await?;
+        let mut last_flow_liters = initial_liters;
+        let mut last_flow_time = Instant::now();
+
+        loop {
+            handle_errors!(self.peripherals.water(WATER_LITERS_COOLDOWN_MS)).await?;
+            tokio::time::sleep(WATER_LITERS_POLL_PERIOD).await;
+
+            let current_liters = self.read_water_liters().await?;
+            if initial_liters - current_liters >= liters {
+                return Ok(());
+            }
+
+            if last_flow_liters - current_liters >= WATER_LITERS_MIN_FLOW {
+                last_flow_liters = current_liters;
+                last_flow_time = Instant::now();
+            } else if last_flow_time.elapsed() >= no_flow_timeout {
+                return Err(StateHandlerError::WaterNotFlowing {
+                    requested_liters: liters,
+                    delivered_liters: initial_liters - current_liters,
+                });
+            }
+        }
+    }
+
+    /// Reads the water scale, updates [RobotState::water_level] and returns the liters in the tank.
+    async fn read_water_liters(&self) -> Result<f32, StateHandlerError> {
+        let peripherals = handle_errors!(self.peripherals.get_peripherals_state()).await?;
+        let mut state = acquire(&self.state);
+        state.water_level = water_scale_to_level(peripherals.water_scale, &state.parameters);
+        Ok(state.water_level.liters)
+    }
+
     pub async fn lights(&self, cooldown_ms: u64) -> Result<(), StateHandlerError> {
+        if cooldown_ms != 0 {
+            self.check_tool_for(Peripheral::Lights)?;
+        }
         handle_errors!(self.peripherals.lights(cooldown_ms)).await
     }
 
     pub async fn pump(&self, cooldown_ms: u64) -> Result<(), StateHandlerError> {
+        if cooldown_ms != 0 {
+            self.check_tool_for(Peripheral::Pump)?;
+        }
         handle_errors!(self.peripherals.pump(cooldown_ms)).await
     }
 
     pub async fn plow(&self, cooldown_ms: u64) -> Result<(), StateHandlerError> {
+        if cooldown_ms != 0 {
+            self.check_tool_for(Peripheral::Plow)?;
+        }
         handle_errors!(self.peripherals.plow(cooldown_ms)).await
     }
 
@@ -128,7 +285,7 @@ impl StateHandler {
     }
 
     pub async fn home(&self) -> Result<(), StateHandlerError> {
-        self.move_to(0.0, 0.0, 0.0).await
+        self.move_to(0.0, 0.0, 0.0, MoveOrdering::ZUpFirst).await
     }
 
     pub async fn reset(&self) -> Result<(), StateHandlerError> {
@@ -136,43 +293,199 @@ impl StateHandler {
         self.retract().await?;
 
         // then also reset X and Y in parallel (to make things faster)
-        mutate_state!(&self.state, target.x = 0.0, target.y = -state.parameters.arm_length);
+        {
+            let mut state = acquire(&self.state);
+            state.target_joint.x = 0.0;
+            state.target_joint.y = 0.0;
+            update_world_positions(&mut state);
+        }
         let (res_x, res_y) = future::join(
             handle_errors!(self.motor_x.reset_motor()),
             handle_errors!(self.motor_y.reset_motor()),
         ).await;
         res_x?;
         res_y?;
-        mutate_state!(&self.state, position.x = 0.0, position.y = -state.parameters.arm_length);
+        {
+            let mut state = acquire(&self.state);
+            state.position_joint.x = 0.0;
+            state.position_joint.y = 0.0;
+            update_world_positions(&mut state);
+        }
 
         Ok(())
     }
 
     pub async fn retract(&self) -> Result<(), StateHandlerError> {
         // "retract" means resetting just the Z axis
-        mutate_state!(&self.state, target.z = 0.0);
+        {
+            let mut state = acquire(&self.state);
+            state.target_joint.z = 0.0;
+            update_world_positions(&mut state);
+        }
         handle_errors!(self.motor_z.reset_motor()).await?;
-        mutate_state!(&self.state, position.z = 0.0);
+        let mut state = acquire(&self.state);
+        state.position_joint.z = 0.0;
+        update_world_positions(&mut state);
         Ok(())
     }
 
-    pub async fn move_to(&self, x: f32, y: f32, z: f32) -> Result<(), StateHandlerError> {
-        let params = self.get_state().parameters.clone();
+    /// Moves the end effector to the provided world coordinates, going around
+    /// [Parameters::keep_out_volumes] (see [plan_path]), and waits until all motors have reached
+    /// the target. Fails with [StateHandlerError::PathBlocked] if there is no free path, or with
+    /// [StateHandlerError::MoveTimeout] if the motors don't reach a waypoint within
+    /// [Parameters::move_timeout_ms].
+    pub async fn move_to(&self, x: f32, y: f32, z: f32, ordering: MoveOrdering) -> Result<(), StateHandlerError> {
+        let state = self.get_state();
+        let (params, tool) = (&state.parameters, current_tool(&state));
         let world = Vec3 { x, y, z };
-        let Some(joint) = world_to_joint(&world, &params) else {
+        let Some(joint) = world_to_joint(&world, params, tool) else {
             return Err(StateHandlerError::InvalidWorldCoordinates(world));
         };
 
-        // TODO compute trajectory that avoids obstacles and optimizes path
+        // plan starting from where the motors actually are, not from the last known position
+        let [x, y, z] = self.poll_motors().await?;
+        let mut prev = Vec3 { x: x.motor_pos, y: y.motor_pos, z: z.motor_pos };
+        let waypoints = plan_path(&prev, &world, params, tool, ordering)?;
+
         // TODO handle errors while motors are moving and stop everything if errors happen
-        mutate_state!(&self.state, target = world, target_joint = joint.clone());
-        handle_errors!(self.motor_x.move_motor(joint.x)).await?;
-        handle_errors!(self.motor_y.move_motor(joint.y)).await?;
-        handle_errors!(self.motor_z.move_motor(joint.z)).await?;
+        mutate_state!(&self.state, target = world, target_joint = joint);
+        for waypoint in waypoints {
+            // only send the axes that actually need to move
+            let changed = |prev: f32, next: f32| (prev != next).then_some(next);
+            self.move_motors_and_wait([
+                changed(prev.x, waypoint.x),
+                changed(prev.y, waypoint.y),
+                changed(prev.z, waypoint.z),
+            ]).await?;
+            prev = waypoint;
+        }
         Ok(())
     }
 
+    /// Like [`move_to()`](StateHandler::move_to), but if a motor stalls, the motors can't be
+    /// trusted to be where they say anymore, so they are reset before failing. This way the move
+    /// can be retried from a known position, see
+    /// [RetriableError::Stall](crate::action::retry::RetriableError::Stall).
+    pub async fn move_to_resetting_on_stall(&self, x: f32, y: f32, z: f32, ordering: MoveOrdering) -> Result<(), StateHandlerError> {
+        match self.move_to(x, y, z, ordering).await {
+            Err(error @ StateHandlerError::Device { error: DeviceError::Stall { .. }, .. }) => {
+                warn!("Motor stalled while moving, resetting the motors: {error:?}");
+                self.reset().await?;
+                Err(error)
+            }
+            res => res,
+        }
+    }
+
+    /// Sends the joint `targets` to the x, y and z motors at the same time (skipping motors whose
+    /// target is `None`), and then polls the motors until they are all idle at their target. On
+    /// timeout the moving motors are told to hold their current position before returning.
+    async fn move_motors_and_wait(&self, targets: [Option<f32>; 3]) -> Result<(), StateHandlerError> {
+        self.move_motors(targets).await?;
+
+        let [target_x, target_y, target_z] = targets;
+        let timeout = Duration::from_millis(self.get_state().parameters.move_timeout_ms);
+        let start = Instant::now();
+        loop {
+            // give the motors some time to start moving before checking whether they are idle
+            tokio::time::sleep(MOVE_POLL_PERIOD).await;
+
+            let motor_states = self.poll_motors().await?;
+            let arrived = motor_states.iter().zip(targets).all(|(motor_state, target)| match target {
+                Some(target) => motor_state.is_idle && (motor_state.motor_pos - target).abs() <= MOVE_TOLERANCE,
+                None => true,
+            });
+            if arrived {
+                return Ok(());
+            }
+
+            if start.elapsed() >= timeout {
+                self.hold_motors(&motor_states, targets).await;
+                let [x, y, z] = motor_states;
+                let position_joint = Vec3 { x: x.motor_pos, y: y.motor_pos, z: z.motor_pos };
+                return Err(StateHandlerError::MoveTimeout {
+                    target_joint: Vec3 {
+                        x: target_x.unwrap_or(position_joint.x),
+                        y: target_y.unwrap_or(position_joint.y),
+                        z: target_z.unwrap_or(position_joint.z),
+                    },
+                    position_joint,
+                });
+            }
+        }
+    }
+
+    /// Sends the joint `targets` to the x, y and z motors at the same time, skipping motors whose
+    /// target is `None`, without waiting for them to get there.
+    async fn move_motors(&self, targets: [Option<f32>; 3]) -> Result<(), StateHandlerError> {
+        let [target_x, target_y, target_z] = targets;
+        let (res_x, res_y, res_z) = future::join3(
+            async { match target_x {
+                Some(x) => handle_errors!(self.motor_x.move_motor(x)).await,
+                None => Ok(()),
+            } },
+            async { match target_y {
+                Some(y) => handle_errors!(self.motor_y.move_motor(y)).await,
+                None => Ok(()),
+            } },
+            async { match target_z {
+                Some(z) => handle_errors!(self.motor_z.move_motor(z)).await,
+                None => Ok(()),
+            } },
+        ).await;
+        res_x.and(res_y).and(res_z)
+    }
+
+    /// Stops the motors that have a target by moving them to the position in `motor_states`, so
+    /// that they don't keep moving after [StateHandlerError::MoveTimeout] is returned. Errors are
+    /// only logged, since the caller is already failing anyway.
+    async fn hold_motors(&self, motor_states: &[MotorState; 3], targets: [Option<f32>; 3]) {
+        let positions = [0, 1, 2].map(|i| targets[i].map(|_| motor_states[i].motor_pos));
+        if let Err(e) = self.move_motors(positions).await {
+            warn!("Could not stop the motors after a move timeout: {e:?}");
+        }
+    }
+
+    /// Reads the state of the x, y and z motors, and updates the position in the state.
+    async fn poll_motors(&self) -> Result<[MotorState; 3], StateHandlerError> {
+        let (x, y, z) = future::join3(
+            handle_errors!(self.motor_x.get_motor_state()),
+            handle_errors!(self.motor_y.get_motor_state()),
+            handle_errors!(self.motor_z.get_motor_state()),
+        ).await;
+        let motor_states = [x?, y?, z?];
+        for (motor_state, device_name) in motor_states.iter().zip(["motor_x", "motor_y", "motor_z"]) {
+            if let Some(error) = motor_state.error {
+                return Err(StateHandlerError::Device { error, device_name, function_call: "get_motor_state" });
+            }
+        }
+
+        let mut state = acquire(&self.state);
+        state.position_joint = Vec3 {
+            x: motor_states[0].motor_pos,
+            y: motor_states[1].motor_pos,
+            z: motor_states[2].motor_pos,
+        };
+        update_world_positions(&mut state);
+        Ok(motor_states)
+    }
+
+    /// Reads the state of all devices and updates the state with whatever could be read, keeping
+    /// the last known values for the devices that could not be read. The errors are saved in
+    /// [State::errors], see [`update_state()`](StateHandler::update_state) to get them instead.
     pub async fn try_update_state(&self) -> State {
+        self.read_devices().await.0
+    }
+
+    /// Like [`try_update_state()`](StateHandler::try_update_state), but fails if any device
+    /// could not be read, so that decisions are never taken on stale values.
+    pub async fn update_state(&self) -> Result<State, StateHandlerError> {
+        let (state, result) = self.read_devices().await;
+        result.map(|()| state)
+    }
+
+    /// Returns the updated state, along with the first error, if any device could not be read.
+    async fn read_devices(&self) -> (State, Result<(), StateHandlerError>) {
         let (x, y, z, peripherals) = join4(
             handle_errors!(self.motor_x.get_motor_state()),
             handle_errors!(self.motor_y.get_motor_state()),
@@ -182,33 +495,155 @@ impl StateHandler {
 
         let mut state = acquire(&self.state);
 
-        if let Ok(x) = x {
+        // errors returned by get_motor_state() were already saved by handle_errors!
+        let errors = &mut state.errors;
+        for (motor_state, error) in [(&x, &mut errors.motor_x), (&y, &mut errors.motor_y), (&z, &mut errors.motor_z)] {
+            if let Ok(MotorState { error: Some(e), .. }) = motor_state {
+                *error = Some(format!("{e:?}"));
+            }
+        }
+
+        if let Ok(x) = &x {
             state.position_joint.x = x.motor_pos;
         }
-        if let Ok(y) = y {
+        if let Ok(y) = &y {
             state.position_joint.y = y.motor_pos;
         }
-        if let Ok(z) = z {
+        if let Ok(z) = &z {
             state.position_joint.z = z.motor_pos;
         }
-        state.position = joint_to_world(&state.position_joint, &state.parameters);
+        update_world_positions(&mut state);
 
-        if let Ok(peripherals) = peripherals {
+        if let Ok(peripherals) = &peripherals {
             state.actuators.water = peripherals.water;
             state.actuators.lights = peripherals.lights;
             state.actuators.pump = peripherals.pump;
             state.actuators.plow = peripherals.plow;
             state.actuators.led = peripherals.led;
-            state.water_level.proportion = (peripherals.water_scale - state.parameters.water_scale_min) as f32
-                / (state.parameters.water_scale_max - state.parameters.water_scale_min) as f32;
-            state.water_level.liters = state.water_level.proportion * state.parameters.water_tank_liters;
+            state.water_level = water_scale_to_level(peripherals.water_scale, &state.parameters);
             state.battery_level.proportion = (peripherals.battery_voltage - state.parameters.battery_voltage_min)
                 / (state.parameters.battery_voltage_max - state.parameters.battery_voltage_min);
             state.battery_level.volts = peripherals.battery_voltage;
         }
 
-        state.clone()
+        (state.clone(), x.and(y).and(z).and(peripherals).map(|_| ()))
+    }
+
+    /// Sends a [Message::Heartbeat](embedcore::protocol::cyber::Message::Heartbeat) to all
+    /// devices, so that they don't turn everything off thinking that the orchestrator crashed.
+    pub async fn heartbeat(&self) -> Result<(), StateHandlerError> {
+        let (x, y, z, peripherals) = join4(
+            handle_errors!(self.motor_x.heartbeat()),
+            handle_errors!(self.motor_y.heartbeat()),
+            handle_errors!(self.motor_z.heartbeat()),
+            handle_errors!(self.peripherals.heartbeat()),
+        ).await;
+        x.and(y).and(z).and(peripherals)
+    }
+
+    /// Never returns, so should be spawned in its own task. Sends a
+    /// [`heartbeat()`](StateHandler::heartbeat) every `period`, which must be shorter than the
+    /// failsafe timeout of the devices.
+    pub async fn run_heartbeat(&self, period: Duration) {
+        let mut ticker = tokio::time::interval(period);
+        ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        loop {
+            ticker.tick().await;
+            if let Err(e) = self.heartbeat().await {
+                warn!("Could not send heartbeat: {e:?}");
+            }
+        }
     }
+
+    /// Starts receiving the events sent by the devices, which can then be read with
+    /// [`receive_events()`](StateHandler::receive_events).
+    pub fn subscribe_events(&self) -> DeviceEvents {
+        let mut devices: Vec<(String, Arc<Master<SerialStream>>)> = Vec::new();
+        for (name, master) in [
+            ("motor_x", &self.motor_x),
+            ("motor_y", &self.motor_y),
+            ("motor_z", &self.motor_z),
+            ("peripherals", &self.peripherals),
+        ] {
+            // the same device may have more than one role, but its events must be reported once
+            match devices.iter_mut().find(|(_, m)| Arc::ptr_eq(m, master)) {
+                Some((device, _)) => *device = format!("{device}/{name}"),
+                None => devices.push((name.to_string(), master.clone())),
+            }
+        }
+        DeviceEvents {
+            devices: devices.into_iter()
+                .map(|(device, master)| {
+                    let receiver = master.subscribe_events();
+                    (device, master, receiver)
+                })
+                .collect(),
+        }
+    }
+
+    /// Listens to the devices for `listen` (see [Master::listen_for_events]), and then returns
+    /// all of the events received since the last call, including those received while other
+    /// messages were being sent. The events are also saved in [RobotState::events].
+    pub async fn receive_events(&self, events: &mut DeviceEvents, listen: Duration) -> Vec<DeviceEvent> {
+        future::join_all(events.devices.iter().map(|(_, master, _)| master.listen_for_events(listen))).await;
+
+        let received_at = Local::now().naive_local();
+        let mut received = Vec::new();
+        for (device, _, receiver) in &mut events.devices {
+            loop {
+                match receiver.try_recv() {
+                    Ok(event) => received.push(to_device_event(event, device, received_at)),
+                    Err(TryRecvError::Lagged(count)) => warn!("Missed {count} events from {device}"),
+                    Err(TryRecvError::Empty | TryRecvError::Closed) => break,
+                }
+            }
+        }
+
+        if !received.is_empty() {
+            let mut state = acquire(&self.state);
+            state.events.extend(received.iter().cloned());
+            let excess = state.events.len().saturating_sub(MAX_EVENTS);
+            state.events.drain(..excess);
+        }
+        received
+    }
+}
+
+fn to_device_event(event: Event, device: &str, received_at: NaiveDateTime) -> DeviceEvent {
+    let kind = match event {
+        Event::LimitSwitchHit => DeviceEventKind::LimitSwitchHit,
+        Event::Error(DeviceError::DriverFault) => DeviceEventKind::DriverFault,
+        Event::Error(DeviceError::EncoderMismatch { .. }) => DeviceEventKind::EncoderMismatch,
+        Event::Error(DeviceError::LimitSwitchTimeout { .. }) => DeviceEventKind::LimitSwitchTimeout,
+        Event::Error(DeviceError::Stall { .. }) => DeviceEventKind::Stall,
+        Event::Error(DeviceError::InvalidArgument { .. }) => DeviceEventKind::InvalidArgument,
+        Event::Error(DeviceError::Other(_)) => DeviceEventKind::OtherError,
+        Event::CooldownExpired(_) => DeviceEventKind::CooldownExpired,
+    };
+    DeviceEvent { received_at, device: device.to_string(), kind, description: format!("{event:?}") }
+}
+
+/// The tool among [Parameters::tools] that is selected in [RobotState::tool], if any.
+pub fn current_tool(state: &State) -> Option<&Tool> {
+    let name = state.tool.as_ref()?;
+    state.parameters.tools.iter().find(|tool| &tool.name == name)
+}
+
+/// Recomputes [RobotState::position] and [RobotState::target] from their joint space
+/// counterparts, e.g. after the joints moved or the selected tool changed.
+fn update_world_positions(state: &mut State) {
+    let tool = current_tool(state);
+    let position = joint_to_world(&state.position_joint, &state.parameters, tool);
+    let target = joint_to_world(&state.target_joint, &state.parameters, tool);
+    state.position = position;
+    state.target = target;
+}
+
+fn water_scale_to_level(water_scale: u32, parameters: &Parameters) -> WaterLevel {
+    // convert to f32 before subtracting, since the scale might read less than water_scale_min
+    let proportion = (water_scale as f32 - parameters.water_scale_min as f32)
+        / (parameters.water_scale_max as f32 - parameters.water_scale_min as f32);
+    WaterLevel { proportion, liters: proportion * parameters.water_tank_liters }
 }
 
 #[derive(Debug)]
@@ -218,6 +653,51 @@ pub enum StateHandlerError {
         device_name: &'static str,
         function_call: &'static str,
     },
+    /// The device replied with [Response::Error](embedcore::protocol::cyber::Response::Error),
+    /// or reported an error in [MotorState::error].
+    Device {
+        error: DeviceError,
+        device_name: &'static str,
+        function_call: &'static str,
+    },
     InvalidWorldCoordinates(Vec3),
+    MoveTimeout {
+        target_joint: Vec3,
+        position_joint: Vec3,
+    },
+    /// There is no path from `from` to `to` that avoids [Parameters::keep_out_volumes], e.g.
+    /// because `to` is inside one of them.
+    PathBlocked {
+        from: Vec3,
+        to: Vec3,
+    },
+    WaterNotFlowing {
+        requested_liters: f32,
+        delivered_liters: f32,
+    },
+    /// There is no tool with this name in [Parameters::tools].
+    UnknownTool(String),
+    /// The peripherals report that the attached tool has [Tool::hardware_id] `attached`, which
+    /// is not the one of `tool`.
+    ToolNotAttached {
+        tool: Option<String>,
+        attached: Option<u8>,
+    },
+    /// `peripheral` only works with a tool that is not the currently selected `tool`.
+    WrongTool {
+        peripheral: Peripheral,
+        tool: Option<String>,
+    },
     GenericError(String),
 }
+
+impl StateHandlerError {
+    /// Like [StateHandlerError::Communication], except that errors reported by the device
+    /// itself become [StateHandlerError::Device].
+    fn from_communication(error: CommunicationError, device_name: &'static str, function_call: &'static str) -> StateHandlerError {
+        match error {
+            CommunicationError::ErrorResponse(error) => StateHandlerError::Device { error, device_name, function_call },
+            error => StateHandlerError::Communication { error, device_name, function_call },
+        }
+    }
+}
@@ -1,8 +1,9 @@
-use std::{fs::create_dir_all, path::Path};
+use std::{fs::create_dir_all, path::{Path, PathBuf}};
 
-use definitions::Parameters;
+use definitions::{GatingRule, KeepOutVolume, Parameters, Tool};
+use serde_json::Value;
 
-use crate::util::serde::{deserialize_from_json_file, serialize_to_json_file_pretty};
+use crate::{queue::QueueHandler, state::StateHandler, util::serde::{deserialize_from_json_file, serialize_to_json_file_pretty}};
 
 const PARAMETERS_FILE: &str = "parameters.json";
 const PARAMETERS_BACKUP_FILE: &str = "parameters.json.bak";
@@ -10,7 +11,9 @@ const PARAMETERS_BACKUP_FILE: &str = "parameters.json.bak";
 
 pub fn load_parameters_from_disk(data_dir: &Path) -> Parameters {
     let parameters_file = &data_dir.join(PARAMETERS_FILE);
-    match deserialize_from_json_file(parameters_file) {
+    let parameters = deserialize_from_json_file(parameters_file)
+        .and_then(|parameters| validate_parameters(&parameters).map(|_| parameters));
+    match parameters {
         Ok(parameters) => parameters,
         Err(e) => {
             let parameters_backup_file = &data_dir.join(PARAMETERS_BACKUP_FILE);
@@ -26,13 +29,254 @@ pub fn load_parameters_from_disk(data_dir: &Path) -> Parameters {
     }
 }
 
-pub fn save_parameters_to_disk(parameters: &Parameters, data_dir: &Path) {
-    if let Err(e) = create_dir_all(data_dir) {
-        log::error!("Could not create data directory {data_dir:?}: {e:?}");
-        return;
-    }
+pub fn save_parameters_to_disk(parameters: &Parameters, data_dir: &Path) -> Result<(), String> {
+    create_dir_all(data_dir)
+        .map_err(|e| format!("Could not create data directory {data_dir:?}: {e:?}"))?;
     let parameters_file = &data_dir.join(PARAMETERS_FILE);
-    if let Err(e) = serialize_to_json_file_pretty(parameters, parameters_file) {
-        log::error!("Could not serialize and save parameters to {parameters_file:?}: {e:?}");
+    serialize_to_json_file_pretty(parameters, parameters_file)
+        .map_err(|e| format!("Could not serialize and save parameters to {parameters_file:?}: {e}"))
+}
+
+/// Returns a human readable description of the first invalid value found, if any. Invalid values
+/// would otherwise lead to nonsensical kinematics or to divisions by zero when updating the state.
+pub fn validate_parameters(parameters: &Parameters) -> Result<(), String> {
+    // is_finite() also rejects NaN, for which any comparison would be false
+    fn is_positive(value: f32) -> bool {
+        value.is_finite() && value > 0.0
+    }
+
+    if !is_positive(parameters.arm_length) {
+        return Err(format!("arm_length must be positive, got {}", parameters.arm_length));
+    }
+    if !is_positive(parameters.rail_length) {
+        return Err(format!("rail_length must be positive, got {}", parameters.rail_length));
+    }
+    if !parameters.battery_voltage_min.is_finite()
+        || !parameters.battery_voltage_max.is_finite()
+        || parameters.battery_voltage_min >= parameters.battery_voltage_max
+    {
+        return Err(format!(
+            "battery_voltage_min ({}) must be smaller than battery_voltage_max ({})",
+            parameters.battery_voltage_min, parameters.battery_voltage_max
+        ));
+    }
+    if parameters.water_scale_min >= parameters.water_scale_max {
+        return Err(format!(
+            "water_scale_min ({}) must be smaller than water_scale_max ({})",
+            parameters.water_scale_min, parameters.water_scale_max
+        ));
+    }
+    if !is_positive(parameters.water_tank_liters) {
+        return Err(format!("water_tank_liters must be positive, got {}", parameters.water_tank_liters));
+    }
+    if parameters.water_no_flow_timeout_ms == 0 {
+        return Err("water_no_flow_timeout_ms must be positive, got 0".to_string());
+    }
+    if parameters.move_timeout_ms == 0 {
+        return Err("move_timeout_ms must be positive, got 0".to_string());
+    }
+    if !parameters.safe_travel_height.is_finite() {
+        return Err(format!("safe_travel_height must be finite, got {}", parameters.safe_travel_height));
+    }
+    for volume in &parameters.keep_out_volumes {
+        let KeepOutVolume { name, min, max } = volume;
+        let valid = [(min.x, max.x), (min.y, max.y), (min.z, max.z)].iter()
+            .all(|(min, max)| min.is_finite() && max.is_finite() && min <= max);
+        if !valid {
+            return Err(format!("Keep-out volume {name:?} must have finite coordinates and min <= max, got {volume:?}"));
+        }
+    }
+    for (i, tool) in parameters.tools.iter().enumerate() {
+        let Tool { name, offset, hardware_id, .. } = tool;
+        if !offset.x.is_finite() || !offset.y.is_finite() || !offset.z.is_finite() {
+            return Err(format!("Tool {name:?} must have a finite offset, got {offset:?}"));
+        }
+        // otherwise the tip of the tool would be on the rotation axis of the tower
+        if !is_positive((parameters.arm_length + offset.x).hypot(offset.y)) {
+            return Err(format!("Tool {name:?} must not be on the rotation axis of the arm, got {offset:?}"));
+        }
+        for other in &parameters.tools[..i] {
+            if &other.name == name {
+                return Err(format!("There are multiple tools named {name:?}"));
+            }
+            if hardware_id.is_some() && &other.hardware_id == hardware_id {
+                return Err(format!("Tools {:?} and {name:?} have the same hardware_id", other.name));
+            }
+        }
+    }
+    for rule in &parameters.gating_rules {
+        let GatingRule { level, below, resume_above, .. } = rule;
+        if !below.is_finite() || !resume_above.is_finite() || below > resume_above {
+            return Err(format!("Gating rule on {level:?} must have finite levels and below <= resume_above, got {rule:?}"));
+        }
+    }
+    Ok(())
+}
+
+/// Applies `patch` to `parameters` as a JSON merge patch (RFC 7386), i.e. only the fields present
+/// in `patch` are changed. Unknown fields are rejected instead of being silently ignored, so that
+/// typos don't go unnoticed. The result is not validated, see [validate_parameters].
+pub fn patch_parameters(parameters: &Parameters, patch: Value) -> Result<Parameters, String> {
+    let mut value = serde_json::to_value(parameters).map_err(|e| e.to_string())?;
+    merge_patch(&mut value, patch, "")?;
+    serde_json::from_value(value).map_err(|e| e.to_string())
+}
+
+fn merge_patch(target: &mut Value, patch: Value, path: &str) -> Result<(), String> {
+    let Value::Object(patch) = patch else {
+        *target = patch;
+        return Ok(());
+    };
+    let Value::Object(target) = target else {
+        return Err(format!("{path} is not an object"));
+    };
+    for (key, value) in patch {
+        let path = if path.is_empty() { key.clone() } else { format!("{path}.{key}") };
+        let Some(field) = target.get_mut(&key) else {
+            return Err(format!("Unknown parameter {path}"));
+        };
+        merge_patch(field, value, &path)?;
+    }
+    Ok(())
+}
+
+#[derive(Debug)]
+pub enum UpdateParametersError {
+    /// Parameters can't be changed while an action is running, since e.g. changing the arm
+    /// length in the middle of a movement would make the robot go somewhere unexpected.
+    QueueNotIdle,
+    /// The patch could not be applied, or the resulting parameters are not valid.
+    Invalid(String),
+    /// The new parameters were valid but could not be saved, so they were not applied.
+    Save(String),
+}
+
+/// Applies changes to the parameters used by [StateHandler] at runtime, and persists them in the
+/// data directory.
+#[derive(Debug, Clone)]
+pub struct ParametersHandler {
+    data_dir: PathBuf,
+}
+
+impl ParametersHandler {
+    pub fn new(data_dir: PathBuf) -> ParametersHandler {
+        ParametersHandler { data_dir }
+    }
+
+    /// Validates the parameters obtained by applying `patch` (see [patch_parameters]), saves them
+    /// to disk and then updates the state. Fails if the queue is currently running an action.
+    pub fn update(
+        &self,
+        state_handler: &StateHandler,
+        queue_handler: &QueueHandler,
+        patch: Value,
+    ) -> Result<Parameters, UpdateParametersError> {
+        queue_handler
+            .run_if_idle(|| {
+                let parameters = patch_parameters(&state_handler.get_state().parameters, patch)
+                    .map_err(UpdateParametersError::Invalid)?;
+                validate_parameters(&parameters).map_err(UpdateParametersError::Invalid)?;
+                if let Some(tool) = &state_handler.get_state().tool {
+                    if !parameters.tools.iter().any(|t| &t.name == tool) {
+                        return Err(UpdateParametersError::Invalid(
+                            format!("Tool {tool:?} is currently selected and can't be removed")
+                        ));
+                    }
+                }
+                save_parameters_to_disk(&parameters, &self.data_dir)
+                    .map_err(UpdateParametersError::Save)?;
+                state_handler.set_parameters(parameters.clone());
+                Ok(parameters)
+            })
+            .unwrap_or(Err(UpdateParametersError::QueueNotIdle))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::assert_matches::assert_matches;
+
+    use definitions::{GatedLevel, GatingRule, KeepOutVolume, Parameters, Tool, Vec3};
+    use serde_json::json;
+
+    use super::{load_parameters_from_disk, patch_parameters, save_parameters_to_disk, validate_parameters};
+
+    #[test]
+    fn patch_parameters_test() {
+        let parameters = patch_parameters(
+            &Parameters::default(),
+            json!({ "arm_length": 2.0, "water_scale_max": 10000000 }),
+        ).unwrap();
+        assert_eq!(2.0, parameters.arm_length);
+        assert_eq!(10000000, parameters.water_scale_max);
+        assert_eq!(Parameters::default().rail_length, parameters.rail_length);
+
+        assert_matches!(patch_parameters(&Parameters::default(), json!({ "arm_lenght": 2.0 })), Err(_));
+        assert_matches!(patch_parameters(&Parameters::default(), json!({ "arm_length": "2" })), Err(_));
+        assert_matches!(patch_parameters(&Parameters::default(), json!({ "arm_length": null })), Err(_));
+    }
+
+    #[test]
+    fn validate_parameters_test() {
+        assert_matches!(validate_parameters(&Parameters::default()), Ok(()));
+        let invalid = [
+            Parameters { arm_length: -1.0, ..Default::default() },
+            Parameters { arm_length: f32::NAN, ..Default::default() },
+            Parameters { rail_length: 0.0, ..Default::default() },
+            Parameters { battery_voltage_min: 13.5, battery_voltage_max: 13.5, ..Default::default() },
+            Parameters { water_scale_min: 9140000, water_scale_max: 8590000, ..Default::default() },
+            Parameters { water_tank_liters: f32::INFINITY, ..Default::default() },
+            Parameters { water_no_flow_timeout_ms: 0, ..Default::default() },
+            Parameters { move_timeout_ms: 0, ..Default::default() },
+            Parameters {
+                keep_out_volumes: vec![KeepOutVolume {
+                    name: "tank".to_string(),
+                    min: Vec3 { x: 1.0, y: 0.0, z: -1.0 },
+                    max: Vec3 { x: 0.5, y: 0.5, z: 0.0 },
+                }],
+                ..Default::default()
+            },
+            Parameters {
+                tools: vec![
+                    Tool { name: "plow".to_string(), offset: Vec3::default(), peripheral: None, hardware_id: Some(1) },
+                    Tool { name: "plow".to_string(), offset: Vec3::default(), peripheral: None, hardware_id: Some(2) },
+                ],
+                ..Default::default()
+            },
+            Parameters {
+                tools: vec![Tool {
+                    name: "inside tower".to_string(),
+                    offset: Vec3 { x: -1.511, y: 0.0, z: 0.0 },
+                    peripheral: None,
+                    hardware_id: None,
+                }],
+                ..Default::default()
+            },
+            Parameters {
+                gating_rules: vec![GatingRule {
+                    level: GatedLevel::Battery,
+                    below: 0.3,
+                    resume_above: 0.1,
+                    action_types: vec![],
+                }],
+                ..Default::default()
+            },
+        ];
+        for parameters in invalid {
+            assert_matches!(validate_parameters(&parameters), Err(_), "{parameters:?}");
+        }
+    }
+
+    #[test]
+    fn load_invalid_parameters_test() {
+        let data_dir = tempdir::TempDir::new("cyberorto_test").unwrap();
+        let parameters = Parameters { arm_length: 2.0, ..Default::default() };
+        save_parameters_to_disk(&parameters, data_dir.path()).unwrap();
+        assert_eq!(2.0, load_parameters_from_disk(data_dir.path()).arm_length);
+
+        let parameters = Parameters { arm_length: -2.0, ..Default::default() };
+        save_parameters_to_disk(&parameters, data_dir.path()).unwrap();
+        assert_eq!(Parameters::default().arm_length, load_parameters_from_disk(data_dir.path()).arm_length);
+        assert!(data_dir.path().join("parameters.json.bak").exists());
     }
 }
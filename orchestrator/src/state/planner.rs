@@ -0,0 +1,356 @@
+use definitions::{KeepOutVolume, Parameters, Tool, Vec3};
+
+use crate::state::{kinematics::{joint_to_world, world_to_joint}, MoveOrdering, StateHandlerError};
+
+/// The distance in meters between consecutive points when checking paths for collisions.
+const SAMPLE_DISTANCE: f32 = 0.02;
+/// How far in meters the actual path of the end effector (obtained by moving all joints
+/// linearly between two waypoints) may deviate from the planned path in world space. Keep-out
+/// volumes are enlarged by this much horizontally, to make up for the deviation.
+const PATH_TOLERANCE: f32 = 0.01;
+
+/// Plans how to move the end effector from the joint configuration `from_joint` to the world
+/// position `to`, and returns the joint waypoints to go through (excluding `from_joint`). Moving
+/// all joints linearly from one waypoint to the next keeps the Z axis out of
+/// [Parameters::keep_out_volumes].
+///
+/// The direct path is tried first (unless `ordering` is [MoveOrdering::ZUpFirst]), and if it is
+/// blocked the end effector goes up to [Parameters::safe_travel_height], travels there, and
+/// then goes down to `to`. Keep-out volumes the end effector is already in are ignored, so that
+/// it is always possible to get out of them. World positions refer to the tip of `tool`.
+pub fn plan_path(
+    from_joint: &Vec3,
+    to: &Vec3,
+    params: &Parameters,
+    tool: Option<&Tool>,
+    ordering: MoveOrdering,
+) -> Result<Vec<Vec3>, StateHandlerError> {
+    if world_to_joint(to, params, tool).is_none() {
+        return Err(StateHandlerError::InvalidWorldCoordinates(to.clone()));
+    }
+    let from = joint_to_world(from_joint, params, tool);
+    let volumes: Vec<&KeepOutVolume> = params.keep_out_volumes.iter()
+        .filter(|volume| !collides(&from, volume, 0.0))
+        .collect();
+    if volumes.iter().any(|volume| collides(to, volume, 0.0)) {
+        return Err(StateHandlerError::PathBlocked { from, to: to.clone() });
+    }
+
+    if ordering == MoveOrdering::Simultaneous {
+        if let Some(waypoints) = plan_route(from_joint, &[from.clone(), to.clone()], params, tool, &volumes)? {
+            return Ok(waypoints);
+        }
+    }
+
+    // stay at the current height if it is already higher than the safe travel height
+    let travel_z = params.safe_travel_height.max(from.z).max(to.z);
+    let route = [
+        from.clone(),
+        Vec3 { x: from.x, y: from.y, z: travel_z },
+        Vec3 { x: to.x, y: to.y, z: travel_z },
+        to.clone(),
+    ];
+    plan_route(from_joint, &route, params, tool, &volumes)?
+        .ok_or(StateHandlerError::PathBlocked { from, to: to.clone() })
+}
+
+/// Returns `None` if moving along `route` (a list of world points to connect with straight
+/// segments, starting from the world position of `from_joint`) would hit `volumes`.
+fn plan_route(
+    from_joint: &Vec3,
+    route: &[Vec3],
+    params: &Parameters,
+    tool: Option<&Tool>,
+    volumes: &[&KeepOutVolume],
+) -> Result<Option<Vec<Vec3>>, StateHandlerError> {
+    // sample the route densely in world space
+    let mut world_samples = vec![route[0].clone()];
+    let mut joint_samples = vec![from_joint.clone()];
+    for segment in route.windows(2) {
+        let steps = (distance(&segment[0], &segment[1]) / SAMPLE_DISTANCE).ceil().max(1.0) as usize;
+        for i in 1..=steps {
+            let world = lerp(&segment[0], &segment[1], i as f32 / steps as f32);
+            let Some(joint) = world_to_joint(&world, params, tool) else {
+                return Err(StateHandlerError::InvalidWorldCoordinates(world));
+            };
+            world_samples.push(world);
+            joint_samples.push(joint);
+        }
+    }
+
+    // Only keep the waypoints that are needed for the joint space interpolation to follow the
+    // world space path. Any big jump in joint space (e.g. when crossing the rail-end flip in
+    // world_to_joint()) will not be merged, and will be checked for collisions below.
+    let mut waypoints = vec![];
+    let mut anchor = 0;
+    while anchor + 1 < joint_samples.len() {
+        let mut end = anchor + 1;
+        while end + 1 < joint_samples.len()
+            && follows_world_path(&joint_samples, &world_samples, anchor, end + 1, params, tool)
+        {
+            end += 1;
+        }
+        waypoints.push(joint_samples[end].clone());
+        anchor = end;
+    }
+
+    let mut prev = from_joint;
+    for waypoint in &waypoints {
+        if joint_motion_collides(prev, waypoint, params, tool, volumes) {
+            return Ok(None);
+        }
+        prev = waypoint;
+    }
+    Ok(Some(waypoints))
+}
+
+/// Whether moving linearly in joint space from sample `start` to sample `end` passes close to
+/// all world samples in-between.
+fn follows_world_path(
+    joint_samples: &[Vec3],
+    world_samples: &[Vec3],
+    start: usize,
+    end: usize,
+    params: &Parameters,
+    tool: Option<&Tool>,
+) -> bool {
+    (start + 1..end).all(|k| {
+        let t = (k - start) as f32 / (end - start) as f32;
+        let joint = lerp(&joint_samples[start], &joint_samples[end], t);
+        distance(&joint_to_world(&joint, params, tool), &world_samples[k]) <= PATH_TOLERANCE
+    })
+}
+
+fn joint_motion_collides(
+    from: &Vec3,
+    to: &Vec3,
+    params: &Parameters,
+    tool: Option<&Tool>,
+    volumes: &[&KeepOutVolume],
+) -> bool {
+    let max_travel = (to.x - from.x).abs()
+        .max((to.y - from.y).abs() * params.arm_length)
+        .max((to.z - from.z).abs());
+    let steps = (max_travel / SAMPLE_DISTANCE).ceil().max(1.0) as usize;
+    (0..=steps).any(|i| {
+        let world = joint_to_world(&lerp(from, to, i as f32 / steps as f32), params, tool);
+        volumes.iter().any(|volume| collides(&world, volume, PATH_TOLERANCE))
+    })
+}
+
+/// Whether the Z axis, which goes from the end effector at `pos` up to the arm at `z = 0`,
+/// intersects `volume` enlarged horizontally by `margin`.
+fn collides(pos: &Vec3, volume: &KeepOutVolume, margin: f32) -> bool {
+    pos.x >= volume.min.x - margin && pos.x <= volume.max.x + margin
+        && pos.y >= volume.min.y - margin && pos.y <= volume.max.y + margin
+        && volume.max.z >= pos.z.min(0.0) && volume.min.z <= pos.z.max(0.0)
+}
+
+/// Exact at `t = 0` and `t = 1`, so that waypoints land precisely on the route corners.
+fn lerp(a: &Vec3, b: &Vec3, t: f32) -> Vec3 {
+    Vec3 {
+        x: a.x * (1.0 - t) + b.x * t,
+        y: a.y * (1.0 - t) + b.y * t,
+        z: a.z * (1.0 - t) + b.z * t,
+    }
+}
+
+fn distance(a: &Vec3, b: &Vec3) -> f32 {
+    ((a.x - b.x).powi(2) + (a.y - b.y).powi(2) + (a.z - b.z).powi(2)).sqrt()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::assert_matches::assert_matches;
+
+    use definitions::{KeepOutVolume, Parameters, Vec3};
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+
+    use crate::state::{kinematics::{joint_to_world, world_to_joint}, MoveOrdering, StateHandlerError};
+
+    use super::{collides, distance, lerp, plan_path, SAMPLE_DISTANCE};
+
+    fn params_1_3(keep_out_volumes: Vec<KeepOutVolume>) -> Parameters {
+        Parameters {
+            arm_length: 1.0,
+            rail_length: 3.0,
+            keep_out_volumes,
+            safe_travel_height: 0.0,
+            ..Default::default()
+        }
+    }
+
+    const fn vec3(x: f32, y: f32, z: f32) -> Vec3 {
+        Vec3 { x, y, z }
+    }
+
+    fn volume(min: Vec3, max: Vec3) -> KeepOutVolume {
+        KeepOutVolume { name: "test".to_string(), min, max }
+    }
+
+    /// Uniformly samples a point from the world space, with `z` below the arm
+    fn random_world_point(rng: &mut StdRng, params: &Parameters) -> Vec3 {
+        let z = rng.random_range(-1.0..=0.0);
+        loop {
+            let x = rng.random_range(-params.arm_length..=(params.rail_length + params.arm_length));
+            let y = rng.random_range(-params.arm_length..=params.arm_length);
+            let world = Vec3 { x, y, z };
+            if world_to_joint(&world, params, None).is_some() {
+                return world;
+            }
+        }
+    }
+
+    /// Returns the world positions the end effector goes through when moving linearly in joint
+    /// space along the waypoints.
+    fn sample_motion(from_joint: &Vec3, waypoints: &[Vec3], params: &Parameters) -> Vec<Vec3> {
+        let mut samples = vec![];
+        let mut prev = from_joint;
+        for waypoint in waypoints {
+            for i in 0..=100 {
+                samples.push(joint_to_world(&lerp(prev, waypoint, i as f32 / 100.0), params, None));
+            }
+            prev = waypoint;
+        }
+        samples
+    }
+
+    fn assert_reaches(from_joint: &Vec3, to: &Vec3, waypoints: &[Vec3], params: &Parameters) {
+        let last = joint_to_world(waypoints.last().unwrap_or(from_joint), params, None);
+        assert!(distance(&last, to) < 1e-4, "Path from {from_joint:?} ends in {last:?} instead of {to:?}");
+    }
+
+    #[test]
+    fn straight_path_without_rotation() {
+        let params = params_1_3(vec![]);
+        let from_joint = world_to_joint(&vec3(0.5, 0.0, -0.5), &params, None).unwrap();
+        let to = vec3(1.5, 0.0, -0.2);
+        let waypoints = plan_path(&from_joint, &to, &params, None, MoveOrdering::Simultaneous).unwrap();
+        // the arm does not rotate, so moving the joints linearly already follows a straight line
+        assert_eq!(1, waypoints.len());
+        assert_reaches(&from_joint, &to, &waypoints, &params);
+    }
+
+    #[test]
+    fn path_follows_straight_line() {
+        let params = params_1_3(vec![]);
+        let from = vec3(0.5, -0.9, -0.5);
+        let to = vec3(1.5, 0.9, -0.5);
+        let from_joint = world_to_joint(&from, &params, None).unwrap();
+        let waypoints = plan_path(&from_joint, &to, &params, None, MoveOrdering::Simultaneous).unwrap();
+        assert!(waypoints.len() > 1);
+        assert_reaches(&from_joint, &to, &waypoints, &params);
+        for sample in sample_motion(&from_joint, &waypoints, &params) {
+            // the line goes from (0.5, -0.9) to (1.5, 0.9), i.e. y = 1.8x - 1.8
+            let line_distance = (1.8 * sample.x - sample.y - 1.8).abs() / (1.8f32 * 1.8 + 1.0).sqrt();
+            assert!(line_distance < 0.02, "{sample:?} is too far from the straight line");
+        }
+    }
+
+    #[test]
+    fn blocked_path_goes_over_obstacle() {
+        let params = params_1_3(vec![volume(vec3(0.9, -1.0, -1.0), vec3(1.1, 1.0, -0.3))]);
+        let from_joint = world_to_joint(&vec3(0.5, 0.0, -0.5), &params, None).unwrap();
+        let to = vec3(1.5, 0.0, -0.5);
+        let waypoints = plan_path(&from_joint, &to, &params, None, MoveOrdering::Simultaneous).unwrap();
+        assert_reaches(&from_joint, &to, &waypoints, &params);
+        for sample in sample_motion(&from_joint, &waypoints, &params) {
+            assert!(!collides(&sample, &params.keep_out_volumes[0], 0.0), "{sample:?} collides");
+        }
+    }
+
+    #[test]
+    fn unreachable_or_blocked_target() {
+        let params = params_1_3(vec![volume(vec3(0.9, -1.0, -1.0), vec3(1.1, 1.0, 0.5))]);
+        let from_joint = world_to_joint(&vec3(0.5, 0.0, -0.5), &params, None).unwrap();
+        assert_matches!(
+            plan_path(&from_joint, &vec3(-0.8, 0.8, 0.0), &params, None, MoveOrdering::Simultaneous),
+            Err(StateHandlerError::InvalidWorldCoordinates(_))
+        );
+        // the volume reaches above the arm, so there is no way to go over it
+        assert_matches!(
+            plan_path(&from_joint, &vec3(1.5, 0.0, -0.5), &params, None, MoveOrdering::Simultaneous),
+            Err(StateHandlerError::PathBlocked { .. })
+        );
+        assert_matches!(
+            plan_path(&from_joint, &vec3(1.0, 0.0, -0.5), &params, None, MoveOrdering::ZUpFirst),
+            Err(StateHandlerError::PathBlocked { .. })
+        );
+    }
+
+    #[test]
+    fn z_up_first_goes_to_safe_travel_height() {
+        let params = Parameters { safe_travel_height: -0.1, ..params_1_3(vec![]) };
+        let from_joint = world_to_joint(&vec3(0.5, 0.0, -0.5), &params, None).unwrap();
+        let to = vec3(1.5, 0.3, -0.4);
+        let waypoints = plan_path(&from_joint, &to, &params, None, MoveOrdering::ZUpFirst).unwrap();
+        assert_reaches(&from_joint, &to, &waypoints, &params);
+        assert_eq!(-0.1, waypoints[0].z);
+        let max_z = waypoints.iter().map(|waypoint| waypoint.z).fold(f32::MIN, f32::max);
+        assert_eq!(-0.1, max_z);
+    }
+
+    #[test]
+    fn random_paths_avoid_obstacles() {
+        let mut rng = StdRng::from_seed([42; 32]);
+        for _ in 0..200 {
+            let mut params = params_1_3(vec![]);
+            for _ in 0..rng.random_range(0..4) {
+                let corner = random_world_point(&mut rng, &params);
+                let size = vec3(rng.random_range(0.05..0.5), rng.random_range(0.05..0.5), rng.random_range(0.05..0.5));
+                // all volumes are below the safe travel height
+                let max = vec3(corner.x + size.x, corner.y + size.y, (corner.z + size.z).min(-0.05));
+                params.keep_out_volumes.push(volume(corner, max));
+            }
+            let from_joint = world_to_joint(&random_world_point(&mut rng, &params), &params, None).unwrap();
+            let to = random_world_point(&mut rng, &params);
+
+            match plan_path(&from_joint, &to, &params, None, MoveOrdering::Simultaneous) {
+                Ok(waypoints) => {
+                    assert_reaches(&from_joint, &to, &waypoints, &params);
+                    let from = joint_to_world(&from_joint, &params, None);
+                    let volumes: Vec<_> = params.keep_out_volumes.iter()
+                        .filter(|volume| !collides(&from, volume, 0.0))
+                        .collect();
+                    for sample in sample_motion(&from_joint, &waypoints, &params) {
+                        assert!(world_to_joint(&sample, &params, None).is_some(), "{sample:?} is not reachable");
+                        for volume in &volumes {
+                            assert!(!collides(&sample, volume, 0.0), "{sample:?} collides with {volume:?}");
+                        }
+                    }
+                }
+                Err(StateHandlerError::PathBlocked { .. }) => {
+                    // only possible if the target itself is blocked, since the volumes are all
+                    // below the safe travel height
+                    assert!(
+                        params.keep_out_volumes.iter().any(|volume| collides(&to, volume, 0.0)),
+                        "Path from {from_joint:?} to {to:?} unexpectedly blocked: {params:?}"
+                    );
+                }
+                Err(e) => panic!("Unexpected error {e:?} from {from_joint:?} to {to:?}"),
+            }
+        }
+    }
+
+    #[test]
+    fn random_paths_across_rail_end_flip() {
+        let mut rng = StdRng::from_seed([43; 32]);
+        let params = params_1_3(vec![]);
+        for _ in 0..200 {
+            let from = random_world_point(&mut rng, &params);
+            let to = random_world_point(&mut rng, &params);
+            let from_joint = world_to_joint(&from, &params, None).unwrap();
+            let waypoints = plan_path(&from_joint, &to, &params, None, MoveOrdering::Simultaneous).unwrap();
+            assert_reaches(&from_joint, &to, &waypoints, &params);
+
+            // consecutive samples of the motion are close to each other, except when the arm
+            // flips at the end of the rail (which is checked for collisions separately)
+            let flips = sample_motion(&from_joint, &waypoints, &params)
+                .windows(2)
+                .filter(|samples| distance(&samples[0], &samples[1]) > SAMPLE_DISTANCE * 5.0)
+                .count();
+            let crosses_flip = (from.x >= 2.0) != (to.x >= 2.0);
+            assert!(flips == 0 || crosses_flip, "Unexpected jump from {from:?} to {to:?}");
+        }
+    }
+}
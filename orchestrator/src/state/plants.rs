@@ -0,0 +1,150 @@
+use std::{fs::create_dir_all, path::{Path, PathBuf}, sync::{Arc, Mutex}};
+
+use definitions::{Plant, PlantData, PlantId};
+use serde::{Deserialize, Serialize};
+
+use crate::util::serde::{deserialize_from_json_file, serialize_to_json_file_pretty};
+
+const PLANTS_FILE: &str = "plants.json";
+const PLANTS_BACKUP_FILE: &str = "plants.json.bak";
+
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+struct PlantsData {
+    id_counter: PlantId,
+    plants: Vec<Plant>,
+}
+
+/// Keeps track of the plants in the garden, and persists them in `${data_dir}/plants.json` after
+/// every change. Can be cloned cheaply, and all clones refer to the same registry.
+#[derive(Debug, Clone)]
+pub struct PlantRegistry {
+    data: Arc<Mutex<PlantsData>>,
+    data_dir: PathBuf,
+}
+
+impl PlantRegistry {
+    /// Loads the registry from `${data_dir}/plants.json`, or starts with an empty registry if the
+    /// file does not exist. If the file can't be read, it is backupped before starting from scratch.
+    pub fn load_from_disk(data_dir: &Path) -> PlantRegistry {
+        let plants_file = &data_dir.join(PLANTS_FILE);
+        let data = if plants_file.exists() {
+            match deserialize_from_json_file(plants_file) {
+                Ok(data) => data,
+                Err(e) => {
+                    let plants_backup_file = &data_dir.join(PLANTS_BACKUP_FILE);
+                    log::error!("Could not read plants from {plants_file:?}, starting with no plants: {e}");
+                    log::error!("Backupping {plants_file:?} file to {plants_backup_file:?}...");
+                    if let Err(e) = std::fs::rename(plants_file, plants_backup_file) {
+                        log::error!("Could not create backup {plants_backup_file:?}: {e:?}");
+                    }
+                    PlantsData::default()
+                }
+            }
+        } else {
+            PlantsData::default()
+        };
+
+        PlantRegistry {
+            data: Arc::new(Mutex::new(data)),
+            data_dir: data_dir.to_path_buf(),
+        }
+    }
+
+    fn save_to_disk(&self, data: &PlantsData) -> Result<(), String> {
+        create_dir_all(&self.data_dir)
+            .map_err(|e| format!("Could not create data directory {:?}: {e:?}", self.data_dir))?;
+        let plants_file = &self.data_dir.join(PLANTS_FILE);
+        serialize_to_json_file_pretty(data, plants_file)
+            .map_err(|e| format!("Could not serialize and save plants to {plants_file:?}: {e}"))
+    }
+
+    /// Applies `f` to a copy of the data, and replaces the data with the copy only if the copy
+    /// could be saved to disk, so that the registry and the file never diverge.
+    fn mutate_and_save<R>(&self, f: impl FnOnce(&mut PlantsData) -> R) -> Result<R, String> {
+        let mut data = self.data.lock().unwrap();
+        let mut new_data = data.clone();
+        let res = f(&mut new_data);
+        self.save_to_disk(&new_data)?;
+        *data = new_data;
+        Ok(res)
+    }
+
+    pub fn get_all(&self) -> Vec<Plant> {
+        self.data.lock().unwrap().plants.clone()
+    }
+
+    pub fn get(&self, id: PlantId) -> Option<Plant> {
+        self.data.lock().unwrap().plants.iter().find(|plant| plant.id == id).cloned()
+    }
+
+    pub fn add(&self, data: PlantData) -> Result<Plant, String> {
+        self.mutate_and_save(|plants| {
+            let plant = Plant { id: plants.id_counter, data };
+            plants.id_counter += 1;
+            plants.plants.push(plant.clone());
+            plant
+        })
+    }
+
+    /// Returns `None` if there is no plant with the provided `id`.
+    pub fn update(&self, id: PlantId, data: PlantData) -> Result<Option<Plant>, String> {
+        self.mutate_and_save(|plants| {
+            let plant = plants.plants.iter_mut().find(|plant| plant.id == id)?;
+            plant.data = data;
+            Some(plant.clone())
+        })
+    }
+
+    /// Returns `false` if there is no plant with the provided `id`.
+    pub fn remove(&self, id: PlantId) -> Result<bool, String> {
+        self.mutate_and_save(|plants| {
+            let len_before = plants.plants.len();
+            plants.plants.retain(|plant| plant.id != id);
+            plants.plants.len() != len_before
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::NaiveDate;
+    use definitions::{PlantData, Vec3};
+
+    use super::PlantRegistry;
+
+    fn plant_data(name: &str) -> PlantData {
+        PlantData {
+            name: name.to_string(),
+            species: "Solanum lycopersicum".to_string(),
+            position: Vec3 { x: 1.0, y: 0.5, z: -0.3 },
+            watering_duration_ms: 5000,
+            planted_date: NaiveDate::from_ymd_opt(2025, 4, 25).unwrap(),
+        }
+    }
+
+    #[test]
+    fn plant_registry_test() {
+        let data_dir = tempdir::TempDir::new("cyberorto_test").unwrap();
+        let registry = PlantRegistry::load_from_disk(data_dir.path());
+        assert!(registry.get_all().is_empty());
+
+        let a = registry.add(plant_data("a")).unwrap();
+        let b = registry.add(plant_data("b")).unwrap();
+        assert_ne!(a.id, b.id);
+        assert_eq!(Some(a.clone()), registry.get(a.id));
+
+        let b = registry.update(b.id, plant_data("c")).unwrap().unwrap();
+        assert_eq!("c", b.data.name);
+        assert_eq!(None, registry.update(42, plant_data("d")).unwrap());
+
+        assert!(registry.remove(a.id).unwrap());
+        assert!(!registry.remove(a.id).unwrap());
+        assert_eq!(vec![b.clone()], registry.get_all());
+
+        // the registry is persisted and ids are never reused
+        let registry = PlantRegistry::load_from_disk(data_dir.path());
+        assert_eq!(vec![b.clone()], registry.get_all());
+        let c = registry.add(plant_data("e")).unwrap();
+        assert!(c.id > b.id);
+    }
+}
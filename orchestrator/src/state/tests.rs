@@ -1,12 +1,12 @@
 #![cfg(test)]
 
-use embedcore::protocol::{cyber::{Message, Slave}, test_harness::{new_testable_slave, MessageRecorderSlave}};
+use embedcore::protocol::{cyber::{Actuator, Capabilities, Event, Message, MessagesHandler, PeripheralsState, Response, Role, Slave}, test_harness::{new_testable_slave, MessageRecorderSlave}};
 use futures::future::{BoxFuture, FutureExt};
 use tokio::sync::oneshot;
 
-use std::thread::JoinHandle;
+use std::{assert_matches::assert_matches, future::Future, thread::JoinHandle, time::Duration};
 
-use super::*;
+use super::{dummy_message_handler::DummyMessageHandler, kinematics::world_to_joint, *};
 
 const FAKE_BOT_NAME: &[u8; 10] = b"test fake ";
 
@@ -14,16 +14,17 @@ pub struct TestState {
     pub state_handler: StateHandler,
     pub slave_bot_join_handle: JoinHandle<()>,
     pub slave_bot_killer: oneshot::Sender<()>,
+    /// The messages received by the peripherals slave.
     pub slave_bot_data: Arc<Mutex<MessageRecorderSlave>>,
+    /// The messages received by the x, y and z motor slaves, respectively. Each one has its own
+    /// slave so that they can keep track of their own position.
+    pub motor_bots_data: [Arc<Mutex<MessageRecorderSlave>>; 3],
 }
 
-pub fn get_test_state() -> TestState {
-    let (master, slave) = SerialStream::pair().expect("Unable to create tty pair");
-
-    let mut slave_bot: Slave<SerialStream, Arc<Mutex<MessageRecorderSlave>>> =
-        new_testable_slave(slave, *FAKE_BOT_NAME);
-    let slave_bot_data = slave_bot.message_handler.clone();
-
+/// Runs `slave_bots` on their own thread, until something is sent to the returned killer.
+fn spawn_slave_bots<H: MessagesHandler + Send + 'static>(
+    mut slave_bots: Vec<Slave<SerialStream, H>>,
+) -> (JoinHandle<()>, oneshot::Sender<()>) {
     let (slave_bot_killer_tx, slave_bot_killer_rx) = oneshot::channel();
     let slave_bot_join_handle = std::thread::spawn(move || {
         tokio::runtime::Builder::new_current_thread()
@@ -32,24 +33,46 @@ pub fn get_test_state() -> TestState {
             .unwrap()
             .block_on(async {
                 tokio::select! {
-                    _ = slave_bot.run() => {}
+                    _ = future::join_all(slave_bots.iter_mut().map(|slave_bot| slave_bot.run())) => {}
                     _ = slave_bot_killer_rx => {}
                 }
             });
     });
+    (slave_bot_join_handle, slave_bot_killer_tx)
+}
 
+fn new_test_master(serial: SerialStream) -> Arc<Master<SerialStream>> {
     // We need a significantly high timeout here, since other async tests might be running in the
     // background on the same thread and they might be scheduled before slave and master manage to
     // exchange data. Keep resend times to 1 though since we shouldn't have errors.
-    let master = Arc::new(Master::new(master, Duration::from_millis(100), 1));
+    Arc::new(Master::new(serial, Duration::from_millis(100), 1))
+}
+
+pub fn get_test_state() -> TestState {
+    let mut masters = vec![];
+    let mut slave_bots = vec![];
+    for _ in 0..4 {
+        let (master, slave) = SerialStream::pair().expect("Unable to create tty pair");
+        masters.push(new_test_master(master));
+        let slave_bot: Slave<SerialStream, Arc<Mutex<MessageRecorderSlave>>> =
+            new_testable_slave(slave, *FAKE_BOT_NAME);
+        slave_bots.push(slave_bot);
+    }
+    let [x, y, z, peripherals] = <[_; 4]>::try_from(masters).unwrap();
+    let [motor_x_data, motor_y_data, motor_z_data, slave_bot_data] =
+        std::array::from_fn(|i| slave_bots[i].message_handler.clone());
+    let (slave_bot_join_handle, slave_bot_killer_tx) = spawn_slave_bots(slave_bots);
+
     TestState {
         state_handler: StateHandler::new(
-            Masters { x: master.clone(), y: master.clone(), z: master.clone(), peripherals: master },
+            Masters { x, y, z, peripherals },
             Parameters::default(),
+            PlantRegistry::load_from_disk(&tempdir::TempDir::new("cyberorto_test").unwrap().into_path()),
         ),
         slave_bot_join_handle,
         slave_bot_killer: slave_bot_killer_tx,
         slave_bot_data,
+        motor_bots_data: [motor_x_data, motor_y_data, motor_z_data],
     }
 }
 
@@ -67,6 +90,29 @@ pub async fn test_with_state(f: impl Fn(&'_ mut TestState) -> BoxFuture<'_, ()>)
         .expect("Could not join slave bot");
 }
 
+/// Like [test_with_state], but the slave is a [DummyMessageHandler] (e.g. with a simulated water
+/// tank), and all parameters are the default ones except for `parameters`.
+pub async fn test_with_dummy_state<F, Fut>(dummy: DummyMessageHandler, parameters: Parameters, f: F)
+where
+    F: FnOnce(StateHandler) -> Fut,
+    Fut: Future<Output = ()>,
+{
+    let (master, slave) = SerialStream::pair().expect("Unable to create tty pair");
+    let slave_bot = Slave::new(slave, *FAKE_BOT_NAME, Capabilities::for_roles(&Role::ALL), dummy);
+    let (slave_bot_join_handle, slave_bot_killer_tx) = spawn_slave_bots(vec![slave_bot]);
+
+    let master = new_test_master(master);
+    let state_handler = StateHandler::new(
+        Masters { x: master.clone(), y: master.clone(), z: master.clone(), peripherals: master },
+        parameters,
+        PlantRegistry::load_from_disk(&tempdir::TempDir::new("cyberorto_test").unwrap().into_path()),
+    );
+    f(state_handler).await;
+
+    slave_bot_killer_tx.send(()).expect("Could not send kill signal to slave bot");
+    slave_bot_join_handle.join().expect("Could not join slave bot");
+}
+
 macro_rules! test_with_state {
     (async fn $test_name:ident ($state:ident: &mut TestState) $content:block ) => {
         #[tokio::test]
@@ -88,3 +134,234 @@ test_with_state!(
         assert_eq!(messages, s.slave_bot_data.lock().unwrap().incoming);
     }
 );
+
+#[tokio::test]
+async fn test_water_liters() {
+    test_with_dummy_state(DummyMessageHandler::new().0, Parameters::default(), |state_handler| async move {
+        let before = state_handler.try_update_state().await.water_level.liters;
+        state_handler.water_liters(0.3).await.unwrap();
+        let after = state_handler.try_update_state().await;
+        assert!(!after.actuators.water);
+        let delivered = before - after.water_level.liters;
+        // the scale is polled periodically, so a bit more water may flow
+        assert!((0.3..0.4).contains(&delivered), "Delivered {delivered} liters instead of 0.3");
+    }).await;
+}
+
+#[tokio::test]
+async fn test_water_liters_empty_tank() {
+    let parameters = Parameters { water_no_flow_timeout_ms: 300, ..Default::default() };
+    let dummy = DummyMessageHandler::new().0.with_water_liters(0.1);
+    test_with_dummy_state(dummy, parameters, |state_handler| async move {
+        let res = state_handler.water_liters(1.0).await;
+        assert_matches!(
+            res,
+            Err(StateHandlerError::WaterNotFlowing { requested_liters: 1.0, delivered_liters })
+                if (0.05..0.15).contains(&delivered_liters)
+        );
+        assert!(!state_handler.try_update_state().await.actuators.water);
+    }).await;
+}
+
+fn motor_moves(motor_bot_data: &Arc<Mutex<MessageRecorderSlave>>) -> Vec<f32> {
+    motor_bot_data.lock().unwrap().incoming
+        .iter()
+        .filter_map(|message| match message {
+            Message::MoveMotor { x } => Some(*x),
+            _ => None,
+        })
+        .collect()
+}
+
+test_with_state!(
+    async fn test_move_to_simultaneous(s: &mut TestState) {
+        s.state_handler.move_to(1.0, 0.5, -0.3, MoveOrdering::Simultaneous).await.unwrap();
+
+        let state = s.state_handler.get_state();
+        let joint = world_to_joint(&Vec3 { x: 1.0, y: 0.5, z: -0.3 }, &state.parameters, None).unwrap();
+        assert_eq!(joint, state.position_joint);
+        // the arm rotates, so the path is split into multiple waypoints to follow a straight line
+        assert_eq!(Some(&joint.x), motor_moves(&s.motor_bots_data[0]).last());
+        assert_eq!(Some(&joint.y), motor_moves(&s.motor_bots_data[1]).last());
+        assert_eq!(Some(&joint.z), motor_moves(&s.motor_bots_data[2]).last());
+        // the position of each motor was polled at least once after moving
+        for motor_bot_data in &s.motor_bots_data {
+            assert_eq!(Some(&Message::GetMotorState), motor_bot_data.lock().unwrap().incoming.last());
+        }
+    }
+);
+
+test_with_state!(
+    async fn test_move_to_z_up_first(s: &mut TestState) {
+        s.state_handler.move_to(1.0, 0.5, -0.3, MoveOrdering::Simultaneous).await.unwrap();
+        let moves_before = s.motor_bots_data.each_ref().map(|data| motor_moves(data).len());
+        s.state_handler.move_to(1.5, -0.5, -0.2, MoveOrdering::ZUpFirst).await.unwrap();
+
+        let state = s.state_handler.get_state();
+        let joint = world_to_joint(&Vec3 { x: 1.5, y: -0.5, z: -0.2 }, &state.parameters, None).unwrap();
+        assert_eq!(joint, state.position_joint);
+        let [x_moves, y_moves, z_moves] = [0, 1, 2]
+            .map(|i| motor_moves(&s.motor_bots_data[i])[moves_before[i]..].to_vec());
+        assert_eq!(Some(&joint.x), x_moves.last());
+        assert_eq!(Some(&joint.y), y_moves.last());
+        // the safe travel height is 0 by default, and Z does not move while traveling
+        assert_eq!(vec![0.0, joint.z], z_moves);
+    }
+);
+
+test_with_state!(
+    async fn test_motor_error(s: &mut TestState) {
+        s.motor_bots_data[1].lock().unwrap().motor_error = Some(DeviceError::Stall { steps: Some(42) });
+        let res = s.state_handler.move_to(1.0, 0.5, -0.3, MoveOrdering::Simultaneous).await;
+        assert_matches!(
+            res,
+            Err(StateHandlerError::Device {
+                error: DeviceError::Stall { steps: Some(42) },
+                device_name: "motor_y",
+                function_call: "get_motor_state",
+            })
+        );
+        let errors = s.state_handler.try_update_state().await.errors;
+        assert_eq!(None, errors.motor_x);
+        assert_eq!(Some("Stall { steps: Some(42) }".to_string()), errors.motor_y);
+    }
+);
+
+test_with_state!(
+    async fn test_move_to_resetting_on_stall(s: &mut TestState) {
+        s.motor_bots_data[1].lock().unwrap().motor_error = Some(DeviceError::Stall { steps: None });
+        let res = s.state_handler.move_to_resetting_on_stall(1.0, 0.5, -0.3, MoveOrdering::Simultaneous).await;
+        assert_matches!(res, Err(StateHandlerError::Device { error: DeviceError::Stall { steps: None }, .. }));
+        for motor in &s.motor_bots_data {
+            assert!(motor.lock().unwrap().incoming.contains(&Message::ResetMotor));
+        }
+    }
+);
+
+test_with_state!(
+    async fn test_device_events(s: &mut TestState) {
+        let mut events = s.state_handler.subscribe_events();
+        s.motor_bots_data[0].lock().unwrap().pending_events.push_back(Event::Error(DeviceError::DriverFault));
+        s.slave_bot_data.lock().unwrap().pending_events.push_back(Event::CooldownExpired(Actuator::Water));
+
+        let mut received = Vec::new();
+        for _ in 0..100 {
+            received.extend(s.state_handler.receive_events(&mut events, Duration::from_millis(10)).await);
+            if received.len() >= 2 {
+                break;
+            }
+        }
+        let summary: Vec<(&str, DeviceEventKind)> = received.iter().map(|e| (e.device.as_str(), e.kind)).collect();
+        assert_eq!(vec![("motor_x", DeviceEventKind::DriverFault), ("peripherals", DeviceEventKind::CooldownExpired)], summary);
+        assert_eq!("CooldownExpired(Water)", received[1].description);
+        assert_eq!(received, s.state_handler.get_state().events);
+    }
+);
+
+test_with_state!(
+    async fn test_heartbeat(s: &mut TestState) {
+        s.state_handler.heartbeat().await.unwrap();
+        // heartbeats are handled by the slaves directly, without reaching the message handlers
+        assert!(s.slave_bot_data.lock().unwrap().incoming.is_empty());
+        assert_eq!(None, s.state_handler.get_state().errors.peripherals);
+    }
+);
+
+#[tokio::test]
+async fn test_dummy_link_lost() {
+    let (mut dummy, motor) = DummyMessageHandler::new();
+    dummy.water(100000).await;
+    dummy.pump(100000).await;
+    dummy.move_motor(1.0).await;
+    dummy.on_link_lost().await;
+
+    assert_matches!(
+        dummy.get_peripherals_state().await,
+        Response::PeripheralsState(PeripheralsState { water: false, lights: false, pump: false, plow: false, .. })
+    );
+    // the motor did not move in the meantime, so it stays where it is
+    let motor = motor.lock().await;
+    assert_eq!(0.0, motor.pid.setpoint);
+}
+
+#[test]
+fn test_error_response() {
+    let error = CommunicationError::ErrorResponse(DeviceError::InvalidArgument { value: Some(-1) });
+    assert_matches!(
+        StateHandlerError::from_communication(error, "peripherals", "water"),
+        StateHandlerError::Device {
+            error: DeviceError::InvalidArgument { value: Some(-1) },
+            device_name: "peripherals",
+            function_call: "water",
+        }
+    );
+    assert_matches!(
+        StateHandlerError::from_communication(CommunicationError::Timeout, "peripherals", "water"),
+        StateHandlerError::Communication { error: CommunicationError::Timeout, .. }
+    );
+}
+
+test_with_state!(
+    async fn test_move_to_timeout(s: &mut TestState) {
+        s.state_handler.set_parameters(Parameters { move_timeout_ms: 200, ..Default::default() });
+        s.motor_bots_data[1].lock().unwrap().motor_stuck = true;
+        let res = s.state_handler.move_to(1.0, 0.5, -0.3, MoveOrdering::Simultaneous).await;
+        let Err(StateHandlerError::MoveTimeout { position_joint, target_joint }) = res else {
+            panic!("Expected a move timeout, got {res:?}");
+        };
+        assert_eq!(0.0, position_joint.y);
+        assert!(target_joint.y != 0.0);
+
+        // the motors are told to hold where they are, so the stuck one does not try to move forever
+        assert_eq!(vec![target_joint.x, target_joint.x], motor_moves(&s.motor_bots_data[0]));
+        assert_eq!(vec![target_joint.y, 0.0], motor_moves(&s.motor_bots_data[1]));
+        assert_eq!(vec![target_joint.z, target_joint.z], motor_moves(&s.motor_bots_data[2]));
+    }
+);
+
+test_with_state!(
+    async fn test_tools(s: &mut TestState) {
+        let plow = definitions::Tool {
+            name: "plow".to_string(),
+            offset: Vec3 { x: 0.1, y: 0.0, z: -0.2 },
+            peripheral: Some(Peripheral::Plow),
+            hardware_id: Some(3),
+        };
+        s.state_handler.set_parameters(Parameters { tools: vec![plow.clone()], ..Default::default() });
+
+        // the plow can't be used until the plow tool is selected
+        assert_matches!(
+            s.state_handler.plow(1000).await,
+            Err(StateHandlerError::WrongTool { peripheral: Peripheral::Plow, tool: None })
+        );
+        s.state_handler.plow(0).await.unwrap();
+        assert_matches!(s.state_handler.set_tool(Some("shovel".to_string())), Err(StateHandlerError::UnknownTool(_)));
+
+        // the tool has not been attached yet
+        assert_matches!(
+            s.state_handler.change_tool(Some("plow".to_string())).await,
+            Err(StateHandlerError::ToolNotAttached { attached: None, .. })
+        );
+        s.slave_bot_data.lock().unwrap().attached_tool = Some(3);
+        s.state_handler.change_tool(Some("plow".to_string())).await.unwrap();
+        s.state_handler.plow(1000).await.unwrap();
+
+        // positions now refer to the tip of the tool
+        let state = s.state_handler.get_state();
+        assert_eq!(Some("plow".to_string()), state.tool);
+        let joint = state.position_joint.clone();
+        assert_eq!(joint_to_world(&joint, &state.parameters, Some(&plow)), state.position);
+        s.state_handler.move_to(1.0, 0.5, -0.3, MoveOrdering::Simultaneous).await.unwrap();
+        let state = s.state_handler.get_state();
+        let joint = world_to_joint(&Vec3 { x: 1.0, y: 0.5, z: -0.3 }, &state.parameters, Some(&plow)).unwrap();
+        assert_eq!(joint, state.position_joint);
+
+        // a tool that is physically attached can't be deselected
+        assert_matches!(
+            s.state_handler.change_tool(None).await,
+            Err(StateHandlerError::ToolNotAttached { tool: None, attached: Some(3) })
+        );
+        s.state_handler.set_tool(None).unwrap();
+        assert_eq!(None, s.state_handler.get_state().tool);
+    }
+);
@@ -1,15 +1,50 @@
-use std::{fs::File, path::Path};
+use std::{fs::{File, OpenOptions}, io::{BufWriter, Write}, path::Path};
 
 use serde::{de::DeserializeOwned, Serialize};
 
+/// Writes the JSON atomically, see [write_file_atomically].
 pub fn serialize_to_json_file<T: Serialize>(v: &T, file: &Path) -> Result<(), String> {
-    let file = File::create(file).map_err(|e| e.to_string())?;
-    serde_json::to_writer(file, v).map_err(|e| e.to_string())
+    write_file_atomically(file, |writer| serde_json::to_writer(writer, v).map_err(|e| e.to_string()))
 }
 
+/// Writes the pretty-printed JSON atomically, see [write_file_atomically].
 pub fn serialize_to_json_file_pretty<T: Serialize>(v: &T, file: &Path) -> Result<(), String> {
-    let file = File::create(file).map_err(|e| e.to_string())?;
-    serde_json::to_writer_pretty(file, v).map_err(|e| e.to_string())
+    write_file_atomically(file, |writer| serde_json::to_writer_pretty(writer, v).map_err(|e| e.to_string()))
+}
+
+/// Writes to a temporary file first, and then renames it to `file`, so that `file` is never left
+/// half-written (e.g. if the orchestrator is killed or the power goes off while saving).
+fn write_file_atomically(
+    file: &Path,
+    write: impl FnOnce(&mut BufWriter<File>) -> Result<(), String>,
+) -> Result<(), String> {
+    let mut tmp_file_name = file.file_name().ok_or("Invalid file name")?.to_owned();
+    tmp_file_name.push(".tmp");
+    let tmp_file = file.with_file_name(tmp_file_name);
+
+    let mut writer = BufWriter::new(File::create(&tmp_file).map_err(|e| e.to_string())?);
+    write(&mut writer)?;
+    writer.flush().map_err(|e| e.to_string())?;
+    writer.get_ref().sync_all().map_err(|e| e.to_string())?;
+    drop(writer);
+
+    std::fs::rename(&tmp_file, file).map_err(|e| e.to_string())?;
+    // the rename itself is only durable once the directory is synced, too
+    if let Some(dir) = file.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+        File::open(dir).and_then(|dir| dir.sync_all()).map_err(|e| e.to_string())?;
+    }
+    Ok(())
+}
+
+/// Appends `v` as a single line of JSON to `file`, creating it if needed, and waits for the line
+/// to be written to the disk. If the orchestrator is killed in the meantime, at most the last line
+/// of `file` is left half-written.
+pub fn append_json_line<T: Serialize>(v: &T, file: &Path) -> Result<(), String> {
+    let mut line = serde_json::to_vec(v).map_err(|e| e.to_string())?;
+    line.push(b'\n');
+    let mut file = OpenOptions::new().create(true).append(true).open(file).map_err(|e| e.to_string())?;
+    file.write_all(&line).map_err(|e| e.to_string())?;
+    file.sync_data().map_err(|e| e.to_string())
 }
 
 pub fn deserialize_from_json_file<T: DeserializeOwned>(file: &Path) -> Result<T, String> {
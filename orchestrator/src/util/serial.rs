@@ -1,6 +1,6 @@
 use std::{path::Path, process::exit, sync::Arc, time::Duration};
 
-use embedcore::{common::controllers::pid::CalibrationMode, protocol::cyber::{DeviceIdentifier, Master, Slave}};
+use embedcore::{common::controllers::pid::CalibrationMode, protocol::cyber::{Capabilities, DeviceIdentifier, Master, Role, Slave}};
 use log::debug;
 use rocket::futures::never::Never;
 use tokio::task::JoinHandle;
@@ -11,6 +11,9 @@ use crate::state::dummy_message_handler::DummyMessageHandler;
 
 const TIMEOUT: Duration = Duration::from_millis(100);
 const RESEND_TIMES: u8 = 20;
+/// Like real devices, simulated ones turn everything off if they don't receive any message for
+/// this long, so `--heartbeat-period-ms` must be shorter.
+const SIMULATED_FAILSAFE_MS: u64 = 2000;
 
 
 #[derive(Debug, Clone)]
@@ -99,14 +102,18 @@ impl SerialPorts {
 
         fn set_var(
             var: &mut Option<Arc<Master<SerialStream>>>,
-            capability: char,
+            role: Role,
             port: &str,
             master: &Arc<Master<SerialStream>>,
             id: &DeviceIdentifier,
         ) {
-            if id.name.contains(&(capability as u8)) {
+            if id.capabilities.has_role(role) {
+                if let Some(message) = role.messages().iter().find(|m| !id.capabilities.supports(m)) {
+                    eprintln!("Error: Serial device on {port} has role {role:?} but does not support {message:?}, its identifier is {id:?}");
+                    exit(1);
+                }
                 if var.is_some() {
-                    eprintln!("Error: Two serial devices say they can handle capability \"{capability}\", the last of which was {port}, whose identifier is {id:?}");
+                    eprintln!("Error: Two serial devices say they have role {role:?}, the last of which was {port}, whose identifier is {id:?}");
                     exit(1);
                 }
                 *var = Some(master.clone());
@@ -164,10 +171,10 @@ impl SerialPorts {
             eprintln!("Info: Obtained device identifier from port {port}: {id:?}");
 
             let master = Arc::new(master);
-            set_var(&mut x, 'x', port, &master, &id);
-            set_var(&mut y, 'y', port, &master, &id);
-            set_var(&mut z, 'z', port, &master, &id);
-            set_var(&mut peripherals, 'p', port, &master, &id);
+            set_var(&mut x, Role::MotorX, port, &master, &id);
+            set_var(&mut y, Role::MotorY, port, &master, &id);
+            set_var(&mut z, Role::MotorZ, port, &master, &id);
+            set_var(&mut peripherals, Role::Peripherals, port, &master, &id);
         }
 
         MastersOpt { x, y, z, peripherals }
@@ -175,11 +182,11 @@ impl SerialPorts {
 }
 
 impl MastersOpt {
-    fn assert_some(var: Option<Arc<Master<SerialStream>>>, capability: char) -> Arc<Master<SerialStream>> {
+    fn assert_some(var: Option<Arc<Master<SerialStream>>>, role: Role) -> Arc<Master<SerialStream>> {
         match var {
             Some(var) => var,
             None => {
-                eprintln!("Error: No serial device can handle capability \"{capability}\"");
+                eprintln!("Error: No serial device has role {role:?}");
                 exit(1);
             },
         }
@@ -187,10 +194,10 @@ impl MastersOpt {
 
     fn into_masters(self) -> Masters {
         Masters {
-            x: Self::assert_some(self.x, 'x'),
-            y: Self::assert_some(self.y, 'y'),
-            z: Self::assert_some(self.z, 'z'),
-            peripherals: Self::assert_some(self.peripherals, 'p'),
+            x: Self::assert_some(self.x, Role::MotorX),
+            y: Self::assert_some(self.y, Role::MotorY),
+            z: Self::assert_some(self.z, Role::MotorZ),
+            peripherals: Self::assert_some(self.peripherals, Role::Peripherals),
         }
     }
 
@@ -198,11 +205,11 @@ impl MastersOpt {
         let mut masters = vec![];
         let mut motors = vec![];
         let mut join_handles = vec![];
-        for (name, opt_master) in [
-            (b"x         ", self.x),
-            (b"y         ", self.y),
-            (b"z         ", self.z),
-            (b"p         ", self.peripherals),
+        for (role, name, opt_master) in [
+            (Role::MotorX, b"x         ", self.x),
+            (Role::MotorY, b"y         ", self.y),
+            (Role::MotorZ, b"z         ", self.z),
+            (Role::Peripherals, b"p         ", self.peripherals),
         ] {
             if let Some(master) = opt_master {
                 masters.push(master);
@@ -215,11 +222,12 @@ impl MastersOpt {
             masters.push(Arc::new(Master::new(master, TIMEOUT, RESEND_TIMES)));
 
             let (dummy_message_handler, motor) = DummyMessageHandler::new();
-            let mut slave = Slave::new(slave, *name, dummy_message_handler);
+            let mut slave = Slave::new(slave, *name, Capabilities::for_roles(&[role]), dummy_message_handler)
+                .with_failsafe(SIMULATED_FAILSAFE_MS);
             // TODO if the simulated serial hangs, the slave will not recover
             // (should not happen though).
             join_handles.push(tokio::task::spawn(async move { slave.run().await }));
-            if name != b"p         " {
+            if role != Role::Peripherals {
                 // the last device is not a motor but a peripheral
                 motors.push(motor);
             }
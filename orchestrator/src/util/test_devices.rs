@@ -1,6 +1,6 @@
 use std::time::Duration;
 
-use embedcore::protocol::cyber::{DeviceIdentifier, Master, Message, Response};
+use embedcore::protocol::cyber::{Capabilities, DeviceIdentifier, Master, Message, Request, Response, Role, SlaveMessage, FIRMWARE_HASH, PROTOCOL_VERSION};
 use serialmessage::{ParseState, SerMsg};
 use tokio_serial::{SerialPortBuilderExt, SerialStream};
 
@@ -51,7 +51,7 @@ async fn open_port(port: &str) -> Option<tokio_serial::SerialStream> {
 async fn send_who_are_you_raw(serial_port: &mut tokio_serial::SerialStream) {
     println!("Sending {:?} message with id {ID} manually", Message::WhoAreYou);
     let mut buf: [u8; 50] = [0; 50];
-    let msg = match postcard::to_slice(&Message::WhoAreYou, &mut buf) {
+    let msg = match postcard::to_slice(&Request { seq: ID as u32, message: Message::WhoAreYou }, &mut buf) {
         Ok(msg) => msg,
         Err(e) => {
             println!("\x1b[31mpostcard::to_slice failed: {e}\x1b[0m");
@@ -95,7 +95,12 @@ async fn send_who_are_you_raw(serial_port: &mut tokio_serial::SerialStream) {
 async fn receive_i_am_raw(serial_port: &mut tokio_serial::SerialStream) {
     let sample_iam_message = {
         let mut buf: [u8; 50] = [0; 50];
-        let msg = postcard::to_slice(&Response::IAm(DeviceIdentifier { name: *b"x         ", version: 1 }), &mut buf).unwrap();
+        let msg = postcard::to_slice(&SlaveMessage::Response { seq: ID as u32, response: Response::IAm(DeviceIdentifier {
+            name: *b"x         ",
+            version: PROTOCOL_VERSION,
+            capabilities: Capabilities::for_roles(&[Role::MotorX]),
+            firmware_hash: FIRMWARE_HASH,
+        }) }, &mut buf).unwrap();
         let (buf, len) = SerMsg::create_msg_arr(msg, ID).unwrap();
         buf[..len].to_vec()
     };
@@ -150,8 +155,17 @@ async fn receive_i_am_raw(serial_port: &mut tokio_serial::SerialStream) {
         println!("\x1b[31mMismatched IDs: expected {ID}, got {id}\x1b[0m");
     }
 
-    let response = match postcard::from_bytes::<Response>(data) {
-        Ok(response) => response,
+    let response = match postcard::from_bytes::<SlaveMessage>(data) {
+        Ok(SlaveMessage::Response { seq, response }) => {
+            if seq != ID as u32 {
+                println!("\x1b[31mMismatched sequence numbers: expected {ID}, got {seq}\x1b[0m");
+            }
+            response
+        }
+        Ok(SlaveMessage::Event(event)) => {
+            println!("\x1b[31mReceived event {event:?} instead of a response\x1b[0m");
+            return;
+        }
         Err(e) => {
             println!("\x1b[31mCould not parse response: {e}\x1b[0m");
             return;
@@ -162,6 +176,9 @@ async fn receive_i_am_raw(serial_port: &mut tokio_serial::SerialStream) {
 
     if let Response::IAm(iam) = response {
         println!("\x1b[32mReceived Iam successfully: {iam:?}\x1b[0m");
+        if iam.version != PROTOCOL_VERSION {
+            println!("\x1b[31mThe device has protocol version {}, but the orchestrator expects {PROTOCOL_VERSION}\x1b[0m", iam.version);
+        }
     } else {
         println!("\x1b[31mDid not receive Iam as response\x1b[0m");
     }
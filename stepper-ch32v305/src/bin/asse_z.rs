@@ -32,7 +32,7 @@ use embedcore::{
     protocol::{
         AsyncSerial,
         communication::CommunicationError,
-        cyber::{DeviceIdentifier, Message, MessagesHandler, MotorState, Response, Slave},
+        cyber::{Capabilities, DeviceError, DeviceIdentifier, Message, MessagesHandler, MotorState, Response, Role, Slave},
     },
 };
 use qingke::riscv::register::satp::set;
@@ -42,9 +42,14 @@ use serialmessage::{ParseState, SerMsg};
 pub enum Cmd {
     Reset,
     MoveTo(i32),
+    /// Stop where the motor currently is, see [SerialToMotorHandler::on_link_lost].
+    Hold,
     Idle,
-    Error([u8; 10]),
+    Error(DeviceError),
 }
+
+/// If the orchestrator doesn't send anything for this long, the motor is stopped.
+const FAILSAFE_MS: u64 = 2000;
 struct Shared {
     pub cmd: Cmd,
     //pub reset: Bool,
@@ -103,6 +108,15 @@ impl MessagesHandler for SerialToMotorHandler {
         }
         Response::Ok
     }
+    async fn on_link_lost(&mut self) {
+        SHARED.lock(|shared| {
+            let mut shared = shared.borrow_mut();
+            // only interrupt moves, a pending reset or error must not be lost
+            if matches!(shared.cmd, Cmd::MoveTo(_)) {
+                shared.cmd = Cmd::Hold;
+            }
+        });
+    }
 }
 
 #[embassy_executor::task(pool_size = 3)]
@@ -134,6 +148,9 @@ async fn update_motor(
             Cmd::MoveTo(x) => {
                 motor.set_objective(x);
                 while (motor.motor.read() - x).abs() > 5 {
+                    if SHARED.lock(|shared| shared.borrow().cmd == Cmd::Hold) {
+                        break;
+                    }
                     motor.update().await;
                     Timer::after(Duration::from_micros(500)).await;
                     if instant.elapsed().as_millis() > 1000 {
@@ -144,6 +161,16 @@ async fn update_motor(
                     }
                 }
                 motor.update().await;
+                SHARED.lock(|shared| {
+                    let mut shared = shared.borrow_mut();
+                    if shared.cmd != Cmd::Hold {
+                        shared.cmd = Cmd::Idle;
+                    }
+                });
+            }
+            Cmd::Hold => {
+                let cur = motor.motor.read();
+                motor.set_objective(cur);
                 SHARED.lock(|x| {
                     x.borrow_mut().cmd = Cmd::Idle;
                 });
@@ -186,7 +213,8 @@ async fn main(spawner: Spawner) -> ! {
     // spawn message handler thread
     let serial_wrapper = SerialWrapper::new(serial, None);
     let s: Slave<SerialWrapper<'static, USART1>, _> =
-        Slave::new(serial_wrapper, *b"z         ", mh);
+        Slave::new(serial_wrapper, *b"z         ", Capabilities::for_roles(&[Role::MotorZ]), mh)
+            .with_failsafe(FAILSAFE_MS);
     spawner.must_spawn(message_handler(s));
 
     // setup encoder and decoder
@@ -22,7 +22,7 @@ use embedcore::{
     common::{
         controllers::pid::{CalibrationMode, PidController},
         motor::Motor,
-    }, protocol::cyber::{MessagesHandler, MotorState, Response, Slave}, EncoderTrait, SerialWrapper
+    }, protocol::cyber::{Capabilities, DeviceError, MessagesHandler, MotorState, Response, Role, Slave}, EncoderTrait, SerialWrapper
 };
 use defmt_or_log::info;
 
@@ -31,9 +31,14 @@ use defmt_or_log::info;
 pub enum Cmd {
     Reset,
     MoveTo(i32),
+    /// Stop where the motor currently is, see [SerialToMotorHandler::on_link_lost].
+    Hold,
     Idle,
-    Error([u8; 10]),
+    Error(DeviceError),
 }
+
+/// If the orchestrator doesn't send anything for this long, the motor is stopped.
+const FAILSAFE_MS: u64 = 2000;
 struct Shared {
     pub cmd: Cmd,
 }
@@ -90,6 +95,15 @@ impl MessagesHandler for SerialToMotorHandler {
         }
         Response::Ok
     }
+    async fn on_link_lost(&mut self) {
+        SHARED.lock(|shared| {
+            let mut shared = shared.borrow_mut();
+            // only interrupt moves, a pending reset or error must not be lost
+            if matches!(shared.cmd, Cmd::MoveTo(_)) {
+                shared.cmd = Cmd::Hold;
+            }
+        });
+    }
 }
 
 #[embassy_executor::task]
@@ -105,7 +119,8 @@ async fn main(spawner: Spawner) -> ! {
     // spawn message handler thread
     let serial_wrapper = serial(p.USART1, p.PA8, p.PB15, IrqsUsart, p.DMA1_CH4, p.DMA1_CH5);
     let mh = SerialToMotorHandler::new(None);//p.PA4.degrade());
-    let s: Slave<SerialWrapper<'static, USART1>, _> = Slave::new(serial_wrapper, *b"p         ", mh);
+    let s: Slave<SerialWrapper<'static, USART1>, _> = Slave::new(serial_wrapper, *b"p         ", Capabilities::for_roles(&[Role::Peripherals]), mh)
+        .with_failsafe(FAILSAFE_MS);
     spawner.must_spawn(message_handler(s));
 
     // setup motor
@@ -122,13 +137,26 @@ async fn main(spawner: Spawner) -> ! {
             Cmd::MoveTo(x) => {
                 pid.set_objective(x);
                 while (pid.motor.read() - x).abs() > 10 {
+                    if SHARED.lock(|shared| shared.borrow().cmd == Cmd::Hold) {
+                        break;
+                    }
                     pid.update().await;
                     embassy_futures::yield_now().await;
                 }
+                SHARED.lock(|shared| {
+                    let mut shared = shared.borrow_mut();
+                    if shared.cmd != Cmd::Hold {
+                        shared.cmd = Cmd::Idle;
+                    }
+                });
+
+            }
+            Cmd::Hold => {
+                let cur_pos = pid.motor.read();
+                pid.set_objective(cur_pos);
                 SHARED.lock(|x| {
                     x.borrow_mut().cmd = Cmd::Idle;
                 });
-
             }
             Cmd::Reset => {
                 let cur_pos = pid.motor.read();
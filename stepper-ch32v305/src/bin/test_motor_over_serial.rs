@@ -27,7 +27,7 @@ use embedcore::{
         },
         static_encoder::StaticEncoder,
     }, protocol::{
-        communication::CommunicationError, cyber::{DeviceIdentifier, Message, MessagesHandler, MotorState, Response, Slave}, AsyncSerial
+        communication::CommunicationError, cyber::{Capabilities, DeviceError, DeviceIdentifier, Message, MessagesHandler, MotorState, Response, Role, Slave}, AsyncSerial
     }, DiscreteDriver, Drv8843Pwm, EncoderTrait, SerialWrapper
 };
 use qingke::riscv::register::satp::set;
@@ -38,7 +38,7 @@ pub enum Cmd {
     Reset,
     MoveTo(i32),
     Idle,
-    Error([u8; 10]),
+    Error(DeviceError),
 }
 struct Shared {
     pub cmd: Cmd,
@@ -200,7 +200,7 @@ async fn main(spawner: Spawner) -> ! {
     // spawn message handler thread
     let serial_wrapper = SerialWrapper::new(serial, None);
     let s: Slave<SerialWrapper<'static, USART1>, _> =
-        Slave::new(serial_wrapper, *b"z         ", mh);
+        Slave::new(serial_wrapper, *b"z         ", Capabilities::for_roles(&[Role::MotorZ]), mh);
     spawner.must_spawn(message_handler(s));
 
     //spawner.must_spawn(update_motor(pid));